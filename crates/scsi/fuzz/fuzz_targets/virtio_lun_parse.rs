@@ -0,0 +1,7 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| vhost_device_scsi::fuzzing::virtio_lun_parse(data));
@@ -0,0 +1,591 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! A Unix socket for adjusting the device while it is running.
+//!
+//! The protocol is line based: every line sent by the client is a command, and
+//! gets a single line in response, which is either `OK` or `ERROR`, followed
+//! by a space and further information. The supported commands are:
+//!
+//! - `throttle <lun>`: show the I/O limits of a LUN.
+//! - `throttle <lun> <limits>`: update the I/O limits of a LUN, in the same
+//!   format as `--throttle` (e.g. `read-iops=100,write-bps=1048576`).
+//! - `stats [<lun>]`: show the I/O statistics of a LUN, or of all LUNs keyed
+//!   by LUN, as JSON.
+//! - `medium <lun>`: show the medium state of a removable LUN.
+//! - `medium <lun> eject`: remove the medium of a removable LUN.
+//! - `medium <lun> insert <image>`: replace the medium of a removable LUN with
+//!   the image file at the given path (which can't contain whitespace).
+//! - `snapshot <lun> <path>`: write a crash-consistent snapshot of the image of
+//!   a LUN to a new file at the given path, pausing the guest's requests in the
+//!   meantime. The response tells whether the image was reflinked or copied.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use log::{debug, error, info, warn};
+use thiserror::Error as ThisError;
+
+use crate::scsi::emulation::{
+    block_device::{BlockDeviceBackend, FileBackend},
+    encryption::{EncryptedBackend, XtsCipher},
+    fault_injection::{FaultInjectionBackend, FaultRule},
+    image_lock::ImageOptions,
+    medium::{MediumError, RemovableMedium},
+    snapshot,
+    stats::LunStats,
+    throttle::{Throttle, ThrottleLimitsParseError},
+};
+
+#[derive(Debug, ThisError, PartialEq, Eq)]
+pub(crate) enum ControlError {
+    #[error("Empty command")]
+    EmptyCommand,
+    #[error("Unknown command `{0}`")]
+    UnknownCommand(String),
+    #[error("Missing argument `{0}`")]
+    MissingArgument(&'static str),
+    #[error("Too many arguments")]
+    TooManyArguments,
+    #[error("Invalid LUN `{0}`")]
+    InvalidLun(String),
+    #[error("No such LUN {0}")]
+    NoSuchLun(u16),
+    #[error("Invalid limits: {0}")]
+    InvalidLimits(ThrottleLimitsParseError),
+    #[error("LUN {0} isn't removable")]
+    NotRemovable(u16),
+    #[error("Invalid medium action `{0}`")]
+    InvalidMediumAction(String),
+    #[error("Failed opening image {0}: {1}")]
+    FailedOpeningImage(String, String),
+    #[error("{0}")]
+    Medium(MediumError),
+    #[error("LUN {0} doesn't support snapshots")]
+    SnapshotsUnsupported(u16),
+    #[error("Failed writing snapshot {0}: {1}")]
+    FailedSnapshot(String, String),
+}
+
+/// The medium of a removable LUN, as seen by the control socket.
+pub(crate) type Medium = RemovableMedium<Box<dyn BlockDeviceBackend>>;
+
+/// The layers around the images of a LUN, both the one it starts with and any
+/// inserted later.
+#[derive(Default)]
+pub(crate) struct ImageLayers {
+    /// The cipher and the offset of the encrypted data (see `--encrypt`).
+    pub encryption: Option<(Arc<XtsCipher>, u64)>,
+    pub fault_rules: Vec<FaultRule>,
+}
+
+impl ImageLayers {
+    pub(crate) fn wrap(
+        &self,
+        mut backend: Box<dyn BlockDeviceBackend>,
+    ) -> io::Result<Box<dyn BlockDeviceBackend>> {
+        if let Some((cipher, offset)) = &self.encryption {
+            backend = Box::new(EncryptedBackend::new(backend, Arc::clone(cipher), *offset)?);
+        }
+        if !self.fault_rules.is_empty() {
+            backend = Box::new(FaultInjectionBackend::new(
+                backend,
+                self.fault_rules.clone(),
+            ));
+        }
+        Ok(backend)
+    }
+}
+
+/// The runtime adjustable state of a LUN.
+pub(crate) struct LunControl {
+    pub throttle: Arc<Throttle>,
+    pub stats: Arc<LunStats>,
+    /// The medium of the LUN, if it's removable.
+    pub medium: Option<Arc<Medium>>,
+    /// Wrapped around inserted media, like around the image the LUN started
+    /// with.
+    pub layers: ImageLayers,
+    /// Another handle to the image of the LUN, for snapshots. `None` if the
+    /// backend can't be cloned (see `BlockDeviceBackend::try_clone`), or the
+    /// medium has been removed.
+    pub image: Mutex<Option<Box<dyn BlockDeviceBackend>>>,
+}
+
+/// The state shared between the device and the control socket.
+pub(crate) struct Controls {
+    luns: Vec<LunControl>,
+    /// How images inserted into removable LUNs are opened.
+    image_options: ImageOptions,
+    /// See `VhostUserScsiBackend::request_gate`.
+    request_gate: Arc<Mutex<()>>,
+}
+
+impl Controls {
+    pub(crate) fn new(
+        luns: Vec<LunControl>,
+        image_options: ImageOptions,
+        request_gate: Arc<Mutex<()>>,
+    ) -> Self {
+        Self {
+            luns,
+            image_options,
+            request_gate,
+        }
+    }
+
+    fn lun(&self, lun: Option<&str>) -> Result<&LunControl, ControlError> {
+        self.lun_index(lun).map(|(_, control)| control)
+    }
+
+    fn lun_index(&self, lun: Option<&str>) -> Result<(u16, &LunControl), ControlError> {
+        let lun = lun.ok_or(ControlError::MissingArgument("lun"))?;
+        let lun: u16 = lun
+            .parse()
+            .map_err(|_| ControlError::InvalidLun(lun.to_string()))?;
+        let control = self
+            .luns
+            .get(usize::from(lun))
+            .ok_or(ControlError::NoSuchLun(lun))?;
+        Ok((lun, control))
+    }
+
+    fn medium<'a>(
+        &self,
+        lun: Option<&str>,
+        mut args: impl Iterator<Item = &'a str>,
+    ) -> Result<String, ControlError> {
+        let (lun, control) = self.lun_index(lun)?;
+        let medium = control
+            .medium
+            .as_ref()
+            .ok_or(ControlError::NotRemovable(lun))?;
+        match args.next() {
+            None => {}
+            Some("eject") => {
+                medium.eject().map_err(ControlError::Medium)?;
+                *control.image.lock().unwrap() = None;
+            }
+            Some("insert") => {
+                let image = args.next().ok_or(ControlError::MissingArgument("image"))?;
+                let file = self
+                    .image_options
+                    .open(Path::new(image))
+                    .map_err(|e| ControlError::FailedOpeningImage(image.into(), e.to_string()))?;
+                let backend = FileBackend::new(file);
+                // snapshots are of the image as stored, like at startup
+                let clone = backend.try_clone().ok();
+                let backend = control
+                    .layers
+                    .wrap(Box::new(backend))
+                    .map_err(|e| ControlError::FailedOpeningImage(image.into(), e.to_string()))?;
+                medium.insert(backend).map_err(ControlError::Medium)?;
+                *control.image.lock().unwrap() = clone;
+                info!("Inserted {} into LUN {}", image, lun);
+            }
+            Some(action) => return Err(ControlError::InvalidMediumAction(action.to_string())),
+        }
+        Ok(format!(
+            "state={},prevent-removal={}",
+            medium.status(),
+            u8::from(medium.removal_prevented())
+        ))
+    }
+
+    fn snapshot(&self, lun: Option<&str>, path: Option<&str>) -> Result<String, ControlError> {
+        let (lun, control) = self.lun_index(lun)?;
+        let path = path.ok_or(ControlError::MissingArgument("path"))?;
+        let mut image = control.image.lock().unwrap();
+        let image = image
+            .as_mut()
+            .ok_or(ControlError::SnapshotsUnsupported(lun))?;
+
+        let _quiesced = self.request_gate.lock().unwrap();
+        let method = snapshot::snapshot(&mut **image, Path::new(path))
+            .map_err(|e| ControlError::FailedSnapshot(path.into(), e.to_string()))?;
+        info!("Wrote snapshot of LUN {} to {} ({})", lun, path, method);
+        Ok(format!("method={}", method))
+    }
+
+    fn stats(&self, lun: Option<&str>) -> Result<String, ControlError> {
+        // unwraps are safe: the stats only contain strings and integers
+        if lun.is_some() {
+            let stats = self.lun(lun)?.stats.snapshot();
+            Ok(serde_json::to_string(&stats).unwrap())
+        } else {
+            let stats: BTreeMap<_, _> = self
+                .luns
+                .iter()
+                .enumerate()
+                .map(|(lun, control)| (lun, control.stats.snapshot()))
+                .collect();
+            Ok(serde_json::to_string(&stats).unwrap())
+        }
+    }
+
+    /// Execute a single command, returning the text of the `OK` response.
+    pub(crate) fn handle_command(&self, line: &str) -> Result<String, ControlError> {
+        let mut args = line.split_whitespace();
+        let command = args.next().ok_or(ControlError::EmptyCommand)?;
+
+        let response = match command {
+            "throttle" => {
+                let throttle = &self.lun(args.next())?.throttle;
+                if let Some(update) = args.next() {
+                    let mut limits = throttle.limits();
+                    limits.update(update).map_err(ControlError::InvalidLimits)?;
+                    throttle.set_limits(limits);
+                }
+                throttle.limits().to_string()
+            }
+            "stats" => self.stats(args.next())?,
+            "medium" => self.medium(args.next(), &mut args)?,
+            "snapshot" => self.snapshot(args.next(), args.next())?,
+            _ => return Err(ControlError::UnknownCommand(command.to_string())),
+        };
+
+        if args.next().is_some() {
+            return Err(ControlError::TooManyArguments);
+        }
+        Ok(response)
+    }
+
+    fn handle_client(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            debug!("Control command: {}", line);
+            match self.handle_command(&line) {
+                Ok(response) => writeln!(writer, "OK {}", response)?,
+                Err(e) => writeln!(writer, "ERROR {}", e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Listen for control connections on `path`, one client at a time, in a
+/// background thread. A stale socket at `path` is replaced.
+pub(crate) fn spawn_control_server(path: &Path, controls: Arc<Controls>) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Listening for control connections on {}", path.display());
+
+    thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = controls.handle_client(stream) {
+                            warn!("Control connection failed: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed accepting control connection: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::scsi::{
+        emulation::{block_device::ByteOffset, throttle::ThrottleLimits},
+        CmdOutput,
+    };
+
+    fn controls() -> Controls {
+        Controls::new(
+            vec![LunControl {
+                throttle: Arc::new(Throttle::new(ThrottleLimits {
+                    read_iops: Some(100),
+                    ..Default::default()
+                })),
+                stats: Arc::default(),
+                medium: None,
+                layers: ImageLayers::default(),
+                image: Mutex::default(),
+            }],
+            ImageOptions::default(),
+            Arc::default(),
+        )
+    }
+
+    #[test]
+    fn test_throttle_command() {
+        let controls = controls();
+
+        assert_eq!(
+            controls.handle_command("throttle 0").unwrap(),
+            "read-iops=100,write-iops=0,read-bps=0,write-bps=0"
+        );
+        assert_eq!(
+            controls
+                .handle_command("throttle 0 read-iops=0,write-bps=4096")
+                .unwrap(),
+            "read-iops=0,write-iops=0,read-bps=0,write-bps=4096"
+        );
+        assert_eq!(
+            controls.luns[0].throttle.limits(),
+            ThrottleLimits {
+                write_bps: Some(4096),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_stats_command() {
+        let controls = controls();
+        controls.luns[0].stats.record(
+            0x28,
+            512,
+            0,
+            &Ok(CmdOutput::ok()),
+            std::time::Duration::from_micros(30),
+        );
+
+        let stats: serde_json::Value =
+            serde_json::from_str(&controls.handle_command("stats 0").unwrap()).unwrap();
+        assert_eq!(stats["commands"]["0x28"], 1);
+        assert_eq!(stats["bytes_read"], 512);
+        assert_eq!(stats["latency"]["count"], 1);
+
+        let all: serde_json::Value =
+            serde_json::from_str(&controls.handle_command("stats").unwrap()).unwrap();
+        assert_eq!(all["0"], stats);
+
+        assert_eq!(
+            controls.handle_command("stats 1"),
+            Err(ControlError::NoSuchLun(1))
+        );
+    }
+
+    #[test]
+    fn test_medium_command() {
+        assert_eq!(
+            controls().handle_command("medium 0"),
+            Err(ControlError::NotRemovable(0))
+        );
+
+        let medium = Arc::new(Medium::default());
+        let controls = Controls::new(
+            vec![LunControl {
+                throttle: Arc::new(Throttle::new(ThrottleLimits::default())),
+                stats: Arc::default(),
+                medium: Some(Arc::clone(&medium)),
+                layers: ImageLayers::default(),
+                image: Mutex::default(),
+            }],
+            ImageOptions::default(),
+            Arc::default(),
+        );
+
+        assert_eq!(
+            controls.handle_command("medium 0").unwrap(),
+            "state=loaded,prevent-removal=0"
+        );
+        medium.set_prevent_removal(true);
+        assert_eq!(
+            controls.handle_command("medium 0 eject"),
+            Err(ControlError::Medium(MediumError::RemovalPrevented))
+        );
+        medium.set_prevent_removal(false);
+        assert_eq!(
+            controls.handle_command("medium 0 eject").unwrap(),
+            "state=absent,prevent-removal=0"
+        );
+
+        assert_matches!(
+            controls.handle_command("medium 0 insert /nonexistent/image"),
+            Err(ControlError::FailedOpeningImage(_, _))
+        );
+        let image = tempfile::NamedTempFile::new().unwrap();
+        let in_use = ImageOptions::default().open(image.path()).unwrap();
+        assert_matches!(
+            controls.handle_command(&format!("medium 0 insert {}", image.path().display())),
+            Err(ControlError::FailedOpeningImage(_, _))
+        );
+        drop(in_use);
+        assert_eq!(
+            controls
+                .handle_command(&format!("medium 0 insert {}", image.path().display()))
+                .unwrap(),
+            "state=loaded,prevent-removal=0"
+        );
+        assert!(medium.take_inserted().is_some());
+        assert!(controls.luns[0].image.lock().unwrap().is_some());
+        controls.handle_command("medium 0 eject").unwrap();
+        assert!(controls.luns[0].image.lock().unwrap().is_none());
+
+        assert_eq!(
+            controls.handle_command("medium 0 insert"),
+            Err(ControlError::MissingArgument("image"))
+        );
+        assert_matches!(
+            controls.handle_command("medium 0 load"),
+            Err(ControlError::InvalidMediumAction(_))
+        );
+    }
+
+    #[test]
+    fn test_insert_into_encrypted_lun() {
+        let medium = Arc::new(Medium::default());
+        let cipher = Arc::new(XtsCipher::new(&[7; 32]).unwrap());
+        let controls = Controls::new(
+            vec![LunControl {
+                throttle: Arc::new(Throttle::new(ThrottleLimits::default())),
+                stats: Arc::default(),
+                medium: Some(Arc::clone(&medium)),
+                layers: ImageLayers {
+                    encryption: Some((Arc::clone(&cipher), 512)),
+                    fault_rules: Vec::new(),
+                },
+                image: Mutex::default(),
+            }],
+            ImageOptions::default(),
+            Arc::default(),
+        );
+
+        let image = tempfile::NamedTempFile::new().unwrap();
+        image.as_file().set_len(4096).unwrap();
+        controls
+            .handle_command(&format!("medium 0 insert {}", image.path().display()))
+            .unwrap();
+        let mut backend = medium.take_inserted().unwrap();
+        backend
+            .write_exact_at(&[0xaa; 512], ByteOffset::from(0))
+            .unwrap();
+
+        let mut stored = [0; 512];
+        image.as_file().read_exact_at(&mut stored, 512).unwrap();
+        let mut expected = [0xaa; 512];
+        cipher.encrypt(0, &mut expected);
+        assert_eq!(stored, expected);
+    }
+
+    #[test]
+    fn test_snapshot_command() {
+        let controls = controls();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        let command = format!("snapshot 0 {}", path.display());
+        assert_eq!(
+            controls.handle_command(&command),
+            Err(ControlError::SnapshotsUnsupported(0))
+        );
+
+        let image = tempfile::tempfile().unwrap();
+        image.set_len(3 * 1024 * 1024).unwrap();
+        image.write_all_at(&[1; 512], 0).unwrap();
+        image
+            .write_all_at(&[2; 512], 2 * 1024 * 1024 + 512)
+            .unwrap();
+        *controls.luns[0].image.lock().unwrap() = Some(Box::new(FileBackend::new(image)));
+
+        let response = controls.handle_command(&command).unwrap();
+        assert!(
+            ["method=reflink", "method=copy"].contains(&response.as_str()),
+            "{}",
+            response
+        );
+        let snapshot = fs::read(&path).unwrap();
+        assert_eq!(snapshot.len(), 3 * 1024 * 1024);
+        assert!(snapshot[..512].iter().all(|&b| b == 1));
+        assert!(snapshot[512..2 * 1024 * 1024 + 512].iter().all(|&b| b == 0));
+        assert!(snapshot[2 * 1024 * 1024 + 512..][..512]
+            .iter()
+            .all(|&b| b == 2));
+
+        // snapshots don't overwrite existing files
+        assert_matches!(
+            controls.handle_command(&command),
+            Err(ControlError::FailedSnapshot(_, _))
+        );
+        assert_eq!(fs::read(&path).unwrap(), snapshot);
+
+        // the snapshot waits for the request being processed
+        let request_gate = Arc::clone(&controls.request_gate);
+        let processing = request_gate.lock().unwrap();
+        let controls = Arc::new(controls);
+        let snapshot = thread::spawn({
+            let controls = Arc::clone(&controls);
+            let command = format!("snapshot 0 {}", dir.path().join("snapshot2").display());
+            move || controls.handle_command(&command)
+        });
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!snapshot.is_finished());
+        drop(processing);
+        snapshot.join().unwrap().unwrap();
+
+        assert_eq!(
+            controls.handle_command("snapshot 0"),
+            Err(ControlError::MissingArgument("path"))
+        );
+    }
+
+    #[test]
+    fn test_command_errors() {
+        let controls = controls();
+
+        assert_eq!(controls.handle_command(""), Err(ControlError::EmptyCommand));
+        assert_matches!(
+            controls.handle_command("eject 0"),
+            Err(ControlError::UnknownCommand(_))
+        );
+        assert_eq!(
+            controls.handle_command("throttle"),
+            Err(ControlError::MissingArgument("lun"))
+        );
+        assert_eq!(
+            controls.handle_command("throttle 1"),
+            Err(ControlError::NoSuchLun(1))
+        );
+        assert_matches!(
+            controls.handle_command("throttle x"),
+            Err(ControlError::InvalidLun(_))
+        );
+        assert_matches!(
+            controls.handle_command("throttle 0 read-iops=fast"),
+            Err(ControlError::InvalidLimits(_))
+        );
+        assert_eq!(
+            controls.handle_command("throttle 0 read-iops=1 write-iops=1"),
+            Err(ControlError::TooManyArguments)
+        );
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        spawn_control_server(&path, Arc::new(controls())).unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+
+        writeln!(writer, "throttle 0 write-iops=10").unwrap();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            "OK read-iops=100,write-iops=10,read-bps=0,write-bps=0"
+        );
+        writeln!(writer, "throttle 3").unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "ERROR No such LUN 3");
+    }
+}
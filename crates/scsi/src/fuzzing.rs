@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Entry points of the fuzz targets in `fuzz/`.
+//!
+//! Each one takes arbitrary bytes from the fuzzer. Returning is all that's
+//! expected of them: anything the guest sends should at worst get an error
+//! response, so every panic is a bug.
+
+use std::{
+    convert::TryInto,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex},
+};
+
+use vhost_user_backend::{VhostUserBackendMut, VringRwLock, VringT};
+use virtio_bindings::virtio_ring::VRING_DESC_F_WRITE;
+use virtio_queue::{mock::MockSplitQueue, Descriptor};
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
+};
+
+use crate::{
+    scsi::emulation::{
+        block_device::{BlockDevice, BlockDeviceBackend, BlockOffset, BlockSize, ByteOffset},
+        command::Cdb,
+        target::EmulatedTarget,
+    },
+    vhu_scsi::VhostUserScsiBackend,
+    virtio::{Request, VirtioScsiLun},
+};
+
+/// Where the request goes in guest memory; it can be up to 64 KiB long.
+const REQUEST_ADDR: u64 = 0x1_0000;
+/// Where the response goes in guest memory; it can be up to 64 KiB long.
+const RESPONSE_ADDR: u64 = 0x2_0000;
+const MEMORY_SIZE: usize = 0x3_0000;
+/// The number of blocks of the LUN `process_request` sends requests to.
+const LUN_BLOCKS: usize = 16;
+
+pub fn cdb_parse(data: &[u8]) {
+    let _ = Cdb::parse(data);
+}
+
+pub fn virtio_lun_parse(data: &[u8]) {
+    if let Ok(bytes) = data.try_into() {
+        VirtioScsiLun::parse(bytes);
+    }
+}
+
+pub fn virtio_request_parse(mut data: &[u8]) {
+    let _ = Request::parse(&mut data);
+}
+
+/// Put a request on the request queue and process it, with an emulated
+/// target that has a single LUN of `LUN_BLOCKS` zeroed blocks.
+///
+/// The first two bytes of `data` are the length of the device-writable
+/// buffer (little endian), and the rest is the device-readable buffer, i.e.
+/// a `virtio_scsi_cmd_req` followed by the data-out buffer.
+pub fn process_request(data: &[u8]) {
+    let Some((response_len, request)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let Ok(request_len) = u32::try_from(request.len()) else {
+        return;
+    };
+    if RESPONSE_ADDR - REQUEST_ADDR < u64::from(request_len) {
+        return;
+    }
+
+    let mem = GuestMemoryAtomic::new(
+        GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), MEMORY_SIZE)]).unwrap(),
+    );
+    mem.memory()
+        .write_slice(request, GuestAddress(REQUEST_ADDR))
+        .unwrap();
+
+    let mem_handle = mem.memory();
+    let queue = MockSplitQueue::new(&*mem_handle, 16);
+    queue
+        .build_desc_chain(&[
+            Descriptor::new(REQUEST_ADDR, request_len, 0, 0),
+            Descriptor::new(
+                RESPONSE_ADDR,
+                u32::from(u16::from_le_bytes(*response_len)),
+                VRING_DESC_F_WRITE as u16,
+                0,
+            ),
+        ])
+        .unwrap();
+    // make the chain the only available one
+    mem.memory()
+        .write_obj(0u16, queue.avail_addr().unchecked_add(4))
+        .unwrap();
+    mem.memory()
+        .write_obj(1u16, queue.avail_addr().unchecked_add(2))
+        .unwrap();
+
+    let vring = VringRwLock::new(mem.clone(), 16).unwrap();
+    vring.set_queue_size(16);
+    vring
+        .set_queue_info(
+            queue.desc_table_addr().0,
+            queue.avail_addr().0,
+            queue.used_addr().0,
+        )
+        .unwrap();
+    vring.set_queue_ready(true);
+
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(MemoryBackend::new())));
+    let mut backend = VhostUserScsiBackend::new();
+    backend.update_memory(mem).unwrap();
+    backend.add_target(Box::new(target));
+
+    backend.process_request_queue(&vring).unwrap();
+}
+
+/// An image in memory, so that fuzzing doesn't wait for the disk.
+#[derive(Clone)]
+struct MemoryBackend {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    fn new() -> Self {
+        Self {
+            data: Arc::new(Mutex::new(vec![0; LUN_BLOCKS * 512])),
+        }
+    }
+}
+
+fn range(offset: ByteOffset, len: usize) -> io::Result<std::ops::Range<usize>> {
+    let start = usize::try_from(u64::from(offset)).map_err(|_| ErrorKind::UnexpectedEof)?;
+    let end = start.checked_add(len).ok_or(ErrorKind::UnexpectedEof)?;
+    Ok(start..end)
+}
+
+impl BlockDeviceBackend for MemoryBackend {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: ByteOffset) -> io::Result<()> {
+        let data = self.data.lock().unwrap();
+        let src = data
+            .get(range(offset, buf.len())?)
+            .ok_or(ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_exact_at(&mut self, buf: &[u8], offset: ByteOffset) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let dst = data
+            .get_mut(range(offset, buf.len())?)
+            .ok_or(ErrorKind::WriteZero)?;
+        dst.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size_in_blocks(&mut self) -> io::Result<BlockOffset> {
+        Ok(ByteOffset::from(LUN_BLOCKS as u64 * 512) / self.block_size())
+    }
+
+    fn block_size(&self) -> BlockSize {
+        BlockSize::try_from(512).expect("512 should be a valid BlockSize")
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn BlockDeviceBackend>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    /// Run every fuzz target on its seed corpus, which should never crash.
+    #[test]
+    fn test_seed_corpora() {
+        let corpora = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+        for (name, target) in [
+            ("cdb_parse", super::cdb_parse as fn(&[u8])),
+            ("virtio_lun_parse", super::virtio_lun_parse),
+            ("virtio_request_parse", super::virtio_request_parse),
+            ("process_request", super::process_request),
+        ] {
+            let mut seeds = 0;
+            for entry in fs::read_dir(corpora.join(name)).unwrap() {
+                target(&fs::read(entry.unwrap().path()).unwrap());
+                seeds += 1;
+            }
+            assert!(seeds > 0, "no seeds for {}", name);
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! vhost-device-scsi is a binary; this library only exists to give the fuzz
+//! targets in `fuzz/` access to its internals, and is empty unless the
+//! `fuzzing` feature is enabled.
+
+#![cfg(feature = "fuzzing")]
+// most of the daemon is only used by the binary
+#![allow(dead_code)]
+
+pub mod fuzzing;
+mod scsi;
+mod vhu_scsi;
+mod virtio;
@@ -1,14 +1,19 @@
 // SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
 
+mod control;
+#[cfg(test)]
+mod fuzzing;
 mod scsi;
 mod vhu_scsi;
 mod virtio;
 
 use std::{
-    fs::File,
-    path::PathBuf,
+    io,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 use clap::Parser;
@@ -17,12 +22,19 @@ use thiserror::Error as ThisError;
 use vhost::vhost_user::{self, Listener};
 use vhost_user_backend::VhostUserDaemon;
 use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
 
+use crate::control::{Controls, ImageLayers, LunControl, Medium};
 use crate::scsi::emulation::{
-    block_device::{BlockDevice, FileBackend, MediumRotationRate},
+    block_device::{BlockDevice, BlockDeviceBackend, FileBackend, MediumRotationRate},
+    encryption::{EncryptionError, EncryptionSpec},
+    fault_injection::{self, FaultRuleError},
+    image_lock::{ImageOpenError, ImageOptions},
+    nbd::{NbdError, NbdUri},
     target::EmulatedTarget,
+    throttle::{Throttle, ThrottleLimits, ThrottleLimitsParseError},
 };
-use crate::vhu_scsi::VhostUserScsiBackend;
+use crate::vhu_scsi::{VhostUserScsiBackend, DEFERRED_REQUESTS_EVENT};
 
 #[derive(Debug, ThisError)]
 enum Error {
@@ -30,6 +42,20 @@ enum Error {
     TooManyLUNs,
     #[error("Failed creating listener: {0}")]
     FailedCreatingListener(vhost_user::Error),
+    #[error("Failed opening image {0}: {1}")]
+    FailedOpeningImage(String, ImageOpenError),
+    #[error("Failed connecting to NBD export {0}: {1}")]
+    FailedConnectingNbd(String, NbdError),
+    #[error("Failed loading fault rules: {0}")]
+    FailedLoadingFaultRules(FaultRuleError),
+    #[error("Failed registering deferred request timer: {0}")]
+    FailedRegisteringTimer(io::Error),
+    #[error("Failed creating control socket: {0}")]
+    FailedCreatingControlSocket(io::Error),
+    #[error("Failed setting up encryption: {0}")]
+    FailedSettingUpEncryption(EncryptionError),
+    #[error("Failed setting up encryption of {0}: {1}")]
+    FailedEncryptingImage(String, io::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -43,19 +69,140 @@ struct ScsiArgs {
     /// use the Linux SCSI generic API.
     #[arg(long = "read-only", short = 'r')]
     read_only: bool,
+    /// Allow other processes to write to the images while we use them.
+    ///
+    /// Images are locked against concurrent use: read-only images can be
+    /// shared, but writable ones can't. With this, writable images are shared
+    /// as well, which is only safe if the guests coordinate access (e.g. with
+    /// a cluster file system).
+    #[arg(long)]
+    share: bool,
     /// Tell the guest this disk is non-rotational.
     ///
     /// Affects some heuristics in Linux around, for example, scheduling.
     #[arg(long = "solid-state")]
     solid_state: bool,
+    /// Make the medium of all LUNs removable.
+    ///
+    /// The guest can then eject it, and the host can change it through the
+    /// control socket.
+    #[arg(long)]
+    removable: bool,
     /// Location of vhost-user socket.
     #[clap(short, long)]
     socket_path: PathBuf,
+    /// File with rules for failures to inject into I/O to the images.
+    ///
+    /// Meant for testing how guests react to misbehaving storage; see
+    /// `fault_injection.rs` for the format.
+    #[arg(long = "fault-rules")]
+    fault_rules: Option<PathBuf>,
+    /// Limit the I/O of LUNs, e.g. `lun=0,read-iops=100,write-bps=1048576`.
+    ///
+    /// The keys are `lun`, `read-iops`, `write-iops`, `read-bps` and
+    /// `write-bps`; without `lun`, the limits apply to all LUNs. Can be given
+    /// multiple times.
+    #[arg(long = "throttle", value_parser = parse_throttle_arg)]
+    throttle: Vec<ThrottleArg>,
+    /// Encrypt LUNs with AES-XTS, e.g. `lun=0,key-file=/path/to/key`.
+    ///
+    /// The keys are `lun`, `key-file`, `key-fd` and `offset`; without `lun`,
+    /// all LUNs are encrypted with the same key. Can be given multiple times.
+    /// See `encryption.rs` for details.
+    #[arg(long = "encrypt", value_parser = parse_encrypt_arg)]
+    encrypt: Vec<EncryptArg>,
+    /// Seconds to wait for NBD servers when connecting and for each read or
+    /// write, 0 to wait forever.
+    ///
+    /// Requests that time out fail with a sense the guest retries, and the
+    /// connection is reestablished.
+    #[arg(long = "nbd-timeout", default_value_t = 30)]
+    nbd_timeout: u64,
+    /// Location of a socket for adjusting the device at runtime.
+    ///
+    /// See `control.rs` for the protocol.
+    #[arg(long = "control-socket")]
+    control_socket: Option<PathBuf>,
     /// Images against which the SCSI actions are emulated.
+    ///
+    /// Besides paths, these can be NBD URIs: `nbd://host[:port]/export` or
+    /// `nbd+unix:///export?socket=path`.
     images: Vec<PathBuf>,
 }
 
-fn create_backend(args: &ScsiArgs) -> Result<VhostUserScsiBackend> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ThrottleArg {
+    /// `None` applies to all LUNs.
+    lun: Option<u16>,
+    limits: ThrottleLimits,
+}
+
+fn parse_throttle_arg(s: &str) -> std::result::Result<ThrottleArg, ThrottleLimitsParseError> {
+    let mut lun = None;
+    let mut limits = Vec::new();
+    for arg in s.split(',') {
+        match arg.split_once('=') {
+            Some(("lun", val)) => {
+                lun = Some(val.parse().map_err(|_| {
+                    ThrottleLimitsParseError::InvalidValue("lun".to_string(), val.to_string())
+                })?)
+            }
+            _ => limits.push(arg),
+        }
+    }
+
+    let mut throttle = ThrottleArg {
+        lun,
+        limits: ThrottleLimits::default(),
+    };
+    if !limits.is_empty() {
+        throttle.limits.update(&limits.join(","))?;
+    }
+    Ok(throttle)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EncryptArg {
+    /// `None` applies to all LUNs.
+    lun: Option<u16>,
+    spec: EncryptionSpec,
+}
+
+fn parse_encrypt_arg(s: &str) -> std::result::Result<EncryptArg, EncryptionError> {
+    let mut lun = None;
+    let mut spec = Vec::new();
+    for arg in s.split(',') {
+        match arg.split_once('=') {
+            Some(("lun", val)) => {
+                lun = Some(val.parse().map_err(|_| {
+                    EncryptionError::InvalidValue("lun".to_string(), val.to_string())
+                })?)
+            }
+            _ => spec.push(arg),
+        }
+    }
+    Ok(EncryptArg {
+        lun,
+        spec: EncryptionSpec::parse(spec.into_iter())?,
+    })
+}
+
+/// A locally assigned NAA designator for an image, derived from its path so
+/// that it stays the same across restarts.
+fn naa_designator(image: &Path) -> u64 {
+    // FNV-1a, which unlike `DefaultHasher` doesn't change between Rust versions
+    let hash = image
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    // NAA 3h: locally assigned
+    (0x3 << 60) | (hash >> 4)
+}
+
+fn create_backend(args: &ScsiArgs) -> Result<(VhostUserScsiBackend, Controls)> {
     let mut backend = VhostUserScsiBackend::new();
     let mut target = EmulatedTarget::new();
 
@@ -70,28 +217,123 @@ fn create_backend(args: &ScsiArgs) -> Result<VhostUserScsiBackend> {
         warn!("Currently, only read-only images are supported. Unless you know what you're doing, you want to pass -r");
     }
 
-    for image in &args.images {
-        let mut dev = BlockDevice::new(FileBackend::new(
-            File::options()
-                .read(true)
-                .write(true)
-                .open(image)
-                .expect("Opening image"),
-        ));
-        dev.set_write_protected(args.read_only);
+    let fault_rules = match &args.fault_rules {
+        Some(path) => fault_injection::load_rules(path).map_err(Error::FailedLoadingFaultRules)?,
+        None => Vec::new(),
+    };
+    let image_options = ImageOptions {
+        read_only: args.read_only,
+        share: args.share,
+    };
+    let ciphers = args
+        .encrypt
+        .iter()
+        .map(|arg| arg.spec.load_cipher())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(Error::FailedSettingUpEncryption)?;
+    let mut lun_controls = Vec::new();
+
+    for (lun, image) in args.images.iter().enumerate() {
+        // unwrap is safe: we checked above that there are at most 256 images
+        let lun = u16::try_from(lun).unwrap();
+
+        let mut write_protected = args.read_only;
+        let backend: Box<dyn BlockDeviceBackend> = match NbdUri::parse(&image.to_string_lossy()) {
+            Some(uri) => {
+                let failed = |e| Error::FailedConnectingNbd(image.display().to_string(), e);
+                let timeout = Some(Duration::from_secs(args.nbd_timeout))
+                    .filter(|timeout| !timeout.is_zero());
+                let (backend, read_only) =
+                    uri.and_then(|uri| uri.connect(timeout)).map_err(failed)?;
+                if read_only && !args.read_only {
+                    warn!("NBD export {} is read-only", image.display());
+                    write_protected = true;
+                }
+                backend
+            }
+            None => Box::new(FileBackend::new(image_options.open(image).map_err(
+                |e| Error::FailedOpeningImage(image.display().to_string(), e),
+            )?)),
+        };
+
+        // snapshots are of the image as stored, without running into faults
+        let image_handle = backend.try_clone().ok();
+
+        let mut layers = ImageLayers::default();
+        let encryption = args
+            .encrypt
+            .iter()
+            .zip(&ciphers)
+            .rev()
+            .find(|(arg, _)| arg.lun.is_none() || arg.lun == Some(lun));
+        if let Some((arg, cipher)) = encryption {
+            info!("Encrypting LUN {} ({:?})", lun, cipher);
+            layers.encryption = Some((Arc::clone(cipher), arg.spec.offset));
+        }
+
+        layers.fault_rules = fault_rules
+            .iter()
+            .filter(|rule| rule.applies_to_lun(lun))
+            .cloned()
+            .collect();
+        if !layers.fault_rules.is_empty() {
+            warn!(
+                "Injecting faults into LUN {} ({}): {:?}",
+                lun,
+                image.display(),
+                layers.fault_rules
+            );
+        }
+        let backend = layers
+            .wrap(backend)
+            .map_err(|e| Error::FailedEncryptingImage(image.display().to_string(), e))?;
+
+        let mut limits = ThrottleLimits::default();
+        for throttle in &args.throttle {
+            if throttle.lun.is_none() || throttle.lun == Some(lun) {
+                limits = throttle.limits;
+            }
+        }
+        if limits != ThrottleLimits::default() {
+            info!("Throttling LUN {}: {}", lun, limits);
+        }
+        let throttle = Arc::new(Throttle::new(limits));
+
+        let mut dev = BlockDevice::new(backend);
+        dev.set_throttle(Arc::clone(&throttle));
+        dev.set_write_protected(write_protected);
+        dev.set_naa(naa_designator(image));
         dev.set_solid_state(if args.solid_state {
             MediumRotationRate::NonRotating
         } else {
             MediumRotationRate::Unreported
         });
+        let medium = args.removable.then(|| Arc::new(Medium::default()));
+        if let Some(medium) = &medium {
+            dev.set_removable(Arc::clone(medium));
+        }
         target.add_lun(Box::new(dev));
+        lun_controls.push(LunControl {
+            throttle,
+            // unwrap is safe: we just added the LUN
+            stats: target.lun_stats(lun).unwrap(),
+            medium,
+            layers,
+            image: Mutex::new(image_handle),
+        });
     }
 
     backend.add_target(Box::new(target));
-    Ok(backend)
+    let controls = Controls::new(
+        lun_controls,
+        image_options,
+        Arc::clone(&backend.request_gate),
+    );
+    Ok((backend, controls))
 }
 
-fn start_backend(backend: VhostUserScsiBackend, args: ScsiArgs) -> Result<()> {
+fn start_backend(backend: VhostUserScsiBackend, controls: Controls, args: ScsiArgs) -> Result<()> {
+    let deferred_timer = backend.deferred_timer.as_raw_fd();
     let backend = Arc::new(RwLock::new(backend));
     let mut daemon = VhostUserDaemon::new(
         "vhost-device-scsi".into(),
@@ -100,6 +342,20 @@ fn start_backend(backend: VhostUserScsiBackend, args: ScsiArgs) -> Result<()> {
     )
     .expect("Creating daemon");
 
+    // The backend only has a single worker thread.
+    daemon.get_epoll_handlers()[0]
+        .register_listener(
+            deferred_timer,
+            EventSet::IN,
+            u64::from(DEFERRED_REQUESTS_EVENT),
+        )
+        .map_err(Error::FailedRegisteringTimer)?;
+
+    if let Some(path) = &args.control_socket {
+        control::spawn_control_server(path, Arc::new(controls))
+            .map_err(Error::FailedCreatingControlSocket)?;
+    }
+
     daemon
         .start(Listener::new(args.socket_path, true).map_err(Error::FailedCreatingListener)?)
         .expect("Starting daemon");
@@ -132,8 +388,8 @@ fn start_backend(backend: VhostUserScsiBackend, args: ScsiArgs) -> Result<()> {
 fn run() -> Result<()> {
     env_logger::init();
     let args = ScsiArgs::parse();
-    let backend = create_backend(&args)?;
-    start_backend(backend, args)?;
+    let (backend, controls) = create_backend(&args)?;
+    start_backend(backend, controls, args)?;
 
     Ok(())
 }
@@ -147,7 +403,10 @@ fn main() {
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use super::*;
+    use crate::scsi::emulation::encryption::KeySource;
 
     #[test]
     fn test_create_backend() {
@@ -155,26 +414,178 @@ mod tests {
         let args = ScsiArgs {
             images: vec!["/dev/null".into()],
             read_only: true,
+            share: false,
             socket_path: sock.path().into(),
             solid_state: false,
+            removable: false,
+            fault_rules: None,
+            throttle: Vec::new(),
+            encrypt: Vec::new(),
+            nbd_timeout: 30,
+            control_socket: None,
         };
         create_backend(&args).unwrap();
     }
 
+    #[test]
+    fn test_image_in_use() {
+        let sock = tempfile::NamedTempFile::new().unwrap();
+        let image = tempfile::NamedTempFile::new().unwrap();
+        let args = |read_only, share| ScsiArgs {
+            images: vec![image.path().into()],
+            read_only,
+            share,
+            socket_path: sock.path().into(),
+            solid_state: false,
+            removable: false,
+            fault_rules: None,
+            throttle: Vec::new(),
+            encrypt: Vec::new(),
+            nbd_timeout: 30,
+            control_socket: None,
+        };
+
+        let writer = create_backend(&args(false, false)).unwrap();
+        assert!(matches!(
+            create_backend(&args(false, false)),
+            Err(Error::FailedOpeningImage(_, ImageOpenError::InUse))
+        ));
+        assert!(matches!(
+            create_backend(&args(true, false)),
+            Err(Error::FailedOpeningImage(_, ImageOpenError::InUse))
+        ));
+        drop(writer);
+
+        // shared writers and readers
+        let _writer = create_backend(&args(false, true)).unwrap();
+        let _reader = create_backend(&args(true, false)).unwrap();
+        create_backend(&args(false, true)).unwrap();
+        assert!(matches!(
+            create_backend(&args(false, false)),
+            Err(Error::FailedOpeningImage(_, ImageOpenError::InUse))
+        ));
+    }
+
     #[test]
     fn test_fail_listener() {
         let socket_name = "~/path/not/present/scsi";
         let args = ScsiArgs {
             images: vec!["/dev/null".into()],
             read_only: true,
+            share: false,
             socket_path: socket_name.into(),
             solid_state: false,
+            removable: false,
+            fault_rules: None,
+            throttle: Vec::new(),
+            encrypt: Vec::new(),
+            nbd_timeout: 30,
+            control_socket: None,
         };
-        let backend = create_backend(&args).unwrap();
-        let err = start_backend(backend, args).unwrap_err();
+        let (backend, controls) = create_backend(&args).unwrap();
+        let err = start_backend(backend, controls, args).unwrap_err();
         if let Error::FailedCreatingListener(_) = err {
         } else {
             panic!("expected failure when creating listener");
         }
     }
+
+    #[test]
+    fn test_parse_throttle_arg() {
+        assert_eq!(
+            parse_throttle_arg("lun=1,read-iops=100,write-bps=4096").unwrap(),
+            ThrottleArg {
+                lun: Some(1),
+                limits: ThrottleLimits {
+                    read_iops: Some(100),
+                    write_bps: Some(4096),
+                    ..Default::default()
+                },
+            }
+        );
+        assert_eq!(
+            parse_throttle_arg("write-iops=10").unwrap(),
+            ThrottleArg {
+                lun: None,
+                limits: ThrottleLimits {
+                    write_iops: Some(10),
+                    ..Default::default()
+                },
+            }
+        );
+        assert!(parse_throttle_arg("lun=x,read-iops=1").is_err());
+        assert!(parse_throttle_arg("lun=0,read-iops").is_err());
+    }
+
+    #[test]
+    fn test_parse_encrypt_arg() {
+        assert_eq!(
+            parse_encrypt_arg("lun=1,key-file=/etc/key,offset=16777216").unwrap(),
+            EncryptArg {
+                lun: Some(1),
+                spec: EncryptionSpec {
+                    key: KeySource::File("/etc/key".into()),
+                    offset: 16777216,
+                },
+            }
+        );
+        assert_eq!(
+            parse_encrypt_arg("key-fd=3").unwrap(),
+            EncryptArg {
+                lun: None,
+                spec: EncryptionSpec {
+                    key: KeySource::Fd(3),
+                    offset: 0,
+                },
+            }
+        );
+        assert!(matches!(
+            parse_encrypt_arg("lun=0"),
+            Err(EncryptionError::MissingKey)
+        ));
+        assert!(matches!(
+            parse_encrypt_arg("key-fd=3,offset=100"),
+            Err(EncryptionError::InvalidValue(_, _))
+        ));
+        assert!(matches!(
+            parse_encrypt_arg("key-fd=3,cipher=aes"),
+            Err(EncryptionError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_image() {
+        let sock = tempfile::NamedTempFile::new().unwrap();
+        let image = tempfile::NamedTempFile::new().unwrap();
+        image.as_file().set_len(4096).unwrap();
+        let key = tempfile::NamedTempFile::new().unwrap();
+        let args = |key_len| {
+            fs::write(key.path(), vec![1; key_len]).unwrap();
+            ScsiArgs {
+                images: vec![image.path().into()],
+                read_only: true,
+                share: false,
+                socket_path: sock.path().into(),
+                solid_state: false,
+                removable: false,
+                fault_rules: None,
+                throttle: Vec::new(),
+                encrypt: vec![parse_encrypt_arg(&format!(
+                    "lun=0,key-file={}",
+                    key.path().display()
+                ))
+                .unwrap()],
+                nbd_timeout: 30,
+                control_socket: None,
+            }
+        };
+
+        create_backend(&args(64)).unwrap();
+        assert!(matches!(
+            create_backend(&args(16)),
+            Err(Error::FailedSettingUpEncryption(
+                EncryptionError::InvalidKeyLength(16)
+            ))
+        ));
+    }
 }
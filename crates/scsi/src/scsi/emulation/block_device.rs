@@ -2,25 +2,39 @@
 
 use std::{
     convert::{TryFrom, TryInto},
+    fmt,
     fs::File,
     io::{self, Read, Write},
     num::{NonZeroU32, NonZeroU64, TryFromIntError},
     ops::{Add, Div, Mul, Sub},
     os::unix::prelude::*,
+    sync::{atomic::AtomicBool, Arc},
 };
 
 use log::{debug, error, warn};
 
 use super::{
     command::{
-        parse_opcode, CommandType, LunSpecificCommand, ModePageSelection, ModeSensePageControl,
-        ParseOpcodeResult, ReportSupportedOpCodesMode, SenseFormat, VpdPage, OPCODES,
+        parse_opcode, ByteCheck, CommandType, LogPageControl, LunSpecificCommand,
+        ModePageSelection, ModeSensePageControl, ParseOpcodeResult, ReportSupportedOpCodesMode,
+        SenseFormat, VpdPage, OPCODES,
     },
+    log_page::Logs,
+    medium::{MediumStatus, PowerCondition, RemovableMedium},
     mode_page::ModePage,
     response_data::{respond_standard_inquiry_data, SilentlyTruncate},
+    self_test::{
+        run_self_test, BackgroundSelfTest, SelfTestCode, SelfTestKind, SelfTestOutcome,
+        SelfTestResult,
+    },
     target::{LogicalUnit, LunRequest},
+    third_party_copy::{self, CopyEndpoint},
+    throttle::{IoDirection, Throttle},
+};
+use crate::scsi::{
+    sense::{self, SenseTriple},
+    CmdError, CmdOutput,
 };
-use crate::scsi::{sense, CmdError, CmdOutput, TaskAttr};
 
 pub(crate) enum MediumRotationRate {
     Unreported,
@@ -102,6 +116,109 @@ pub(crate) trait BlockDeviceBackend: Send + Sync {
     fn size_in_blocks(&mut self) -> io::Result<BlockOffset>;
     fn block_size(&self) -> BlockSize;
     fn sync(&mut self) -> io::Result<()>;
+
+    /// Deallocate `len` bytes at `offset`, which then have to read back as
+    /// zeros. Backends that can't do that fail with `ErrorKind::Unsupported`,
+    /// and the zeros get written out instead.
+    fn discard(&mut self, _offset: ByteOffset, _len: u64) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// The file descriptor the data is stored in, if it can be accessed
+    /// directly at the same offsets (e.g. for `copy_file_range`).
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Whether the data starting at `offset` is allocated (`true`) or reads
+    /// as zeros without being stored anywhere, and for how many bytes (at
+    /// most `len`) that holds. Backends that can't tell report everything as
+    /// allocated.
+    fn allocation_status(&mut self, _offset: ByteOffset, len: u64) -> io::Result<(bool, u64)> {
+        Ok((true, len))
+    }
+
+    /// Hint that `len` bytes at `offset` will be read soon.
+    fn prefetch(&mut self, _offset: ByteOffset, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Another handle to the same data, which can be used from a different
+    /// thread (e.g. by a background self-test). Backends that can't provide
+    /// one fail with `ErrorKind::Unsupported`.
+    fn try_clone(&self) -> io::Result<Box<dyn BlockDeviceBackend>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl<T: BlockDeviceBackend + ?Sized> BlockDeviceBackend for Box<T> {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: ByteOffset) -> io::Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+
+    fn write_exact_at(&mut self, buf: &[u8], offset: ByteOffset) -> io::Result<()> {
+        (**self).write_exact_at(buf, offset)
+    }
+
+    fn size_in_blocks(&mut self) -> io::Result<BlockOffset> {
+        (**self).size_in_blocks()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        (**self).block_size()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+
+    fn discard(&mut self, offset: ByteOffset, len: u64) -> io::Result<()> {
+        (**self).discard(offset, len)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+
+    fn allocation_status(&mut self, offset: ByteOffset, len: u64) -> io::Result<(bool, u64)> {
+        (**self).allocation_status(offset, len)
+    }
+
+    fn prefetch(&mut self, offset: ByteOffset, len: u64) -> io::Result<()> {
+        (**self).prefetch(offset, len)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn BlockDeviceBackend>> {
+        (**self).try_clone()
+    }
+}
+
+/// An error a `BlockDeviceBackend` can return (wrapped in an `io::Error`) to
+/// pick the sense data reported to the driver. Any other I/O error is reported
+/// with a generic sense code depending on the command that failed.
+#[derive(Debug)]
+pub(crate) struct BackendSenseError(pub SenseTriple);
+
+impl fmt::Display for BackendSenseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "backend failure with sense {:?}", self.0)
+    }
+}
+
+impl std::error::Error for BackendSenseError {}
+
+impl From<BackendSenseError> for io::Error {
+    fn from(e: BackendSenseError) -> Self {
+        io::Error::other(e)
+    }
+}
+
+/// The sense data to report for a failed backend operation: whatever the
+/// backend asked for, or `default` otherwise.
+pub(crate) fn sense_for_io_error(e: &io::Error, default: SenseTriple) -> SenseTriple {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<BackendSenseError>())
+        .map_or(default, |e| e.0)
 }
 
 pub(crate) struct FileBackend {
@@ -140,12 +257,88 @@ impl BlockDeviceBackend for FileBackend {
     fn sync(&mut self) -> io::Result<()> {
         self.file.sync_data()
     }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+
+    fn allocation_status(&mut self, offset: ByteOffset, len: u64) -> io::Result<(bool, u64)> {
+        let offset = u64::from(offset);
+        let seek = |whence| -> io::Result<Option<u64>> {
+            let off = libc::off_t::try_from(offset)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+            // SAFETY: lseek doesn't access our memory, and the kernel checks
+            // the file descriptor.
+            let ret = unsafe { libc::lseek(self.file.as_raw_fd(), off, whence) };
+            match u64::try_from(ret) {
+                Ok(pos) => Ok(Some(pos)),
+                // ENXIO: there's no data (or hole) from `offset` on
+                Err(_) => match io::Error::last_os_error() {
+                    e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+                    e => Err(e),
+                },
+            }
+        };
+
+        match seek(libc::SEEK_DATA) {
+            Ok(Some(data)) if data == offset => {
+                let hole = seek(libc::SEEK_HOLE)?.unwrap_or(u64::MAX);
+                Ok((true, len.min(hole - offset)))
+            }
+            Ok(Some(data)) => Ok((false, len.min(data - offset))),
+            Ok(None) => Ok((false, len)),
+            // the file doesn't support looking for holes
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EOPNOTSUPP)) => {
+                Ok((true, len))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn prefetch(&mut self, offset: ByteOffset, len: u64) -> io::Result<()> {
+        let (Ok(offset), Ok(len)) = (
+            libc::off_t::try_from(u64::from(offset)),
+            libc::off_t::try_from(len),
+        ) else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+        // SAFETY: posix_fadvise doesn't access our memory, and the kernel
+        // checks the file descriptor.
+        match unsafe {
+            libc::posix_fadvise(
+                self.file.as_raw_fd(),
+                offset,
+                len,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        } {
+            0 => Ok(()),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn BlockDeviceBackend>> {
+        Ok(Box::new(Self {
+            file: self.file.try_clone()?,
+            block_size: self.block_size,
+        }))
+    }
 }
 
 pub(crate) struct BlockDevice<T: BlockDeviceBackend> {
     backend: T,
     write_protected: bool,
     rotation_rate: MediumRotationRate,
+    throttle: Option<Arc<Throttle>>,
+    naa: Option<u64>,
+    power_condition: PowerCondition,
+    medium: Option<Arc<RemovableMedium<T>>>,
+    /// A unit attention condition to report with the next command.
+    unit_attention: Option<SenseTriple>,
+    logs: Logs,
+    /// The background self-test in progress, if any. Its result is the most
+    /// recent one in `logs`.
+    self_test: Option<BackgroundSelfTest>,
 }
 
 impl<T: BlockDeviceBackend> BlockDevice<T> {
@@ -154,6 +347,125 @@ impl<T: BlockDeviceBackend> BlockDevice<T> {
             backend,
             write_protected: false,
             rotation_rate: MediumRotationRate::Unreported,
+            throttle: None,
+            naa: None,
+            power_condition: PowerCondition::Active,
+            medium: None,
+            unit_attention: None,
+            logs: Logs::new(),
+            self_test: None,
+        }
+    }
+
+    /// Switch to a medium the host inserted since the last command, if any.
+    fn poll_medium(&mut self) {
+        if let Some(backend) = self.medium.as_ref().and_then(|m| m.take_inserted()) {
+            self.backend = backend;
+            self.power_condition = PowerCondition::Active;
+            self.unit_attention = Some(sense::NOT_READY_TO_READY_CHANGE);
+        }
+    }
+
+    /// The sense data explaining why the medium can't be accessed, if it
+    /// can't.
+    fn not_ready(&self) -> Option<SenseTriple> {
+        match self.medium.as_ref().map(|m| m.status()) {
+            Some(MediumStatus::Ejected) => Some(sense::MEDIUM_NOT_PRESENT_TRAY_OPEN),
+            Some(MediumStatus::Absent) => Some(sense::MEDIUM_NOT_PRESENT),
+            _ if self.power_condition == PowerCondition::Stopped => {
+                Some(sense::LOGICAL_UNIT_NOT_READY_INITIALIZING_COMMAND_REQUIRED)
+            }
+            _ => None,
+        }
+    }
+
+    /// Log the outcome of the background self-test, if it's finished.
+    fn poll_self_test(&mut self) {
+        if matches!(&self.self_test, Some(t) if t.is_finished()) {
+            let self_test = self.self_test.take().unwrap();
+            self.finish_self_test(self_test.join());
+        }
+    }
+
+    /// Log the outcome of the most recently started self-test.
+    fn finish_self_test(&mut self, outcome: SelfTestOutcome) {
+        if let Some(result) = self.logs.self_tests.front_mut() {
+            result.outcome = outcome;
+        }
+    }
+
+    /// Run, start or abort a self-test as requested by SEND DIAGNOSTIC.
+    fn self_test(&mut self, code: SelfTestCode) -> CmdOutput {
+        if code == SelfTestCode::AbortBackground {
+            if let Some(self_test) = self.self_test.take() {
+                self.finish_self_test(self_test.abort());
+            }
+            return CmdOutput::ok();
+        }
+        if self.self_test.is_some() {
+            return CmdOutput::check_condition(sense::LOGICAL_UNIT_NOT_READY_SELF_TEST_IN_PROGRESS);
+        }
+        if let Some(sense) = self.not_ready() {
+            return CmdOutput::check_condition(sense);
+        }
+
+        let kind = match code {
+            SelfTestCode::Default => {
+                // the default self-test isn't logged
+                let outcome = run_self_test(
+                    &mut self.backend,
+                    SelfTestKind::Short,
+                    &AtomicBool::new(false),
+                );
+                return match outcome {
+                    SelfTestOutcome::Passed => CmdOutput::ok(),
+                    _ => CmdOutput::check_condition(sense::LOGICAL_UNIT_FAILED_SELF_TEST),
+                };
+            }
+            SelfTestCode::Foreground(kind) | SelfTestCode::Background(kind) => kind,
+            SelfTestCode::AbortBackground => unreachable!(),
+        };
+        self.logs.push_self_test(SelfTestResult::new(code.code()));
+
+        if matches!(code, SelfTestCode::Background(_)) {
+            let spawned = self
+                .backend
+                .try_clone()
+                .and_then(|backend| BackgroundSelfTest::spawn(backend, kind));
+            match spawned {
+                Ok(self_test) => {
+                    self.self_test = Some(self_test);
+                    return CmdOutput::ok();
+                }
+                // run it right away instead; the guest can't tell
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+                Err(e) => {
+                    error!("Error starting background self-test: {}", e);
+                    self.finish_self_test(SelfTestOutcome::Error);
+                    return CmdOutput::ok();
+                }
+            }
+        }
+
+        let outcome = run_self_test(&mut self.backend, kind, &AtomicBool::new(false));
+        self.finish_self_test(outcome);
+        match (code, outcome) {
+            (
+                SelfTestCode::Foreground(_),
+                SelfTestOutcome::Failed { .. } | SelfTestOutcome::Error,
+            ) => CmdOutput::check_condition(sense::LOGICAL_UNIT_FAILED_SELF_TEST),
+            _ => CmdOutput::ok(),
+        }
+    }
+
+    /// Take the throttle tokens for transferring `blocks` blocks, or fail
+    /// with `CmdError::Deferred` if the command has to wait.
+    fn admit(&self, direction: IoDirection, blocks: BlockOffset) -> Result<(), CmdError> {
+        match &self.throttle {
+            Some(throttle) => throttle
+                .admit(direction, u64::from(blocks * self.backend.block_size()))
+                .map_err(CmdError::Deferred),
+            None => Ok(()),
         }
     }
 
@@ -192,6 +504,124 @@ impl<T: BlockDeviceBackend> BlockDevice<T> {
         Ok(())
     }
 
+    /// Check that `blocks` blocks starting at `lba` are on the medium, and
+    /// return the number of blocks of the medium.
+    fn check_lba_range(
+        &mut self,
+        lba: BlockOffset,
+        blocks: BlockOffset,
+    ) -> Result<BlockOffset, CmdOutput> {
+        let size = self.backend.size_in_blocks().map_err(|e| {
+            error!("Error getting image size: {}", e);
+            CmdOutput::check_condition(sense_for_io_error(&e, sense::UNRECOVERED_READ_ERROR))
+        })?;
+        match u64::from(lba).checked_add(u64::from(blocks)) {
+            Some(end) if end <= u64::from(size) => Ok(size),
+            _ => Err(CmdOutput::check_condition(
+                sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE,
+            )),
+        }
+    }
+
+    /// Check that `blocks` blocks starting at `lba` can be read and, depending
+    /// on `byte_check`, match the data in `data_out`.
+    fn verify_blocks(
+        &mut self,
+        lba: BlockOffset,
+        blocks: BlockOffset,
+        byte_check: ByteCheck,
+        data_out: &mut dyn Read,
+    ) -> Result<(), SenseTriple> {
+        // Verify in chunks, so that we don't need to allocate a buffer for the
+        // whole range.
+        const CHUNK_BLOCKS: u64 = 128;
+
+        let block_size = usize::try_from(u32::from(self.backend.block_size()))
+            .expect("block_size should fit usize");
+        let mut expected = Vec::new();
+        if byte_check == ByteCheck::SingleBlock {
+            expected.resize(block_size, 0);
+            data_out.read_exact(&mut expected).map_err(|e| {
+                error!("Error reading from data_out: {}", e);
+                sense::TARGET_FAILURE
+            })?;
+        }
+
+        let end = u64::from(lba + blocks);
+        let mut next = u64::from(lba);
+        while next < end {
+            let count = BlockOffset(CHUNK_BLOCKS.min(end - next));
+            let actual = self.read_blocks(BlockOffset(next), count);
+            self.logs
+                .read_errors
+                .record(u64::from(count * self.backend.block_size()), &actual);
+            let actual = actual.map_err(|e| {
+                error!("Error reading image: {}", e);
+                sense_for_io_error(&e, sense::UNRECOVERED_READ_ERROR)
+            })?;
+            let matches = match byte_check {
+                ByteCheck::None => true,
+                ByteCheck::Blocks => {
+                    expected.resize(actual.len(), 0);
+                    data_out.read_exact(&mut expected).map_err(|e| {
+                        error!("Error reading from data_out: {}", e);
+                        sense::TARGET_FAILURE
+                    })?;
+                    actual == expected
+                }
+                ByteCheck::SingleBlock => actual
+                    .chunks_exact(block_size)
+                    .all(|block| block == expected),
+            };
+            if !matches {
+                return Err(sense::MISCOMPARE_DURING_VERIFY_OPERATION);
+            }
+            next += u64::from(count);
+        }
+        Ok(())
+    }
+
+    /// Describe the provisioning status of the blocks from `lba` on as
+    /// (LBA, number of blocks, mapped) extents, up to `max_descriptors` of
+    /// them.
+    fn lba_status(
+        &mut self,
+        lba: BlockOffset,
+        size: BlockOffset,
+        max_descriptors: usize,
+    ) -> io::Result<Vec<(u64, u32, bool)>> {
+        let block_size = u64::from(u32::from(self.backend.block_size()));
+        let mut descriptors: Vec<(u64, u32, bool)> = Vec::new();
+        let mut next = u64::from(lba);
+        while next < u64::from(size) && descriptors.len() < max_descriptors {
+            let remaining = u64::from(size) - next;
+            let (mapped, len) = self.backend.allocation_status(
+                BlockOffset(next) * self.backend.block_size(),
+                remaining * block_size,
+            )?;
+            // Blocks that are only partially allocated count as mapped.
+            let (mapped, blocks) = match len / block_size {
+                _ if mapped => (true, len.div_ceil(block_size)),
+                0 => (true, 1),
+                blocks => (false, blocks),
+            };
+            let blocks = u32::try_from(blocks.clamp(1, remaining)).unwrap_or(u32::MAX);
+
+            match descriptors.last_mut() {
+                Some((start, count, was_mapped))
+                    if *was_mapped == mapped
+                        && *start + u64::from(*count) == next
+                        && count.checked_add(blocks).is_some() =>
+                {
+                    *count += blocks;
+                }
+                _ => descriptors.push((next, blocks, mapped)),
+            }
+            next += u64::from(blocks);
+        }
+        Ok(descriptors)
+    }
+
     fn write_same_block(
         &mut self,
         lba_start: BlockOffset,
@@ -213,9 +643,38 @@ impl<T: BlockDeviceBackend> BlockDevice<T> {
     pub fn set_solid_state(&mut self, rotation_rate: MediumRotationRate) {
         self.rotation_rate = rotation_rate;
     }
+
+    pub fn set_throttle(&mut self, throttle: Arc<Throttle>) {
+        self.throttle = Some(throttle);
+    }
+
+    /// Identify the logical unit by an NAA designator in the Device
+    /// Identification VPD page, which is also what copy commands refer to it
+    /// by.
+    pub fn set_naa(&mut self, naa: u64) {
+        self.naa = Some(naa);
+    }
+
+    /// Make the medium removable, with `medium` holding its state. The medium
+    /// starts out loaded with the current backend.
+    pub fn set_removable(&mut self, medium: Arc<RemovableMedium<T>>) {
+        self.medium = Some(medium);
+    }
 }
 
 impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
+    fn copy_endpoint(&mut self) -> Option<CopyEndpoint<'_>> {
+        self.poll_medium();
+        if self.not_ready().is_some() {
+            return None;
+        }
+        Some(CopyEndpoint {
+            backend: &mut self.backend,
+            naa: self.naa,
+            throttle: self.throttle.as_deref(),
+        })
+    }
+
     fn execute_command(
         &mut self,
         data_in: &mut SilentlyTruncate<&mut dyn Write>,
@@ -229,26 +688,45 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
             warn!("Received non-zero CRN: {}", req.crn);
         }
 
-        if req.task_attr != TaskAttr::Simple {
-            // virtio-scsi spec allows us to treat all task attrs as SIMPLE.
-            warn!("Ignoring non-simple task attr of {:?}", req.task_attr);
-        }
+        debug!("Incoming command: {:?}", command);
 
-        if req.prio != 0 {
-            // My reading of SAM-6 is that priority is purely advisory, so it's fine to
-            // ignore it.
-            warn!("Ignoring non-zero priority of {}.", req.prio);
+        self.poll_medium();
+        self.poll_self_test();
+        // INQUIRY doesn't report unit attention conditions, and REQUEST SENSE
+        // reports (and clears) them as its sense data rather than with a
+        // CHECK CONDITION, see SAM-6 5.14
+        if !matches!(
+            command,
+            LunSpecificCommand::Inquiry(_) | LunSpecificCommand::RequestSense(_)
+        ) {
+            if let Some(sense) = self.unit_attention.take() {
+                return Ok(CmdOutput::check_condition(sense));
+            }
         }
 
-        if req.naca {
-            // We don't support NACA, and say as much in our INQUIRY data, so if
-            // we get it that's an error.
-            warn!("Driver set NACA bit, which is unsupported.");
-            return Ok(CmdOutput::check_condition(sense::INVALID_FIELD_IN_CDB));
+        if matches!(
+            command,
+            LunSpecificCommand::TestUnitReady
+                | LunSpecificCommand::ReadCapacity10
+                | LunSpecificCommand::ReadCapacity16
+                | LunSpecificCommand::Read10 { .. }
+                | LunSpecificCommand::Write10 { .. }
+                | LunSpecificCommand::WriteSame16 { .. }
+                | LunSpecificCommand::SynchronizeCache { .. }
+                | LunSpecificCommand::Verify { .. }
+                | LunSpecificCommand::PreFetch { .. }
+                | LunSpecificCommand::GetLbaStatus { .. }
+        ) {
+            if let Some(sense) = self.not_ready() {
+                return Ok(CmdOutput::check_condition(sense));
+            }
+            // accessing the medium wakes us up from idle or standby, but
+            // checking whether we're ready doesn't
+            if !matches!(command, LunSpecificCommand::TestUnitReady) {
+                self.power_condition = PowerCondition::Active;
+            }
         }
 
-        debug!("Incoming command: {:?}", command);
-
         match command {
             LunSpecificCommand::TestUnitReady => Ok(CmdOutput::ok()),
             LunSpecificCommand::ReadCapacity10 => {
@@ -276,7 +754,10 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     Err(e) => {
                         error!("Error getting image size: {}", e);
                         // TODO: Is this a reasonable sense code to send?
-                        Ok(CmdOutput::check_condition(sense::UNRECOVERED_READ_ERROR))
+                        Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::UNRECOVERED_READ_ERROR,
+                        )))
                     }
                 }
             }
@@ -311,7 +792,10 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     Err(e) => {
                         error!("Error getting image size: {}", e);
                         // TODO: Is this a reasonable sense code to send?
-                        Ok(CmdOutput::check_condition(sense::UNRECOVERED_READ_ERROR))
+                        Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::UNRECOVERED_READ_ERROR,
+                        )))
                     }
                 }
             }
@@ -416,7 +900,10 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
 
                     if let Err(e) = self.backend.sync() {
                         error!("Error syncing file: {}", e);
-                        return Ok(CmdOutput::check_condition(sense::TARGET_FAILURE));
+                        return Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::TARGET_FAILURE,
+                        )));
                     }
                 }
 
@@ -427,7 +914,10 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     Ok(size) => size,
                     Err(e) => {
                         error!("Error getting image size for read: {}", e);
-                        return Ok(CmdOutput::check_condition(sense::UNRECOVERED_READ_ERROR));
+                        return Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::UNRECOVERED_READ_ERROR,
+                        )));
                     }
                 };
 
@@ -440,7 +930,13 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     ));
                 }
 
+                self.admit(IoDirection::Read, transfer_length)?;
+
                 let read_result = self.read_blocks(lba, transfer_length);
+                self.logs.read_errors.record(
+                    u64::from(transfer_length * self.backend.block_size()),
+                    &read_result,
+                );
 
                 match read_result {
                     Ok(bytes) => {
@@ -449,7 +945,10 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     }
                     Err(e) => {
                         error!("Error reading image: {}", e);
-                        Ok(CmdOutput::check_condition(sense::UNRECOVERED_READ_ERROR))
+                        Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::UNRECOVERED_READ_ERROR,
+                        )))
                     }
                 }
             }
@@ -469,7 +968,10 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     Ok(size) => size,
                     Err(e) => {
                         error!("Error getting image size for read: {}", e);
-                        return Ok(CmdOutput::check_condition(sense::TARGET_FAILURE));
+                        return Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::TARGET_FAILURE,
+                        )));
                     }
                 };
 
@@ -482,12 +984,21 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     ));
                 }
 
+                self.admit(IoDirection::Write, transfer_length)?;
+
                 let write_result = self.write_blocks(lba, transfer_length, data_out);
+                self.logs.write_errors.record(
+                    u64::from(transfer_length * self.backend.block_size()),
+                    &write_result,
+                );
 
                 if fua {
                     if let Err(e) = self.backend.sync() {
                         error!("Error syncing file: {}", e);
-                        return Ok(CmdOutput::check_condition(sense::TARGET_FAILURE));
+                        return Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::TARGET_FAILURE,
+                        )));
                     }
                 }
 
@@ -495,7 +1006,10 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     Ok(()) => Ok(CmdOutput::ok()),
                     Err(e) => {
                         error!("Error writing to block device: {}", e);
-                        Ok(CmdOutput::check_condition(sense::TARGET_FAILURE))
+                        Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::TARGET_FAILURE,
+                        )))
                     }
                 }
             }
@@ -503,6 +1017,7 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                 lba,
                 number_of_logical_blocks,
                 anchor,
+                unmap,
             } => {
                 // We do not support block provisioning
                 if anchor {
@@ -510,14 +1025,18 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                 }
 
                 // This command can be used to unmap/discard a region of blocks...
-                // TODO: Do something smarter and punch holes into the backend,
-                // for now we will just write A LOT of zeros in a very inefficient way.
+                // TODO: Punch holes into files, too; for now, unless the backend
+                // supports discarding, we will just write A LOT of zeros in a very
+                // inefficient way.
 
                 let size = match self.backend.size_in_blocks() {
                     Ok(size) => size,
                     Err(e) => {
                         error!("Error getting image size for read: {}", e);
-                        return Ok(CmdOutput::check_condition(sense::UNRECOVERED_READ_ERROR));
+                        return Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::UNRECOVERED_READ_ERROR,
+                        )));
                     }
                 };
 
@@ -530,6 +1049,8 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     ));
                 }
 
+                self.admit(IoDirection::Write, number_of_logical_blocks)?;
+
                 let mut buf = vec![
                     0;
                     usize::try_from(u32::from(self.backend.block_size()))
@@ -541,26 +1062,52 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     return Ok(CmdOutput::check_condition(sense::TARGET_FAILURE));
                 }
 
-                let write_result = self.write_same_block(lba, number_of_logical_blocks, &buf);
+                // We report unmapped blocks as reading zeros, so only zeros can
+                // be discarded.
+                let write_result = if unmap && buf.iter().all(|&b| b == 0) {
+                    let block_size = self.backend.block_size();
+                    match self.backend.discard(
+                        lba * block_size,
+                        u64::from(number_of_logical_blocks * block_size),
+                    ) {
+                        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                            self.write_same_block(lba, number_of_logical_blocks, &buf)
+                        }
+                        result => result,
+                    }
+                } else {
+                    self.write_same_block(lba, number_of_logical_blocks, &buf)
+                };
+                self.logs.write_errors.record(
+                    u64::from(number_of_logical_blocks * self.backend.block_size()),
+                    &write_result,
+                );
 
                 match write_result {
                     Ok(()) => Ok(CmdOutput::ok()),
                     Err(e) => {
                         error!("Error writing to block device: {}", e);
-                        Ok(CmdOutput::check_condition(sense::TARGET_FAILURE))
+                        Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::TARGET_FAILURE,
+                        )))
                     }
                 }
             }
             LunSpecificCommand::Inquiry(page_code) => {
                 // top 3 bits 0: peripheral device code = exists and ready
                 // bottom 5 bits 0: device type = block device
-                data_in.write_all(&[0]).map_err(CmdError::DataIn)?;
+                let peripheral = [0];
 
                 if let Some(code) = page_code {
                     let mut out = vec![];
                     match code {
                         VpdPage::SupportedVpdPages => {
                             out.push(VpdPage::SupportedVpdPages.into());
+                            if self.naa.is_some() {
+                                out.push(VpdPage::DeviceIdentification.into());
+                            }
+                            out.push(VpdPage::ThirdPartyCopy.into());
                             out.push(VpdPage::BlockDeviceCharacteristics.into());
                             out.push(VpdPage::LogicalBlockProvisioning.into());
                         }
@@ -582,9 +1129,23 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                             out.push(0b0000_0010); // thin provisioned
                             out.push(0); // no threshold % support
                         }
+                        VpdPage::DeviceIdentification => {
+                            let Some(naa) = self.naa else {
+                                return Ok(CmdOutput::check_condition(sense::INVALID_FIELD_IN_CDB));
+                            };
+                            out.push(0x1); // code set: binary
+                            out.push(0x3); // associated with the LU, type: NAA
+                            out.push(0); // reserved
+                            out.push(8); // designator length
+                            out.extend_from_slice(&naa.to_be_bytes());
+                        }
+                        VpdPage::ThirdPartyCopy => {
+                            third_party_copy::write_vpd_page(&mut out);
+                        }
                         _ => return Ok(CmdOutput::check_condition(sense::INVALID_FIELD_IN_CDB)),
                     }
 
+                    data_in.write_all(&peripheral).map_err(CmdError::DataIn)?;
                     data_in
                         .write_all(&[code.into()])
                         .map_err(CmdError::DataIn)?;
@@ -597,7 +1158,9 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                         .map_err(CmdError::DataIn)?;
                     data_in.write_all(&out).map_err(CmdError::DataIn)?;
                 } else {
-                    respond_standard_inquiry_data(data_in).map_err(CmdError::DataIn)?;
+                    data_in.write_all(&peripheral).map_err(CmdError::DataIn)?;
+                    respond_standard_inquiry_data(data_in, self.medium.is_some(), true, true)
+                        .map_err(CmdError::DataIn)?;
                 }
 
                 Ok(CmdOutput::ok())
@@ -752,8 +1315,19 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
             LunSpecificCommand::RequestSense(format) => {
                 match format {
                     SenseFormat::Fixed => {
+                        let sense = self
+                            .unit_attention
+                            .take()
+                            .or_else(|| self.not_ready())
+                            .unwrap_or(match self.power_condition {
+                                PowerCondition::Idle => sense::IDLE_CONDITION_ACTIVATED_BY_COMMAND,
+                                PowerCondition::Standby => {
+                                    sense::STANDBY_CONDITION_ACTIVATED_BY_COMMAND
+                                }
+                                _ => sense::NO_ADDITIONAL_SENSE_INFORMATION,
+                            });
                         data_in
-                            .write_all(&sense::NO_ADDITIONAL_SENSE_INFORMATION.to_fixed_sense())
+                            .write_all(&sense.to_fixed_sense())
                             .map_err(CmdError::DataIn)?;
                         Ok(CmdOutput::ok())
                     }
@@ -763,14 +1337,249 @@ impl<T: BlockDeviceBackend> LogicalUnit for BlockDevice<T> {
                     }
                 }
             }
-            LunSpecificCommand::SynchronizeCache10 => {
+            LunSpecificCommand::SynchronizeCache {
+                lba,
+                number_of_blocks,
+            } => {
+                if let Err(output) =
+                    self.check_lba_range(BlockOffset(lba), BlockOffset(number_of_blocks.into()))
+                {
+                    return Ok(output);
+                }
+
                 // While SCSI allows just syncing a range, we just sync the entire file
                 match self.backend.sync() {
                     Ok(()) => Ok(CmdOutput::ok()),
                     Err(e) => {
                         error!("Error syncing block device: {}", e);
-                        Ok(CmdOutput::check_condition(sense::TARGET_FAILURE))
+                        Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::TARGET_FAILURE,
+                        )))
+                    }
+                }
+            }
+            LunSpecificCommand::Verify {
+                lba,
+                verification_length,
+                byte_check,
+            } => {
+                let lba = BlockOffset(lba);
+                let blocks = BlockOffset(verification_length.into());
+                if let Err(output) = self.check_lba_range(lba, blocks) {
+                    return Ok(output);
+                }
+
+                self.admit(IoDirection::Read, blocks)?;
+
+                match self.verify_blocks(lba, blocks, byte_check, data_out) {
+                    Ok(()) => Ok(CmdOutput::ok()),
+                    Err(sense) => Ok(CmdOutput::check_condition(sense)),
+                }
+            }
+            LunSpecificCommand::PreFetch {
+                lba,
+                prefetch_length,
+            } => {
+                let lba = BlockOffset(lba);
+                let size = match self.check_lba_range(lba, BlockOffset(prefetch_length.into())) {
+                    Ok(size) => size,
+                    Err(output) => return Ok(output),
+                };
+                let blocks = match prefetch_length {
+                    0 => size - lba,
+                    len => BlockOffset(len.into()),
+                };
+
+                // We don't have a cache of our own, so all we can do is ask
+                // the host to fill its page cache. We don't know whether that
+                // worked out, so we never report CONDITION MET.
+                let block_size = self.backend.block_size();
+                match self
+                    .backend
+                    .prefetch(lba * block_size, u64::from(blocks * block_size))
+                {
+                    Ok(()) => Ok(CmdOutput::ok()),
+                    Err(e) => {
+                        error!("Error prefetching blocks: {}", e);
+                        Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::UNRECOVERED_READ_ERROR,
+                        )))
+                    }
+                }
+            }
+            LunSpecificCommand::GetLbaStatus {
+                lba,
+                allocation_length,
+            } => {
+                let lba = BlockOffset(lba);
+                let size = match self.check_lba_range(lba, BlockOffset(1)) {
+                    Ok(size) => size,
+                    Err(output) => return Ok(output),
+                };
+
+                // an 8 byte header, followed by 16 byte descriptors; always
+                // return at least one descriptor, even if it gets truncated
+                let max_descriptors = usize::try_from(allocation_length.saturating_sub(8) / 16)
+                    .unwrap_or(usize::MAX)
+                    .max(1);
+                let descriptors = match self.lba_status(lba, size, max_descriptors) {
+                    Ok(descriptors) => descriptors,
+                    Err(e) => {
+                        error!("Error getting LBA status: {}", e);
+                        return Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::UNRECOVERED_READ_ERROR,
+                        )));
+                    }
+                };
+
+                let mut out = Vec::with_capacity(8 + 16 * descriptors.len());
+                out.extend_from_slice(
+                    &u32::try_from(4 + 16 * descriptors.len())
+                        .expect("descriptors should fit the allocation length")
+                        .to_be_bytes(),
+                );
+                out.extend_from_slice(&[0; 4]); // reserved
+                for (lba, blocks, mapped) in descriptors {
+                    out.extend_from_slice(&lba.to_be_bytes());
+                    out.extend_from_slice(&blocks.to_be_bytes());
+                    // provisioning status: mapped or deallocated
+                    out.push(if mapped { 0 } else { 1 });
+                    out.extend_from_slice(&[0; 3]); // reserved
+                }
+                data_in.write_all(&out).map_err(CmdError::DataIn)?;
+                Ok(CmdOutput::ok())
+            }
+            LunSpecificCommand::LogSense {
+                pc,
+                page,
+                parameter_pointer,
+            } => {
+                let logs = match pc {
+                    LogPageControl::Cumulative => self.logs.clone(),
+                    LogPageControl::DefaultCumulative => Logs::default(),
+                    // we don't have any thresholds
+                    LogPageControl::Threshold | LogPageControl::DefaultThreshold => {
+                        return Ok(CmdOutput::check_condition(sense::INVALID_FIELD_IN_CDB))
+                    }
+                };
+                page.write(&logs, parameter_pointer, data_in)
+                    .map_err(CmdError::DataIn)?;
+                Ok(CmdOutput::ok())
+            }
+            LunSpecificCommand::LogSelect {
+                pcr,
+                pc,
+                page,
+                parameter_list_length,
+            } => {
+                // None of our log parameters can be changed by the guest, so
+                // any parameter list is invalid.
+                let mut parameter_list = vec![0; usize::from(parameter_list_length)];
+                if let Err(e) = data_out.read_exact(&mut parameter_list) {
+                    error!("Error reading from data_out: {}", e);
+                    return Ok(CmdOutput::check_condition(sense::TARGET_FAILURE));
+                }
+                if !parameter_list.is_empty() {
+                    return Ok(CmdOutput::check_condition(
+                        sense::INVALID_FIELD_IN_PARAMETER_LIST,
+                    ));
+                }
+                // Without a parameter list, LOG SELECT resets the parameters
+                // selected by PC to their defaults; only the cumulative ones
+                // exist, which PCR resets regardless of PC.
+                if pcr
+                    || matches!(
+                        pc,
+                        LogPageControl::Cumulative | LogPageControl::DefaultCumulative
+                    )
+                {
+                    self.logs.reset(page);
+                }
+                Ok(CmdOutput::ok())
+            }
+            LunSpecificCommand::StartStopUnit {
+                power_condition,
+                no_flush,
+                load_eject,
+                start,
+            } => {
+                // START and LOEJ are ignored when a power condition is given
+                if let Some(power_condition) = power_condition {
+                    self.power_condition = power_condition;
+                    return Ok(CmdOutput::ok());
+                }
+
+                if !start && !no_flush {
+                    if let Err(e) = self.backend.sync() {
+                        error!("Error syncing block device: {}", e);
+                        return Ok(CmdOutput::check_condition(sense_for_io_error(
+                            &e,
+                            sense::TARGET_FAILURE,
+                        )));
+                    }
+                }
+
+                // There's nothing to load or eject on a non-removable medium,
+                // so LOEJ only starts or stops those.
+                if let Some(medium) = self.medium.as_ref().filter(|_| load_eject) {
+                    let result = if start {
+                        medium.load()
+                    } else {
+                        medium.unload()
+                    };
+                    if let Err(sense) = result {
+                        return Ok(CmdOutput::check_condition(sense));
+                    }
+                }
+
+                self.power_condition = if start {
+                    PowerCondition::Active
+                } else {
+                    PowerCondition::Stopped
+                };
+                Ok(CmdOutput::ok())
+            }
+            LunSpecificCommand::PreventAllowMediumRemoval { prevent } => {
+                if let Some(medium) = &self.medium {
+                    medium.set_prevent_removal(prevent);
+                }
+                Ok(CmdOutput::ok())
+            }
+            LunSpecificCommand::SendDiagnostic {
+                self_test,
+                parameter_list_length,
+            } => {
+                if let Some(code) = self_test {
+                    return Ok(self.self_test(code));
+                }
+
+                // The only diagnostic page we support is the Supported
+                // Diagnostic Pages page, which is sent without parameters.
+                let mut parameter_list = vec![0; usize::from(parameter_list_length)];
+                if let Err(e) = data_out.read_exact(&mut parameter_list) {
+                    error!("Error reading from data_out: {}", e);
+                    return Ok(CmdOutput::check_condition(sense::TARGET_FAILURE));
+                }
+                if !matches!(parameter_list[..], [] | [0, 0, 0, 0]) {
+                    return Ok(CmdOutput::check_condition(
+                        sense::INVALID_FIELD_IN_PARAMETER_LIST,
+                    ));
+                }
+                Ok(CmdOutput::ok())
+            }
+            LunSpecificCommand::ReceiveDiagnosticResults { page_code } => {
+                match page_code {
+                    // Supported Diagnostic Pages, listing just itself
+                    None | Some(0x00) => {
+                        data_in
+                            .write_all(&[0x00, 0, 0, 1, 0x00])
+                            .map_err(CmdError::DataIn)?;
+                        Ok(CmdOutput::ok())
                     }
+                    Some(_) => Ok(CmdOutput::check_condition(sense::INVALID_FIELD_IN_CDB)),
                 }
             }
         }
@@ -15,7 +15,12 @@ use std::convert::{TryFrom, TryInto};
 use log::warn;
 use num_enum::TryFromPrimitive;
 
-use crate::scsi::emulation::mode_page::ModePage;
+use crate::scsi::emulation::{
+    log_page::LogPage,
+    medium::PowerCondition,
+    mode_page::ModePage,
+    self_test::{SelfTestCode, SelfTestKind},
+};
 
 /// One of the modes supported by SCSI's REPORT LUNS command.
 #[derive(PartialEq, Eq, TryFromPrimitive, Debug, Copy, Clone)]
@@ -70,6 +75,16 @@ pub(crate) enum ModeSensePageControl {
     Saved = 0b11,
 }
 
+/// The PC field of LOG SENSE and LOG SELECT.
+#[derive(PartialEq, Eq, TryFromPrimitive, Debug, Copy, Clone)]
+#[repr(u8)]
+pub(crate) enum LogPageControl {
+    Threshold = 0b00,
+    Cumulative = 0b01,
+    DefaultThreshold = 0b10,
+    DefaultCumulative = 0b11,
+}
+
 impl TryFrom<u8> for VpdPage {
     type Error = ();
 
@@ -145,6 +160,17 @@ pub(crate) enum SenseFormat {
     Descriptor,
 }
 
+/// The BYTCHK field of VERIFY.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum ByteCheck {
+    /// Only check that the blocks can be read.
+    None,
+    /// Compare the blocks with the data-out buffer.
+    Blocks,
+    /// Compare every block with the single block in the data-out buffer.
+    SingleBlock,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub(crate) enum ModePageSelection {
     AllPageZeros,
@@ -187,6 +213,8 @@ pub(crate) enum LunSpecificCommand {
         lba: u64,
         number_of_logical_blocks: u32,
         anchor: bool,
+        /// The blocks may be deallocated instead of written to.
+        unmap: bool,
     },
     ReadCapacity10,
     ReadCapacity16,
@@ -197,29 +225,124 @@ pub(crate) enum LunSpecificCommand {
     },
     RequestSense(SenseFormat),
     TestUnitReady,
-    SynchronizeCache10,
+    /// SYNCHRONIZE CACHE (10) and (16)
+    SynchronizeCache {
+        lba: u64,
+        /// 0 means all blocks from `lba` on.
+        number_of_blocks: u32,
+    },
+    /// VERIFY (10) and (16)
+    Verify {
+        lba: u64,
+        verification_length: u32,
+        byte_check: ByteCheck,
+    },
+    /// PRE-FETCH (10) and (16)
+    PreFetch {
+        lba: u64,
+        /// 0 means all blocks from `lba` on.
+        prefetch_length: u32,
+    },
+    GetLbaStatus {
+        lba: u64,
+        allocation_length: u32,
+    },
+    LogSense {
+        pc: LogPageControl,
+        page: LogPage,
+        parameter_pointer: u16,
+    },
+    LogSelect {
+        /// Parameter code reset
+        pcr: bool,
+        pc: LogPageControl,
+        /// The page to select, or `None` for all pages.
+        page: Option<LogPage>,
+        parameter_list_length: u16,
+    },
+    StartStopUnit {
+        /// The power condition to move to, or `None` to go by `start` and
+        /// `load_eject`.
+        power_condition: Option<PowerCondition>,
+        /// Don't flush the cache before stopping.
+        no_flush: bool,
+        /// Load (if `start`) or eject (otherwise) the medium.
+        load_eject: bool,
+        start: bool,
+    },
+    PreventAllowMediumRemoval {
+        prevent: bool,
+    },
+    SendDiagnostic {
+        /// The self-test to run, or `None` to process the parameter list.
+        self_test: Option<SelfTestCode>,
+        parameter_list_length: u16,
+    },
+    ReceiveDiagnosticResults {
+        /// The requested diagnostic page, if PCV is set.
+        page_code: Option<u8>,
+    },
+}
+
+/// Commands executed by the target's copy manager rather than by the logical
+/// unit, since they may access other logical units.
+#[derive(Debug)]
+pub(crate) enum CopyCommand {
+    ExtendedCopy {
+        parameter_list_length: u32,
+    },
+    PopulateToken {
+        list_identifier: u32,
+        parameter_list_length: u32,
+    },
+    WriteUsingToken {
+        list_identifier: u32,
+        parameter_list_length: u32,
+    },
+    ReceiveCopyOperatingParameters,
+    ReceiveRodTokenInformation {
+        list_identifier: u32,
+    },
 }
 
 #[derive(Debug)]
 pub(crate) enum Command {
     LunIndependentCommand(LunIndependentCommand),
     LunSpecificCommand(LunSpecificCommand),
+    ThirdPartyCopy(CopyCommand),
 }
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum CommandType {
+    ExtendedCopy,
+    GetLbaStatus,
     Inquiry,
+    LogSelect,
+    LogSense,
     ModeSense6,
+    PopulateToken,
+    PreFetch10,
+    PreFetch16,
+    PreventAllowMediumRemoval,
     Read10,
     ReadCapacity10,
     ReadCapacity16,
+    ReceiveCopyOperatingParameters,
+    ReceiveDiagnosticResults,
+    ReceiveRodTokenInformation,
     ReportLuns,
     ReportSupportedOperationCodes,
     RequestSense,
+    SendDiagnostic,
+    StartStopUnit,
     TestUnitReady,
+    Verify10,
+    Verify16,
     Write10,
     WriteSame16,
+    WriteUsingToken,
     SynchronizeCache10,
+    SynchronizeCache16,
 }
 
 pub(crate) const OPCODES: &[(CommandType, (u8, Option<u16>))] = &[
@@ -227,12 +350,32 @@ pub(crate) const OPCODES: &[(CommandType, (u8, Option<u16>))] = &[
     (CommandType::RequestSense, (0x3, None)),
     (CommandType::Inquiry, (0x12, None)),
     (CommandType::ModeSense6, (0x1a, None)),
+    (CommandType::StartStopUnit, (0x1b, None)),
+    (CommandType::ReceiveDiagnosticResults, (0x1c, None)),
+    (CommandType::SendDiagnostic, (0x1d, None)),
+    (CommandType::PreventAllowMediumRemoval, (0x1e, None)),
     (CommandType::ReadCapacity10, (0x25, None)),
     (CommandType::Read10, (0x28, None)),
     (CommandType::Write10, (0x2a, None)),
+    (CommandType::Verify10, (0x2f, None)),
+    (CommandType::PreFetch10, (0x34, None)),
     (CommandType::SynchronizeCache10, (0x35, None)),
+    (CommandType::LogSelect, (0x4c, None)),
+    (CommandType::LogSense, (0x4d, None)),
+    (CommandType::ExtendedCopy, (0x83, Some(0x0))),
+    (CommandType::PopulateToken, (0x83, Some(0x10))),
+    (CommandType::WriteUsingToken, (0x83, Some(0x11))),
+    (
+        CommandType::ReceiveCopyOperatingParameters,
+        (0x84, Some(0x3)),
+    ),
+    (CommandType::ReceiveRodTokenInformation, (0x84, Some(0x7))),
+    (CommandType::Verify16, (0x8f, None)),
+    (CommandType::PreFetch16, (0x90, None)),
+    (CommandType::SynchronizeCache16, (0x91, None)),
     (CommandType::WriteSame16, (0x93, None)),
     (CommandType::ReadCapacity16, (0x9e, Some(0x10))),
+    (CommandType::GetLbaStatus, (0x9e, Some(0x12))),
     (CommandType::ReportLuns, (0xa0, None)),
     (
         CommandType::ReportSupportedOperationCodes,
@@ -298,10 +441,13 @@ impl CommandType {
         // TODO: Variable-length CDBs put the service action in a different
         // place. This'll need to change if we ever support those. IIRC, Linux
         // doesn't ever use them, so it may never be relevant.
-        match parse_opcode(cdb[0]) {
+        let opcode = *cdb.first().ok_or(ParseError::TooSmall)?;
+        match parse_opcode(opcode) {
             ParseOpcodeResult::Command(ty) => Ok(ty),
             ParseOpcodeResult::ServiceAction(sa) => sa
-                .parse(u16::from(cdb[1] & 0b0001_1111))
+                .parse(u16::from(
+                    cdb.get(1).ok_or(ParseError::TooSmall)? & 0b0001_1111,
+                ))
                 .ok_or(ParseError::InvalidField),
             ParseOpcodeResult::Invalid => Err(ParseError::InvalidCommand),
         }
@@ -447,8 +593,250 @@ impl CommandType {
                 0b0000_0000,
                 0b0000_0100,
             ],
+            Self::ExtendedCopy => &[
+                0x83,
+                0x0,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0100,
+            ],
+            Self::PopulateToken => &[
+                0x83,
+                0x10,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0100,
+            ],
+            Self::WriteUsingToken => &[
+                0x83,
+                0x11,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0100,
+            ],
+            Self::ReceiveCopyOperatingParameters => &[
+                0x84,
+                0x3,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0100,
+            ],
+            Self::ReceiveRodTokenInformation => &[
+                0x84,
+                0x7,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0100,
+            ],
+            Self::StartStopUnit => &[
+                0x1b,
+                0b0000_0001,
+                0b0000_0000,
+                0b0000_1111,
+                0b1111_0111,
+                0b0000_0100,
+            ],
+            Self::ReceiveDiagnosticResults => &[
+                0x1c,
+                0b0000_0001,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0100,
+            ],
+            Self::SendDiagnostic => &[
+                0x1d,
+                0b1111_0111,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0100,
+            ],
+            Self::PreventAllowMediumRemoval => &[
+                0x1e,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0011,
+                0b0000_0100,
+            ],
+            Self::LogSelect => &[
+                0x4c,
+                0b0000_0010,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0100,
+            ],
+            Self::LogSense => &[
+                0x4d,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0100,
+            ],
+            Self::Verify10 => &[
+                0x2f,
+                0b1111_0110,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0011_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0100,
+            ],
+            Self::Verify16 => &[
+                0x8f,
+                0b1111_0110,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0011_1111,
+                0b0000_0100,
+            ],
+            Self::PreFetch10 => &[
+                0x34,
+                0b0000_0010,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0011_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0100,
+            ],
+            Self::PreFetch16 => &[
+                0x90,
+                0b0000_0010,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0011_1111,
+                0b0000_0100,
+            ],
+            Self::SynchronizeCache16 => &[
+                0x91,
+                0b0000_0010,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0011_1111,
+                0b0000_0100,
+            ],
+            Self::GetLbaStatus => &[
+                0x9e,
+                0x12,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b1111_1111,
+                0b0000_0000,
+                0b0000_0100,
+            ],
             Self::SynchronizeCache10 => &[
-                0x53,
+                0x35,
                 0b0000_0010,
                 0b1111_1111,
                 0b1111_1111,
@@ -608,16 +996,126 @@ impl Cdb {
                             cdb[10..14].try_into().expect("block count should fit u32"),
                         ),
                         anchor: (cdb[1] & 0b0001_0000) != 0,
+                        unmap: (cdb[1] & 0b0000_1000) != 0,
                     }),
                     allocation_length: None,
                     naca: (cdb[15] & 0b0000_0100) != 0,
                 })
             }
+            // IMMED doesn't matter to us, since we complete commands right
+            // away either way
             CommandType::SynchronizeCache10 => Ok(Self {
-                command: Command::LunSpecificCommand(LunSpecificCommand::SynchronizeCache10),
+                command: Command::LunSpecificCommand(LunSpecificCommand::SynchronizeCache {
+                    lba: u32::from_be_bytes(cdb[2..6].try_into().unwrap()).into(),
+                    number_of_blocks: u16::from_be_bytes(cdb[7..9].try_into().unwrap()).into(),
+                }),
+                allocation_length: None,
+                naca: (cdb[9] & 0b0000_0100) != 0,
+            }),
+            CommandType::SynchronizeCache16 => Ok(Self {
+                command: Command::LunSpecificCommand(LunSpecificCommand::SynchronizeCache {
+                    lba: u64::from_be_bytes(cdb[2..10].try_into().unwrap()),
+                    number_of_blocks: u32::from_be_bytes(cdb[10..14].try_into().unwrap()),
+                }),
+                allocation_length: None,
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
+            CommandType::Verify10 => Ok(Self {
+                command: Command::LunSpecificCommand(LunSpecificCommand::Verify {
+                    lba: u32::from_be_bytes(cdb[2..6].try_into().unwrap()).into(),
+                    verification_length: u16::from_be_bytes(cdb[7..9].try_into().unwrap()).into(),
+                    byte_check: parse_byte_check(cdb[1])?,
+                }),
+                allocation_length: None,
+                naca: (cdb[9] & 0b0000_0100) != 0,
+            }),
+            CommandType::Verify16 => Ok(Self {
+                command: Command::LunSpecificCommand(LunSpecificCommand::Verify {
+                    lba: u64::from_be_bytes(cdb[2..10].try_into().unwrap()),
+                    verification_length: u32::from_be_bytes(cdb[10..14].try_into().unwrap()),
+                    byte_check: parse_byte_check(cdb[1])?,
+                }),
+                allocation_length: None,
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
+            CommandType::PreFetch10 => Ok(Self {
+                command: Command::LunSpecificCommand(LunSpecificCommand::PreFetch {
+                    lba: u32::from_be_bytes(cdb[2..6].try_into().unwrap()).into(),
+                    prefetch_length: u16::from_be_bytes(cdb[7..9].try_into().unwrap()).into(),
+                }),
                 allocation_length: None,
                 naca: (cdb[9] & 0b0000_0100) != 0,
             }),
+            CommandType::PreFetch16 => Ok(Self {
+                command: Command::LunSpecificCommand(LunSpecificCommand::PreFetch {
+                    lba: u64::from_be_bytes(cdb[2..10].try_into().unwrap()),
+                    prefetch_length: u32::from_be_bytes(cdb[10..14].try_into().unwrap()),
+                }),
+                allocation_length: None,
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
+            CommandType::LogSense => {
+                if cdb[1] != 0 {
+                    // SP: we can't save log parameters; PPC is obsolete
+                    return Err(ParseError::InvalidField);
+                }
+                let page_code = cdb[2] & 0b0011_1111;
+                let subpage_code = cdb[3];
+                let Some(page) = LogPage::from_page_code(page_code, subpage_code) else {
+                    warn!(
+                        "Rejecting request for unknown log page {:#2x}/{:#2x}.",
+                        page_code, subpage_code
+                    );
+                    return Err(ParseError::InvalidField);
+                };
+                Ok(Self {
+                    command: Command::LunSpecificCommand(LunSpecificCommand::LogSense {
+                        pc: ((cdb[2] & 0b1100_0000) >> 6).try_into().unwrap(),
+                        page,
+                        parameter_pointer: u16::from_be_bytes(cdb[5..7].try_into().unwrap()),
+                    }),
+                    allocation_length: Some(u32::from(u16::from_be_bytes(
+                        cdb[7..9].try_into().unwrap(),
+                    ))),
+                    naca: (cdb[9] & 0b0000_0100) != 0,
+                })
+            }
+            CommandType::LogSelect => {
+                let pcr = match cdb[1] {
+                    0b0000_0000 => false,
+                    0b0000_0010 => true,
+                    // SP: we can't save log parameters
+                    _ => return Err(ParseError::InvalidField),
+                };
+                let page = match (cdb[2] & 0b0011_1111, cdb[3]) {
+                    (0, 0) => None,
+                    (page_code, subpage_code) => Some(
+                        LogPage::from_page_code(page_code, subpage_code)
+                            .ok_or(ParseError::InvalidField)?,
+                    ),
+                };
+                Ok(Self {
+                    command: Command::LunSpecificCommand(LunSpecificCommand::LogSelect {
+                        pcr,
+                        pc: ((cdb[2] & 0b1100_0000) >> 6).try_into().unwrap(),
+                        page,
+                        parameter_list_length: u16::from_be_bytes(cdb[7..9].try_into().unwrap()),
+                    }),
+                    allocation_length: None,
+                    naca: (cdb[9] & 0b0000_0100) != 0,
+                })
+            }
+            CommandType::GetLbaStatus => {
+                let allocation_length = u32::from_be_bytes(cdb[10..14].try_into().unwrap());
+                Ok(Self {
+                    command: Command::LunSpecificCommand(LunSpecificCommand::GetLbaStatus {
+                        lba: u64::from_be_bytes(cdb[2..10].try_into().unwrap()),
+                        allocation_length,
+                    }),
+                    allocation_length: Some(allocation_length),
+                    naca: (cdb[15] & 0b0000_0100) != 0,
+                })
+            }
             CommandType::ReadCapacity10 => Ok(Self {
                 command: Command::LunSpecificCommand(LunSpecificCommand::ReadCapacity10),
                 allocation_length: None,
@@ -676,6 +1174,136 @@ impl Cdb {
                 allocation_length: None,
                 naca: (cdb[5] & 0b0000_0100) != 0,
             }),
+            CommandType::StartStopUnit => {
+                // IMMED (cdb[1] bit 0) doesn't matter, since we complete the
+                // command right away either way. We have no use for the power
+                // condition modifier (cdb[3]) either.
+                let power_condition = match cdb[4] >> 4 {
+                    0x0 => None,
+                    // LU_CONTROL hands control back to the power condition
+                    // timers, which we don't have; that leaves us active
+                    0x1 | 0x7 => Some(PowerCondition::Active),
+                    0x2 | 0xa => Some(PowerCondition::Idle),
+                    0x3 | 0xb => Some(PowerCondition::Standby),
+                    _ => return Err(ParseError::InvalidField),
+                };
+                Ok(Self {
+                    command: Command::LunSpecificCommand(LunSpecificCommand::StartStopUnit {
+                        power_condition,
+                        no_flush: cdb[4] & 0b0000_0100 != 0,
+                        load_eject: cdb[4] & 0b0000_0010 != 0,
+                        start: cdb[4] & 0b0000_0001 != 0,
+                    }),
+                    allocation_length: None,
+                    naca: (cdb[5] & 0b0000_0100) != 0,
+                })
+            }
+            CommandType::PreventAllowMediumRemoval => {
+                let prevent = match cdb[4] & 0b0000_0011 {
+                    0b00 => false,
+                    0b01 => true,
+                    // the other values are for medium changers
+                    _ => return Err(ParseError::InvalidField),
+                };
+                Ok(Self {
+                    command: Command::LunSpecificCommand(
+                        LunSpecificCommand::PreventAllowMediumRemoval { prevent },
+                    ),
+                    allocation_length: None,
+                    naca: (cdb[5] & 0b0000_0100) != 0,
+                })
+            }
+            CommandType::SendDiagnostic => {
+                // PF, DEVOFFL and UNITOFFL don't matter to us: the parameter
+                // list can only be in page format, and self-tests don't affect
+                // other logical units or commands (they just read through a
+                // clone of the backend).
+                let self_test = match (cdb[1] >> 5, cdb[1] & 0b0000_0100 != 0) {
+                    (0b000, false) => None,
+                    (0b000, true) => Some(SelfTestCode::Default),
+                    (_, true) => return Err(ParseError::InvalidField),
+                    (0b001, false) => Some(SelfTestCode::Background(SelfTestKind::Short)),
+                    (0b010, false) => Some(SelfTestCode::Background(SelfTestKind::Extended)),
+                    (0b100, false) => Some(SelfTestCode::AbortBackground),
+                    (0b101, false) => Some(SelfTestCode::Foreground(SelfTestKind::Short)),
+                    (0b110, false) => Some(SelfTestCode::Foreground(SelfTestKind::Extended)),
+                    _ => return Err(ParseError::InvalidField),
+                };
+                let parameter_list_length = u16::from_be_bytes(cdb[3..5].try_into().unwrap());
+                if self_test.is_some() && parameter_list_length != 0 {
+                    return Err(ParseError::InvalidField);
+                }
+                Ok(Self {
+                    command: Command::LunSpecificCommand(LunSpecificCommand::SendDiagnostic {
+                        self_test,
+                        parameter_list_length,
+                    }),
+                    allocation_length: None,
+                    naca: (cdb[5] & 0b0000_0100) != 0,
+                })
+            }
+            CommandType::ReceiveDiagnosticResults => {
+                let page_code = (cdb[1] & 0b0000_0001 != 0).then_some(cdb[2]);
+                Ok(Self {
+                    command: Command::LunSpecificCommand(
+                        LunSpecificCommand::ReceiveDiagnosticResults { page_code },
+                    ),
+                    allocation_length: Some(u32::from(u16::from_be_bytes(
+                        cdb[3..5].try_into().unwrap(),
+                    ))),
+                    naca: (cdb[5] & 0b0000_0100) != 0,
+                })
+            }
+            CommandType::ExtendedCopy => Ok(Self {
+                command: Command::ThirdPartyCopy(CopyCommand::ExtendedCopy {
+                    parameter_list_length: u32::from_be_bytes(cdb[10..14].try_into().unwrap()),
+                }),
+                allocation_length: None,
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
+            CommandType::PopulateToken => Ok(Self {
+                command: Command::ThirdPartyCopy(CopyCommand::PopulateToken {
+                    list_identifier: u32::from_be_bytes(cdb[6..10].try_into().unwrap()),
+                    parameter_list_length: u32::from_be_bytes(cdb[10..14].try_into().unwrap()),
+                }),
+                allocation_length: None,
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
+            CommandType::WriteUsingToken => Ok(Self {
+                command: Command::ThirdPartyCopy(CopyCommand::WriteUsingToken {
+                    list_identifier: u32::from_be_bytes(cdb[6..10].try_into().unwrap()),
+                    parameter_list_length: u32::from_be_bytes(cdb[10..14].try_into().unwrap()),
+                }),
+                allocation_length: None,
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
+            CommandType::ReceiveCopyOperatingParameters => Ok(Self {
+                command: Command::ThirdPartyCopy(CopyCommand::ReceiveCopyOperatingParameters),
+                allocation_length: Some(u32::from_be_bytes(cdb[10..14].try_into().unwrap())),
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
+            CommandType::ReceiveRodTokenInformation => Ok(Self {
+                command: Command::ThirdPartyCopy(CopyCommand::ReceiveRodTokenInformation {
+                    list_identifier: u32::from_be_bytes(cdb[2..6].try_into().unwrap()),
+                }),
+                allocation_length: Some(u32::from_be_bytes(cdb[10..14].try_into().unwrap())),
+                naca: (cdb[15] & 0b0000_0100) != 0,
+            }),
         }
     }
 }
+
+/// Parse byte 1 of a VERIFY CDB.
+fn parse_byte_check(byte: u8) -> Result<ByteCheck, ParseError> {
+    if byte & 0b1110_0000 != 0 {
+        // VRPROTECT: we don't support protection information
+        return Err(ParseError::InvalidField);
+    }
+    // DPO is just a hint, which we ignore
+    match (byte & 0b0000_0110) >> 1 {
+        0b00 => Ok(ByteCheck::None),
+        0b01 => Ok(ByteCheck::Blocks),
+        0b11 => Ok(ByteCheck::SingleBlock),
+        _ => Err(ParseError::InvalidField),
+    }
+}
@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! A `BlockDeviceBackend` wrapper that encrypts images at rest with AES-XTS.
+//!
+//! Every 512 byte sector is encrypted on its own, with its number (counted from
+//! the start of the encrypted data, little endian) as the tweak. This is
+//! dm-crypt's `aes-xts-plain64`, so images can also be opened with
+//! `cryptsetup open --type plain --cipher aes-xts-plain64`. The data of LUKS2
+//! images with 512 byte sectors is encrypted the same way, so they can be
+//! used by giving their volume key (`cryptsetup luksDump --dump-volume-key`)
+//! and the offset of their data segment; we don't parse LUKS2 headers,
+//! though.
+//!
+//! Encryption is set up per LUN, with comma separated key=value pairs:
+//!
+//! ```text
+//! lun=0,key-file=/etc/vhost-device-scsi/disk0.key,offset=16777216
+//! ```
+//!
+//! The allowed keys are:
+//! - `key-file` or `key-fd` (one of them is required): where the key is read
+//!   from, e.g. a pipe set up by a key manager. The key is 32 bytes for
+//!   AES-128-XTS or 64 bytes for AES-256-XTS, and is read once at startup.
+//! - `offset`: where the encrypted data starts in the image, in bytes. Has to
+//!   be a multiple of 512. Defaults to 0.
+//! - `lun`: only encrypt this LUN. Defaults to all LUNs (with the same key).
+//!
+//! Holes in the image decrypt to garbage rather than zeros, so the encrypted
+//! backend doesn't pass on discards, and reports everything as allocated.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::prelude::*,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use aes::{
+    cipher::{
+        consts::U16, generic_array::GenericArray, BlockDecrypt, BlockEncrypt, BlockSizeUser,
+        KeyInit,
+    },
+    Aes128, Aes256,
+};
+use thiserror::Error as ThisError;
+
+use super::block_device::{BlockDeviceBackend, BlockOffset, BlockSize, ByteOffset};
+
+/// The size of the units encrypted on their own.
+pub(crate) const SECTOR_SIZE: u64 = 512;
+const AES_BLOCK_SIZE: usize = 16;
+
+#[derive(Debug, ThisError)]
+pub(crate) enum EncryptionError {
+    #[error("Bad argument `{0}`")]
+    BadArgument(String),
+    #[error("Invalid key `{0}`")]
+    InvalidKey(String),
+    #[error("Invalid value `{1}` for key `{0}`")]
+    InvalidValue(String, String),
+    #[error("Either `key-file` or `key-fd` is required")]
+    MissingKey,
+    #[error("Failed reading key: {0}")]
+    FailedReadingKey(io::Error),
+    #[error("Invalid key length {0}, expected 32 or 64 bytes")]
+    InvalidKeyLength(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum KeySource {
+    File(PathBuf),
+    Fd(RawFd),
+}
+
+/// How a LUN is encrypted, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EncryptionSpec {
+    pub key: KeySource,
+    /// The offset of the encrypted data in the image, in bytes.
+    pub offset: u64,
+}
+
+impl EncryptionSpec {
+    /// Parse the keys other than `lun`.
+    pub(crate) fn parse<'a>(args: impl Iterator<Item = &'a str>) -> Result<Self, EncryptionError> {
+        let mut key = None;
+        let mut offset: u64 = 0;
+        for arg in args {
+            let (name, val) = arg
+                .split_once('=')
+                .ok_or_else(|| EncryptionError::BadArgument(arg.to_string()))?;
+            let invalid = || EncryptionError::InvalidValue(name.to_string(), val.to_string());
+            match name {
+                "key-file" => key = Some(KeySource::File(val.into())),
+                "key-fd" => key = Some(KeySource::Fd(val.parse().map_err(|_| invalid())?)),
+                "offset" => {
+                    offset = val.parse().map_err(|_| invalid())?;
+                    if offset % SECTOR_SIZE != 0 {
+                        return Err(invalid());
+                    }
+                }
+                _ => return Err(EncryptionError::InvalidKey(name.to_string())),
+            }
+        }
+        Ok(Self {
+            key: key.ok_or(EncryptionError::MissingKey)?,
+            offset,
+        })
+    }
+
+    /// Read the key, which can only be done once for `KeySource::Fd`.
+    pub(crate) fn load_cipher(&self) -> Result<Arc<XtsCipher>, EncryptionError> {
+        let mut key = match &self.key {
+            KeySource::File(path) => fs::read(path),
+            KeySource::Fd(fd) => {
+                let mut key = Vec::new();
+                // SAFETY: the user handed the file descriptor to us; we read
+                // the key from it and close it.
+                unsafe { File::from_raw_fd(*fd) }
+                    .read_to_end(&mut key)
+                    .map(|_| key)
+            }
+        }
+        .map_err(EncryptionError::FailedReadingKey)?;
+        let cipher = XtsCipher::new(&key);
+        key.fill(0);
+        cipher.map(Arc::new)
+    }
+}
+
+/// The data and tweak keys of AES-XTS.
+enum XtsKeys {
+    Aes128(Box<(Aes128, Aes128)>),
+    Aes256(Box<(Aes256, Aes256)>),
+}
+
+pub(crate) struct XtsCipher(XtsKeys);
+
+impl fmt::Debug for XtsCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // no keys in logs
+        f.write_str(match self.0 {
+            XtsKeys::Aes128(..) => "XtsCipher(AES-128)",
+            XtsKeys::Aes256(..) => "XtsCipher(AES-256)",
+        })
+    }
+}
+
+impl XtsCipher {
+    /// `key` is the data key followed by the tweak key.
+    pub(crate) fn new(key: &[u8]) -> Result<Self, EncryptionError> {
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        // unwraps are safe: the halves have the right length for each key size
+        Ok(Self(match key.len() {
+            32 => XtsKeys::Aes128(Box::new((
+                Aes128::new_from_slice(data_key).unwrap(),
+                Aes128::new_from_slice(tweak_key).unwrap(),
+            ))),
+            64 => XtsKeys::Aes256(Box::new((
+                Aes256::new_from_slice(data_key).unwrap(),
+                Aes256::new_from_slice(tweak_key).unwrap(),
+            ))),
+            len => return Err(EncryptionError::InvalidKeyLength(len)),
+        }))
+    }
+
+    /// Encrypt the sectors in `buf` in place, the first of which is `sector`.
+    pub(crate) fn encrypt(&self, sector: u64, buf: &mut [u8]) {
+        match &self.0 {
+            XtsKeys::Aes128(keys) => xts(&keys.0, &keys.1, sector, buf, true),
+            XtsKeys::Aes256(keys) => xts(&keys.0, &keys.1, sector, buf, true),
+        }
+    }
+
+    /// Decrypt the sectors in `buf` in place, the first of which is `sector`.
+    pub(crate) fn decrypt(&self, sector: u64, buf: &mut [u8]) {
+        match &self.0 {
+            XtsKeys::Aes128(keys) => xts(&keys.0, &keys.1, sector, buf, false),
+            XtsKeys::Aes256(keys) => xts(&keys.0, &keys.1, sector, buf, false),
+        }
+    }
+}
+
+fn xts<C: BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>>(
+    data_key: &C,
+    tweak_key: &C,
+    first_sector: u64,
+    buf: &mut [u8],
+    encrypt: bool,
+) {
+    let sector_size = usize::try_from(SECTOR_SIZE).expect("SECTOR_SIZE should fit usize");
+    for (sector, data) in (first_sector..).zip(buf.chunks_exact_mut(sector_size)) {
+        let mut tweak = GenericArray::from(u128::from(sector).to_le_bytes());
+        tweak_key.encrypt_block(&mut tweak);
+        let mut tweak = u128::from_le_bytes(tweak.into());
+
+        for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+            let block = GenericArray::from_mut_slice(block);
+            block
+                .iter_mut()
+                .zip(tweak.to_le_bytes())
+                .for_each(|(b, t)| *b ^= t);
+            if encrypt {
+                data_key.encrypt_block(block);
+            } else {
+                data_key.decrypt_block(block);
+            }
+            block
+                .iter_mut()
+                .zip(tweak.to_le_bytes())
+                .for_each(|(b, t)| *b ^= t);
+            // multiply by x in GF(2^128), modulo x^128 + x^7 + x^2 + x + 1
+            tweak = (tweak << 1) ^ ((tweak >> 127) * 0x87);
+        }
+    }
+}
+
+pub(crate) struct EncryptedBackend<T> {
+    inner: T,
+    cipher: Arc<XtsCipher>,
+    offset: u64,
+}
+
+impl<T: BlockDeviceBackend> EncryptedBackend<T> {
+    pub(crate) fn new(inner: T, cipher: Arc<XtsCipher>, offset: u64) -> io::Result<Self> {
+        if u64::from(u32::from(inner.block_size())) != SECTOR_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encryption needs 512 byte blocks",
+            ));
+        }
+        Ok(Self {
+            inner,
+            cipher,
+            offset,
+        })
+    }
+
+    /// The number of the first sector at `offset`, and the offset in the
+    /// image. I/O always covers whole sectors.
+    fn sector(&self, offset: ByteOffset, len: usize) -> io::Result<(u64, ByteOffset)> {
+        let offset = u64::from(offset);
+        if offset % SECTOR_SIZE != 0 || len as u64 % SECTOR_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unaligned I/O to an encrypted image",
+            ));
+        }
+        Ok((offset / SECTOR_SIZE, ByteOffset::from(self.offset + offset)))
+    }
+}
+
+impl<T: BlockDeviceBackend> BlockDeviceBackend for EncryptedBackend<T> {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: ByteOffset) -> io::Result<()> {
+        let (sector, offset) = self.sector(offset, buf.len())?;
+        self.inner.read_exact_at(buf, offset)?;
+        self.cipher.decrypt(sector, buf);
+        Ok(())
+    }
+
+    fn write_exact_at(&mut self, buf: &[u8], offset: ByteOffset) -> io::Result<()> {
+        let (sector, offset) = self.sector(offset, buf.len())?;
+        let mut encrypted = buf.to_vec();
+        self.cipher.encrypt(sector, &mut encrypted);
+        self.inner.write_exact_at(&encrypted, offset)
+    }
+
+    fn size_in_blocks(&mut self) -> io::Result<BlockOffset> {
+        let size = u64::from(self.inner.size_in_blocks()?) * SECTOR_SIZE;
+        Ok(BlockOffset::from(
+            size.saturating_sub(self.offset) / SECTOR_SIZE,
+        ))
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn prefetch(&mut self, offset: ByteOffset, len: u64) -> io::Result<()> {
+        self.inner
+            .prefetch(ByteOffset::from(self.offset + u64::from(offset)), len)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn BlockDeviceBackend>> {
+        Ok(Box::new(EncryptedBackend {
+            inner: self.inner.try_clone()?,
+            cipher: Arc::clone(&self.cipher),
+            offset: self.offset,
+        }))
+    }
+}
@@ -0,0 +1,307 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! A `BlockDeviceBackend` wrapper that injects failures, for testing how
+//! guests (and their filesystems) cope with misbehaving storage.
+//!
+//! Faults are described by rules. A rules file has one rule per line, in the
+//! form of comma separated key=value pairs; empty lines and lines starting
+//! with `#` are ignored:
+//!
+//! ```text
+//! # every read touching blocks 1000-1999 of LUN 0 fails
+//! fault=read-error,op=read,lba=1000-1999,lun=0
+//! # 1% of all requests take an extra 200ms
+//! fault=latency,delay-ms=200,probability=0.01
+//! ```
+//!
+//! The allowed keys are:
+//! - `fault` (required): `read-error` (UNRECOVERED READ ERROR), `write-fault`
+//!   (WRITE ERROR), `not-ready` (LOGICAL UNIT NOT READY) or `latency`.
+//! - `op`: the backend operation to match, `read`, `write` (including
+//!   discards), `sync` or `any` (the default).
+//! - `lba`: an inclusive range of blocks (`first-last`) or a single block.
+//!   Requests overlapping it match; `sync` requests never match a rule with a
+//!   range. Defaults to the whole device.
+//! - `probability`: chance of the fault firing on a matching request, between
+//!   0 and 1. Defaults to 1.
+//! - `lun`: only apply the rule to this LUN. Defaults to all LUNs.
+//! - `delay-ms`: how long a `latency` fault stalls the request.
+//!
+//! All matching latency rules are applied before the request is carried out;
+//! the first matching error rule fails it.
+
+use std::{fs, io, ops::RangeInclusive, path::Path, thread, time::Duration};
+
+use log::debug;
+use rand::Rng;
+use thiserror::Error as ThisError;
+
+use super::block_device::{
+    BackendSenseError, BlockDeviceBackend, BlockOffset, BlockSize, ByteOffset,
+};
+use crate::scsi::sense::{self, SenseTriple};
+
+#[derive(Debug, ThisError)]
+pub(crate) enum FaultRuleError {
+    #[error("Failed reading fault rules: {0}")]
+    Io(io::Error),
+    #[error("Line {0}: bad argument `{1}`")]
+    BadArgument(usize, String),
+    #[error("Line {0}: invalid key `{1}`")]
+    InvalidKey(usize, String),
+    #[error("Line {0}: invalid value `{2}` for key `{1}`")]
+    InvalidValue(usize, String, String),
+    #[error("Line {0}: required key `{1}` not found")]
+    RequiredKeyNotFound(usize, String),
+}
+
+/// The failure a rule injects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Fault {
+    ReadError,
+    WriteError,
+    NotReady,
+    Latency(Duration),
+}
+
+impl Fault {
+    /// The sense data to fail the request with, if this isn't just a delay.
+    fn sense(self) -> Option<SenseTriple> {
+        match self {
+            Self::ReadError => Some(sense::UNRECOVERED_READ_ERROR),
+            Self::WriteError => Some(sense::WRITE_ERROR),
+            Self::NotReady => Some(sense::LOGICAL_UNIT_NOT_READY),
+            Self::Latency(_) => None,
+        }
+    }
+}
+
+/// A backend operation a rule can match on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FaultOp {
+    Read,
+    Write,
+    Sync,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FaultRule {
+    pub fault: Fault,
+    /// `None` matches any operation.
+    pub op: Option<FaultOp>,
+    /// `None` matches the whole device.
+    pub lba: Option<RangeInclusive<u64>>,
+    pub probability: f64,
+    /// `None` applies to all LUNs.
+    pub lun: Option<u16>,
+}
+
+impl FaultRule {
+    pub(crate) fn applies_to_lun(&self, lun: u16) -> bool {
+        self.lun.is_none() || self.lun == Some(lun)
+    }
+
+    fn matches(&self, op: FaultOp, blocks: Option<RangeInclusive<u64>>) -> bool {
+        if matches!(self.op, Some(o) if o != op) {
+            return false;
+        }
+        match (&self.lba, blocks) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(rule), Some(req)) => rule.start() <= req.end() && req.start() <= rule.end(),
+        }
+    }
+
+    fn parse(line_nr: usize, s: &str) -> Result<Self, FaultRuleError> {
+        let invalid = |key: &str, val: &str| {
+            FaultRuleError::InvalidValue(line_nr, key.to_string(), val.to_string())
+        };
+
+        let mut fault = None;
+        let mut delay = None;
+        let mut op = None;
+        let mut lba = None;
+        let mut probability = 1.0;
+        let mut lun = None;
+
+        for arg in s.trim().split(',') {
+            let mut parts = arg.trim().split('=');
+            let (key, val) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(key), Some(val), None) => (key.trim(), val.trim()),
+                _ => return Err(FaultRuleError::BadArgument(line_nr, arg.to_string())),
+            };
+
+            match key {
+                "fault" => fault = Some(val),
+                "delay-ms" | "delay_ms" => {
+                    delay = Some(Duration::from_millis(
+                        val.parse().map_err(|_| invalid(key, val))?,
+                    ))
+                }
+                "op" => {
+                    op = match val {
+                        "read" => Some(FaultOp::Read),
+                        "write" => Some(FaultOp::Write),
+                        "sync" => Some(FaultOp::Sync),
+                        "any" => None,
+                        _ => return Err(invalid(key, val)),
+                    }
+                }
+                "lba" => {
+                    let range = match val.split_once('-') {
+                        Some((first, last)) => first.parse().and_then(|first| {
+                            last.parse()
+                                .map(|last: u64| RangeInclusive::new(first, last))
+                        }),
+                        None => val.parse().map(|lba| lba..=lba),
+                    }
+                    .map_err(|_| invalid(key, val))?;
+                    if range.is_empty() {
+                        return Err(invalid(key, val));
+                    }
+                    lba = Some(range);
+                }
+                "probability" => {
+                    probability = val.parse().map_err(|_| invalid(key, val))?;
+                    if !(0.0..=1.0).contains(&probability) {
+                        return Err(invalid(key, val));
+                    }
+                }
+                "lun" => lun = Some(val.parse().map_err(|_| invalid(key, val))?),
+                _ => return Err(FaultRuleError::InvalidKey(line_nr, key.to_string())),
+            }
+        }
+
+        let fault = match fault {
+            Some("read-error") => Fault::ReadError,
+            Some("write-fault") => Fault::WriteError,
+            Some("not-ready") => Fault::NotReady,
+            Some("latency") => Fault::Latency(delay.ok_or_else(|| {
+                FaultRuleError::RequiredKeyNotFound(line_nr, "delay-ms".to_string())
+            })?),
+            Some(val) => return Err(invalid("fault", val)),
+            None => {
+                return Err(FaultRuleError::RequiredKeyNotFound(
+                    line_nr,
+                    "fault".to_string(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            fault,
+            op,
+            lba,
+            probability,
+            lun,
+        })
+    }
+}
+
+/// Parse a set of fault rules, in the format described in the module
+/// documentation.
+pub(crate) fn parse_rules(rules: &str) -> Result<Vec<FaultRule>, FaultRuleError> {
+    rules
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_nr, line)| FaultRule::parse(line_nr, line))
+        .collect()
+}
+
+pub(crate) fn load_rules(path: &Path) -> Result<Vec<FaultRule>, FaultRuleError> {
+    parse_rules(&fs::read_to_string(path).map_err(FaultRuleError::Io)?)
+}
+
+pub(crate) struct FaultInjectionBackend<T: BlockDeviceBackend> {
+    inner: T,
+    rules: Vec<FaultRule>,
+}
+
+impl<T: BlockDeviceBackend> FaultInjectionBackend<T> {
+    pub(crate) fn new(inner: T, rules: Vec<FaultRule>) -> Self {
+        Self { inner, rules }
+    }
+
+    /// The (inclusive) range of blocks a request of `len` bytes at `offset`
+    /// touches.
+    fn blocks(&self, offset: ByteOffset, len: u64) -> RangeInclusive<u64> {
+        let block_size = self.inner.block_size();
+        let first = offset / block_size;
+        let last = ByteOffset::from(u64::from(offset) + len.max(1) - 1) / block_size;
+        u64::from(first)..=u64::from(last)
+    }
+
+    fn inject(&self, op: FaultOp, blocks: Option<RangeInclusive<u64>>) -> io::Result<()> {
+        let mut rng = rand::thread_rng();
+        for rule in &self.rules {
+            if !rule.matches(op, blocks.clone()) {
+                continue;
+            }
+            if rule.probability < 1.0 && !rng.gen_bool(rule.probability) {
+                continue;
+            }
+
+            match rule.fault.sense() {
+                Some(sense) => {
+                    debug!("Injecting {:?} into {:?} of {:?}", rule.fault, op, blocks);
+                    return Err(BackendSenseError(sense).into());
+                }
+                None => {
+                    if let Fault::Latency(delay) = rule.fault {
+                        debug!("Delaying {:?} of {:?} by {:?}", op, blocks, delay);
+                        thread::sleep(delay);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: BlockDeviceBackend> BlockDeviceBackend for FaultInjectionBackend<T> {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: ByteOffset) -> io::Result<()> {
+        self.inject(FaultOp::Read, Some(self.blocks(offset, buf.len() as u64)))?;
+        self.inner.read_exact_at(buf, offset)
+    }
+
+    fn write_exact_at(&mut self, buf: &[u8], offset: ByteOffset) -> io::Result<()> {
+        self.inject(FaultOp::Write, Some(self.blocks(offset, buf.len() as u64)))?;
+        self.inner.write_exact_at(buf, offset)
+    }
+
+    fn size_in_blocks(&mut self) -> io::Result<BlockOffset> {
+        self.inner.size_in_blocks()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.inner.block_size()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inject(FaultOp::Sync, None)?;
+        self.inner.sync()
+    }
+
+    fn discard(&mut self, offset: ByteOffset, len: u64) -> io::Result<()> {
+        self.inject(FaultOp::Write, Some(self.blocks(offset, len)))?;
+        self.inner.discard(offset, len)
+    }
+
+    fn allocation_status(&mut self, offset: ByteOffset, len: u64) -> io::Result<(bool, u64)> {
+        self.inner.allocation_status(offset, len)
+    }
+
+    fn prefetch(&mut self, offset: ByteOffset, len: u64) -> io::Result<()> {
+        self.inject(FaultOp::Read, Some(self.blocks(offset, len)))?;
+        self.inner.prefetch(offset, len)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn BlockDeviceBackend>> {
+        Ok(Box::new(FaultInjectionBackend::new(
+            self.inner.try_clone()?,
+            self.rules.clone(),
+        )))
+    }
+}
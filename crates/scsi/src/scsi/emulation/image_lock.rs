@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Advisory locking of image files.
+//!
+//! Images are locked when they're opened, so that two daemons (or a daemon and
+//! QEMU, which uses the same kind of locks) don't write to the same image
+//! without knowing about each other. Read-only images get a shared lock, and
+//! writable ones an exclusive lock, unless the images are explicitly shared
+//! (`--share`), e.g. because the guests coordinate access through a cluster
+//! file system. The locks are released when the last file descriptor referring
+//! to the image is closed.
+//!
+//! We use open file description locks, which conflict with the POSIX locks
+//! QEMU falls back to, and fall back to `flock` on kernels that don't support
+//! them.
+
+use std::{fs::File, io, os::unix::prelude::*, path::Path};
+
+use log::debug;
+use thiserror::Error as ThisError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug, ThisError)]
+pub(crate) enum ImageOpenError {
+    #[error("{0}")]
+    Io(io::Error),
+    #[error("Image is in use by another process (see --share)")]
+    InUse,
+}
+
+/// How image files are opened.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ImageOptions {
+    pub read_only: bool,
+    /// Take a shared lock on writable images, too.
+    pub share: bool,
+}
+
+impl ImageOptions {
+    pub(crate) const fn lock_mode(&self) -> LockMode {
+        if self.read_only || self.share {
+            LockMode::Shared
+        } else {
+            LockMode::Exclusive
+        }
+    }
+
+    /// Open and lock the image at `path`.
+    pub(crate) fn open(&self, path: &Path) -> Result<File, ImageOpenError> {
+        let file = File::options()
+            .read(true)
+            .write(!self.read_only)
+            .open(path)
+            .map_err(ImageOpenError::Io)?;
+        lock(&file, self.lock_mode())?;
+        debug!("Locked {} ({:?})", path.display(), self.lock_mode());
+        Ok(file)
+    }
+}
+
+/// Lock all of `file` without waiting.
+pub(crate) fn lock(file: &File, mode: LockMode) -> Result<(), ImageOpenError> {
+    let fd = file.as_raw_fd();
+    let in_use = |e: io::Error| match e.raw_os_error() {
+        Some(libc::EAGAIN | libc::EACCES) => ImageOpenError::InUse,
+        _ => ImageOpenError::Io(e),
+    };
+
+    // SAFETY: all-zero is a valid `flock`, which covers the whole file
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = match mode {
+        LockMode::Shared => libc::F_RDLCK,
+        LockMode::Exclusive => libc::F_WRLCK,
+    } as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    // SAFETY: `lock` is a valid `flock` that outlives the call, and the kernel
+    // checks the file descriptor.
+    if unsafe { libc::fcntl(fd, libc::F_OFD_SETLK, &lock) } == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::EINVAL) {
+        return Err(in_use(e));
+    }
+
+    // no OFD locks before Linux 3.15
+    let operation = match mode {
+        LockMode::Shared => libc::LOCK_SH,
+        LockMode::Exclusive => libc::LOCK_EX,
+    };
+    // SAFETY: flock doesn't access our memory, and the kernel checks the file
+    // descriptor.
+    if unsafe { libc::flock(fd, operation | libc::LOCK_NB) } == 0 {
+        Ok(())
+    } else {
+        Err(in_use(io::Error::last_os_error()))
+    }
+}
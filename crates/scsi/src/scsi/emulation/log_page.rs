@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+use super::self_test::SelfTestResult;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum LogPage {
+    SupportedPages,
+    SupportedPagesAndSubpages,
+    WriteErrorCounter,
+    ReadErrorCounter,
+    Temperature,
+    SelfTestResults,
+    InformationalExceptions,
+}
+
+/// Error counters for one direction, as reported in the Read and Write Error
+/// Counter log pages. We can't correct errors, so we only count uncorrected
+/// ones.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ErrorCounters {
+    pub bytes_processed: u64,
+    pub uncorrected_errors: u64,
+}
+
+impl ErrorCounters {
+    /// Count the outcome of transferring `bytes` bytes.
+    pub(crate) fn record<T>(&mut self, bytes: u64, result: &io::Result<T>) {
+        match result {
+            Ok(_) => self.bytes_processed += bytes,
+            Err(_) => self.uncorrected_errors += 1,
+        }
+    }
+}
+
+/// The state of a logical unit that's reported in log pages.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Logs {
+    pub read_errors: ErrorCounters,
+    pub write_errors: ErrorCounters,
+    /// The results of the most recent self-tests, most recent first.
+    pub self_tests: VecDeque<SelfTestResult>,
+}
+
+impl Logs {
+    pub(crate) const fn new() -> Self {
+        let counters = ErrorCounters {
+            bytes_processed: 0,
+            uncorrected_errors: 0,
+        };
+        Self {
+            read_errors: counters,
+            write_errors: counters,
+            self_tests: VecDeque::new(),
+        }
+    }
+
+    /// Log a self-test that was just started.
+    pub(crate) fn push_self_test(&mut self, result: SelfTestResult) {
+        self.self_tests.truncate(SELF_TEST_RESULTS - 1);
+        self.self_tests.push_front(result);
+    }
+
+    /// Reset the parameters of `page` (or of all pages) to their defaults, as
+    /// requested by LOG SELECT.
+    pub(crate) fn reset(&mut self, page: Option<LogPage>) {
+        match page {
+            // self-test results can't be reset
+            None => {
+                self.read_errors = ErrorCounters::default();
+                self.write_errors = ErrorCounters::default();
+            }
+            Some(LogPage::ReadErrorCounter) => self.read_errors = ErrorCounters::default(),
+            Some(LogPage::WriteErrorCounter) => self.write_errors = ErrorCounters::default(),
+            Some(_) => {}
+        }
+    }
+}
+
+/// The "temperature" we report, since we don't have a sensor.
+const TEMPERATURE_NOT_AVAILABLE: u8 = 0xff;
+
+/// The control byte of log parameters that are counters: saving isn't
+/// supported and they just stop at their maximum.
+const BOUNDED_COUNTER: u8 = 0b0000_0000;
+/// The control byte of other log parameters.
+const BINARY_LIST: u8 = 0b0000_0011;
+
+/// The number of self-test results kept in the Self-Test Results log page.
+const SELF_TEST_RESULTS: usize = 20;
+
+impl LogPage {
+    /// All supported pages, ordered by page and subpage code.
+    pub(crate) const ALL: &'static [Self] = &[
+        Self::SupportedPages,
+        Self::SupportedPagesAndSubpages,
+        Self::WriteErrorCounter,
+        Self::ReadErrorCounter,
+        Self::Temperature,
+        Self::SelfTestResults,
+        Self::InformationalExceptions,
+    ];
+
+    pub(crate) const fn page_code(self) -> (u8, u8) {
+        match self {
+            Self::SupportedPages => (0x0, 0),
+            Self::SupportedPagesAndSubpages => (0x0, 0xff),
+            Self::WriteErrorCounter => (0x2, 0),
+            Self::ReadErrorCounter => (0x3, 0),
+            Self::Temperature => (0xd, 0),
+            Self::SelfTestResults => (0x10, 0),
+            Self::InformationalExceptions => (0x2f, 0),
+        }
+    }
+
+    pub(crate) fn from_page_code(page_code: u8, subpage_code: u8) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|page| page.page_code() == (page_code, subpage_code))
+    }
+
+    /// Write the page, with the parameters (if any) with parameter codes of at
+    /// least `parameter_pointer`.
+    pub(crate) fn write(
+        self,
+        logs: &Logs,
+        parameter_pointer: u16,
+        data_in: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut out = Vec::new();
+        match self {
+            Self::SupportedPages => {
+                out = Self::ALL.iter().map(|p| p.page_code().0).collect();
+                out.dedup();
+            }
+            Self::SupportedPagesAndSubpages => {
+                for page in Self::ALL {
+                    let (page_code, subpage_code) = page.page_code();
+                    out.extend_from_slice(&[page_code, subpage_code]);
+                }
+            }
+            Self::WriteErrorCounter | Self::ReadErrorCounter => {
+                let counters = if self == Self::ReadErrorCounter {
+                    logs.read_errors
+                } else {
+                    logs.write_errors
+                };
+                // parameters 0-4 count corrected errors and retries, which we
+                // don't have
+                let values = [
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    counters.bytes_processed,
+                    counters.uncorrected_errors,
+                ];
+                for (code, value) in (0..).zip(values) {
+                    if code >= parameter_pointer {
+                        write_parameter(&mut out, code, BOUNDED_COUNTER, &value.to_be_bytes());
+                    }
+                }
+            }
+            Self::Temperature => {
+                // current and reference temperature
+                for code in 0..2 {
+                    if code >= parameter_pointer {
+                        write_parameter(
+                            &mut out,
+                            code,
+                            BINARY_LIST,
+                            &[0, TEMPERATURE_NOT_AVAILABLE],
+                        );
+                    }
+                }
+            }
+            Self::SelfTestResults => {
+                // parameters 1-20, with the unused ones zeroed
+                for (code, result) in (1..).zip(
+                    logs.self_tests
+                        .iter()
+                        .map(Some)
+                        .chain(std::iter::repeat(None))
+                        .take(SELF_TEST_RESULTS),
+                ) {
+                    if code >= parameter_pointer {
+                        let value = result.map_or([0; 16], SelfTestResult::parameter_value);
+                        write_parameter(&mut out, code, BINARY_LIST, &value);
+                    }
+                }
+            }
+            Self::InformationalExceptions => {
+                if parameter_pointer == 0 {
+                    // no failure predicted (ASC and ASCQ 0), and the most
+                    // recent temperature
+                    write_parameter(&mut out, 0, BINARY_LIST, &[0, 0, TEMPERATURE_NOT_AVAILABLE]);
+                }
+            }
+        }
+
+        let (page_code, subpage_code) = self.page_code();
+        let spf = if subpage_code == 0 { 0 } else { 0b0100_0000 };
+        data_in.write_all(&[page_code | spf, subpage_code])?;
+        data_in.write_all(
+            &u16::try_from(out.len())
+                .expect("log page should fit 2^16 bytes")
+                .to_be_bytes(),
+        )?;
+        data_in.write_all(&out)
+    }
+}
+
+fn write_parameter(out: &mut Vec<u8>, code: u16, control: u8, value: &[u8]) {
+    out.extend_from_slice(&code.to_be_bytes());
+    out.push(control);
+    out.push(u8::try_from(value.len()).expect("log parameter should fit 255 bytes"));
+    out.extend_from_slice(value);
+}
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Power conditions and removable media of block devices.
+//!
+//! The guest moves a `BlockDevice` between power conditions with START STOP
+//! UNIT; a stopped logical unit reports NOT READY for commands that access the
+//! medium until it's started again. Idle and standby are only bookkeeping: we
+//! report them in REQUEST SENSE, and return to active on the next medium
+//! access.
+//!
+//! The medium of a removable logical unit lives in a `RemovableMedium`, which
+//! is shared with the control socket so that the host can eject it or insert a
+//! new image. The guest can eject and reload the medium with START STOP UNIT,
+//! and lock it in with PREVENT ALLOW MEDIUM REMOVAL, which the host respects as
+//! well. A newly inserted medium is only picked up by the `BlockDevice` before
+//! its next command, so that commands never race with a medium change.
+
+use std::{fmt, sync::Mutex};
+
+use thiserror::Error as ThisError;
+
+use crate::scsi::sense::{self, SenseTriple};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PowerCondition {
+    Active,
+    Idle,
+    Standby,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MediumStatus {
+    /// The medium is loaded and can be accessed.
+    Loaded,
+    /// The guest ejected the medium; it can load it again.
+    Ejected,
+    /// There is no medium.
+    Absent,
+}
+
+impl fmt::Display for MediumStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Loaded => "loaded",
+            Self::Ejected => "ejected",
+            Self::Absent => "absent",
+        })
+    }
+}
+
+#[derive(Debug, ThisError, PartialEq, Eq)]
+pub(crate) enum MediumError {
+    #[error("The guest prevents medium removal")]
+    RemovalPrevented,
+}
+
+struct MediumState<T> {
+    status: MediumStatus,
+    prevent_removal: bool,
+    /// A medium the host inserted that the logical unit hasn't switched to yet.
+    inserted: Option<T>,
+}
+
+/// The medium of a removable logical unit, with `T` being the backend a new
+/// medium is accessed through.
+pub(crate) struct RemovableMedium<T>(Mutex<MediumState<T>>);
+
+impl<T> Default for RemovableMedium<T> {
+    fn default() -> Self {
+        Self(Mutex::new(MediumState {
+            status: MediumStatus::Loaded,
+            prevent_removal: false,
+            inserted: None,
+        }))
+    }
+}
+
+impl<T> RemovableMedium<T> {
+    pub(crate) fn status(&self) -> MediumStatus {
+        self.0.lock().unwrap().status
+    }
+
+    pub(crate) fn removal_prevented(&self) -> bool {
+        self.0.lock().unwrap().prevent_removal
+    }
+
+    /// Remove the medium on behalf of the host.
+    pub(crate) fn eject(&self) -> Result<(), MediumError> {
+        let mut state = self.0.lock().unwrap();
+        if state.prevent_removal && state.status == MediumStatus::Loaded {
+            return Err(MediumError::RemovalPrevented);
+        }
+        state.status = MediumStatus::Absent;
+        state.inserted = None;
+        Ok(())
+    }
+
+    /// Replace the medium with `backend` on behalf of the host. The guest
+    /// learns about the change through a unit attention.
+    pub(crate) fn insert(&self, backend: T) -> Result<(), MediumError> {
+        let mut state = self.0.lock().unwrap();
+        if state.prevent_removal && state.status == MediumStatus::Loaded {
+            return Err(MediumError::RemovalPrevented);
+        }
+        state.status = MediumStatus::Loaded;
+        state.inserted = Some(backend);
+        Ok(())
+    }
+
+    /// Take the medium the host inserted since the last call, if any.
+    pub(crate) fn take_inserted(&self) -> Option<T> {
+        self.0.lock().unwrap().inserted.take()
+    }
+
+    /// Unload (START STOP UNIT with LOEJ set and START clear) the medium on
+    /// behalf of the guest.
+    pub(crate) fn unload(&self) -> Result<(), SenseTriple> {
+        let mut state = self.0.lock().unwrap();
+        match state.status {
+            MediumStatus::Loaded if state.prevent_removal => Err(sense::MEDIUM_REMOVAL_PREVENTED),
+            MediumStatus::Loaded => {
+                state.status = MediumStatus::Ejected;
+                Ok(())
+            }
+            MediumStatus::Ejected | MediumStatus::Absent => Ok(()),
+        }
+    }
+
+    /// Load (START STOP UNIT with LOEJ and START set) the medium on behalf of
+    /// the guest.
+    pub(crate) fn load(&self) -> Result<(), SenseTriple> {
+        let mut state = self.0.lock().unwrap();
+        match state.status {
+            MediumStatus::Loaded => Ok(()),
+            MediumStatus::Ejected => {
+                state.status = MediumStatus::Loaded;
+                Ok(())
+            }
+            MediumStatus::Absent => Err(sense::MEDIUM_NOT_PRESENT),
+        }
+    }
+
+    pub(crate) fn set_prevent_removal(&self, prevent: bool) {
+        self.0.lock().unwrap().prevent_removal = prevent;
+    }
+}
@@ -35,7 +35,8 @@ impl LogicalUnit for MissingLun {
                         data_in.write_all(&[0]).map_err(DataIn)?;
                     }
                     None => {
-                        respond_standard_inquiry_data(data_in).map_err(DataIn)?;
+                        respond_standard_inquiry_data(data_in, false, false, false)
+                            .map_err(DataIn)?;
                     }
                 }
                 Ok(CmdOutput::ok())
@@ -1,11 +1,23 @@
 // SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
 
 pub(crate) mod block_device;
-mod command;
+pub(crate) mod command;
+pub(crate) mod encryption;
+pub(crate) mod fault_injection;
+pub(crate) mod image_lock;
+pub(crate) mod log_page;
+pub(crate) mod medium;
 pub(crate) mod missing_lun;
 pub(crate) mod mode_page;
+pub(crate) mod nbd;
 mod response_data;
+pub(crate) mod self_test;
+pub(crate) mod snapshot;
+pub(crate) mod stats;
 pub(crate) mod target;
+pub(crate) mod task_set;
+pub(crate) mod third_party_copy;
+pub(crate) mod throttle;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,647 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! A `BlockDeviceBackend` for images served by an NBD server.
+//!
+//! We only speak the fixed newstyle handshake, preferring `NBD_OPT_GO` and
+//! falling back to `NBD_OPT_EXPORT_NAME` for servers that don't know it. We
+//! don't negotiate structured replies, so every request gets a simple reply.
+//! See <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md>.
+//!
+//! Images are given as URIs, in the same format as QEMU uses:
+//! `nbd://host[:port]/export` for TCP and `nbd+unix:///export?socket=path`
+//! for Unix sockets.
+//!
+//! Requests are executed on the vring thread, so a server that stops
+//! responding would stall the whole device. Connecting, sending and receiving
+//! are therefore subject to a timeout. A request that times out fails with a
+//! sense the guest retries, and since the connection is out of sync at that
+//! point, the next request reconnects first.
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use thiserror::Error as ThisError;
+
+use super::block_device::{
+    BackendSenseError, BlockDeviceBackend, BlockOffset, BlockSize, ByteOffset,
+};
+use crate::scsi::sense;
+
+pub(crate) const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+pub(crate) const IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+pub(crate) const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+pub(crate) const REQUEST_MAGIC: u32 = 0x2560_9513;
+pub(crate) const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// handshake flags
+pub(crate) const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub(crate) const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+// client flags
+pub(crate) const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+pub(crate) const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+pub(crate) const NBD_OPT_EXPORT_NAME: u32 = 1;
+pub(crate) const NBD_OPT_GO: u32 = 7;
+
+pub(crate) const NBD_REP_ACK: u32 = 1;
+pub(crate) const NBD_REP_INFO: u32 = 3;
+pub(crate) const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+pub(crate) const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+
+pub(crate) const NBD_INFO_EXPORT: u16 = 0;
+pub(crate) const NBD_INFO_BLOCK_SIZE: u16 = 3;
+
+// transmission flags
+pub(crate) const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub(crate) const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub(crate) const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub(crate) const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+pub(crate) const NBD_CMD_READ: u16 = 0;
+pub(crate) const NBD_CMD_WRITE: u16 = 1;
+pub(crate) const NBD_CMD_DISC: u16 = 2;
+pub(crate) const NBD_CMD_FLUSH: u16 = 3;
+pub(crate) const NBD_CMD_WRITE_ZEROES: u16 = 6;
+
+const DEFAULT_PORT: u16 = 10809;
+/// The largest request servers have to accept, unless they tell us otherwise.
+const DEFAULT_MAX_PAYLOAD: u32 = 32 * 1024 * 1024;
+/// Largest WRITE ZEROES request we send; it has no payload, but the length
+/// still needs to fit 32 bits.
+const MAX_WRITE_ZEROES: u32 = 1 << 30;
+/// Option replies we are willing to buffer.
+const MAX_OPTION_REPLY: u32 = 64 * 1024;
+/// Servers don't have to accept longer export names.
+const MAX_EXPORT_NAME: usize = 4096;
+
+#[derive(Debug, ThisError)]
+pub(crate) enum NbdError {
+    #[error("I/O error talking to NBD server: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid NBD URI `{0}`")]
+    InvalidUri(String),
+    #[error("Export name is longer than 4096 bytes")]
+    ExportNameTooLong,
+    #[error("Server doesn't speak the NBD protocol")]
+    BadMagic,
+    #[error("Server doesn't support the fixed newstyle handshake")]
+    NotFixedNewstyle,
+    #[error("Server rejected option {0} with error {1:#x}: {2}")]
+    OptionRejected(u32, u32, String),
+    #[error("Malformed reply from server")]
+    MalformedReply,
+    #[error("Server replied to request {1:#x} while waiting for {0:#x}")]
+    UnexpectedCookie(u64, u64),
+    #[error("Unsupported minimum block size {0}")]
+    UnsupportedBlockSize(u32),
+    #[error("Maximum payload {0} is smaller than the block size")]
+    MaxPayloadTooSmall(u32),
+    #[error("Export changed its size or block size while reconnecting")]
+    ExportChanged,
+}
+
+impl From<NbdError> for io::Error {
+    fn from(e: NbdError) -> Self {
+        match e {
+            NbdError::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NbdAddress {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+/// Where to find an export.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NbdUri {
+    pub address: NbdAddress,
+    pub export: String,
+}
+
+impl NbdUri {
+    /// Parse an NBD URI; returns `None` if `uri` isn't one at all (i.e. it is
+    /// the path of an image file).
+    pub(crate) fn parse(uri: &str) -> Option<Result<Self, NbdError>> {
+        let invalid = || NbdError::InvalidUri(uri.to_string());
+
+        if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            let parse = || {
+                let (path, query) = rest.split_once('?').ok_or_else(invalid)?;
+                // the authority has to be empty, so the path starts right away
+                let export = path.strip_prefix('/').ok_or_else(invalid)?;
+                let socket = query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("socket="))
+                    .filter(|socket| !socket.is_empty())
+                    .ok_or_else(invalid)?;
+                Ok(Self {
+                    address: NbdAddress::Unix(socket.into()),
+                    export: export.to_string(),
+                })
+            };
+            Some(parse())
+        } else if let Some(rest) = uri.strip_prefix("nbd://") {
+            let parse = || {
+                let (authority, export) = rest.split_once('/').unwrap_or((rest, ""));
+                let (host, port) = match authority.rsplit_once(':') {
+                    // don't mistake the colons of a bare IPv6 address for a port
+                    Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                        (host, port.parse().map_err(|_| invalid())?)
+                    }
+                    _ => (authority, DEFAULT_PORT),
+                };
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                if host.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(Self {
+                    address: NbdAddress::Tcp(host.to_string(), port),
+                    export: export.to_string(),
+                })
+            };
+            Some(parse())
+        } else {
+            None
+        }
+    }
+
+    /// Connect to the export. Also returns whether the server only allows
+    /// reading. `timeout` applies to connecting and to every read and write
+    /// on the connection; `None` waits forever.
+    pub(crate) fn connect(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(Box<dyn BlockDeviceBackend>, bool), NbdError> {
+        fn handshake<S: Read + Write + Send + Sync + 'static>(
+            open: impl Fn() -> io::Result<S> + Send + Sync + 'static,
+            export: &str,
+        ) -> Result<(Box<dyn BlockDeviceBackend>, bool), NbdError> {
+            let mut backend = NbdBackend::connect(open()?, export)?;
+            backend.set_reconnect(open);
+            let read_only = backend.is_read_only();
+            Ok((Box::new(backend), read_only))
+        }
+
+        match &self.address {
+            NbdAddress::Tcp(host, port) => {
+                let (host, port) = (host.clone(), *port);
+                handshake(move || connect_tcp(&host, port, timeout), &self.export)
+            }
+            NbdAddress::Unix(path) => {
+                let path = path.clone();
+                let open = move || {
+                    let stream = UnixStream::connect(&path)?;
+                    stream.set_read_timeout(timeout)?;
+                    stream.set_write_timeout(timeout)?;
+                    Ok(stream)
+                };
+                handshake(open, &self.export)
+            }
+        }
+    }
+}
+
+fn connect_tcp(host: &str, port: u16, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let stream = match timeout {
+        None => TcpStream::connect((host, port))?,
+        Some(timeout) => {
+            // like TcpStream::connect, try every address the host resolves to
+            let mut last_error = None;
+            let mut stream = None;
+            for addr in (host, port).to_socket_addrs()? {
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(s) => {
+                        stream = Some(s);
+                        break;
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            match (stream, last_error) {
+                (Some(stream), _) => stream,
+                (None, Some(e)) => return Err(e),
+                (None, None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "host didn't resolve to any address",
+                    ))
+                }
+            }
+        }
+    };
+    // requests are sent in one go and wait for the reply anyway
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    Ok(stream)
+}
+
+/// Whether `e` is a read or write running into the socket's timeout.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// What we learned about an export during the handshake.
+struct ExportInfo {
+    size: u64,
+    flags: u16,
+    min_block_size: Option<u32>,
+    max_payload: Option<u32>,
+}
+
+/// Opens a new connection to the server, see `NbdBackend::set_reconnect`.
+type Reconnect<S> = Box<dyn Fn() -> io::Result<S> + Send + Sync>;
+
+pub(crate) struct NbdBackend<S: Read + Write> {
+    stream: S,
+    export: String,
+    size: u64,
+    flags: u16,
+    block_size: BlockSize,
+    max_payload: u32,
+    next_cookie: u64,
+    /// Whether the last request timed out, leaving the connection in an
+    /// unknown state.
+    timed_out: bool,
+    reconnect: Option<Reconnect<S>>,
+}
+
+impl<S: Read + Write> NbdBackend<S> {
+    /// Perform the handshake for `export` on a freshly connected `stream`.
+    pub(crate) fn connect(mut stream: S, export: &str) -> Result<Self, NbdError> {
+        if export.len() > MAX_EXPORT_NAME {
+            return Err(NbdError::ExportNameTooLong);
+        }
+        if read_u64(&mut stream)? != NBD_MAGIC {
+            return Err(NbdError::BadMagic);
+        }
+        // oldstyle servers send the export size here instead
+        if read_u64(&mut stream)? != IHAVEOPT {
+            return Err(NbdError::NotFixedNewstyle);
+        }
+        let handshake_flags = read_u16(&mut stream)?;
+        if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(NbdError::NotFixedNewstyle);
+        }
+        let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+        let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+        if no_zeroes {
+            client_flags |= NBD_FLAG_C_NO_ZEROES;
+        }
+        stream.write_all(&client_flags.to_be_bytes())?;
+
+        let info = match Self::opt_go(&mut stream, export)? {
+            Some(info) => info,
+            None => {
+                debug!("NBD server doesn't support NBD_OPT_GO, using NBD_OPT_EXPORT_NAME");
+                Self::opt_export_name(&mut stream, export, no_zeroes)?
+            }
+        };
+
+        // We prefer the traditional block size, but if the server can't do
+        // that, we need to expose the larger block size to the guest.
+        let block_size = match info.min_block_size {
+            None => 512,
+            Some(min) if min.is_power_of_two() && min <= 4096 => min.max(512),
+            Some(min) => return Err(NbdError::UnsupportedBlockSize(min)),
+        };
+        // Requests are split at the maximum payload, and must stay aligned
+        // to the block size.
+        let max_payload = info.max_payload.unwrap_or(DEFAULT_MAX_PAYLOAD);
+        if max_payload < block_size {
+            return Err(NbdError::MaxPayloadTooSmall(max_payload));
+        }
+        let max_payload = max_payload - max_payload % block_size;
+        // unwrap is safe: block_size isn't 0
+        let block_size = BlockSize::try_from(block_size).unwrap();
+        if info.size % u64::from(u32::from(block_size)) != 0 {
+            warn!(
+                "NBD export size {} isn't a multiple of the block size; ignoring the tail",
+                info.size
+            );
+        }
+
+        Ok(Self {
+            stream,
+            export: export.to_string(),
+            size: info.size,
+            flags: info.flags,
+            block_size,
+            max_payload,
+            next_cookie: 0,
+            timed_out: false,
+            reconnect: None,
+        })
+    }
+
+    /// Set how to connect to the server again after a request timed out.
+    /// Without this, requests keep failing once one timed out.
+    pub(crate) fn set_reconnect(
+        &mut self,
+        reconnect: impl Fn() -> io::Result<S> + Send + Sync + 'static,
+    ) {
+        self.reconnect = Some(Box::new(reconnect));
+    }
+
+    /// Replace the connection after a timeout with a new one to the same
+    /// export.
+    fn reconnect(&mut self) -> Result<(), NbdError> {
+        let reconnect = self.reconnect.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "NBD connection timed out")
+        })?;
+        let mut new = Self::connect(reconnect()?, &self.export)?;
+        if new.size != self.size || new.block_size != self.block_size {
+            return Err(NbdError::ExportChanged);
+        }
+        // The old connection ends up in `new`, and gets closed without a
+        // disconnect request (see `Drop`).
+        std::mem::swap(&mut self.stream, &mut new.stream);
+        new.timed_out = true;
+        self.flags = new.flags;
+        self.max_payload = new.max_payload;
+        self.timed_out = false;
+        debug!("Reconnected to NBD export `{}`", self.export);
+        Ok(())
+    }
+
+    fn send_option(stream: &mut S, option: u32, data: &[u8]) -> io::Result<()> {
+        let mut msg = Vec::with_capacity(16 + data.len());
+        msg.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        msg.extend_from_slice(&option.to_be_bytes());
+        // unwrap is safe: the only variable length data are export names,
+        // which are limited to MAX_EXPORT_NAME
+        msg.extend_from_slice(&u32::try_from(data.len()).unwrap().to_be_bytes());
+        msg.extend_from_slice(data);
+        stream.write_all(&msg)
+    }
+
+    /// Select the export with `NBD_OPT_GO`; returns `None` if the server
+    /// doesn't support the option.
+    fn opt_go(stream: &mut S, export: &str) -> Result<Option<ExportInfo>, NbdError> {
+        let mut data = Vec::new();
+        // unwrap is safe: export names are limited to MAX_EXPORT_NAME
+        data.extend_from_slice(&u32::try_from(export.len()).unwrap().to_be_bytes());
+        data.extend_from_slice(export.as_bytes());
+        // we are interested in the block size constraints
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&NBD_INFO_BLOCK_SIZE.to_be_bytes());
+        Self::send_option(stream, NBD_OPT_GO, &data)?;
+
+        let mut export_info = None;
+        let mut min_block_size = None;
+        let mut max_payload = None;
+        loop {
+            if read_u64(stream)? != OPTION_REPLY_MAGIC || read_u32(stream)? != NBD_OPT_GO {
+                return Err(NbdError::MalformedReply);
+            }
+            let reply_type = read_u32(stream)?;
+            let len = read_u32(stream)?;
+            if len > MAX_OPTION_REPLY {
+                return Err(NbdError::MalformedReply);
+            }
+            let mut data = vec![0; len as usize];
+            stream.read_exact(&mut data)?;
+
+            match reply_type {
+                NBD_REP_ACK => break,
+                NBD_REP_INFO => {
+                    let mut data = &data[..];
+                    match read_u16(&mut data)? {
+                        NBD_INFO_EXPORT => {
+                            export_info = Some((read_u64(&mut data)?, read_u16(&mut data)?));
+                        }
+                        NBD_INFO_BLOCK_SIZE => {
+                            min_block_size = Some(read_u32(&mut data)?);
+                            let _preferred = read_u32(&mut data)?;
+                            max_payload = Some(read_u32(&mut data)?);
+                        }
+                        _ => (),
+                    }
+                }
+                NBD_REP_ERR_UNSUP => return Ok(None),
+                reply_type if reply_type & NBD_REP_FLAG_ERROR != 0 => {
+                    return Err(NbdError::OptionRejected(
+                        NBD_OPT_GO,
+                        reply_type,
+                        String::from_utf8_lossy(&data).into_owned(),
+                    ))
+                }
+                // unknown replies can safely be ignored
+                _ => (),
+            }
+        }
+
+        let (size, flags) = export_info.ok_or(NbdError::MalformedReply)?;
+        Ok(Some(ExportInfo {
+            size,
+            flags,
+            min_block_size,
+            max_payload,
+        }))
+    }
+
+    fn opt_export_name(
+        stream: &mut S,
+        export: &str,
+        no_zeroes: bool,
+    ) -> Result<ExportInfo, NbdError> {
+        // There is no error reply for this option; the server just hangs up
+        // if it doesn't know the export.
+        Self::send_option(stream, NBD_OPT_EXPORT_NAME, export.as_bytes())?;
+        let size = read_u64(stream)?;
+        let flags = read_u16(stream)?;
+        if !no_zeroes {
+            stream.read_exact(&mut [0; 124])?;
+        }
+        Ok(ExportInfo {
+            size,
+            flags,
+            min_block_size: None,
+            max_payload: None,
+        })
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.has_flag(NBD_FLAG_READ_ONLY)
+    }
+
+    fn has_flag(&self, flag: u16) -> bool {
+        self.flags & NBD_FLAG_HAS_FLAGS != 0 && self.flags & flag != 0
+    }
+
+    /// Send a request and wait for its reply. `data_out` is the payload of a
+    /// write, `data_in` receives the payload of a read.
+    ///
+    /// Timeouts, and failing to reconnect after one, are reported as
+    /// `BackendSenseError`s the guest retries.
+    fn request(
+        &mut self,
+        command: u16,
+        offset: u64,
+        len: u32,
+        data_out: &[u8],
+        data_in: &mut [u8],
+    ) -> io::Result<()> {
+        if self.timed_out {
+            if let Err(e) = self.reconnect() {
+                warn!("Failed reconnecting to NBD server: {}", e);
+                return Err(BackendSenseError(sense::LOGICAL_UNIT_COMMUNICATION_FAILURE).into());
+            }
+        }
+
+        match self.exchange(command, offset, len, data_out, data_in) {
+            Err(e) if is_timeout(&e) => {
+                warn!("NBD request timed out, reconnecting with the next one");
+                self.timed_out = true;
+                Err(BackendSenseError(sense::LOGICAL_UNIT_COMMUNICATION_TIME_OUT).into())
+            }
+            result => result,
+        }
+    }
+
+    fn exchange(
+        &mut self,
+        command: u16,
+        offset: u64,
+        len: u32,
+        data_out: &[u8],
+        data_in: &mut [u8],
+    ) -> io::Result<()> {
+        let cookie = self.next_cookie;
+        self.next_cookie = self.next_cookie.wrapping_add(1);
+
+        let mut msg = Vec::with_capacity(28 + data_out.len());
+        msg.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes()); // command flags
+        msg.extend_from_slice(&command.to_be_bytes());
+        msg.extend_from_slice(&cookie.to_be_bytes());
+        msg.extend_from_slice(&offset.to_be_bytes());
+        msg.extend_from_slice(&len.to_be_bytes());
+        msg.extend_from_slice(data_out);
+        self.stream.write_all(&msg)?;
+
+        if read_u32(&mut self.stream)? != SIMPLE_REPLY_MAGIC {
+            return Err(NbdError::MalformedReply.into());
+        }
+        let error = read_u32(&mut self.stream)?;
+        let reply_cookie = read_u64(&mut self.stream)?;
+        if reply_cookie != cookie {
+            return Err(NbdError::UnexpectedCookie(cookie, reply_cookie).into());
+        }
+        if error != 0 {
+            // NBD errors are a subset of the Linux errnos
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+        self.stream.read_exact(data_in)
+    }
+}
+
+impl<S: Read + Write> Drop for NbdBackend<S> {
+    fn drop(&mut self) {
+        if self.timed_out {
+            // The server may still be in the middle of an earlier request,
+            // and would take the disconnect request for part of it.
+            return;
+        }
+        // There is no reply to a disconnect request, so don't use request().
+        let mut msg = Vec::with_capacity(28);
+        msg.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&NBD_CMD_DISC.to_be_bytes());
+        msg.extend_from_slice(&[0; 20]);
+        if let Err(e) = self.stream.write_all(&msg) {
+            debug!("Failed disconnecting from NBD server: {}", e);
+        }
+    }
+}
+
+impl<S: Read + Write + Send + Sync> BlockDeviceBackend for NbdBackend<S> {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: ByteOffset) -> io::Result<()> {
+        let mut offset = u64::from(offset);
+        for chunk in buf.chunks_mut(self.max_payload as usize) {
+            // unwrap is safe: chunks are at most max_payload long
+            let len = u32::try_from(chunk.len()).unwrap();
+            self.request(NBD_CMD_READ, offset, len, &[], chunk)?;
+            offset += u64::from(len);
+        }
+        Ok(())
+    }
+
+    fn write_exact_at(&mut self, buf: &[u8], offset: ByteOffset) -> io::Result<()> {
+        let mut offset = u64::from(offset);
+        for chunk in buf.chunks(self.max_payload as usize) {
+            // unwrap is safe: chunks are at most max_payload long
+            let len = u32::try_from(chunk.len()).unwrap();
+            self.request(NBD_CMD_WRITE, offset, len, chunk, &mut [])?;
+            offset += u64::from(len);
+        }
+        Ok(())
+    }
+
+    fn size_in_blocks(&mut self) -> io::Result<BlockOffset> {
+        Ok(ByteOffset::from(self.size) / self.block_size)
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.has_flag(NBD_FLAG_SEND_FLUSH) {
+            self.request(NBD_CMD_FLUSH, 0, 0, &[], &mut [])
+        } else {
+            // the server doesn't have a write cache
+            Ok(())
+        }
+    }
+
+    fn discard(&mut self, offset: ByteOffset, len: u64) -> io::Result<()> {
+        // NBD_CMD_TRIM doesn't guarantee the blocks read back as zeros, but
+        // WRITE ZEROES without NBD_CMD_FLAG_NO_HOLE lets the server punch holes.
+        if !self.has_flag(NBD_FLAG_SEND_WRITE_ZEROES) {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        let mut offset = u64::from(offset);
+        let end = offset + len;
+        while offset < end {
+            // unwrap is safe: the chunk is at most MAX_WRITE_ZEROES long
+            let chunk = u32::try_from((end - offset).min(MAX_WRITE_ZEROES.into())).unwrap();
+            self.request(NBD_CMD_WRITE_ZEROES, offset, chunk, &[], &mut [])?;
+            offset += u64::from(chunk);
+        }
+        Ok(())
+    }
+}
@@ -60,22 +60,29 @@ where
 }
 
 /// Write the response data for a standard (i.e. not VPD) inquiry, excluding the
-/// first byte (the peripheal qualifier and device type).
-pub fn respond_standard_inquiry_data(data_in: &mut impl Write) -> io::Result<()> {
+/// first byte (the peripheal qualifier and device type). `removable` sets the
+/// RMB bit, `norm_aca` the NormACA bit, i.e. whether the LUN has a task set
+/// that can establish ACA, and `third_party_copy` sets the 3PC bit, i.e.
+/// whether copy commands are supported.
+pub fn respond_standard_inquiry_data(
+    data_in: &mut impl Write,
+    removable: bool,
+    norm_aca: bool,
+    third_party_copy: bool,
+) -> io::Result<()> {
     // TODO: Feature bits here we might want to support:
-    // - NormACA
     // - command queueing
     data_in.write_all(&[
-        // various bits: not removable, not part of a
+        // various bits: removable medium or not, not part of a
         // conglomerate, no info on hotpluggability
-        0,
+        u8::from(removable) << 7,
         0x7, // version: SPC-6
-        // bits: don't support NormACA, support modern LUN format
+        // bits: NormACA, support modern LUN format
         // INQUIRY data version 2
-        0b0001_0000 | 0x2,
+        u8::from(norm_aca) << 5 | 0b0001_0000 | 0x2,
         91, // additional INQURIY data length
-        // bunch of feature bits we don't support:
-        0,
+        // bunch of feature bits we don't support, except for 3PC:
+        u8::from(third_party_copy) << 3,
         0,
         0,
     ])?;
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Self-tests started by SEND DIAGNOSTIC.
+//!
+//! A self-test reads the medium through the `BlockDeviceBackend`: all of it for
+//! an extended self-test, or chunks spread evenly across it for a short one.
+//! Background self-tests read through a clone of the backend (see
+//! `BlockDeviceBackend::try_clone`) in a thread of their own, so that the
+//! logical unit keeps processing commands in the meantime. The outcome ends up
+//! in the Self-Test Results log page.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use log::{error, info};
+
+use super::block_device::{sense_for_io_error, BlockDeviceBackend, ByteOffset};
+use crate::scsi::sense::{self, SenseTriple};
+
+/// The number of blocks read at once.
+const CHUNK_BLOCKS: u64 = 128;
+/// The number of chunks a short self-test reads.
+const SHORT_TEST_CHUNKS: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SelfTestKind {
+    Short,
+    Extended,
+}
+
+/// The self-test requested by SEND DIAGNOSTIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SelfTestCode {
+    /// The SELFTEST bit: a foreground test, whose result isn't logged.
+    Default,
+    Background(SelfTestKind),
+    Foreground(SelfTestKind),
+    AbortBackground,
+}
+
+impl SelfTestCode {
+    /// The SELF-TEST CODE field.
+    pub(crate) const fn code(self) -> u8 {
+        match self {
+            Self::Default => 0b000,
+            Self::Background(SelfTestKind::Short) => 0b001,
+            Self::Background(SelfTestKind::Extended) => 0b010,
+            Self::AbortBackground => 0b100,
+            Self::Foreground(SelfTestKind::Short) => 0b101,
+            Self::Foreground(SelfTestKind::Extended) => 0b110,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SelfTestOutcome {
+    Passed,
+    /// Aborted by SEND DIAGNOSTIC.
+    Aborted,
+    /// The medium couldn't be tested at all.
+    Error,
+    /// Reading the block at `lba` failed with `sense`.
+    Failed {
+        lba: u64,
+        sense: SenseTriple,
+    },
+    InProgress,
+}
+
+/// An entry of the Self-Test Results log page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SelfTestResult {
+    /// The SELF-TEST CODE of SEND DIAGNOSTIC that started the self-test.
+    pub code: u8,
+    pub outcome: SelfTestOutcome,
+    /// The "accumulated power on hours" when the self-test was started.
+    pub timestamp: u16,
+}
+
+impl SelfTestResult {
+    pub(crate) fn new(code: u8) -> Self {
+        // We don't keep track of how long the device has been powered on, so
+        // count from the first self-test of this process.
+        static POWER_ON: OnceLock<Instant> = OnceLock::new();
+        let hours = POWER_ON.get_or_init(Instant::now).elapsed().as_secs() / 3600;
+        Self {
+            code,
+            outcome: SelfTestOutcome::InProgress,
+            timestamp: u16::try_from(hours).unwrap_or(u16::MAX),
+        }
+    }
+
+    /// The value of the result's log parameter.
+    pub(crate) fn parameter_value(&self) -> [u8; 16] {
+        let (result, lba, sense) = match self.outcome {
+            SelfTestOutcome::Passed => (0x0, None, None),
+            SelfTestOutcome::Aborted => (0x1, None, None),
+            SelfTestOutcome::Error => (0x3, None, None),
+            // we don't have segments, so the failed one is unknown
+            SelfTestOutcome::Failed { lba, sense } => (0x4, Some(lba), Some(sense)),
+            SelfTestOutcome::InProgress => (0xf, None, None),
+        };
+
+        let mut value = [0; 16];
+        value[0] = (self.code << 5) | result;
+        value[2..4].copy_from_slice(&self.timestamp.to_be_bytes());
+        value[4..12].copy_from_slice(&lba.unwrap_or(u64::MAX).to_be_bytes());
+        if let Some(sense) = sense {
+            let fixed = sense.to_fixed_sense();
+            value[12] = fixed[2]; // sense key
+            value[13] = fixed[12]; // ASC
+            value[14] = fixed[13]; // ASCQ
+        }
+        value
+    }
+}
+
+/// Read the blocks checked by a self-test of `kind`, stopping early if
+/// `abort` gets set.
+pub(crate) fn run_self_test(
+    backend: &mut dyn BlockDeviceBackend,
+    kind: SelfTestKind,
+    abort: &AtomicBool,
+) -> SelfTestOutcome {
+    let block_size = u64::from(u32::from(backend.block_size()));
+    let blocks = match backend.size_in_blocks() {
+        Ok(blocks) => u64::from(blocks),
+        Err(e) => {
+            error!("Error getting image size for self-test: {}", e);
+            return SelfTestOutcome::Error;
+        }
+    };
+
+    let chunks = blocks.div_ceil(CHUNK_BLOCKS);
+    let step = match kind {
+        SelfTestKind::Short => (chunks / SHORT_TEST_CHUNKS).max(1),
+        SelfTestKind::Extended => 1,
+    };
+    let mut buf = vec![
+        0;
+        usize::try_from(CHUNK_BLOCKS * block_size)
+            .expect("chunk length in bytes should fit usize")
+    ];
+
+    for chunk in (0..chunks).step_by(usize::try_from(step).unwrap_or(usize::MAX)) {
+        if abort.load(Ordering::Relaxed) {
+            return SelfTestOutcome::Aborted;
+        }
+
+        let start = chunk * CHUNK_BLOCKS;
+        let count = CHUNK_BLOCKS.min(blocks - start);
+        let len = usize::try_from(count * block_size).expect("count is at most CHUNK_BLOCKS");
+        if backend
+            .read_exact_at(&mut buf[..len], ByteOffset::from(start * block_size))
+            .is_ok()
+        {
+            continue;
+        }
+
+        // find the first block that can't be read
+        let block_len = usize::try_from(block_size).expect("block_size should fit usize");
+        for lba in start..start + count {
+            if let Err(e) =
+                backend.read_exact_at(&mut buf[..block_len], ByteOffset::from(lba * block_size))
+            {
+                error!("Self-test failed reading block {}: {}", lba, e);
+                return SelfTestOutcome::Failed {
+                    lba,
+                    sense: sense_for_io_error(&e, sense::UNRECOVERED_READ_ERROR),
+                };
+            }
+        }
+    }
+    SelfTestOutcome::Passed
+}
+
+/// A self-test running in the background.
+pub(crate) struct BackgroundSelfTest {
+    abort: Arc<AtomicBool>,
+    handle: JoinHandle<SelfTestOutcome>,
+}
+
+impl BackgroundSelfTest {
+    pub(crate) fn spawn(
+        mut backend: Box<dyn BlockDeviceBackend>,
+        kind: SelfTestKind,
+    ) -> io::Result<Self> {
+        let abort = Arc::new(AtomicBool::new(false));
+        let handle = thread::Builder::new().name("self-test".into()).spawn({
+            let abort = Arc::clone(&abort);
+            move || {
+                let outcome = run_self_test(&mut *backend, kind, &abort);
+                info!("Background {:?} self-test finished: {:?}", kind, outcome);
+                outcome
+            }
+        })?;
+        Ok(Self { abort, handle })
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Wait for the self-test to finish.
+    pub(crate) fn join(self) -> SelfTestOutcome {
+        self.handle.join().unwrap_or_else(|_| {
+            error!("Background self-test panicked");
+            SelfTestOutcome::Error
+        })
+    }
+
+    pub(crate) fn abort(self) -> SelfTestOutcome {
+        self.abort.store(true, Ordering::Relaxed);
+        self.join()
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Snapshots of images while the guest is running.
+//!
+//! The control socket quiesces the request queue before taking a snapshot, so
+//! that no request is in progress, and the image is flushed with
+//! `BlockDeviceBackend::sync`. The snapshot then has exactly the writes the
+//! guest saw complete, like the image after a power loss would. It's a reflink
+//! of the image on file systems that support them (e.g. XFS and Btrfs), which
+//! only takes a moment, and a copy of the allocated parts of the image
+//! otherwise, during which the guest's requests wait.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io,
+    os::unix::prelude::*,
+    path::Path,
+};
+
+use log::debug;
+
+use super::block_device::{BlockDeviceBackend, ByteOffset};
+
+/// `FICLONE` from `linux/fs.h`, which libc doesn't have yet.
+const FICLONE: libc::c_ulong = 0x4004_9409;
+/// How much is copied at once if the image can't be reflinked.
+const COPY_CHUNK: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SnapshotMethod {
+    Reflink,
+    Copy,
+}
+
+impl fmt::Display for SnapshotMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Reflink => "reflink",
+            Self::Copy => "copy",
+        })
+    }
+}
+
+/// Flush `image` and write a snapshot of it to a new file at `path`. The
+/// caller has to make sure nothing writes to the image in the meantime.
+pub(crate) fn snapshot(
+    image: &mut dyn BlockDeviceBackend,
+    path: &Path,
+) -> io::Result<SnapshotMethod> {
+    image.sync()?;
+    let file = File::options().write(true).create_new(true).open(path)?;
+    let res = write_snapshot(image, &file);
+    if res.is_err() {
+        // don't leave a partial snapshot behind
+        let _ = fs::remove_file(path);
+    }
+    res
+}
+
+fn write_snapshot(image: &mut dyn BlockDeviceBackend, file: &File) -> io::Result<SnapshotMethod> {
+    if let Some(fd) = image.raw_fd() {
+        // SAFETY: FICLONE only takes a file descriptor, and the kernel checks
+        // both of them.
+        if unsafe { libc::ioctl(file.as_raw_fd(), FICLONE, fd) } == 0 {
+            return Ok(SnapshotMethod::Reflink);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            // not supported by the file system, or across file systems
+            Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY) => {
+                debug!("Can't reflink image ({}), copying it", e);
+            }
+            _ => return Err(e),
+        }
+    }
+
+    let size = u64::from(image.size_in_blocks()?) * u64::from(u32::from(image.block_size()));
+    // holes stay holes
+    file.set_len(size)?;
+    let mut buf = vec![0; usize::try_from(COPY_CHUNK).expect("COPY_CHUNK should fit usize")];
+    let mut offset = 0;
+    while offset < size {
+        let (allocated, len) =
+            image.allocation_status(ByteOffset::from(offset), COPY_CHUNK.min(size - offset))?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if allocated {
+            let buf = &mut buf[..usize::try_from(len).expect("len is at most COPY_CHUNK")];
+            image.read_exact_at(buf, ByteOffset::from(offset))?;
+            file.write_all_at(buf, offset)?;
+        }
+        offset += len;
+    }
+    file.sync_all()?;
+    Ok(SnapshotMethod::Copy)
+}
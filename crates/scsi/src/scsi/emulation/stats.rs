@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Per-LUN I/O statistics, collected by `EmulatedTarget` and served as JSON
+//! over the control socket.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::scsi::{CmdError, CmdOutput};
+
+/// Upper bounds (inclusive, in microseconds) of the latency histogram buckets.
+/// Latencies above the last bound go into an overflow bucket.
+const LATENCY_BUCKETS_US: &[u64] = &[
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+    500_000, 1_000_000,
+];
+
+const SENSE_KEY_NAMES: [&str; 16] = [
+    "no-sense",
+    "recovered-error",
+    "not-ready",
+    "medium-error",
+    "hardware-error",
+    "illegal-request",
+    "unit-attention",
+    "data-protect",
+    "blank-check",
+    "vendor-specific",
+    "copy-aborted",
+    "aborted-command",
+    "reserved",
+    "volume-overflow",
+    "miscompare",
+    "completed",
+];
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub(crate) struct LatencyBucket {
+    /// Upper bound in microseconds; `None` for the overflow bucket.
+    pub le_us: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub(crate) struct LatencyHistogram {
+    pub buckets: Vec<LatencyBucket>,
+    pub count: u64,
+    pub sum_us: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS_US
+                .iter()
+                .map(|&le| Some(le))
+                .chain([None])
+                .map(|le_us| LatencyBucket { le_us, count: 0 })
+                .collect(),
+            count: 0,
+            sum_us: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&le| us <= le)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].count += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+    }
+}
+
+/// A point-in-time copy of the statistics of a LUN.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct LunStatsSnapshot {
+    /// Completed commands by opcode (formatted as hex, e.g. `0x28`).
+    pub commands: BTreeMap<String, u64>,
+    /// Bytes transferred to the guest.
+    pub bytes_read: u64,
+    /// Bytes transferred from the guest.
+    pub bytes_written: u64,
+    /// Bytes copy offload commands read from the LUN, on the host.
+    pub copy_bytes_read: u64,
+    /// Bytes copy offload commands wrote to the LUN, on the host.
+    pub copy_bytes_written: u64,
+    /// Commands that completed with CHECK CONDITION, by sense key.
+    pub errors: BTreeMap<String, u64>,
+    /// Commands that failed at the transport level.
+    pub transport_errors: u64,
+    /// Time spent executing commands. This doesn't include time spent
+    /// deferred by throttling.
+    pub latency: LatencyHistogram,
+}
+
+/// The statistics of a LUN. Shared between the target and the control
+/// socket.
+#[derive(Debug, Default)]
+pub(crate) struct LunStats(Mutex<LunStatsSnapshot>);
+
+impl LunStats {
+    pub(crate) fn snapshot(&self) -> LunStatsSnapshot {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Record a finished command. Deferred commands aren't counted, since
+    /// they will be executed again.
+    pub(crate) fn record(
+        &self,
+        opcode: u8,
+        bytes_read: u64,
+        bytes_written: u64,
+        result: &Result<CmdOutput, CmdError>,
+        latency: Duration,
+    ) {
+        if let Err(CmdError::Deferred(_)) = result {
+            return;
+        }
+
+        let mut stats = self.0.lock().unwrap();
+        *stats.commands.entry(format!("{opcode:#04x}")).or_default() += 1;
+        stats.bytes_read += bytes_read;
+        stats.bytes_written += bytes_written;
+        match result {
+            Ok(output) if output.status == CmdOutput::CHECK_CONDITION => {
+                // fixed format sense data has the sense key in byte 2
+                let key = output.sense.get(2).map_or(0, |sk| sk & 0xf);
+                *stats
+                    .errors
+                    .entry(SENSE_KEY_NAMES[usize::from(key)].to_string())
+                    .or_default() += 1;
+            }
+            Ok(_) => (),
+            Err(_) => stats.transport_errors += 1,
+        }
+        stats.latency.record(latency);
+    }
+
+    /// Record data a copy offload command copied from or to the LUN. The
+    /// command itself is recorded by `record`, like any other.
+    pub(crate) fn record_copy(&self, bytes_read: u64, bytes_written: u64) {
+        let mut stats = self.0.lock().unwrap();
+        stats.copy_bytes_read += bytes_read;
+        stats.copy_bytes_written += bytes_written;
+    }
+}
+
+/// Counts the bytes passing through a reader or writer.
+pub(crate) struct ByteCounter<T> {
+    inner: T,
+    pub count: u64,
+}
+
+impl<T> ByteCounter<T> {
+    pub(crate) const fn new(inner: T) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<T: Read> Read for ByteCounter<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for ByteCounter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -2,6 +2,8 @@
 
 use std::convert::TryFrom;
 use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Instant;
 
 use log::error;
 
@@ -11,16 +13,20 @@ use super::{
     },
     missing_lun::MissingLun,
     response_data::{respond_report_luns, SilentlyTruncate},
+    stats::{ByteCounter, LunStats},
+    task_set::{Admission, TaskSet},
+    third_party_copy::{CopyEndpoint, CopyManager},
+};
+use crate::scsi::{
+    sense, CmdError, CmdOutput, Request, Target, TaskManagementFunction, TaskManagementResponse,
 };
-use crate::scsi::{sense, CmdError, CmdOutput, Request, Target, TaskAttr};
 
+/// The parts of a request a logical unit gets to see. Task attributes and
+/// NACA are handled by the target's task set.
 pub(crate) struct LunRequest {
     pub _id: u64,
-    pub task_attr: TaskAttr,
     pub crn: u8,
-    pub prio: u8,
     pub _allocation_length: Option<u32>,
-    pub naca: bool,
 }
 
 /// A single logical unit of an emulated SCSI device.
@@ -42,20 +48,42 @@ pub(crate) trait LogicalUnit: Send + Sync {
         parameters: LunRequest,
         command: LunSpecificCommand,
     ) -> Result<CmdOutput, CmdError>;
+
+    /// The blocks of this logical unit, for the target's copy manager.
+    /// Logical units that can't take part in copies return `None`.
+    fn copy_endpoint(&mut self) -> Option<CopyEndpoint<'_>> {
+        None
+    }
 }
 
 /// A SCSI target implemented by emulating a device within vhost-device-scsi.
 pub(crate) struct EmulatedTarget {
     luns: Vec<Box<dyn LogicalUnit>>,
+    stats: Vec<Arc<LunStats>>,
+    task_sets: Vec<TaskSet>,
+    copy_manager: CopyManager,
 }
 
 impl EmulatedTarget {
     pub(crate) fn new() -> Self {
-        Self { luns: Vec::new() }
+        Self {
+            luns: Vec::new(),
+            stats: Vec::new(),
+            task_sets: Vec::new(),
+            copy_manager: CopyManager::default(),
+        }
     }
 
     pub(crate) fn add_lun(&mut self, logical_unit: Box<dyn LogicalUnit>) {
         self.luns.push(logical_unit);
+        self.stats.push(Arc::default());
+        self.task_sets.push(TaskSet::default());
+    }
+
+    /// The I/O statistics of a LUN, which keep being updated as commands are
+    /// executed.
+    pub(crate) fn lun_stats(&self, lun: u16) -> Option<Arc<LunStats>> {
+        self.stats.get(usize::from(lun)).cloned()
     }
 
     pub(crate) fn luns(&self) -> impl Iterator<Item = u16> + ExactSizeIterator + '_ {
@@ -73,24 +101,24 @@ impl Default for EmulatedTarget {
     }
 }
 
-impl Target for EmulatedTarget {
-    fn execute_command(
+impl EmulatedTarget {
+    fn execute_cdb(
         &mut self,
         lun: u16,
         data_out: &mut dyn Read,
         data_in: &mut dyn Write,
         req: Request,
+        cdb: Result<Cdb, ParseError>,
     ) -> Result<CmdOutput, CmdError> {
-        match Cdb::parse(req.cdb) {
+        match cdb {
             Ok(cdb) => {
-                let mut data_in = SilentlyTruncate::new(
-                    data_in,
-                    cdb.allocation_length.map_or(usize::MAX, |x| x as usize),
-                );
+                let allocation_length = cdb.allocation_length.map_or(usize::MAX, |x| x as usize);
+                let req_opcode = req.cdb[0];
 
                 match cdb.command {
                     Command::LunIndependentCommand(cmd) => match cmd {
                         LunIndependentCommand::ReportLuns(select_report) => {
+                            let mut data_in = SilentlyTruncate::new(data_in, allocation_length);
                             match select_report {
                                 ReportLunsSelectReport::NoWellKnown
                                 | ReportLunsSelectReport::All => {
@@ -111,16 +139,53 @@ impl Target for EmulatedTarget {
                     Command::LunSpecificCommand(cmd) => {
                         let req = LunRequest {
                             _id: req.id,
-                            task_attr: req.task_attr,
                             crn: req.crn,
-                            prio: req.prio,
                             _allocation_length: cdb.allocation_length,
-                            naca: cdb.naca,
                         };
                         match self.luns.get_mut(lun as usize) {
-                            Some(lun) => lun.execute_command(&mut data_in, data_out, req, cmd),
-                            None => MissingLun.execute_command(&mut data_in, data_out, req, cmd),
+                            Some(logical_unit) => execute_with_stats(
+                                &self.stats[lun as usize],
+                                req_opcode,
+                                data_in,
+                                data_out,
+                                allocation_length,
+                                |data_in, data_out| {
+                                    logical_unit.execute_command(data_in, data_out, req, cmd)
+                                },
+                            ),
+                            None => MissingLun.execute_command(
+                                &mut SilentlyTruncate::new(data_in, allocation_length),
+                                data_out,
+                                req,
+                                cmd,
+                            ),
+                        }
+                    }
+                    Command::ThirdPartyCopy(cmd) => {
+                        if usize::from(lun) >= self.luns.len() {
+                            return Ok(CmdOutput::check_condition(
+                                sense::LOGICAL_UNIT_NOT_SUPPORTED,
+                            ));
                         }
+                        let (luns, stats, copy_manager) =
+                            (&mut self.luns, &self.stats, &mut self.copy_manager);
+                        execute_with_stats(
+                            &stats[usize::from(lun)],
+                            req_opcode,
+                            data_in,
+                            data_out,
+                            allocation_length,
+                            |data_in, data_out| {
+                                copy_manager.execute_command(
+                                    luns,
+                                    stats,
+                                    usize::from(lun),
+                                    data_in,
+                                    data_out,
+                                    cmd,
+                                )
+                            },
+                        )
                     }
                 }
             }
@@ -141,3 +206,104 @@ impl Target for EmulatedTarget {
         }
     }
 }
+
+/// Execute a command for a LUN and record it in the LUN's statistics. Only
+/// what actually reaches the guest is counted, i.e. after truncating to the
+/// allocation length.
+fn execute_with_stats(
+    stats: &LunStats,
+    opcode: u8,
+    data_in: &mut dyn Write,
+    data_out: &mut dyn Read,
+    allocation_length: usize,
+    execute: impl FnOnce(
+        &mut SilentlyTruncate<&mut dyn Write>,
+        &mut dyn Read,
+    ) -> Result<CmdOutput, CmdError>,
+) -> Result<CmdOutput, CmdError> {
+    let start = Instant::now();
+    let mut data_in = ByteCounter::new(data_in);
+    let mut data_out = ByteCounter::new(data_out);
+    let result = execute(
+        &mut SilentlyTruncate::new(&mut data_in as &mut dyn Write, allocation_length),
+        &mut data_out,
+    );
+    stats.record(
+        opcode,
+        data_in.count,
+        data_out.count,
+        &result,
+        start.elapsed(),
+    );
+    result
+}
+
+impl Target for EmulatedTarget {
+    fn execute_command(
+        &mut self,
+        lun: u16,
+        data_out: &mut dyn Read,
+        data_in: &mut dyn Write,
+        req: Request,
+    ) -> Result<CmdOutput, CmdError> {
+        let (id, task_attr, prio) = (req.id, req.task_attr, req.prio);
+        let cdb = Cdb::parse(req.cdb);
+        let naca = cdb.as_ref().is_ok_and(|cdb| cdb.naca);
+
+        // Commands for LUNs that don't exist have no task set to wait for.
+        if let Some(task_set) = self.task_sets.get_mut(usize::from(lun)) {
+            match task_set.admit(id, task_attr, prio, Instant::now()) {
+                Admission::Enabled => (),
+                Admission::Blocked(delay) => return Err(CmdError::Deferred(delay)),
+                Admission::Rejected(output) => return Ok(output),
+            }
+        }
+
+        let result = self.execute_cdb(lun, data_out, data_in, req, cdb);
+
+        if let Some(task_set) = self.task_sets.get_mut(usize::from(lun)) {
+            task_set.complete(id, task_attr, prio, naca, &result, Instant::now());
+        }
+        result
+    }
+
+    fn task_management(
+        &mut self,
+        lun: u16,
+        function: TaskManagementFunction,
+    ) -> TaskManagementResponse {
+        if function == TaskManagementFunction::ITNexusReset {
+            for task_set in &mut self.task_sets {
+                task_set.abort_all();
+                task_set.clear_aca();
+            }
+            return TaskManagementResponse::FunctionComplete;
+        }
+
+        let Some(task_set) = self.task_sets.get_mut(usize::from(lun)) else {
+            return TaskManagementResponse::IncorrectLun;
+        };
+        match function {
+            TaskManagementFunction::AbortTask(id) => task_set.abort(id),
+            TaskManagementFunction::AbortTaskSet | TaskManagementFunction::ClearTaskSet => {
+                task_set.abort_all();
+            }
+            TaskManagementFunction::ClearAca => task_set.clear_aca(),
+            TaskManagementFunction::LogicalUnitReset | TaskManagementFunction::ITNexusReset => {
+                task_set.abort_all();
+                task_set.clear_aca();
+            }
+            TaskManagementFunction::QueryTask(id) => {
+                if task_set.contains(id) {
+                    return TaskManagementResponse::FunctionSucceeded;
+                }
+            }
+            TaskManagementFunction::QueryTaskSet => {
+                if !task_set.is_empty() {
+                    return TaskManagementResponse::FunctionSucceeded;
+                }
+            }
+        }
+        TaskManagementResponse::FunctionComplete
+    }
+}
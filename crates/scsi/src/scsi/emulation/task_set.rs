@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! The task set of a logical unit, which orders tasks according to their task
+//! attributes and tracks auto contingent allegiance (ACA). See SAM-5 5.9 and 8.
+//!
+//! We execute commands synchronously, so the only tasks that stay in the task
+//! set are the ones that got deferred (`CmdError::Deferred`). Tasks that
+//! can't be enabled yet because of older tasks are deferred as well, until
+//! those complete.
+//!
+//! Task priority (SAM-5 8.7) only orders SIMPLE tasks that are due at the same
+//! time: a task yields to tasks of higher priority by deferring itself until
+//! they ran.
+
+use std::time::{Duration, Instant};
+
+use crate::scsi::{sense, CmdError, CmdOutput, TaskAttr};
+
+/// How long to defer tasks that are blocked by ACA. The transport resubmits
+/// them right away when ACA is cleared, so this is just a fallback.
+const ACA_BLOCKED_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether a task may be executed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Admission {
+    /// The task is enabled and may be executed now.
+    Enabled,
+    /// The task has to wait for other tasks; it should be deferred by the
+    /// given delay.
+    Blocked(Duration),
+    /// The task must not be executed and completes with the given output.
+    Rejected(CmdOutput),
+}
+
+/// Rank of a task priority, lower is more urgent. Priority 1 is the highest
+/// and 15 the lowest; tasks without a priority (0) go last.
+const fn priority_rank(prio: u8) -> u8 {
+    match prio {
+        0 => u8::MAX,
+        prio => prio,
+    }
+}
+
+#[derive(Debug)]
+struct Task {
+    id: u64,
+    attr: TaskAttr,
+    prio: u8,
+    /// When the task is expected to be resubmitted.
+    resubmit_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TaskSet {
+    /// Tasks that haven't completed yet, oldest first.
+    tasks: Vec<Task>,
+    /// Whether an ACA condition is established.
+    aca: bool,
+}
+
+impl TaskSet {
+    /// Decide whether the task `id` may be executed now. Blocked tasks are
+    /// added to the task set.
+    pub(crate) fn admit(&mut self, id: u64, attr: TaskAttr, prio: u8, now: Instant) -> Admission {
+        let position = self.tasks.iter().position(|task| task.id == id);
+        let older = &self.tasks[..position.unwrap_or(self.tasks.len())];
+
+        let admission = if self.aca {
+            match attr {
+                // Only one ACA task may exist at a time.
+                TaskAttr::Aca
+                    if self
+                        .tasks
+                        .iter()
+                        .any(|task| task.attr == TaskAttr::Aca && task.id != id) =>
+                {
+                    Admission::Rejected(CmdOutput::aca_active())
+                }
+                TaskAttr::Aca => Admission::Enabled,
+                // Tasks that were already in the task set when ACA got
+                // established are blocked until it is cleared; new ones are
+                // rejected.
+                _ if position.is_some() => Admission::Blocked(ACA_BLOCKED_DELAY),
+                _ => Admission::Rejected(CmdOutput::aca_active()),
+            }
+        } else {
+            match attr {
+                TaskAttr::Aca => {
+                    Admission::Rejected(CmdOutput::check_condition(sense::INVALID_MESSAGE_ERROR))
+                }
+                TaskAttr::HeadOfQueue => Admission::Enabled,
+                // ORDERED tasks wait for all older tasks ...
+                TaskAttr::Ordered => Self::blocked_by(older.iter(), now),
+                // ... and SIMPLE ones only for older ORDERED and HEAD OF QUEUE
+                // tasks.
+                TaskAttr::Simple => match Self::blocked_by(
+                    older.iter().filter(|task| task.attr != TaskAttr::Simple),
+                    now,
+                ) {
+                    Admission::Enabled if self.has_more_urgent_task(id, prio, now) => {
+                        Admission::Blocked(Duration::ZERO)
+                    }
+                    admission => admission,
+                },
+            }
+        };
+
+        match &admission {
+            Admission::Enabled => (),
+            Admission::Blocked(delay) => self.defer(id, attr, prio, now + *delay),
+            Admission::Rejected(_) => self.remove(id),
+        }
+        admission
+    }
+
+    /// Blocked until the last of `blockers` is resubmitted. Since the
+    /// transport resubmits due tasks in order, the blockers get to run first.
+    fn blocked_by<'a>(blockers: impl Iterator<Item = &'a Task>, now: Instant) -> Admission {
+        blockers
+            .map(|task| task.resubmit_at)
+            .max()
+            .map_or(Admission::Enabled, |resubmit_at| {
+                Admission::Blocked(resubmit_at.saturating_duration_since(now))
+            })
+    }
+
+    /// Whether another SIMPLE task with a higher priority than `prio` is due.
+    fn has_more_urgent_task(&self, id: u64, prio: u8, now: Instant) -> bool {
+        self.tasks.iter().any(|task| {
+            task.id != id
+                && task.attr == TaskAttr::Simple
+                && task.resubmit_at <= now
+                && priority_rank(task.prio) < priority_rank(prio)
+        })
+    }
+
+    fn defer(&mut self, id: u64, attr: TaskAttr, prio: u8, resubmit_at: Instant) {
+        match self.tasks.iter_mut().find(|task| task.id == id) {
+            Some(task) => task.resubmit_at = resubmit_at,
+            None => self.tasks.push(Task {
+                id,
+                attr,
+                prio,
+                resubmit_at,
+            }),
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.tasks.retain(|task| task.id != id);
+    }
+
+    /// Record the result of executing an enabled task. A CHECK CONDITION for
+    /// a command with the NACA bit set establishes ACA.
+    pub(crate) fn complete(
+        &mut self,
+        id: u64,
+        attr: TaskAttr,
+        prio: u8,
+        naca: bool,
+        result: &Result<CmdOutput, CmdError>,
+        now: Instant,
+    ) {
+        match result {
+            Err(CmdError::Deferred(delay)) => self.defer(id, attr, prio, now + *delay),
+            Ok(output) => {
+                self.remove(id);
+                if naca && output.status == CmdOutput::CHECK_CONDITION {
+                    self.aca = true;
+                }
+            }
+            Err(_) => self.remove(id),
+        }
+    }
+
+    /// Whether task `id` is in the task set.
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.tasks.iter().any(|task| task.id == id)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    pub(crate) fn abort(&mut self, id: u64) {
+        self.remove(id);
+    }
+
+    pub(crate) fn abort_all(&mut self) {
+        self.tasks.clear();
+    }
+
+    pub(crate) fn clear_aca(&mut self) {
+        self.aca = false;
+    }
+}
@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::{io::Write, os::unix::fs::FileExt};
+
+use tempfile::tempfile;
+
+use super::{do_command_fail, do_command_in, test_image};
+use crate::scsi::{
+    emulation::{
+        block_device::{BlockDevice, FileBackend},
+        target::EmulatedTarget,
+    },
+    sense::{self, SenseTriple},
+    CmdOutput, Request, Target, TaskAttr,
+};
+
+/// VERIFY (10) of 2 blocks at LBA 1, with the given BYTCHK.
+fn verify_10(bytchk: u8) -> [u8; 10] {
+    [
+        0x2f,        // VERIFY (10)
+        bytchk << 1, // flags
+        0,
+        0,
+        0,
+        1, // LBA: 1
+        0, // reserved, group #
+        0,
+        2, // verification length: 2
+        0, // control
+    ]
+}
+
+fn do_verify_fail(
+    target: &mut EmulatedTarget,
+    cdb: &[u8],
+    data_out: &[u8],
+    expected_error: SenseTriple,
+) {
+    let res = target.execute_command(
+        0,
+        &mut &data_out[..],
+        &mut Vec::new(),
+        Request {
+            id: 0,
+            cdb,
+            task_attr: TaskAttr::Simple,
+            crn: 0,
+            prio: 0,
+        },
+    );
+    assert_eq!(res.unwrap(), CmdOutput::check_condition(expected_error));
+}
+
+fn test_target() -> EmulatedTarget {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(test_image())));
+    target
+}
+
+#[test]
+fn test_verify() {
+    let mut target = test_target();
+
+    do_command_in(&mut target, &verify_10(0b00), &[], &[]);
+
+    let mut expected = [b'1'; 1024];
+    expected[512..].fill(b'2');
+    do_command_in(&mut target, &verify_10(0b01), &expected, &[]);
+    expected[1023] = b'x';
+    do_verify_fail(
+        &mut target,
+        &verify_10(0b01),
+        &expected,
+        sense::MISCOMPARE_DURING_VERIFY_OPERATION,
+    );
+
+    do_command_fail(&mut target, &verify_10(0b10), sense::INVALID_FIELD_IN_CDB);
+
+    let mut cdb = verify_10(0b00);
+    cdb[5] = 15;
+    do_command_fail(&mut target, &cdb, sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE);
+}
+
+#[test]
+fn test_verify_16_single_block() {
+    let mut target = test_target();
+
+    let mut cdb = [0; 16];
+    cdb[0] = 0x8f; // VERIFY (16)
+    cdb[1] = 0b11 << 1; // BYTCHK: compare with a single block
+    cdb[9] = 10; // LBA: 10
+    cdb[13] = 1; // verification length: 1
+    do_command_in(&mut target, &cdb, &[b'a'; 512], &[]);
+
+    // block 11 consists of 'b's
+    cdb[13] = 2;
+    do_verify_fail(
+        &mut target,
+        &cdb,
+        &[b'a'; 512],
+        sense::MISCOMPARE_DURING_VERIFY_OPERATION,
+    );
+
+    // no protection information
+    cdb[1] = 0b0010_0000;
+    do_command_fail(&mut target, &cdb, sense::INVALID_FIELD_IN_CDB);
+}
+
+#[test]
+fn test_synchronize_cache_16() {
+    let mut target = test_target();
+
+    let mut cdb = [0; 16];
+    cdb[0] = 0x91; // SYNCHRONIZE CACHE (16)
+    cdb[9] = 4; // LBA: 4
+    cdb[13] = 12; // blocks: 12
+    do_command_in(&mut target, &cdb, &[], &[]);
+
+    cdb[13] = 13;
+    do_command_fail(&mut target, &cdb, sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE);
+
+    // LBA overflow
+    cdb[2..10].fill(0xff);
+    do_command_fail(&mut target, &cdb, sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE);
+}
+
+#[test]
+fn test_pre_fetch() {
+    let mut target = test_target();
+
+    do_command_in(
+        &mut target,
+        &[
+            0x34, // PRE-FETCH (10)
+            0,    // flags
+            0, 0, 0, 8, // LBA: 8
+            0, // reserved, group #
+            0, 0, // prefetch length: until the end
+            0, // control
+        ],
+        &[],
+        &[],
+    );
+    do_command_fail(
+        &mut target,
+        &[
+            0x34, // PRE-FETCH (10)
+            0,    // flags
+            0, 0, 0, 17, // LBA: 17
+            0,  // reserved, group #
+            0, 0, // prefetch length: until the end
+            0, // control
+        ],
+        sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE,
+    );
+}
+
+/// GET LBA STATUS at `lba`.
+fn get_lba_status(lba: u8, allocation_length: u8) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = 0x9e; // SERVICE ACTION IN (16)
+    cdb[1] = 0x12; // GET LBA STATUS
+    cdb[9] = lba;
+    cdb[13] = allocation_length;
+    cdb
+}
+
+fn lba_status_descriptor(lba: u64, blocks: u32, mapped: bool) -> Vec<u8> {
+    let mut desc = lba.to_be_bytes().to_vec();
+    desc.extend_from_slice(&blocks.to_be_bytes());
+    desc.extend_from_slice(&[u8::from(!mapped), 0, 0, 0]);
+    desc
+}
+
+#[test]
+fn test_get_lba_status() {
+    // 64 blocks, with only blocks 16-23 (i.e. the third 4k page) allocated
+    let mut f = tempfile().unwrap();
+    f.set_len(512 * 64).unwrap();
+    f.write_all_at(&[b'x'; 4096], 512 * 16).unwrap();
+    f.flush().unwrap();
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(FileBackend::new(f))));
+
+    let mut expected = vec![0, 0, 0, 52, 0, 0, 0, 0];
+    expected.extend(lba_status_descriptor(0, 16, false));
+    expected.extend(lba_status_descriptor(16, 8, true));
+    expected.extend(lba_status_descriptor(24, 40, false));
+    do_command_in(&mut target, &get_lba_status(0, 255), &[], &expected);
+
+    // the allocation length limits the number of descriptors
+    let mut expected = vec![0, 0, 0, 20, 0, 0, 0, 0];
+    expected.extend(lba_status_descriptor(20, 4, true));
+    do_command_in(&mut target, &get_lba_status(20, 24), &[], &expected);
+
+    do_command_fail(
+        &mut target,
+        &get_lba_status(64, 255),
+        sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE,
+    );
+}
@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::{
+    fs::File,
+    io::Write,
+    os::unix::{fs::FileExt, prelude::*},
+    sync::Arc,
+};
+
+use tempfile::tempfile;
+
+use super::{do_command_in, test_image};
+use crate::scsi::emulation::{
+    block_device::{BlockDevice, BlockDeviceBackend, ByteOffset, FileBackend},
+    encryption::{EncryptedBackend, EncryptionError, EncryptionSpec, KeySource, XtsCipher},
+    target::EmulatedTarget,
+};
+
+fn plaintext() -> Vec<u8> {
+    (0..512).map(|i| i as u8).collect()
+}
+
+/// Check the first and last 32 bytes of the sector encrypted from
+/// `plaintext()`.
+fn check_sector(cipher: &XtsCipher, sector: u64, head: &str, tail: &str) {
+    let mut buf = plaintext();
+    cipher.encrypt(sector, &mut buf);
+    let hex = |b: &[u8]| b.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    assert_eq!(hex(&buf[..32]), head);
+    assert_eq!(hex(&buf[480..]), tail);
+
+    cipher.decrypt(sector, &mut buf);
+    assert_eq!(buf, plaintext());
+}
+
+#[test]
+fn test_xts() {
+    // the expected ciphertexts are from OpenSSL
+    let key: Vec<u8> = (0..64).collect();
+    let cipher = XtsCipher::new(&key[..32]).unwrap();
+    check_sector(
+        &cipher,
+        0,
+        "74a109aabf1937c022d19da4b96cbc40b8ddc9c0653a7fb0dc8425c7ef276dea",
+        "4e93f253940f78f1223ed15c3d7193643f58e86f7caadb805120eab0bbc04cdc",
+    );
+    check_sector(
+        &cipher,
+        0x12_3456_789a,
+        "3ca6c425e2f83bf789206f1403f8532260fb0aba46365325d6fb0b92d1be3066",
+        "394855aaa4c83efb2a7193c82c11bba8710f1033f78af9e41600dd4a882f41a3",
+    );
+
+    let cipher = XtsCipher::new(&key).unwrap();
+    check_sector(
+        &cipher,
+        0,
+        "dc8c665b97cbc0246d4f1639a9678a3e2a2dcf4a3fbf1342ebbb771234f1a1c3",
+        "3c88ac86abd05303e21acdf2908ecfd5f04100379dedfce370af7f179a14ca5a",
+    );
+    check_sector(
+        &cipher,
+        0x12_3456_789a,
+        "1ad4aaecba8050a2cdaaa7327fec9bf450bf671c1fb491a77a01bb3517f343cc",
+        "782f1bc3444c6f5752fe2db89dd13118375c22de994b439a13670532c94c0efc",
+    );
+
+    assert!(matches!(
+        XtsCipher::new(&key[..48]),
+        Err(EncryptionError::InvalidKeyLength(48))
+    ));
+}
+
+#[test]
+fn test_encrypted_backend() {
+    let cipher = Arc::new(XtsCipher::new(&[7; 32]).unwrap());
+    let image = tempfile().unwrap();
+    // a 1 KiB header, followed by 16 blocks of encrypted data
+    image.set_len(1024 + 16 * 512).unwrap();
+    image.write_all_at(&[0xaa; 1024], 0).unwrap();
+
+    let backend = EncryptedBackend::new(
+        FileBackend::new(image.try_clone().unwrap()),
+        Arc::clone(&cipher),
+        1024,
+    )
+    .unwrap();
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(backend)));
+
+    do_command_in(
+        &mut target,
+        &[
+            0x25, // READ CAPACITY (10)
+            0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ],
+        &[],
+        &[
+            0, 0, 0, 15, // returned LBA (last valid LBA)
+            0, 0, 2, 0, // block size
+        ],
+    );
+    do_command_in(
+        &mut target,
+        &[
+            0x2a, // WRITE (10)
+            0,    // flags
+            0, 0, 0, 1, // LBA: 1
+            0, // reserved, group #
+            0, 2, // transfer length: 2
+            0, // control
+        ],
+        &[b'1'; 1024],
+        &[],
+    );
+    do_command_in(
+        &mut target,
+        &[
+            0x28, // READ (10)
+            0,    // flags
+            0, 0, 0, 1, // LBA: 1
+            0, // reserved, group #
+            0, 1, // transfer length: 1
+            0, // control
+        ],
+        &[],
+        &[b'1'; 512],
+    );
+
+    // the image has the header and ciphertext
+    let mut header = [0; 1024];
+    image.read_exact_at(&mut header, 0).unwrap();
+    assert_eq!(header, [0xaa; 1024]);
+    let mut data = [0; 1024];
+    image.read_exact_at(&mut data, 1024 + 512).unwrap();
+    let mut expected = [b'1'; 1024];
+    cipher.encrypt(1, &mut expected);
+    assert_eq!(data, expected);
+    assert_ne!(data[..512], data[512..]);
+}
+
+#[test]
+fn test_encrypted_backend_unaligned() {
+    let mut backend =
+        EncryptedBackend::new(test_image(), Arc::new(XtsCipher::new(&[7; 32]).unwrap()), 0)
+            .unwrap();
+    let mut buf = [0; 512];
+    assert!(backend
+        .read_exact_at(&mut buf, ByteOffset::from(1))
+        .is_err());
+    assert!(backend
+        .read_exact_at(&mut buf[..100], ByteOffset::from(0))
+        .is_err());
+    assert!(backend.write_exact_at(&buf, ByteOffset::from(256)).is_err());
+    backend
+        .read_exact_at(&mut buf, ByteOffset::from(512))
+        .unwrap();
+
+    // discards would leave garbage behind
+    assert!(backend.discard(ByteOffset::from(0), 512).is_err());
+    assert!(backend.raw_fd().is_none());
+}
+
+#[test]
+fn test_load_key() {
+    let mut key_file = tempfile::NamedTempFile::new().unwrap();
+    key_file.write_all(&[1; 64]).unwrap();
+    let spec = EncryptionSpec {
+        key: KeySource::File(key_file.path().into()),
+        offset: 0,
+    };
+    spec.load_cipher().unwrap();
+
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends of the pipe
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    // SAFETY: we just created the pipe, and nothing else owns its write end
+    let mut writer = unsafe { File::from_raw_fd(fds[1]) };
+    writer.write_all(&[1; 32]).unwrap();
+    drop(writer);
+    let spec = EncryptionSpec {
+        key: KeySource::Fd(fds[0]),
+        offset: 0,
+    };
+    spec.load_cipher().unwrap();
+}
@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::time::{Duration, Instant};
+
+use assert_matches::assert_matches;
+
+use super::{do_command_fail, do_command_in, TestBackend};
+use crate::scsi::{
+    emulation::{
+        block_device::BlockDevice,
+        fault_injection::{
+            parse_rules, Fault, FaultInjectionBackend, FaultOp, FaultRule, FaultRuleError,
+        },
+        target::EmulatedTarget,
+    },
+    sense::{self, SenseTriple},
+    CmdOutput, Request, Target, TaskAttr,
+};
+
+const READ_10_LBA_5: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 5, // LBA: 5
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+const READ_10_LBA_9: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 9, // LBA: 9
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+const WRITE_10_LBA_5: &[u8] = &[
+    0x2a, // WRITE (10)
+    0,    // flags
+    0, 0, 0, 5, // LBA: 5
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+const SYNCHRONIZE_CACHE_10: &[u8] = &[
+    0x35, // SYNCHRONIZE CACHE (10)
+    0, 0, 0, 0, 0, 0, 0, 0, // flags, LBA, group, blocks
+    0, // control
+];
+
+fn faulty_target(rules: &str) -> EmulatedTarget {
+    let mut target = EmulatedTarget::new();
+    let backend = FaultInjectionBackend::new(TestBackend::new(), parse_rules(rules).unwrap());
+    target.add_lun(Box::new(BlockDevice::new(backend)));
+    target
+}
+
+fn do_write_fail(target: &mut EmulatedTarget, cdb: &[u8], expected_error: SenseTriple) {
+    let mut data_in = Vec::new();
+
+    let res = target.execute_command(
+        0,
+        &mut &[b'w'; 512][..],
+        &mut data_in,
+        Request {
+            id: 0,
+            cdb,
+            task_attr: TaskAttr::Simple,
+            crn: 0,
+            prio: 0,
+        },
+    );
+
+    assert_eq!(res.unwrap(), CmdOutput::check_condition(expected_error));
+}
+
+#[test]
+fn test_parse_rules() {
+    let rules = parse_rules(
+        "# comment
+
+        fault=read-error,op=read,lba=1000-1999,lun=1
+        fault=latency, delay-ms=200, probability=0.25
+        fault=not-ready,op=any,lba=7",
+    )
+    .unwrap();
+
+    assert_eq!(
+        rules,
+        vec![
+            FaultRule {
+                fault: Fault::ReadError,
+                op: Some(FaultOp::Read),
+                lba: Some(1000..=1999),
+                probability: 1.0,
+                lun: Some(1),
+            },
+            FaultRule {
+                fault: Fault::Latency(Duration::from_millis(200)),
+                op: None,
+                lba: None,
+                probability: 0.25,
+                lun: None,
+            },
+            FaultRule {
+                fault: Fault::NotReady,
+                op: None,
+                lba: Some(7..=7),
+                probability: 1.0,
+                lun: None,
+            },
+        ]
+    );
+    assert!(!rules[0].applies_to_lun(0));
+    assert!(rules[0].applies_to_lun(1));
+    assert!(rules[1].applies_to_lun(0));
+}
+
+#[test]
+fn test_parse_rules_errors() {
+    assert_matches!(
+        parse_rules("fault=read-error\nop=read"),
+        Err(FaultRuleError::RequiredKeyNotFound(2, key)) if key == "fault"
+    );
+    assert_matches!(
+        parse_rules("fault=latency"),
+        Err(FaultRuleError::RequiredKeyNotFound(1, key)) if key == "delay-ms"
+    );
+    assert_matches!(
+        parse_rules("fault=explode"),
+        Err(FaultRuleError::InvalidValue(1, key, _)) if key == "fault"
+    );
+    assert_matches!(
+        parse_rules("fault=read-error,probability=1.5"),
+        Err(FaultRuleError::InvalidValue(1, key, _)) if key == "probability"
+    );
+    assert_matches!(
+        parse_rules("fault=read-error,lba=20-10"),
+        Err(FaultRuleError::InvalidValue(1, key, _)) if key == "lba"
+    );
+    assert_matches!(
+        parse_rules("fault=read-error,color=red"),
+        Err(FaultRuleError::InvalidKey(1, key)) if key == "color"
+    );
+    assert_matches!(
+        parse_rules("fault=read-error,op"),
+        Err(FaultRuleError::BadArgument(1, _))
+    );
+}
+
+#[test]
+fn test_read_error_in_range() {
+    let mut target = faulty_target("fault=read-error,op=read,lba=3-6");
+
+    do_command_fail(&mut target, READ_10_LBA_5, sense::UNRECOVERED_READ_ERROR);
+    do_command_in(&mut target, READ_10_LBA_9, &[], &[0; 512]);
+    // the rule only matches reads
+    do_command_in(&mut target, WRITE_10_LBA_5, &[b'w'; 512], &[]);
+}
+
+#[test]
+fn test_write_fault() {
+    let mut target = faulty_target("fault=write-fault,op=write");
+
+    do_write_fail(&mut target, WRITE_10_LBA_5, sense::WRITE_ERROR);
+    do_command_in(&mut target, READ_10_LBA_5, &[], &[0; 512]);
+}
+
+#[test]
+fn test_not_ready() {
+    let mut target = faulty_target("fault=not-ready");
+
+    do_command_fail(&mut target, READ_10_LBA_5, sense::LOGICAL_UNIT_NOT_READY);
+    do_command_fail(
+        &mut target,
+        SYNCHRONIZE_CACHE_10,
+        sense::LOGICAL_UNIT_NOT_READY,
+    );
+}
+
+#[test]
+fn test_probability_zero_never_fires() {
+    let mut target = faulty_target("fault=read-error,probability=0");
+
+    for _ in 0..100 {
+        do_command_in(&mut target, READ_10_LBA_5, &[], &[0; 512]);
+    }
+}
+
+#[test]
+fn test_latency() {
+    let mut target = faulty_target("fault=latency,delay-ms=50,lba=5");
+
+    let start = Instant::now();
+    do_command_in(&mut target, READ_10_LBA_5, &[], &[0; 512]);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use super::{do_command_fail, do_command_in, TestBackend};
+use crate::scsi::{
+    emulation::{
+        block_device::BlockDevice,
+        fault_injection::{parse_rules, FaultInjectionBackend},
+        target::EmulatedTarget,
+    },
+    sense, CmdOutput, Request, Target, TaskAttr,
+};
+
+/// LOG SENSE of the cumulative values of a page.
+fn log_sense(page_code: u8, subpage_code: u8, parameter_pointer: u8) -> [u8; 10] {
+    [
+        0x4d,                    // LOG SENSE
+        0,                       // flags
+        0b0100_0000 | page_code, // PC: cumulative values
+        subpage_code,
+        0, // reserved
+        0,
+        parameter_pointer,
+        0,
+        255, // allocation length
+        0,   // control
+    ]
+}
+
+const READ_10_LBA_5: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 5, // LBA: 5
+    0, // reserved, group #
+    0, 2, // transfer length: 2
+    0, // control
+];
+
+const WRITE_10: &[u8] = &[
+    0x2a, // WRITE (10)
+    0,    // flags
+    0, 0, 0, 0, // LBA: 0
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+/// An error counter log page header and parameters with the given bytes
+/// processed and uncorrected errors.
+fn error_counter_page(page_code: u8, bytes: u64, errors: u64) -> Vec<u8> {
+    let mut page = vec![page_code, 0, 0, 7 * 12];
+    for (code, value) in [0, 0, 0, 0, 0, bytes, errors].into_iter().enumerate() {
+        page.extend_from_slice(&[0, u8::try_from(code).unwrap(), 0, 8]);
+        page.extend_from_slice(&value.to_be_bytes());
+    }
+    page
+}
+
+#[test]
+fn test_supported_log_pages() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+
+    do_command_in(
+        &mut target,
+        &log_sense(0, 0, 0),
+        &[],
+        &[0, 0, 0, 6, 0x0, 0x2, 0x3, 0xd, 0x10, 0x2f],
+    );
+    do_command_in(
+        &mut target,
+        &log_sense(0, 0xff, 0),
+        &[],
+        &[
+            0x40, 0xff, 0, 14, // header
+            0x0, 0x0, 0x0, 0xff, 0x2, 0x0, 0x3, 0x0, 0xd, 0x0, 0x10, 0x0, 0x2f, 0x0,
+        ],
+    );
+    do_command_in(
+        &mut target,
+        &log_sense(0xd, 0, 0),
+        &[],
+        &[
+            0xd, 0, 0, 12, 0, 0, 0b11, 2, 0, 0xff, 0, 1, 0b11, 2, 0, 0xff,
+        ],
+    );
+    do_command_in(
+        &mut target,
+        &log_sense(0x2f, 0, 0),
+        &[],
+        &[0x2f, 0, 0, 7, 0, 0, 0b11, 3, 0, 0, 0xff],
+    );
+
+    do_command_fail(
+        &mut target,
+        &log_sense(0x30, 0, 0),
+        sense::INVALID_FIELD_IN_CDB,
+    );
+    // no thresholds
+    let mut cdb = log_sense(0x3, 0, 0);
+    cdb[2] = 0x3;
+    do_command_fail(&mut target, &cdb, sense::INVALID_FIELD_IN_CDB);
+}
+
+#[test]
+fn test_error_counters() {
+    let mut target = EmulatedTarget::new();
+    let backend = FaultInjectionBackend::new(
+        TestBackend::new(),
+        parse_rules("fault=read-error,op=read,lba=8-9").unwrap(),
+    );
+    target.add_lun(Box::new(BlockDevice::new(backend)));
+
+    do_command_in(&mut target, READ_10_LBA_5, &[], &[0; 1024]);
+    let mut cdb = READ_10_LBA_5.to_vec();
+    cdb[5] = 8;
+    do_command_fail(&mut target, &cdb, sense::UNRECOVERED_READ_ERROR);
+    do_command_in(&mut target, WRITE_10, &[0; 512], &[]);
+
+    do_command_in(
+        &mut target,
+        &log_sense(0x3, 0, 0),
+        &[],
+        &error_counter_page(0x3, 1024, 1),
+    );
+    do_command_in(
+        &mut target,
+        &log_sense(0x2, 0, 0),
+        &[],
+        &error_counter_page(0x2, 512, 0),
+    );
+    // the parameter pointer skips parameters with lower codes
+    do_command_in(
+        &mut target,
+        &log_sense(0x3, 0, 6),
+        &[],
+        &[0x3, 0, 0, 12, 0, 6, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1],
+    );
+
+    // LOG SELECT with PCR resets the counters
+    do_command_in(
+        &mut target,
+        &[0x4c, 0b10, 0b0100_0000, 0, 0, 0, 0, 0, 0, 0],
+        &[],
+        &[],
+    );
+    do_command_in(
+        &mut target,
+        &log_sense(0x3, 0, 0),
+        &[],
+        &error_counter_page(0x3, 0, 0),
+    );
+}
+
+#[test]
+fn test_log_select_errors() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+
+    // SP
+    do_command_fail(
+        &mut target,
+        &[0x4c, 0b11, 0b0100_0000, 0, 0, 0, 0, 0, 0, 0],
+        sense::INVALID_FIELD_IN_CDB,
+    );
+    // a parameter list, which is transferred and then rejected
+    let parameter_list = [0x3, 0, 0, 12, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut data_out = &parameter_list[..];
+    let res = target.execute_command(
+        0,
+        &mut data_out,
+        &mut Vec::new(),
+        Request {
+            id: 0,
+            cdb: &[0x4c, 0, 0b0100_0011, 0, 0, 0, 0, 0, 16, 0],
+            task_attr: TaskAttr::Simple,
+            crn: 0,
+            prio: 0,
+        },
+    );
+    assert_eq!(
+        res.unwrap(),
+        CmdOutput::check_condition(sense::INVALID_FIELD_IN_PARAMETER_LIST)
+    );
+    assert!(data_out.is_empty());
+}
@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::sync::Arc;
+
+use super::{do_command_fail, do_command_in, TestBackend};
+use crate::scsi::{
+    emulation::{
+        block_device::BlockDevice,
+        medium::{MediumStatus, RemovableMedium},
+        target::EmulatedTarget,
+    },
+    sense, Request, Target, TaskAttr,
+};
+
+const TEST_UNIT_READY: &[u8] = &[0; 6];
+
+const READ_10: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 0, // LBA: 0
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+const REQUEST_SENSE: &[u8] = &[
+    0x3, // REQUEST SENSE
+    0,   // desc bit: 0
+    0, 0,   // reserved
+    255, // alloc length
+    0,   // control
+];
+
+/// START STOP UNIT with the given byte 4 (power condition, LOEJ and START).
+fn start_stop_unit(flags: u8) -> [u8; 6] {
+    [0x1b, 0, 0, 0, flags, 0]
+}
+
+/// PREVENT ALLOW MEDIUM REMOVAL.
+fn prevent_allow(prevent: bool) -> [u8; 6] {
+    [0x1e, 0, 0, 0, u8::from(prevent), 0]
+}
+
+fn removable_target() -> (EmulatedTarget, Arc<RemovableMedium<TestBackend>>) {
+    let medium = Arc::new(RemovableMedium::default());
+    let mut dev = BlockDevice::new(TestBackend::new());
+    dev.set_removable(Arc::clone(&medium));
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(dev));
+    (target, medium)
+}
+
+#[test]
+fn test_start_stop_unit() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+
+    do_command_in(&mut target, &start_stop_unit(0b0000_0000), &[], &[]);
+    do_command_fail(
+        &mut target,
+        TEST_UNIT_READY,
+        sense::LOGICAL_UNIT_NOT_READY_INITIALIZING_COMMAND_REQUIRED,
+    );
+    do_command_fail(
+        &mut target,
+        READ_10,
+        sense::LOGICAL_UNIT_NOT_READY_INITIALIZING_COMMAND_REQUIRED,
+    );
+    do_command_in(
+        &mut target,
+        REQUEST_SENSE,
+        &[],
+        &sense::LOGICAL_UNIT_NOT_READY_INITIALIZING_COMMAND_REQUIRED.to_fixed_sense(),
+    );
+
+    // LOEJ does nothing else on a non-removable medium
+    do_command_in(&mut target, &start_stop_unit(0b0000_0011), &[], &[]);
+    do_command_in(&mut target, TEST_UNIT_READY, &[], &[]);
+}
+
+#[test]
+fn test_power_conditions() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+
+    // STANDBY; START is ignored when a power condition is given
+    do_command_in(&mut target, &start_stop_unit(0x30), &[], &[]);
+    do_command_in(&mut target, TEST_UNIT_READY, &[], &[]);
+    do_command_in(
+        &mut target,
+        REQUEST_SENSE,
+        &[],
+        &sense::STANDBY_CONDITION_ACTIVATED_BY_COMMAND.to_fixed_sense(),
+    );
+
+    // FORCE_IDLE_0
+    do_command_in(&mut target, &start_stop_unit(0xa0), &[], &[]);
+    do_command_in(
+        &mut target,
+        REQUEST_SENSE,
+        &[],
+        &sense::IDLE_CONDITION_ACTIVATED_BY_COMMAND.to_fixed_sense(),
+    );
+
+    // accessing the medium makes the logical unit active again
+    do_command_in(&mut target, READ_10, &[], &[0; 512]);
+    do_command_in(
+        &mut target,
+        REQUEST_SENSE,
+        &[],
+        &sense::NO_ADDITIONAL_SENSE_INFORMATION.to_fixed_sense(),
+    );
+
+    do_command_fail(
+        &mut target,
+        &start_stop_unit(0x40),
+        sense::INVALID_FIELD_IN_CDB,
+    );
+}
+
+#[test]
+fn test_guest_eject() {
+    let (mut target, medium) = removable_target();
+
+    // RMB is set in the standard INQUIRY data
+    let mut data_in = Vec::new();
+    let mut data_out: &[u8] = &[];
+    target
+        .execute_command(
+            0,
+            &mut data_out,
+            &mut data_in,
+            Request {
+                id: 0,
+                cdb: &[0x12, 0, 0, 0, 36, 0],
+                task_attr: TaskAttr::Simple,
+                crn: 0,
+                prio: 0,
+            },
+        )
+        .unwrap();
+    assert_eq!(data_in[1], 0b1000_0000);
+
+    do_command_in(&mut target, &prevent_allow(true), &[], &[]);
+    do_command_fail(
+        &mut target,
+        &start_stop_unit(0b0000_0010),
+        sense::MEDIUM_REMOVAL_PREVENTED,
+    );
+    assert_eq!(medium.status(), MediumStatus::Loaded);
+
+    do_command_in(&mut target, &prevent_allow(false), &[], &[]);
+    do_command_in(&mut target, &start_stop_unit(0b0000_0010), &[], &[]);
+    assert_eq!(medium.status(), MediumStatus::Ejected);
+    do_command_fail(
+        &mut target,
+        TEST_UNIT_READY,
+        sense::MEDIUM_NOT_PRESENT_TRAY_OPEN,
+    );
+    do_command_fail(&mut target, READ_10, sense::MEDIUM_NOT_PRESENT_TRAY_OPEN);
+
+    // loading starts the logical unit as well
+    do_command_in(&mut target, &start_stop_unit(0b0000_0011), &[], &[]);
+    assert_eq!(medium.status(), MediumStatus::Loaded);
+    do_command_in(&mut target, TEST_UNIT_READY, &[], &[]);
+}
+
+#[test]
+fn test_host_medium_change() {
+    let (mut target, medium) = removable_target();
+
+    medium.set_prevent_removal(true);
+    assert!(medium.eject().is_err());
+    medium.set_prevent_removal(false);
+    medium.eject().unwrap();
+    do_command_fail(&mut target, TEST_UNIT_READY, sense::MEDIUM_NOT_PRESENT);
+    // the guest can't load a medium that isn't there
+    do_command_fail(
+        &mut target,
+        &start_stop_unit(0b0000_0011),
+        sense::MEDIUM_NOT_PRESENT,
+    );
+
+    let backend = TestBackend::new();
+    backend.data.lock().unwrap()[..512].fill(b'x');
+    medium.insert(backend).unwrap();
+
+    // the next command reports the change, once
+    do_command_fail(
+        &mut target,
+        TEST_UNIT_READY,
+        sense::NOT_READY_TO_READY_CHANGE,
+    );
+    do_command_in(&mut target, TEST_UNIT_READY, &[], &[]);
+    do_command_in(&mut target, READ_10, &[], &[b'x'; 512]);
+}
+
+#[test]
+fn test_unit_attention_in_request_sense() {
+    let (mut target, medium) = removable_target();
+
+    medium.insert(TestBackend::new()).unwrap();
+    do_command_in(
+        &mut target,
+        REQUEST_SENSE,
+        &[],
+        &sense::NOT_READY_TO_READY_CHANGE.to_fixed_sense(),
+    );
+    do_command_in(&mut target, TEST_UNIT_READY, &[], &[]);
+}
@@ -3,8 +3,20 @@
 #![cfg(test)]
 
 mod bad_lun;
+mod block_commands;
+mod encryption;
+mod fault_injection;
 mod generic;
+mod log_pages;
+mod medium;
+mod nbd;
 mod report_supported_operation_codes;
+mod script;
+mod self_test;
+mod stats;
+mod task_set;
+mod third_party_copy;
+mod throttle;
 
 use std::{
     fs::File,
@@ -138,7 +150,7 @@ fn do_command_fail_lun(
     );
 
     assert_eq!(res.unwrap(), CmdOutput::check_condition(expected_error));
-    assert_eq!(&data_in, &[]);
+    assert_eq!(&data_in, &[] as &[u8]);
 }
 
 fn do_command_in(
@@ -452,9 +464,10 @@ fn test_inquiry() {
             0,    // accessible; direct acccess block device
             0,    // features
             0x7,  // version
-            0x12, // response data format v2, HiSup = 1
+            0x32, // response data format v2, HiSup = 1, NormACA = 1
             91,   // addl length
-            0, 0, 0, // unsupported features
+            0x8,  // 3PC = 1
+            0, 0, // unsupported features
             // vendor
             b'r', b'u', b's', b't', b'-', b'v', b'm', b'm', //
             // product
@@ -493,8 +506,8 @@ fn test_request_sense() {
         &[],
         // We'll always return this - modern SCSI has autosense, so any errors are sent with the
         // response to the command that caused them (and therefore immediately cleared), and
-        // REQUEST SENSE returns an actual error only under some exceptional circumstances
-        // we don't implement.
+        // REQUEST SENSE returns an actual error only under some exceptional circumstances,
+        // like a stopped unit or a missing medium.
         &sense::NO_ADDITIONAL_SENSE_INFORMATION.to_fixed_sense(),
     );
 }
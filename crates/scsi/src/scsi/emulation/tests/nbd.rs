@@ -0,0 +1,555 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use assert_matches::assert_matches;
+
+use super::{do_command_fail, do_command_in};
+use crate::scsi::{
+    emulation::{
+        block_device::{BlockDevice, BlockDeviceBackend, ByteOffset},
+        nbd::*,
+        target::EmulatedTarget,
+    },
+    sense,
+};
+
+const NBD_REP_ERR_UNKNOWN: u32 = NBD_REP_FLAG_ERROR | 6;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+const DEFAULT_STUB_MAX_PAYLOAD: u32 = 32 * 1024 * 1024;
+
+/// A request the stub server received: command, offset and length.
+type RecordedRequest = (u16, u64, u32);
+
+/// Configuration of the in-process NBD server stub.
+#[derive(Clone)]
+struct StubServer {
+    export: &'static str,
+    data: Arc<Mutex<Vec<u8>>>,
+    transmission_flags: u16,
+    support_go: bool,
+    no_zeroes: bool,
+    min_block_size: u32,
+    max_payload: u32,
+    /// Reads at this offset fail with EIO.
+    bad_offset: Option<u64>,
+    /// Reads at this offset never get a reply.
+    stalled_offset: Option<u64>,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl StubServer {
+    fn new() -> Self {
+        Self {
+            export: "disk",
+            data: Arc::new(Mutex::new(
+                (0..16u8).flat_map(|i| [b'a' + i; 512]).collect(),
+            )),
+            transmission_flags: NBD_FLAG_HAS_FLAGS
+                | NBD_FLAG_SEND_FLUSH
+                | NBD_FLAG_SEND_WRITE_ZEROES,
+            support_go: true,
+            no_zeroes: true,
+            min_block_size: 1,
+            max_payload: DEFAULT_STUB_MAX_PAYLOAD,
+            bad_offset: None,
+            stalled_offset: None,
+            requests: Arc::default(),
+        }
+    }
+
+    /// Start serving on a socket pair, returning the client end.
+    fn spawn(&self) -> UnixStream {
+        let (client, server) = UnixStream::pair().unwrap();
+        let stub = self.clone();
+        thread::spawn(move || stub.serve(server));
+        client
+    }
+
+    fn connect(&self) -> NbdBackend<UnixStream> {
+        NbdBackend::connect(self.spawn(), self.export).unwrap()
+    }
+
+    fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn serve(self, mut s: UnixStream) -> io::Result<()> {
+        let mut handshake_flags = NBD_FLAG_FIXED_NEWSTYLE;
+        if self.no_zeroes {
+            handshake_flags |= NBD_FLAG_NO_ZEROES;
+        }
+        s.write_all(&NBD_MAGIC.to_be_bytes())?;
+        s.write_all(&IHAVEOPT.to_be_bytes())?;
+        s.write_all(&handshake_flags.to_be_bytes())?;
+        let client_flags = read_u32(&mut s)?;
+        assert_ne!(client_flags & NBD_FLAG_C_FIXED_NEWSTYLE, 0);
+
+        let size = self.data.lock().unwrap().len() as u64;
+        loop {
+            assert_eq!(read_u64(&mut s)?, IHAVEOPT);
+            let option = read_u32(&mut s)?;
+            let mut data = vec![0; read_u32(&mut s)? as usize];
+            s.read_exact(&mut data)?;
+
+            match option {
+                NBD_OPT_GO if self.support_go => {
+                    let name_len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+                    if &data[4..4 + name_len] != self.export.as_bytes() {
+                        option_reply(&mut s, option, NBD_REP_ERR_UNKNOWN, b"no such export")?;
+                        continue;
+                    }
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&size.to_be_bytes());
+                    info.extend_from_slice(&self.transmission_flags.to_be_bytes());
+                    option_reply(&mut s, option, NBD_REP_INFO, &info)?;
+                    let mut info = NBD_INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                    info.extend_from_slice(&self.min_block_size.to_be_bytes());
+                    info.extend_from_slice(&4096u32.to_be_bytes());
+                    info.extend_from_slice(&self.max_payload.to_be_bytes());
+                    option_reply(&mut s, option, NBD_REP_INFO, &info)?;
+                    option_reply(&mut s, option, NBD_REP_ACK, &[])?;
+                    break;
+                }
+                NBD_OPT_EXPORT_NAME => {
+                    if data != self.export.as_bytes() {
+                        // all the server can do is hang up
+                        return Ok(());
+                    }
+                    s.write_all(&size.to_be_bytes())?;
+                    s.write_all(&self.transmission_flags.to_be_bytes())?;
+                    if !self.no_zeroes {
+                        s.write_all(&[0; 124])?;
+                    }
+                    break;
+                }
+                _ => option_reply(&mut s, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+
+        loop {
+            assert_eq!(read_u32(&mut s)?, REQUEST_MAGIC);
+            let _flags = read_u16(&mut s)?;
+            let command = read_u16(&mut s)?;
+            let cookie = read_u64(&mut s)?;
+            let offset = read_u64(&mut s)?;
+            let len = read_u32(&mut s)?;
+            if command == NBD_CMD_DISC {
+                return Ok(());
+            }
+            self.requests.lock().unwrap().push((command, offset, len));
+
+            let range = offset as usize..(offset + u64::from(len)) as usize;
+            let mut data = self.data.lock().unwrap();
+            let in_range = range.end <= data.len();
+            let reply = |s: &mut UnixStream, error: u32, payload: &[u8]| -> io::Result<()> {
+                s.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+                s.write_all(&error.to_be_bytes())?;
+                s.write_all(&cookie.to_be_bytes())?;
+                s.write_all(payload)
+            };
+
+            match command {
+                NBD_CMD_READ if self.bad_offset == Some(offset) => reply(&mut s, EIO, &[])?,
+                NBD_CMD_READ if self.stalled_offset == Some(offset) => (),
+                NBD_CMD_READ if in_range => reply(&mut s, 0, &data[range])?,
+                NBD_CMD_WRITE => {
+                    let mut buf = vec![0; len as usize];
+                    s.read_exact(&mut buf)?;
+                    if in_range {
+                        data[range].copy_from_slice(&buf);
+                        reply(&mut s, 0, &[])?;
+                    } else {
+                        reply(&mut s, EINVAL, &[])?;
+                    }
+                }
+                NBD_CMD_FLUSH => reply(&mut s, 0, &[])?,
+                NBD_CMD_WRITE_ZEROES if in_range => {
+                    data[range].fill(0);
+                    reply(&mut s, 0, &[])?;
+                }
+                _ => reply(&mut s, EINVAL, &[])?,
+            }
+        }
+    }
+}
+
+fn option_reply(s: &mut UnixStream, option: u32, reply_type: u32, data: &[u8]) -> io::Result<()> {
+    s.write_all(&OPTION_REPLY_MAGIC.to_be_bytes())?;
+    s.write_all(&option.to_be_bytes())?;
+    s.write_all(&reply_type.to_be_bytes())?;
+    s.write_all(&(data.len() as u32).to_be_bytes())?;
+    s.write_all(data)
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn nbd_target(stub: &StubServer) -> EmulatedTarget {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(stub.connect())));
+    target
+}
+
+#[test]
+fn test_parse_uri() {
+    assert_eq!(NbdUri::parse("/images/disk.raw").map(|r| r.is_ok()), None);
+    assert_eq!(
+        NbdUri::parse("nbd://localhost/disk").unwrap().unwrap(),
+        NbdUri {
+            address: NbdAddress::Tcp("localhost".to_string(), 10809),
+            export: "disk".to_string(),
+        }
+    );
+    assert_eq!(
+        NbdUri::parse("nbd://[::1]:1234").unwrap().unwrap(),
+        NbdUri {
+            address: NbdAddress::Tcp("::1".to_string(), 1234),
+            export: String::new(),
+        }
+    );
+    assert_eq!(
+        NbdUri::parse("nbd+unix:///disk?socket=/run/nbd.sock")
+            .unwrap()
+            .unwrap(),
+        NbdUri {
+            address: NbdAddress::Unix("/run/nbd.sock".into()),
+            export: "disk".to_string(),
+        }
+    );
+    assert_matches!(
+        NbdUri::parse("nbd+unix:///disk"),
+        Some(Err(NbdError::InvalidUri(_)))
+    );
+    assert_matches!(
+        NbdUri::parse("nbd://host:port/disk"),
+        Some(Err(NbdError::InvalidUri(_)))
+    );
+}
+
+#[test]
+fn test_handshake() {
+    let stub = StubServer::new();
+    let mut backend = stub.connect();
+
+    assert_eq!(u64::from(backend.size_in_blocks().unwrap()), 16);
+    assert_eq!(u32::from(backend.block_size()), 512);
+    assert!(!backend.is_read_only());
+}
+
+#[test]
+fn test_handshake_export_name_fallback() {
+    let stub = StubServer {
+        support_go: false,
+        no_zeroes: false,
+        transmission_flags: NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY,
+        ..StubServer::new()
+    };
+    let mut backend = stub.connect();
+
+    assert_eq!(u64::from(backend.size_in_blocks().unwrap()), 16);
+    assert!(backend.is_read_only());
+    let mut buf = [0; 512];
+    backend
+        .read_exact_at(&mut buf, ByteOffset::from(512))
+        .unwrap();
+    assert_eq!(buf, [b'b'; 512]);
+}
+
+#[test]
+fn test_handshake_unknown_export() {
+    let stub = StubServer::new();
+    assert_matches!(
+        NbdBackend::connect(stub.spawn(), "other").err(),
+        Some(NbdError::OptionRejected(NBD_OPT_GO, NBD_REP_ERR_UNKNOWN, msg)) if msg == "no such export"
+    );
+
+    let stub = StubServer {
+        support_go: false,
+        ..StubServer::new()
+    };
+    assert_matches!(
+        NbdBackend::connect(stub.spawn(), "other").err(),
+        Some(NbdError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn test_handshake_block_size_constraints() {
+    // the block size is raised to the server's minimum
+    let stub = StubServer {
+        min_block_size: 4096,
+        ..StubServer::new()
+    };
+    let mut backend = stub.connect();
+    assert_eq!(u32::from(backend.block_size()), 4096);
+    assert_eq!(u64::from(backend.size_in_blocks().unwrap()), 2);
+
+    // but not beyond what guests support
+    let stub = StubServer {
+        min_block_size: 8192,
+        ..StubServer::new()
+    };
+    assert_matches!(
+        NbdBackend::connect(stub.spawn(), stub.export).err(),
+        Some(NbdError::UnsupportedBlockSize(8192))
+    );
+    let stub = StubServer {
+        min_block_size: 0,
+        ..StubServer::new()
+    };
+    assert_matches!(
+        NbdBackend::connect(stub.spawn(), stub.export).err(),
+        Some(NbdError::UnsupportedBlockSize(0))
+    );
+
+    // requests can't be split below the block size
+    for max_payload in [0, 256] {
+        let stub = StubServer {
+            max_payload,
+            ..StubServer::new()
+        };
+        assert_matches!(
+            NbdBackend::connect(stub.spawn(), stub.export).err(),
+            Some(NbdError::MaxPayloadTooSmall(payload)) if payload == max_payload
+        );
+    }
+
+    // and are split at a multiple of the block size
+    let stub = StubServer {
+        max_payload: 1000,
+        ..StubServer::new()
+    };
+    let mut backend = stub.connect();
+    let mut buf = [0; 1024];
+    backend
+        .read_exact_at(&mut buf, ByteOffset::from(0))
+        .unwrap();
+    assert_eq!(
+        stub.requests(),
+        vec![(NBD_CMD_READ, 0, 512), (NBD_CMD_READ, 512, 512)]
+    );
+}
+
+#[test]
+fn test_read_write() {
+    let stub = StubServer::new();
+    let mut target = nbd_target(&stub);
+
+    do_command_in(
+        &mut target,
+        &[
+            0x28, // READ (10)
+            0,    // flags
+            0, 0, 0, 2, // LBA: 2
+            0, // reserved, group #
+            0, 1, // transfer length: 1
+            0, // control
+        ],
+        &[],
+        &[b'c'; 512],
+    );
+    do_command_in(
+        &mut target,
+        &[
+            0x2a, // WRITE (10)
+            0,    // flags
+            0, 0, 0, 3, // LBA: 3
+            0, // reserved, group #
+            0, 1, // transfer length: 1
+            0, // control
+        ],
+        &[b'w'; 512],
+        &[],
+    );
+
+    assert_eq!(&stub.data.lock().unwrap()[512 * 3..512 * 4], &[b'w'; 512]);
+    assert_eq!(
+        stub.requests(),
+        vec![(NBD_CMD_READ, 1024, 512), (NBD_CMD_WRITE, 1536, 512)]
+    );
+}
+
+#[test]
+fn test_requests_split_at_max_payload() {
+    let stub = StubServer {
+        max_payload: 1024,
+        ..StubServer::new()
+    };
+    let mut backend = stub.connect();
+
+    let mut buf = [0; 2048];
+    backend
+        .read_exact_at(&mut buf, ByteOffset::from(0))
+        .unwrap();
+    assert_eq!(&buf[..512], &[b'a'; 512]);
+    assert_eq!(&buf[1536..], &[b'd'; 512]);
+    backend
+        .write_exact_at(&[b'w'; 1536], ByteOffset::from(0))
+        .unwrap();
+
+    assert_eq!(
+        stub.requests(),
+        vec![
+            (NBD_CMD_READ, 0, 1024),
+            (NBD_CMD_READ, 1024, 1024),
+            (NBD_CMD_WRITE, 0, 1024),
+            (NBD_CMD_WRITE, 1024, 512),
+        ]
+    );
+}
+
+#[test]
+fn test_read_error() {
+    let stub = StubServer {
+        bad_offset: Some(512),
+        ..StubServer::new()
+    };
+    let mut target = nbd_target(&stub);
+
+    do_command_fail(
+        &mut target,
+        &[
+            0x28, // READ (10)
+            0,    // flags
+            0, 0, 0, 1, // LBA: 1
+            0, // reserved, group #
+            0, 1, // transfer length: 1
+            0, // control
+        ],
+        sense::UNRECOVERED_READ_ERROR,
+    );
+}
+
+#[test]
+fn test_request_timeout() {
+    let stub = StubServer {
+        stalled_offset: Some(512),
+        ..StubServer::new()
+    };
+    let open = {
+        let stub = stub.clone();
+        move || {
+            let stream = stub.spawn();
+            stream.set_read_timeout(Some(Duration::from_millis(50)))?;
+            Ok(stream)
+        }
+    };
+    let mut backend = NbdBackend::connect(open().unwrap(), stub.export).unwrap();
+    backend.set_reconnect(open);
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(backend)));
+
+    // the stalled read fails with a sense the guest retries ...
+    do_command_fail(
+        &mut target,
+        &[
+            0x28, // READ (10)
+            0,    // flags
+            0, 0, 0, 1, // LBA: 1
+            0, // reserved, group #
+            0, 1, // transfer length: 1
+            0, // control
+        ],
+        sense::LOGICAL_UNIT_COMMUNICATION_TIME_OUT,
+    );
+    // ... and the next request goes to a new connection
+    do_command_in(
+        &mut target,
+        &[
+            0x28, // READ (10)
+            0,    // flags
+            0, 0, 0, 0, // LBA: 0
+            0, // reserved, group #
+            0, 1, // transfer length: 1
+            0, // control
+        ],
+        &[],
+        &[b'a'; 512],
+    );
+}
+
+#[test]
+fn test_sync() {
+    let stub = StubServer::new();
+    stub.connect().sync().unwrap();
+    assert_eq!(stub.requests(), vec![(NBD_CMD_FLUSH, 0, 0)]);
+
+    // without a write cache, there is nothing to flush
+    let stub = StubServer {
+        transmission_flags: NBD_FLAG_HAS_FLAGS,
+        ..StubServer::new()
+    };
+    stub.connect().sync().unwrap();
+    assert_eq!(stub.requests(), vec![]);
+}
+
+const WRITE_SAME_16_UNMAP: &[u8] = &[
+    0x93,        // WRITE SAME (16)
+    0b0000_1000, // flags: UNMAP
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    4, // LBA: 4
+    0,
+    0,
+    0,
+    2, // number of blocks: 2
+    0, // reserved, group #
+    0, // control
+];
+
+#[test]
+fn test_write_same_unmap_writes_zeroes() {
+    let stub = StubServer::new();
+    let mut target = nbd_target(&stub);
+
+    do_command_in(&mut target, WRITE_SAME_16_UNMAP, &[0; 512], &[]);
+
+    assert_eq!(stub.requests(), vec![(NBD_CMD_WRITE_ZEROES, 2048, 1024)]);
+    assert_eq!(&stub.data.lock().unwrap()[2048..3072], &[0; 1024]);
+}
+
+#[test]
+fn test_write_same_unmap_fallback() {
+    let stub = StubServer {
+        transmission_flags: NBD_FLAG_HAS_FLAGS,
+        ..StubServer::new()
+    };
+    let mut target = nbd_target(&stub);
+
+    do_command_in(&mut target, WRITE_SAME_16_UNMAP, &[0; 512], &[]);
+
+    assert_eq!(
+        stub.requests(),
+        vec![(NBD_CMD_WRITE, 2048, 512), (NBD_CMD_WRITE, 2560, 512)]
+    );
+    assert_eq!(&stub.data.lock().unwrap()[2048..3072], &[0; 1024]);
+}
@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Conformance tests driven by scripts of CDBs and their expected results.
+//!
+//! Every script in `scripts/` runs against a fresh `EmulatedTarget`, with one
+//! command after another. The format is line based; `#` starts a comment:
+//!
+//! ```text
+//! # add a LUN: `test` (16 blocks, block n filled with the ASCII hex digit
+//! # of n), `null` (no blocks) or `zero <blocks>`
+//! lun test
+//! # send the following commands to LUN 1 (by default, they go to LUN 0)
+//! select 1
+//! # a command; the next `cdb` (or the end of the script) runs it
+//! cdb 28 00 00 00 00 01 00 00 01 00
+//! # the data-out buffer, if any, where `<byte>*<count>` repeats a byte
+//! data-out 31*512
+//! # the expected status: `good` (the default), `check-condition <sense key>
+//! # <ASC> <ASCQ>`, or `cdb-too-short`
+//! status good
+//! # the expected data-in buffer, which is empty if not given
+//! data-in 31*512
+//! ```
+//!
+//! `data-out` and `data-in` can be given multiple times, which concatenates
+//! the buffers. All numbers are in hex.
+
+use std::{fs, path::Path};
+
+use super::{null_image, test_image};
+use crate::scsi::{
+    emulation::{
+        block_device::{BlockDevice, FileBackend},
+        target::EmulatedTarget,
+    },
+    CmdError, CmdOutput, Request, Target, TaskAttr,
+};
+use crate::virtio::{VirtioScsiLun, CDB_SIZE, REPORT_LUNS, SENSE_SIZE};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Status {
+    Good,
+    CheckCondition(u8, u8, u8),
+    CdbTooShort,
+}
+
+#[derive(Debug)]
+struct Command {
+    /// The line of the script the command starts at.
+    line_nr: usize,
+    lun: u16,
+    cdb: Vec<u8>,
+    data_out: Vec<u8>,
+    status: Status,
+    data_in: Vec<u8>,
+}
+
+fn parse_byte(token: &str) -> Result<u8, String> {
+    u8::from_str_radix(token, 16).map_err(|_| format!("invalid byte `{}`", token))
+}
+
+/// Parse a list of bytes, e.g. `00 01 ff*16`.
+fn parse_bytes<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for token in tokens {
+        match token.split_once('*') {
+            Some((byte, count)) => {
+                let count = usize::from_str_radix(count, 16)
+                    .map_err(|_| format!("invalid count `{}`", count))?;
+                bytes.resize(bytes.len() + count, parse_byte(byte)?);
+            }
+            None => bytes.push(parse_byte(token)?),
+        }
+    }
+    Ok(bytes)
+}
+
+fn zero_image(blocks: u64) -> FileBackend {
+    let file = tempfile::tempfile().unwrap();
+    file.set_len(blocks * 512).unwrap();
+    FileBackend::new(file)
+}
+
+/// Parse `script`, adding its LUNs to `target` and returning its commands.
+fn parse_script(target: &mut EmulatedTarget, script: &str) -> Result<Vec<Command>, String> {
+    let mut commands: Vec<Command> = Vec::new();
+    let mut lun = 0;
+
+    for (line_nr, line) in (1..).zip(script.lines()) {
+        let line = line.split_once('#').map_or(line, |(line, _)| line);
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let err = |e: String| format!("line {}: {}", line_nr, e);
+        let no_command = || err(format!("`{}` before the first `cdb`", keyword));
+
+        match keyword {
+            "lun" => match (tokens.next(), tokens.next()) {
+                (Some("test"), None) => target.add_lun(Box::new(BlockDevice::new(test_image()))),
+                (Some("null"), None) => target.add_lun(Box::new(BlockDevice::new(null_image()))),
+                (Some("zero"), Some(blocks)) => {
+                    let blocks = u64::from_str_radix(blocks, 16)
+                        .map_err(|_| err(format!("invalid block count `{}`", blocks)))?;
+                    target.add_lun(Box::new(BlockDevice::new(zero_image(blocks))));
+                }
+                _ => return Err(err(format!("invalid LUN `{}`", line.trim()))),
+            },
+            "select" => {
+                let arg = tokens.next().unwrap_or_default();
+                lun = u16::from_str_radix(arg, 16)
+                    .map_err(|_| err(format!("invalid LUN `{}`", arg)))?;
+            }
+            "cdb" => commands.push(Command {
+                line_nr,
+                lun,
+                cdb: parse_bytes(&mut tokens).map_err(err)?,
+                data_out: Vec::new(),
+                status: Status::Good,
+                data_in: Vec::new(),
+            }),
+            "data-out" => {
+                let bytes = parse_bytes(&mut tokens).map_err(err)?;
+                commands
+                    .last_mut()
+                    .ok_or_else(no_command)?
+                    .data_out
+                    .extend(bytes);
+            }
+            "data-in" => {
+                let bytes = parse_bytes(&mut tokens).map_err(err)?;
+                commands
+                    .last_mut()
+                    .ok_or_else(no_command)?
+                    .data_in
+                    .extend(bytes);
+            }
+            "status" => {
+                let status = match tokens.next() {
+                    Some("good") => Status::Good,
+                    Some("cdb-too-short") => Status::CdbTooShort,
+                    Some("check-condition") => match parse_bytes(&mut tokens).map_err(err)?[..] {
+                        [key, asc, ascq] => Status::CheckCondition(key, asc, ascq),
+                        _ => return Err(err("expected sense key, ASC and ASCQ".into())),
+                    },
+                    _ => return Err(err(format!("invalid status `{}`", line.trim()))),
+                };
+                commands.last_mut().ok_or_else(no_command)?.status = status;
+            }
+            _ => return Err(err(format!("unknown keyword `{}`", keyword))),
+        }
+        if tokens.next().is_some() {
+            return Err(err("trailing arguments".into()));
+        }
+    }
+    Ok(commands)
+}
+
+fn run_script(name: &str, script: &str) {
+    let mut target = EmulatedTarget::new();
+    let commands = parse_script(&mut target, script)
+        .unwrap_or_else(|e| panic!("{}: failed parsing script: {}", name, e));
+
+    for command in commands {
+        let mut data_in = Vec::new();
+        let res = target.execute_command(
+            command.lun,
+            &mut &command.data_out[..],
+            &mut data_in,
+            Request {
+                id: 0,
+                cdb: &command.cdb,
+                task_attr: TaskAttr::Simple,
+                crn: 0,
+                prio: 0,
+            },
+        );
+        let status = match res {
+            Ok(output) if output == CmdOutput::ok() => Status::Good,
+            Ok(output) if output.status == CmdOutput::CHECK_CONDITION => {
+                Status::CheckCondition(output.sense[2], output.sense[12], output.sense[13])
+            }
+            Err(CmdError::CdbTooShort) => Status::CdbTooShort,
+            res => panic!("{}:{}: unexpected result {:?}", name, command.line_nr, res),
+        };
+        assert_eq!(
+            status, command.status,
+            "{}:{}: unexpected status",
+            name, command.line_nr
+        );
+        assert_eq!(
+            data_in, command.data_in,
+            "{}:{}: unexpected data-in",
+            name, command.line_nr
+        );
+    }
+}
+
+macro_rules! script_tests {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                run_script(
+                    concat!(stringify!($name), ".cdb"),
+                    include_str!(concat!("scripts/", stringify!($name), ".cdb")),
+                );
+            }
+        )*
+
+        const SCRIPTS: &[(&str, &str)] = &[
+            $((
+                stringify!($name),
+                include_str!(concat!("scripts/", stringify!($name), ".cdb")),
+            ),)*
+        ];
+    };
+}
+
+script_tests! {
+    spc_inquiry,
+    spc_report_luns,
+    spc_request_sense,
+    spc_unsupported_commands,
+    sbc_read_capacity,
+    sbc_read_write,
+    sbc_write_same,
+    sbc_synchronize_cache,
+}
+
+#[test]
+fn test_parse_errors() {
+    let mut target = EmulatedTarget::new();
+    for (script, error) in [
+        ("data-in 00", "line 1: `data-in` before the first `cdb`"),
+        ("cdb 00 0g", "line 1: invalid byte `0g`"),
+        (
+            "\ncdb 00\nstatus bad",
+            "line 3: invalid status `status bad`",
+        ),
+        (
+            "cdb 00\nstatus check-condition 5 24",
+            "line 2: expected sense key, ASC and ASCQ",
+        ),
+        ("lun tape", "line 1: invalid LUN `lun tape`"),
+        ("select 1 2", "line 1: trailing arguments"),
+        ("execute 00", "line 1: unknown keyword `execute`"),
+    ] {
+        assert_eq!(parse_script(&mut target, script).unwrap_err(), error);
+    }
+
+    let commands = parse_script(&mut target, "cdb 00 ff*3 # comment\ndata-out 1*2 2").unwrap();
+    assert_eq!(commands[0].cdb, [0, 0xff, 0xff, 0xff]);
+    assert_eq!(commands[0].data_out, [1, 1, 2]);
+}
+
+/// Write the seed corpora of the fuzz targets in `fuzz/`, made from the
+/// commands of the scripts. Run it with `cargo test -- --ignored` after
+/// changing the scripts.
+#[test]
+#[ignore]
+fn write_fuzz_corpora() {
+    let corpora = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+    let write = |target: &str, name: &str, data: &[u8]| {
+        let dir = corpora.join(target);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), data).unwrap();
+    };
+
+    let mut luns = vec![REPORT_LUNS];
+    for (name, script) in SCRIPTS {
+        let commands = parse_script(&mut EmulatedTarget::new(), script).unwrap();
+        for command in commands {
+            let name = format!("{}-{}", name, command.line_nr);
+            write("cdb_parse", &name, &command.cdb);
+            if command.cdb.len() > CDB_SIZE {
+                continue;
+            }
+
+            let lun_bytes = u16::to_be_bytes(command.lun);
+            let lun = [
+                0x1,
+                0,
+                lun_bytes[0] | VirtioScsiLun::FLAT_SPACE_ADDRESSING_METHOD,
+                lun_bytes[1],
+                0,
+                0,
+                0,
+                0,
+            ];
+            if !luns.contains(&lun) {
+                luns.push(lun);
+            }
+
+            // virtio_scsi_cmd_req: LUN, tag, task attribute, priority, CRN
+            // and CDB, followed by the data-out buffer
+            let mut request = lun.to_vec();
+            request.extend_from_slice(&[0; 11]);
+            request.extend_from_slice(&command.cdb);
+            request.resize(request.len() + CDB_SIZE - command.cdb.len(), 0);
+            request.extend_from_slice(&command.data_out);
+            write("virtio_request_parse", &name, &request);
+
+            // virtio_scsi_cmd_resp is 12 bytes and the sense buffer
+            let response_len = 12 + SENSE_SIZE + command.data_in.len();
+            let mut input = u16::try_from(response_len).unwrap().to_le_bytes().to_vec();
+            input.extend_from_slice(&request);
+            write("process_request", &name, &input);
+        }
+    }
+    for (i, lun) in luns.iter().enumerate() {
+        write("virtio_lun_parse", &format!("lun-{}", i), lun);
+    }
+}
@@ -0,0 +1,238 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use tempfile::tempfile;
+
+use super::{do_command_fail, do_command_in, test_image, TestBackend};
+use crate::scsi::{
+    emulation::{
+        block_device::{BlockDevice, FileBackend},
+        fault_injection::{parse_rules, FaultInjectionBackend},
+        target::EmulatedTarget,
+    },
+    sense, Request, Target, TaskAttr,
+};
+
+/// SEND DIAGNOSTIC with the given SELF-TEST CODE.
+fn send_diagnostic(self_test_code: u8) -> [u8; 6] {
+    [
+        0x1d,                // SEND DIAGNOSTIC
+        self_test_code << 5, // flags
+        0,                   // reserved
+        0,
+        0, // parameter list length: 0
+        0, // control
+    ]
+}
+
+const LOG_SENSE_SELF_TEST_RESULTS: &[u8] = &[
+    0x4d,        // LOG SENSE
+    0,           // flags
+    0b0101_0000, // PC: cumulative values, page code: 0x10
+    0,           // subpage code
+    0,           // reserved
+    0,
+    0, // parameter pointer: 0
+    0,
+    24, // allocation length: header and the first parameter
+    0,  // control
+];
+
+/// The Self-Test Results log page, truncated to the most recent result.
+fn most_recent_result(code_and_result: u8, lba: Option<u64>, sense: [u8; 3]) -> Vec<u8> {
+    let mut page = vec![0x10, 0, 0x01, 0x90]; // header: 20 parameters
+    page.extend_from_slice(&[0, 1, 0x03, 0x10]); // parameter header
+    page.extend_from_slice(&[code_and_result, 0, 0, 0]);
+    page.extend_from_slice(&lba.unwrap_or(u64::MAX).to_be_bytes());
+    page.extend_from_slice(&sense);
+    page.push(0);
+    page
+}
+
+/// The most recent self-test result in `target`.
+fn self_test_results(target: &mut EmulatedTarget) -> Vec<u8> {
+    let mut data_in = Vec::new();
+    target
+        .execute_command(
+            0,
+            &mut &[][..],
+            &mut data_in,
+            Request {
+                id: 0,
+                cdb: LOG_SENSE_SELF_TEST_RESULTS,
+                task_attr: TaskAttr::Simple,
+                crn: 0,
+                prio: 0,
+            },
+        )
+        .unwrap();
+    data_in
+}
+
+/// A target whose single LU has 3 chunks of blocks for self-tests to read,
+/// with every read taking 100ms.
+fn slow_target() -> EmulatedTarget {
+    let f = tempfile().unwrap();
+    f.set_len(512 * 128 * 3).unwrap();
+    let backend = FaultInjectionBackend::new(
+        FileBackend::new(f),
+        parse_rules("fault=latency,delay-ms=100").unwrap(),
+    );
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(backend)));
+    target
+}
+
+#[test]
+fn test_foreground_self_test() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(test_image())));
+
+    do_command_in(&mut target, &send_diagnostic(0b110), &[], &[]);
+    do_command_in(
+        &mut target,
+        LOG_SENSE_SELF_TEST_RESULTS,
+        &[],
+        &most_recent_result(0b1100_0000, None, [0; 3]),
+    );
+
+    // the default self-test isn't logged
+    do_command_in(
+        &mut target,
+        &[
+            0x1d,        // SEND DIAGNOSTIC
+            0b0000_0100, // flags: SELFTEST
+            0,           // reserved
+            0,
+            0, // parameter list length: 0
+            0, // control
+        ],
+        &[],
+        &[],
+    );
+    do_command_in(
+        &mut target,
+        LOG_SENSE_SELF_TEST_RESULTS,
+        &[],
+        &most_recent_result(0b1100_0000, None, [0; 3]),
+    );
+}
+
+#[test]
+fn test_foreground_self_test_failure() {
+    let mut target = EmulatedTarget::new();
+    let backend = FaultInjectionBackend::new(
+        TestBackend::new(),
+        parse_rules("fault=read-error,op=read,lba=9").unwrap(),
+    );
+    target.add_lun(Box::new(BlockDevice::new(backend)));
+
+    do_command_fail(
+        &mut target,
+        &send_diagnostic(0b101),
+        sense::LOGICAL_UNIT_FAILED_SELF_TEST,
+    );
+    do_command_in(
+        &mut target,
+        LOG_SENSE_SELF_TEST_RESULTS,
+        &[],
+        &most_recent_result(0b1010_0100, Some(9), [0x3, 0x11, 0x0]),
+    );
+}
+
+#[test]
+fn test_background_self_test() {
+    let mut target = slow_target();
+
+    do_command_in(&mut target, &send_diagnostic(0b010), &[], &[]);
+    do_command_in(
+        &mut target,
+        LOG_SENSE_SELF_TEST_RESULTS,
+        &[],
+        &most_recent_result(0b0100_1111, None, [0; 3]),
+    );
+    do_command_fail(
+        &mut target,
+        &send_diagnostic(0b001),
+        sense::LOGICAL_UNIT_NOT_READY_SELF_TEST_IN_PROGRESS,
+    );
+
+    let passed = most_recent_result(0b0100_0000, None, [0; 3]);
+    let start = Instant::now();
+    while self_test_results(&mut target) != passed {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_abort_self_test() {
+    let mut target = slow_target();
+
+    do_command_in(&mut target, &send_diagnostic(0b001), &[], &[]);
+    do_command_in(&mut target, &send_diagnostic(0b100), &[], &[]);
+    do_command_in(
+        &mut target,
+        LOG_SENSE_SELF_TEST_RESULTS,
+        &[],
+        &most_recent_result(0b0010_0001, None, [0; 3]),
+    );
+
+    // aborting without a self-test in progress does nothing
+    do_command_in(&mut target, &send_diagnostic(0b100), &[], &[]);
+}
+
+#[test]
+fn test_invalid_self_test() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+
+    // reserved self-test codes
+    do_command_fail(
+        &mut target,
+        &send_diagnostic(0b011),
+        sense::INVALID_FIELD_IN_CDB,
+    );
+    do_command_fail(
+        &mut target,
+        &send_diagnostic(0b111),
+        sense::INVALID_FIELD_IN_CDB,
+    );
+    // a self-test code along with SELFTEST
+    let mut cdb = send_diagnostic(0b001);
+    cdb[1] |= 0b0000_0100;
+    do_command_fail(&mut target, &cdb, sense::INVALID_FIELD_IN_CDB);
+    // a self-test with a parameter list
+    let mut cdb = send_diagnostic(0b001);
+    cdb[4] = 4;
+    do_command_fail(&mut target, &cdb, sense::INVALID_FIELD_IN_CDB);
+}
+
+#[test]
+fn test_diagnostic_pages() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+
+    let mut cdb = send_diagnostic(0);
+    cdb[1] = 0b0001_0000; // PF
+    cdb[4] = 4; // parameter list length: 4
+    do_command_in(&mut target, &cdb, &[0, 0, 0, 0], &[]);
+
+    let receive = |pcv, page_code| {
+        [
+            0x1c,      // RECEIVE DIAGNOSTIC RESULTS
+            pcv,       // flags
+            page_code, // page code
+            0,         // allocation length (MSB)
+            255,       // allocation length (LSB)
+            0,         // control
+        ]
+    };
+    do_command_in(&mut target, &receive(0, 0), &[], &[0, 0, 0, 1, 0]);
+    do_command_in(&mut target, &receive(1, 0), &[], &[0, 0, 0, 1, 0]);
+    do_command_fail(&mut target, &receive(1, 0x3f), sense::INVALID_FIELD_IN_CDB);
+}
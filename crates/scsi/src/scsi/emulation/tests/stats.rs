@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::time::Duration;
+
+use super::{do_command_fail, do_command_in, test_image, TestBackend};
+use crate::scsi::{
+    emulation::{block_device::BlockDevice, stats::LunStats, target::EmulatedTarget},
+    sense, CmdError, CmdOutput, Request, Target, TaskAttr,
+};
+
+const READ_10: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 0, // LBA: 0
+    0, // reserved, group #
+    0, 2, // transfer length: 2
+    0, // control
+];
+
+const WRITE_10: &[u8] = &[
+    0x2a, // WRITE (10)
+    0,    // flags
+    0, 0, 0, 1, // LBA: 1
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+const READ_10_OUT_OF_RANGE: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 16, // LBA: 16
+    0,  // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+#[test]
+fn test_stats() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+    let stats = target.lun_stats(0).unwrap();
+
+    do_command_in(&mut target, READ_10, &[], &[0; 1024]);
+    do_command_in(&mut target, WRITE_10, &[b'w'; 512], &[]);
+    do_command_fail(
+        &mut target,
+        READ_10_OUT_OF_RANGE,
+        sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE,
+    );
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.commands.get("0x28"), Some(&2));
+    assert_eq!(snapshot.commands.get("0x2a"), Some(&1));
+    assert_eq!(snapshot.bytes_read, 1024);
+    assert_eq!(snapshot.bytes_written, 512);
+    assert_eq!(snapshot.errors.get("illegal-request"), Some(&1));
+    assert_eq!(snapshot.errors.len(), 1);
+    assert_eq!(snapshot.transport_errors, 0);
+    assert_eq!(snapshot.latency.count, 3);
+    assert_eq!(
+        snapshot
+            .latency
+            .buckets
+            .iter()
+            .map(|b| b.count)
+            .sum::<u64>(),
+        3
+    );
+}
+
+#[test]
+fn test_stats_truncated_data_in() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(test_image())));
+    let stats = target.lun_stats(0).unwrap();
+
+    // INQUIRY with an allocation length of 4 only transfers 4 bytes
+    let mut data_in = Vec::new();
+    let res = target.execute_command(
+        0,
+        &mut &[][..],
+        &mut data_in,
+        Request {
+            id: 0,
+            cdb: &[0x12, 0, 0, 0, 4, 0],
+            task_attr: TaskAttr::Simple,
+            crn: 0,
+            prio: 0,
+        },
+    );
+    assert_eq!(res.unwrap(), CmdOutput::ok());
+    assert_eq!(data_in.len(), 4);
+
+    assert_eq!(stats.snapshot().bytes_read, 4);
+    assert!(target.lun_stats(1).is_none());
+}
+
+#[test]
+fn test_latency_histogram() {
+    let stats = LunStats::default();
+    for latency in [5, 10, 11, 2_000_000] {
+        stats.record(
+            0x0,
+            0,
+            0,
+            &Ok(CmdOutput::ok()),
+            Duration::from_micros(latency),
+        );
+    }
+    // deferred commands aren't counted
+    stats.record(
+        0x28,
+        0,
+        0,
+        &Err(CmdError::Deferred(Duration::from_millis(1))),
+        Duration::ZERO,
+    );
+
+    let latency = stats.snapshot().latency;
+    assert_eq!(latency.count, 4);
+    assert_eq!(latency.sum_us, 2_000_026);
+    assert_eq!(latency.buckets[0].le_us, Some(10));
+    assert_eq!(latency.buckets[0].count, 2);
+    assert_eq!(latency.buckets[1].le_us, Some(25));
+    assert_eq!(latency.buckets[1].count, 1);
+    let overflow = latency.buckets.last().unwrap();
+    assert_eq!(overflow.le_us, None);
+    assert_eq!(overflow.count, 1);
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use assert_matches::assert_matches;
+
+use super::{do_command_in, test_image, TestBackend};
+use crate::scsi::{
+    emulation::{
+        block_device::BlockDevice,
+        target::EmulatedTarget,
+        task_set::{Admission, TaskSet},
+        throttle::{Throttle, ThrottleLimits},
+    },
+    sense, CmdError, CmdOutput, Request, Target, TaskAttr, TaskManagementFunction,
+    TaskManagementResponse,
+};
+
+const READ_10: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 0, // LBA: 0
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+const TEST_UNIT_READY: &[u8] = &[0; 6];
+
+/// READ (10) beyond the end of `test_image()`, with NACA set.
+const READ_10_OUT_OF_RANGE_NACA: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 16, // LBA: 16
+    0,  // reserved, group #
+    0, 1,   // transfer length: 1
+    0x4, // control: NACA
+];
+
+fn submit(
+    target: &mut EmulatedTarget,
+    id: u64,
+    task_attr: TaskAttr,
+    cdb: &[u8],
+) -> Result<CmdOutput, CmdError> {
+    target.execute_command(
+        0,
+        &mut &[][..],
+        &mut Vec::new(),
+        Request {
+            id,
+            cdb,
+            task_attr,
+            crn: 0,
+            prio: 0,
+        },
+    )
+}
+
+/// A target whose LUN 0 is throttled to a single read per second, with the
+/// throttle already exhausted.
+fn throttled_target() -> (EmulatedTarget, Arc<Throttle>) {
+    let throttle = Arc::new(Throttle::new(ThrottleLimits {
+        read_iops: Some(1),
+        ..Default::default()
+    }));
+    let mut dev = BlockDevice::new(TestBackend::new());
+    dev.set_throttle(Arc::clone(&throttle));
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(dev));
+
+    do_command_in(&mut target, READ_10, &[], &[0; 512]);
+    do_command_in(&mut target, READ_10, &[], &[0; 512]);
+    (target, throttle)
+}
+
+#[test]
+fn test_ordered_waits_for_older_tasks() {
+    let (mut target, throttle) = throttled_target();
+
+    assert_matches!(
+        submit(&mut target, 1, TaskAttr::Simple, READ_10),
+        Err(CmdError::Deferred(_))
+    );
+    // SIMPLE tasks don't wait for each other ...
+    assert_eq!(
+        submit(&mut target, 2, TaskAttr::Simple, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+    // ... but ORDERED ones wait for all older tasks, and newer SIMPLE tasks
+    // wait for them
+    assert_matches!(
+        submit(&mut target, 3, TaskAttr::Ordered, TEST_UNIT_READY),
+        Err(CmdError::Deferred(_))
+    );
+    assert_matches!(
+        submit(&mut target, 4, TaskAttr::Simple, TEST_UNIT_READY),
+        Err(CmdError::Deferred(_))
+    );
+    // HEAD OF QUEUE tasks don't wait at all
+    assert_eq!(
+        submit(&mut target, 5, TaskAttr::HeadOfQueue, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+    assert_eq!(
+        target.task_management(0, TaskManagementFunction::QueryTaskSet),
+        TaskManagementResponse::FunctionSucceeded
+    );
+
+    // resubmitting in order lets everything complete
+    throttle.set_limits(ThrottleLimits::default());
+    assert_matches!(
+        submit(&mut target, 4, TaskAttr::Simple, TEST_UNIT_READY),
+        Err(CmdError::Deferred(_))
+    );
+    assert_eq!(
+        submit(&mut target, 1, TaskAttr::Simple, READ_10).unwrap(),
+        CmdOutput::ok()
+    );
+    assert_eq!(
+        submit(&mut target, 3, TaskAttr::Ordered, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+    assert_eq!(
+        submit(&mut target, 4, TaskAttr::Simple, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+    assert_eq!(
+        target.task_management(0, TaskManagementFunction::QueryTaskSet),
+        TaskManagementResponse::FunctionComplete
+    );
+}
+
+#[test]
+fn test_aca() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(test_image())));
+
+    // ACA tasks are only allowed while ACA is established
+    assert_eq!(
+        submit(&mut target, 1, TaskAttr::Aca, TEST_UNIT_READY).unwrap(),
+        CmdOutput::check_condition(sense::INVALID_MESSAGE_ERROR)
+    );
+
+    assert_eq!(
+        submit(&mut target, 2, TaskAttr::Simple, READ_10_OUT_OF_RANGE_NACA).unwrap(),
+        CmdOutput::check_condition(sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE)
+    );
+    assert_eq!(
+        submit(&mut target, 3, TaskAttr::Simple, TEST_UNIT_READY).unwrap(),
+        CmdOutput::aca_active()
+    );
+    assert_eq!(
+        submit(&mut target, 4, TaskAttr::Aca, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+
+    assert_eq!(
+        target.task_management(0, TaskManagementFunction::ClearAca),
+        TaskManagementResponse::FunctionComplete
+    );
+    assert_eq!(
+        submit(&mut target, 5, TaskAttr::Simple, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+}
+
+#[test]
+fn test_check_condition_without_naca() {
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(test_image())));
+
+    let mut cdb = READ_10_OUT_OF_RANGE_NACA.to_vec();
+    cdb[9] = 0;
+    assert_eq!(
+        submit(&mut target, 1, TaskAttr::Simple, &cdb).unwrap(),
+        CmdOutput::check_condition(sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE)
+    );
+    assert_eq!(
+        submit(&mut target, 2, TaskAttr::Simple, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+}
+
+#[test]
+fn test_aca_blocks_deferred_tasks() {
+    let (mut target, throttle) = throttled_target();
+
+    assert_matches!(
+        submit(&mut target, 1, TaskAttr::Simple, READ_10),
+        Err(CmdError::Deferred(_))
+    );
+    // READ (10) beyond the end of TestBackend's 16 blocks
+    let mut cdb = READ_10_OUT_OF_RANGE_NACA.to_vec();
+    cdb[2..6].copy_from_slice(&u32::MAX.to_be_bytes());
+    throttle.set_limits(ThrottleLimits::default());
+    assert_eq!(
+        submit(&mut target, 2, TaskAttr::HeadOfQueue, &cdb)
+            .unwrap()
+            .status,
+        CmdOutput::CHECK_CONDITION
+    );
+
+    // the deferred task stays blocked until ACA is cleared
+    assert_matches!(
+        submit(&mut target, 1, TaskAttr::Simple, READ_10),
+        Err(CmdError::Deferred(_))
+    );
+    target.task_management(0, TaskManagementFunction::ClearAca);
+    assert_eq!(
+        submit(&mut target, 1, TaskAttr::Simple, READ_10).unwrap(),
+        CmdOutput::ok()
+    );
+}
+
+#[test]
+fn test_task_management() {
+    let (mut target, _throttle) = throttled_target();
+
+    assert_matches!(
+        submit(&mut target, 1, TaskAttr::Ordered, READ_10),
+        Err(CmdError::Deferred(_))
+    );
+    assert_matches!(
+        submit(&mut target, 2, TaskAttr::Simple, TEST_UNIT_READY),
+        Err(CmdError::Deferred(_))
+    );
+    assert_eq!(
+        target.task_management(0, TaskManagementFunction::QueryTask(1)),
+        TaskManagementResponse::FunctionSucceeded
+    );
+
+    // once the ORDERED task is aborted, the SIMPLE one may continue
+    assert_eq!(
+        target.task_management(0, TaskManagementFunction::AbortTask(1)),
+        TaskManagementResponse::FunctionComplete
+    );
+    assert_eq!(
+        target.task_management(0, TaskManagementFunction::QueryTask(1)),
+        TaskManagementResponse::FunctionComplete
+    );
+    assert_eq!(
+        submit(&mut target, 2, TaskAttr::Simple, TEST_UNIT_READY).unwrap(),
+        CmdOutput::ok()
+    );
+
+    assert_eq!(
+        target.task_management(1, TaskManagementFunction::LogicalUnitReset),
+        TaskManagementResponse::IncorrectLun
+    );
+}
+
+#[test]
+fn test_priority() {
+    let mut task_set = TaskSet::default();
+    let now = Instant::now();
+
+    task_set.complete(
+        1,
+        TaskAttr::Simple,
+        0,
+        false,
+        &Err(CmdError::Deferred(Duration::ZERO)),
+        now,
+    );
+    task_set.complete(
+        2,
+        TaskAttr::Simple,
+        3,
+        false,
+        &Err(CmdError::Deferred(Duration::ZERO)),
+        now,
+    );
+
+    // task 1 has no priority, so it yields to task 2
+    assert_eq!(
+        task_set.admit(1, TaskAttr::Simple, 0, now),
+        Admission::Blocked(Duration::ZERO)
+    );
+    assert_eq!(
+        task_set.admit(2, TaskAttr::Simple, 3, now),
+        Admission::Enabled
+    );
+    task_set.complete(2, TaskAttr::Simple, 3, false, &Ok(CmdOutput::ok()), now);
+    assert_eq!(
+        task_set.admit(1, TaskAttr::Simple, 0, now),
+        Admission::Enabled
+    );
+}
@@ -0,0 +1,483 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::sync::Arc;
+
+use assert_matches::assert_matches;
+use tempfile::tempfile;
+
+use super::{do_command_in_lun, test_image, TestBackend};
+use crate::scsi::{
+    emulation::{
+        block_device::{BlockDevice, FileBackend},
+        target::EmulatedTarget,
+        throttle::{Throttle, ThrottleLimits},
+    },
+    sense::{self, SenseTriple},
+    CmdError, CmdOutput, Request, Target, TaskAttr,
+};
+
+const NAA_0: u64 = 0x3000_0000_0000_0001;
+const NAA_1: u64 = 0x3000_0000_0000_0002;
+
+/// 16 blocks of zeros.
+fn zero_image() -> FileBackend {
+    let f = tempfile().unwrap();
+    f.set_len(512 * 16).unwrap();
+    FileBackend::new(f)
+}
+
+/// A target with `test_image()` as LUN 0 and `zero_image()` as LUN 1.
+fn copy_target() -> EmulatedTarget {
+    let mut target = EmulatedTarget::new();
+    let mut dev = BlockDevice::new(test_image());
+    dev.set_naa(NAA_0);
+    target.add_lun(Box::new(dev));
+    let mut dev = BlockDevice::new(zero_image());
+    dev.set_naa(NAA_1);
+    target.add_lun(Box::new(dev));
+    target
+}
+
+fn execute(
+    target: &mut EmulatedTarget,
+    lun: u16,
+    cdb: &[u8],
+    data_out: &[u8],
+) -> (CmdOutput, Vec<u8>) {
+    let mut data_in = Vec::new();
+    let output = target
+        .execute_command(
+            lun,
+            &mut &data_out[..],
+            &mut data_in,
+            Request {
+                id: 0,
+                cdb,
+                task_attr: TaskAttr::Simple,
+                crn: 0,
+                prio: 0,
+            },
+        )
+        .unwrap();
+    (output, data_in)
+}
+
+fn copy_fail(
+    target: &mut EmulatedTarget,
+    cdb: &[u8],
+    data_out: &[u8],
+    expected_error: SenseTriple,
+) {
+    assert_eq!(
+        execute(target, 0, cdb, data_out).0,
+        CmdOutput::check_condition(expected_error)
+    );
+}
+
+fn read_block(target: &mut EmulatedTarget, lun: u16, lba: u8, expected: u8) {
+    do_command_in_lun(
+        target,
+        lun,
+        &[
+            0x28, // READ (10)
+            0,    // flags
+            0, 0, 0, lba, // LBA
+            0,   // reserved, group #
+            0, 1, // transfer length: 1
+            0, // control
+        ],
+        &[],
+        &[expected; 512],
+    );
+}
+
+/// A third party copy CDB (EXTENDED COPY and friends) with the given service
+/// action, list identifier and parameter list length.
+fn copy_cdb(service_action: u8, list_identifier: u32, len: usize) -> Vec<u8> {
+    let mut cdb = vec![0x83, service_action];
+    cdb.extend_from_slice(&[0; 4]);
+    cdb.extend_from_slice(&list_identifier.to_be_bytes());
+    cdb.extend_from_slice(&u32::try_from(len).unwrap().to_be_bytes());
+    cdb.extend_from_slice(&[0, 0]); // group number, control
+    cdb
+}
+
+/// The parameter list of EXTENDED COPY, copying (source CSCD, destination
+/// CSCD, blocks, source LBA, destination LBA) segments between the LUNs
+/// identified by `naas`.
+fn extended_copy_params(naas: &[u64], segments: &[(u16, u16, u16, u64, u64)]) -> Vec<u8> {
+    let mut cscds = Vec::new();
+    for naa in naas {
+        cscds.extend_from_slice(&[
+            0xe4, // identification descriptor
+            0,    // block device
+            0, 0, // relative initiator port
+            1, // code set: binary
+            3, // associated with the LU, type: NAA
+            0, // reserved
+            8, // designator length
+        ]);
+        cscds.extend_from_slice(&naa.to_be_bytes());
+        cscds.extend_from_slice(&[0; 13]);
+        cscds.extend_from_slice(&[0, 2, 0]); // block length: 512
+    }
+    let mut segment_list = Vec::new();
+    for &(src, dst, blocks, src_lba, dst_lba) in segments {
+        segment_list.extend_from_slice(&[0x02, 0, 0, 24]); // block to block
+        segment_list.extend_from_slice(&src.to_be_bytes());
+        segment_list.extend_from_slice(&dst.to_be_bytes());
+        segment_list.extend_from_slice(&[0, 0]);
+        segment_list.extend_from_slice(&blocks.to_be_bytes());
+        segment_list.extend_from_slice(&src_lba.to_be_bytes());
+        segment_list.extend_from_slice(&dst_lba.to_be_bytes());
+    }
+
+    let mut params = vec![0, 0b0001_1000]; // list ID, no list ID usage
+    params.extend_from_slice(&u16::try_from(cscds.len()).unwrap().to_be_bytes());
+    params.extend_from_slice(&[0; 4]);
+    params.extend_from_slice(&u32::try_from(segment_list.len()).unwrap().to_be_bytes());
+    params.extend_from_slice(&[0; 4]); // inline data length
+    params.extend_from_slice(&cscds);
+    params.extend_from_slice(&segment_list);
+    params
+}
+
+/// A block device range descriptor list, preceded by its length.
+fn ranges(ranges: &[(u64, u32)]) -> Vec<u8> {
+    let mut list = u16::try_from(ranges.len() * 16)
+        .unwrap()
+        .to_be_bytes()
+        .to_vec();
+    for &(lba, blocks) in ranges {
+        list.extend_from_slice(&lba.to_be_bytes());
+        list.extend_from_slice(&blocks.to_be_bytes());
+        list.extend_from_slice(&[0; 4]);
+    }
+    list
+}
+
+fn populate_token_params(src: &[(u64, u32)]) -> Vec<u8> {
+    let mut params = vec![0; 14];
+    params.extend_from_slice(&ranges(src));
+    params
+}
+
+fn write_using_token_params(token: &[u8], offset: u64, dst: &[(u64, u32)]) -> Vec<u8> {
+    let mut params = vec![0; 8];
+    params.extend_from_slice(&offset.to_be_bytes());
+    params.extend_from_slice(token);
+    params.extend_from_slice(&[0; 6]);
+    params.extend_from_slice(&ranges(dst));
+    params
+}
+
+/// RECEIVE ROD TOKEN INFORMATION for `list_identifier`.
+fn rod_token_information(target: &mut EmulatedTarget, lun: u16, list_identifier: u32) -> Vec<u8> {
+    let mut cdb = vec![0x84, 0x7];
+    cdb.extend_from_slice(&list_identifier.to_be_bytes());
+    cdb.extend_from_slice(&[0; 4]);
+    cdb.extend_from_slice(&1024_u32.to_be_bytes());
+    cdb.extend_from_slice(&[0, 0]);
+    let (output, data_in) = execute(target, lun, &cdb, &[]);
+    assert_eq!(output, CmdOutput::ok());
+    data_in
+}
+
+#[test]
+fn test_device_identification() {
+    let mut target = copy_target();
+
+    do_command_in_lun(
+        &mut target,
+        1,
+        &[
+            0x12, // INQUIRY
+            1,    // EVPD
+            0x83, // device identification
+            0, 64, // alloc length
+            0,  // control
+        ],
+        &[],
+        &[
+            0, 0x83, // block device, page code
+            0, 12, // page length
+            1, 3, 0, 8, // binary NAA designator for the LU
+            0x30, 0, 0, 0, 0, 0, 0, 2,
+        ],
+    );
+
+    // without a designator, there's no page
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(BlockDevice::new(TestBackend::new())));
+    copy_fail(
+        &mut target,
+        &[0x12, 1, 0x83, 0, 64, 0],
+        &[],
+        sense::INVALID_FIELD_IN_CDB,
+    );
+}
+
+#[test]
+fn test_third_party_copy_vpd_page() {
+    let mut target = copy_target();
+
+    let (output, data_in) = execute(&mut target, 0, &[0x12, 1, 0x8f, 4, 0, 0], &[]);
+    assert_eq!(output, CmdOutput::ok());
+    assert_eq!(&data_in[..2], &[0, 0x8f]);
+    assert_eq!(
+        usize::from(u16::from_be_bytes([data_in[2], data_in[3]])),
+        data_in.len() - 4
+    );
+
+    // walk the descriptors, which are all padded to 4 bytes
+    let mut descriptors = Vec::new();
+    let mut rest = &data_in[4..];
+    while !rest.is_empty() {
+        let len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+        assert_eq!(len % 4, 0);
+        descriptors.push(u16::from_be_bytes([rest[0], rest[1]]));
+        rest = &rest[4 + len..];
+    }
+    assert_eq!(
+        descriptors,
+        [0x0000, 0x0001, 0x0008, 0x0004, 0x000c, 0x0108, 0x8001]
+    );
+}
+
+#[test]
+fn test_extended_copy() {
+    let mut target = copy_target();
+
+    let params = extended_copy_params(&[NAA_1, NAA_0], &[(1, 0, 3, 2, 8), (1, 0, 1, 15, 0)]);
+    let (output, _) = execute(&mut target, 0, &copy_cdb(0, 0, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+
+    read_block(&mut target, 1, 0, b'f');
+    read_block(&mut target, 1, 7, 0);
+    read_block(&mut target, 1, 8, b'2');
+    read_block(&mut target, 1, 10, b'4');
+    read_block(&mut target, 1, 11, 0);
+}
+
+#[test]
+fn test_extended_copy_overlapping() {
+    let mut target = EmulatedTarget::new();
+    let mut dev = BlockDevice::new(test_image());
+    dev.set_naa(NAA_0);
+    target.add_lun(Box::new(dev));
+
+    // copying forward within the LUN must not clobber the source
+    let params = extended_copy_params(&[NAA_0], &[(0, 0, 4, 0, 2)]);
+    let (output, _) = execute(&mut target, 0, &copy_cdb(0, 0, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+
+    for (lba, expected) in [
+        (1, b'1'),
+        (2, b'0'),
+        (3, b'1'),
+        (4, b'2'),
+        (5, b'3'),
+        (6, b'6'),
+    ] {
+        read_block(&mut target, 0, lba, expected);
+    }
+}
+
+#[test]
+fn test_extended_copy_without_file() {
+    let mut target = EmulatedTarget::new();
+    let mut dev = BlockDevice::new(test_image());
+    dev.set_naa(NAA_0);
+    target.add_lun(Box::new(dev));
+    let mut dev = BlockDevice::new(TestBackend::new());
+    dev.set_naa(NAA_1);
+    target.add_lun(Box::new(dev));
+
+    let params = extended_copy_params(&[NAA_0, NAA_1], &[(0, 1, 2, 9, 3)]);
+    let (output, _) = execute(&mut target, 1, &copy_cdb(0, 0, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+
+    read_block(&mut target, 1, 3, b'9');
+    read_block(&mut target, 1, 4, b'a');
+}
+
+#[test]
+fn test_extended_copy_errors() {
+    let mut target = copy_target();
+
+    let params = extended_copy_params(&[NAA_0, 0x3000_0000_0000_0003], &[(0, 1, 1, 0, 0)]);
+    copy_fail(
+        &mut target,
+        &copy_cdb(0, 0, params.len()),
+        &params,
+        sense::UNREACHABLE_COPY_TARGET,
+    );
+
+    let params = extended_copy_params(&[NAA_0, NAA_1], &[(0, 1, 2, 15, 0)]);
+    copy_fail(
+        &mut target,
+        &copy_cdb(0, 0, params.len()),
+        &params,
+        sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE,
+    );
+
+    let mut params = extended_copy_params(&[NAA_0, NAA_1], &[(0, 1, 1, 0, 0)]);
+    params[80] = 0x0a; // stream to stream segment
+    copy_fail(
+        &mut target,
+        &copy_cdb(0, 0, params.len()),
+        &params,
+        sense::UNSUPPORTED_SEGMENT_DESCRIPTOR_TYPE_CODE,
+    );
+
+    copy_fail(
+        &mut target,
+        &copy_cdb(0, 0, params.len() + 1),
+        &params,
+        sense::TARGET_FAILURE,
+    );
+
+    // nothing was copied by the failed commands
+    read_block(&mut target, 1, 0, 0);
+}
+
+#[test]
+fn test_receive_copy_operating_parameters() {
+    let mut target = copy_target();
+
+    let mut cdb = vec![0x84, 0x3];
+    cdb.extend_from_slice(&[0; 8]);
+    cdb.extend_from_slice(&64_u32.to_be_bytes());
+    cdb.extend_from_slice(&[0, 0]);
+    let (output, data_in) = execute(&mut target, 0, &cdb, &[]);
+    assert_eq!(output, CmdOutput::ok());
+    assert_eq!(data_in.len(), 46);
+    assert_eq!(&data_in[43..], &[2, 0x02, 0xe4]);
+}
+
+#[test]
+fn test_populate_token_and_write_using_token() {
+    let mut target = copy_target();
+
+    let params = populate_token_params(&[(1, 2), (10, 1)]);
+    let (output, _) = execute(&mut target, 0, &copy_cdb(0x10, 7, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+
+    let info = rod_token_information(&mut target, 0, 7);
+    assert_eq!(info.len(), 4 + 28 + 4 + 2 + 512);
+    assert_eq!(&info[4..6], &[0x10, 0x1]); // POPULATE TOKEN, completed
+    assert_eq!(&info[16..24], &3_u64.to_be_bytes()); // transfer count
+    let token = info[38..].to_vec();
+
+    // skip the first block of the token, and spread the rest out
+    let params = write_using_token_params(&token, 1, &[(4, 1), (6, 5)]);
+    let (output, _) = execute(&mut target, 1, &copy_cdb(0x11, 8, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+
+    read_block(&mut target, 1, 4, b'2');
+    read_block(&mut target, 1, 5, 0);
+    read_block(&mut target, 1, 6, b'a');
+    read_block(&mut target, 1, 7, 0);
+
+    let info = rod_token_information(&mut target, 1, 8);
+    assert_eq!(&info[4..6], &[0x11, 0x1]); // WRITE USING TOKEN, completed
+    assert_eq!(&info[16..24], &2_u64.to_be_bytes()); // transfer count
+
+    // the token stays valid until it's deleted
+    let mut params = write_using_token_params(&token, 0, &[(0, 1)]);
+    params[2] = 0b10; // DEL_TKN
+    let (output, _) = execute(&mut target, 1, &copy_cdb(0x11, 9, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+    read_block(&mut target, 1, 0, b'1');
+
+    copy_fail(
+        &mut target,
+        &copy_cdb(0x11, 10, params.len()),
+        &params,
+        sense::TOKEN_UNKNOWN,
+    );
+}
+
+#[test]
+fn test_write_using_block_device_zero_token() {
+    let mut target = copy_target();
+
+    let mut token = vec![0; 512];
+    token[0..4].copy_from_slice(&0xffff_0001_u32.to_be_bytes());
+    let params = write_using_token_params(&token, 0, &[(3, 2)]);
+    let (output, _) = execute(&mut target, 0, &copy_cdb(0x11, 0, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+
+    read_block(&mut target, 0, 2, b'2');
+    read_block(&mut target, 0, 3, 0);
+    read_block(&mut target, 0, 4, 0);
+    read_block(&mut target, 0, 5, b'5');
+}
+
+#[test]
+fn test_copy_throttling_and_stats() {
+    let mut target = EmulatedTarget::new();
+    let mut dev = BlockDevice::new(test_image());
+    dev.set_naa(NAA_0);
+    target.add_lun(Box::new(dev));
+    let mut dev = BlockDevice::new(zero_image());
+    dev.set_naa(NAA_1);
+    dev.set_throttle(Arc::new(Throttle::new(ThrottleLimits {
+        write_bps: Some(2048),
+        ..Default::default()
+    })));
+    target.add_lun(Box::new(dev));
+
+    // the destination's bucket holds 4 blocks, so this puts it into debt
+    let params = extended_copy_params(&[NAA_0, NAA_1], &[(0, 1, 4, 0, 0), (0, 1, 4, 8, 8)]);
+    let (output, _) = execute(&mut target, 0, &copy_cdb(0, 0, params.len()), &params);
+    assert_eq!(output, CmdOutput::ok());
+    read_block(&mut target, 1, 3, b'3');
+    read_block(&mut target, 1, 11, b'b');
+
+    // and the next copy to it has to wait, without copying anything
+    let params = extended_copy_params(&[NAA_0, NAA_1], &[(0, 1, 1, 15, 4)]);
+    let result = target.execute_command(
+        0,
+        &mut &params[..],
+        &mut Vec::new(),
+        Request {
+            id: 0,
+            cdb: &copy_cdb(0, 0, params.len()),
+            task_attr: TaskAttr::Simple,
+            crn: 0,
+            prio: 0,
+        },
+    );
+    assert_matches!(result, Err(CmdError::Deferred(_)));
+    read_block(&mut target, 1, 4, 0);
+
+    // so does zeroing it
+    let mut token = vec![0; 512];
+    token[0..4].copy_from_slice(&0xffff_0001_u32.to_be_bytes());
+    let params = write_using_token_params(&token, 0, &[(0, 1)]);
+    let result = target.execute_command(
+        1,
+        &mut &params[..],
+        &mut Vec::new(),
+        Request {
+            id: 1,
+            cdb: &copy_cdb(0x11, 0, params.len()),
+            task_attr: TaskAttr::Simple,
+            crn: 0,
+            prio: 0,
+        },
+    );
+    assert_matches!(result, Err(CmdError::Deferred(_)));
+    read_block(&mut target, 1, 0, b'0');
+
+    // the copied bytes are counted on both sides, but only once
+    let src = target.lun_stats(0).unwrap().snapshot();
+    assert_eq!(src.copy_bytes_read, 8 * 512);
+    assert_eq!(src.copy_bytes_written, 0);
+    assert_eq!(src.commands.get("0x83"), Some(&1));
+    let dst = target.lun_stats(1).unwrap().snapshot();
+    assert_eq!(dst.copy_bytes_read, 0);
+    assert_eq!(dst.copy_bytes_written, 8 * 512);
+    assert_eq!(dst.commands.get("0x83"), None);
+}
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use assert_matches::assert_matches;
+
+use super::{do_command_in, TestBackend};
+use crate::scsi::{
+    emulation::{
+        block_device::BlockDevice,
+        target::EmulatedTarget,
+        throttle::{IoDirection, Throttle, ThrottleLimits, ThrottleLimitsParseError},
+    },
+    CmdError, Request, Target, TaskAttr,
+};
+
+const READ_10: &[u8] = &[
+    0x28, // READ (10)
+    0,    // flags
+    0, 0, 0, 0, // LBA: 0
+    0, // reserved, group #
+    0, 1, // transfer length: 1
+    0, // control
+];
+
+#[test]
+fn test_update_limits() {
+    let mut limits = ThrottleLimits {
+        read_iops: Some(10),
+        write_iops: Some(20),
+        ..Default::default()
+    };
+    limits.update("write-iops=0, read-bps=4096").unwrap();
+    assert_eq!(
+        limits,
+        ThrottleLimits {
+            read_iops: Some(10),
+            read_bps: Some(4096),
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        limits.to_string(),
+        "read-iops=10,write-iops=0,read-bps=4096,write-bps=0"
+    );
+
+    assert_matches!(
+        limits.update("read-iops"),
+        Err(ThrottleLimitsParseError::BadArgument(_))
+    );
+    assert_matches!(
+        limits.update("iops=1"),
+        Err(ThrottleLimitsParseError::InvalidKey(_))
+    );
+    assert_matches!(
+        limits.update("read-iops=-1"),
+        Err(ThrottleLimitsParseError::InvalidValue(_, _))
+    );
+}
+
+#[test]
+fn test_iops_limit() {
+    let throttle = Throttle::new(ThrottleLimits {
+        read_iops: Some(2),
+        ..Default::default()
+    });
+    let start = Instant::now();
+
+    // the bucket starts full, and the second request puts it into debt
+    throttle.admit_at(IoDirection::Read, 512, start).unwrap();
+    throttle.admit_at(IoDirection::Read, 512, start).unwrap();
+    throttle.admit_at(IoDirection::Read, 512, start).unwrap();
+    assert_eq!(
+        throttle.admit_at(IoDirection::Read, 512, start),
+        Err(Duration::from_millis(500))
+    );
+    // writes are limited separately
+    throttle.admit_at(IoDirection::Write, 512, start).unwrap();
+
+    throttle
+        .admit_at(IoDirection::Read, 512, start + Duration::from_millis(500))
+        .unwrap();
+}
+
+#[test]
+fn test_bps_limit() {
+    let throttle = Throttle::new(ThrottleLimits {
+        write_bps: Some(1024),
+        ..Default::default()
+    });
+    let start = Instant::now();
+
+    // a request bigger than the bucket still gets through, but has to be paid
+    // back before the next one
+    throttle.admit_at(IoDirection::Write, 3072, start).unwrap();
+    assert_eq!(
+        throttle.admit_at(IoDirection::Write, 512, start),
+        Err(Duration::from_secs(2))
+    );
+    assert_eq!(
+        throttle.admit_at(IoDirection::Write, 512, start + Duration::from_secs(1)),
+        Err(Duration::from_secs(1))
+    );
+    throttle
+        .admit_at(IoDirection::Write, 512, start + Duration::from_secs(2))
+        .unwrap();
+}
+
+#[test]
+fn test_set_limits() {
+    let throttle = Throttle::new(ThrottleLimits {
+        read_iops: Some(1),
+        ..Default::default()
+    });
+    throttle.admit(IoDirection::Read, 512).unwrap();
+    throttle.admit(IoDirection::Read, 512).unwrap();
+    assert!(throttle.admit(IoDirection::Read, 512).is_err());
+
+    throttle.set_limits(ThrottleLimits::default());
+    for _ in 0..100 {
+        throttle.admit(IoDirection::Read, 512).unwrap();
+    }
+}
+
+#[test]
+fn test_throttled_device_defers() {
+    let throttle = Arc::new(Throttle::new(ThrottleLimits {
+        read_iops: Some(1),
+        ..Default::default()
+    }));
+    let mut dev = BlockDevice::new(TestBackend::new());
+    dev.set_throttle(Arc::clone(&throttle));
+    let mut target = EmulatedTarget::new();
+    target.add_lun(Box::new(dev));
+
+    do_command_in(&mut target, READ_10, &[], &[0; 512]);
+    do_command_in(&mut target, READ_10, &[], &[0; 512]);
+
+    let mut data_in = Vec::new();
+    let res = target.execute_command(
+        0,
+        &mut &[][..],
+        &mut data_in,
+        Request {
+            id: 0,
+            cdb: READ_10,
+            task_attr: TaskAttr::Simple,
+            crn: 0,
+            prio: 0,
+        },
+    );
+    assert_matches!(res, Err(CmdError::Deferred(delay)) if delay > Duration::ZERO);
+    assert!(data_in.is_empty());
+
+    // lifting the limit lets the command through
+    throttle.set_limits(ThrottleLimits::default());
+    do_command_in(&mut target, READ_10, &[], &[0; 512]);
+}
@@ -0,0 +1,964 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! The copy manager, which implements the third party copy commands of SPC-4
+//! 6.4 and SBC-3 5.x: EXTENDED COPY (LID1), and the token based POPULATE TOKEN
+//! and WRITE USING TOKEN. Copies only work between logical units of the same
+//! `EmulatedTarget`, which are identified by their NAA designators. If both
+//! sides are backed by files, the data is copied on the host with
+//! `copy_file_range`; otherwise, it's read and written in chunks.
+//!
+//! Copies are executed synchronously, so by the time RECEIVE ROD TOKEN
+//! INFORMATION asks for the status of a copy, it has completed. ROD tokens
+//! are of the "access upon reference" type: they don't preserve the data they
+//! represent, so writes to the source after POPULATE TOKEN are visible to a
+//! later WRITE USING TOKEN.
+//!
+//! Copies are subject to the throttling of the logical units involved: all
+//! of a command's reads and writes are charged before anything gets copied,
+//! so a command is either deferred as a whole or runs to completion. The
+//! copied bytes are counted in the statistics of the logical units.
+
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
+    io::{self, Read, Write},
+    os::unix::io::RawFd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use super::{
+    block_device::{sense_for_io_error, BlockDeviceBackend, ByteOffset},
+    command::CopyCommand,
+    response_data::SilentlyTruncate,
+    stats::LunStats,
+    target::LogicalUnit,
+    throttle::{IoDirection, Throttle},
+};
+use crate::scsi::{
+    sense::{self, SenseTriple},
+    CmdError, CmdOutput,
+};
+
+const CSCD_DESCRIPTOR_LENGTH: usize = 32;
+const SEGMENT_DESCRIPTOR_LENGTH: usize = 28;
+const RANGE_DESCRIPTOR_LENGTH: usize = 16;
+const ROD_TOKEN_LENGTH: usize = 512;
+
+const MAX_CSCD_DESCRIPTORS: u16 = 8;
+const MAX_SEGMENT_DESCRIPTORS: u16 = 64;
+const MAX_DESCRIPTOR_LIST_LENGTH: u32 = MAX_CSCD_DESCRIPTORS as u32 * CSCD_DESCRIPTOR_LENGTH as u32
+    + MAX_SEGMENT_DESCRIPTORS as u32 * SEGMENT_DESCRIPTOR_LENGTH as u32;
+/// In bytes.
+const MAX_SEGMENT_LENGTH: u32 = 64 << 20;
+const MAX_RANGE_DESCRIPTORS: u16 = 64;
+/// Longer parameter lists are rejected before reading them; none of the
+/// limits above allow for them anyway.
+const MAX_PARAMETER_LIST_LENGTH: u32 = 4096;
+/// In seconds.
+const DEFAULT_INACTIVITY_TIMEOUT: u32 = 60;
+const MAX_INACTIVITY_TIMEOUT: u32 = 3600;
+/// In blocks.
+const MAX_TOKEN_TRANSFER_SIZE: u64 = 1 << 24;
+const OPTIMAL_TRANSFER_COUNT: u32 = 1 << 16;
+/// How many tokens and copy results we keep at most; the oldest ones are
+/// dropped first.
+const MAX_TOKENS: usize = 64;
+const MAX_RESULTS: usize = 16;
+
+/// Chunk size for copies that can't use `copy_file_range`.
+const COPY_CHUNK_SIZE: u64 = 1 << 20;
+
+const CSCD_TYPE_IDENTIFICATION: u8 = 0xe4;
+const SEGMENT_TYPE_BLOCK_TO_BLOCK: u8 = 0x02;
+const DESIGNATOR_TYPE_NAA: u8 = 0x3;
+
+const ROD_TYPE_ACCESS_UPON_REFERENCE: u32 = 0x0080_0000;
+const ROD_TYPE_BLOCK_DEVICE_ZERO: u32 = 0xffff_0001;
+
+const SERVICE_ACTION_POPULATE_TOKEN: u8 = 0x10;
+const SERVICE_ACTION_WRITE_USING_TOKEN: u8 = 0x11;
+
+/// A logical unit's blocks, as seen by the copy manager.
+pub(crate) struct CopyEndpoint<'a> {
+    pub backend: &'a mut dyn BlockDeviceBackend,
+    /// The NAA designator copy commands refer to the logical unit by.
+    pub naa: Option<u64>,
+    pub throttle: Option<&'a Throttle>,
+}
+
+/// Why a copy didn't complete.
+#[derive(Debug)]
+enum CopyError {
+    /// The copy failed, or wasn't started, with this sense.
+    Sense(SenseTriple),
+    /// A logical unit's throttle didn't admit the copy; nothing was copied.
+    Deferred(Duration),
+}
+
+impl From<SenseTriple> for CopyError {
+    fn from(sense: SenseTriple) -> Self {
+        Self::Sense(sense)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockRange {
+    lba: u64,
+    blocks: u64,
+}
+
+/// A contiguous run of blocks to copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    src_lun: usize,
+    src_lba: u64,
+    dst_lun: usize,
+    dst_lba: u64,
+    blocks: u64,
+}
+
+struct Token {
+    id: u64,
+    lun: usize,
+    ranges: Vec<BlockRange>,
+    inactivity_timeout: Duration,
+    expires_at: Instant,
+}
+
+/// The outcome of a token based copy, for RECEIVE ROD TOKEN INFORMATION.
+struct CopyResult {
+    list_identifier: u32,
+    service_action: u8,
+    /// In blocks.
+    transfer_count: u64,
+    token: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub(crate) struct CopyManager {
+    tokens: VecDeque<Token>,
+    results: VecDeque<CopyResult>,
+}
+
+impl CopyManager {
+    /// Execute a copy command received by `lun`. `stats` are the statistics
+    /// of `luns`, which copied bytes are counted in.
+    pub(crate) fn execute_command(
+        &mut self,
+        luns: &mut [Box<dyn LogicalUnit>],
+        stats: &[Arc<LunStats>],
+        lun: usize,
+        data_in: &mut SilentlyTruncate<&mut dyn Write>,
+        data_out: &mut dyn Read,
+        command: CopyCommand,
+    ) -> Result<CmdOutput, CmdError> {
+        let result = match command {
+            // SPC-4 6.4.1: a parameter list length of zero isn't an error,
+            // there's just nothing to do.
+            CopyCommand::ExtendedCopy {
+                parameter_list_length: 0,
+            }
+            | CopyCommand::PopulateToken {
+                parameter_list_length: 0,
+                ..
+            }
+            | CopyCommand::WriteUsingToken {
+                parameter_list_length: 0,
+                ..
+            } => Ok(()),
+            CopyCommand::ExtendedCopy {
+                parameter_list_length,
+            } => read_parameter_list(data_out, parameter_list_length, 16)
+                .map_err(CopyError::from)
+                .and_then(|params| extended_copy(luns, stats, &params)),
+            CopyCommand::PopulateToken {
+                list_identifier,
+                parameter_list_length,
+            } => read_parameter_list(data_out, parameter_list_length, 16)
+                .and_then(|params| self.populate_token(luns, lun, list_identifier, &params))
+                .map_err(CopyError::from),
+            CopyCommand::WriteUsingToken {
+                list_identifier,
+                parameter_list_length,
+            } => read_parameter_list(data_out, parameter_list_length, 536)
+                .map_err(CopyError::from)
+                .and_then(|params| {
+                    self.write_using_token(luns, stats, lun, list_identifier, &params)
+                }),
+            CopyCommand::ReceiveCopyOperatingParameters => {
+                data_in
+                    .write_all(&operating_parameters())
+                    .map_err(CmdError::DataIn)?;
+                Ok(())
+            }
+            CopyCommand::ReceiveRodTokenInformation { list_identifier } => {
+                match self
+                    .results
+                    .iter()
+                    .find(|result| result.list_identifier == list_identifier)
+                {
+                    Some(result) => {
+                        data_in
+                            .write_all(&rod_token_information(result))
+                            .map_err(CmdError::DataIn)?;
+                        Ok(())
+                    }
+                    None => Err(sense::INVALID_FIELD_IN_CDB.into()),
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => Ok(CmdOutput::ok()),
+            Err(CopyError::Sense(sense)) => Ok(CmdOutput::check_condition(sense)),
+            Err(CopyError::Deferred(delay)) => Err(CmdError::Deferred(delay)),
+        }
+    }
+
+    fn populate_token(
+        &mut self,
+        luns: &mut [Box<dyn LogicalUnit>],
+        lun: usize,
+        list_identifier: u32,
+        params: &[u8],
+    ) -> Result<(), SenseTriple> {
+        let inactivity_timeout = match u32::from_be_bytes(params[4..8].try_into().unwrap()) {
+            0 => DEFAULT_INACTIVITY_TIMEOUT,
+            timeout if timeout <= MAX_INACTIVITY_TIMEOUT => timeout,
+            _ => return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST),
+        };
+        match u32::from_be_bytes(params[8..12].try_into().unwrap()) {
+            // 0 lets us pick the ROD type
+            0 | ROD_TYPE_ACCESS_UPON_REFERENCE => (),
+            _ => return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST),
+        }
+
+        let ranges = parse_ranges(&params[14..])?;
+        let mut blocks = 0;
+        for &range in &ranges {
+            check_range(luns, lun, range)?;
+            blocks += range.blocks;
+        }
+        if blocks > MAX_TOKEN_TRANSFER_SIZE {
+            return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST);
+        }
+
+        let block_size = block_size(luns, lun)?;
+        let id = rand::random();
+        let token = rod_token(id, endpoint(luns, lun)?.naa, blocks * block_size);
+
+        let now = Instant::now();
+        let inactivity_timeout = Duration::from_secs(u64::from(inactivity_timeout));
+        self.tokens.push_back(Token {
+            id,
+            lun,
+            ranges,
+            inactivity_timeout,
+            expires_at: now + inactivity_timeout,
+        });
+        self.expire_tokens(now);
+        self.push_result(CopyResult {
+            list_identifier,
+            service_action: SERVICE_ACTION_POPULATE_TOKEN,
+            transfer_count: blocks,
+            token: Some(token),
+        });
+        Ok(())
+    }
+
+    fn write_using_token(
+        &mut self,
+        luns: &mut [Box<dyn LogicalUnit>],
+        stats: &[Arc<LunStats>],
+        lun: usize,
+        list_identifier: u32,
+        params: &[u8],
+    ) -> Result<(), CopyError> {
+        let delete_token = params[2] & 0b0000_0010 != 0;
+        let offset = u64::from_be_bytes(params[8..16].try_into().unwrap());
+        let token_bytes = &params[16..16 + ROD_TOKEN_LENGTH];
+
+        let ranges = parse_ranges(&params[534..])?;
+        for &range in &ranges {
+            check_range(luns, lun, range)?;
+        }
+        let block_size = block_size(luns, lun)?;
+
+        let transfer_count = match u32::from_be_bytes(token_bytes[0..4].try_into().unwrap()) {
+            ROD_TYPE_BLOCK_DEVICE_ZERO => {
+                let blocks = ranges.iter().map(|range| range.blocks).sum();
+                admit(luns, &[(lun, IoDirection::Write, blocks * block_size)])?;
+                let endpoint = endpoint(luns, lun)?;
+                for range in &ranges {
+                    let len = range.blocks * block_size;
+                    zero_bytes(endpoint.backend, range.lba * block_size, len).map_err(|e| {
+                        error!("Error zeroing blocks: {}", e);
+                        sense_for_io_error(&e, sense::THIRD_PARTY_DEVICE_FAILURE)
+                    })?;
+                    stats[lun].record_copy(0, len);
+                }
+                blocks
+            }
+            ROD_TYPE_ACCESS_UPON_REFERENCE => {
+                let now = Instant::now();
+                self.expire_tokens(now);
+                let id = u64::from_be_bytes(token_bytes[8..16].try_into().unwrap());
+                let Some(index) = self.tokens.iter().position(|token| token.id == id) else {
+                    return Err(sense::TOKEN_UNKNOWN.into());
+                };
+                let token = &mut self.tokens[index];
+                token.expires_at = now + token.inactivity_timeout;
+
+                if self::block_size(luns, token.lun)? != block_size {
+                    return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST.into());
+                }
+                let segments = pair_ranges(token.lun, &token.ranges, offset, lun, &ranges)?;
+                admit(luns, &segment_transfers(&segments, block_size))?;
+                for segment in &segments {
+                    copy_segment(luns, stats, segment, block_size)?;
+                }
+                if delete_token {
+                    self.tokens.remove(index);
+                }
+                segments.iter().map(|segment| segment.blocks).sum()
+            }
+            _ => return Err(sense::UNSUPPORTED_TOKEN_TYPE.into()),
+        };
+
+        self.push_result(CopyResult {
+            list_identifier,
+            service_action: SERVICE_ACTION_WRITE_USING_TOKEN,
+            transfer_count,
+            token: None,
+        });
+        Ok(())
+    }
+
+    fn expire_tokens(&mut self, now: Instant) {
+        self.tokens.retain(|token| token.expires_at > now);
+        while self.tokens.len() > MAX_TOKENS {
+            self.tokens.pop_front();
+        }
+    }
+
+    fn push_result(&mut self, result: CopyResult) {
+        self.results
+            .retain(|old| old.list_identifier != result.list_identifier);
+        self.results.push_back(result);
+        while self.results.len() > MAX_RESULTS {
+            self.results.pop_front();
+        }
+    }
+}
+
+/// Read a parameter list of `len` bytes, which has to be at least `min_len`
+/// bytes long.
+fn read_parameter_list(
+    data_out: &mut dyn Read,
+    len: u32,
+    min_len: usize,
+) -> Result<Vec<u8>, SenseTriple> {
+    if len > MAX_PARAMETER_LIST_LENGTH {
+        return Err(sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+    let len = usize::try_from(len).expect("parameter list length should fit usize");
+    if len < min_len {
+        return Err(sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+
+    let mut params = vec![0; len];
+    data_out.read_exact(&mut params).map_err(|e| {
+        error!("Error reading from data_out: {}", e);
+        sense::TARGET_FAILURE
+    })?;
+    Ok(params)
+}
+
+/// Execute an EXTENDED COPY (LID1) parameter list (SPC-4 6.4.3). All
+/// descriptors are checked before anything gets copied.
+fn extended_copy(
+    luns: &mut [Box<dyn LogicalUnit>],
+    stats: &[Arc<LunStats>],
+    params: &[u8],
+) -> Result<(), CopyError> {
+    let cscd_list_length = usize::from(u16::from_be_bytes(params[2..4].try_into().unwrap()));
+    let segment_list_length =
+        usize::try_from(u32::from_be_bytes(params[8..12].try_into().unwrap()))
+            .expect("segment descriptor list length should fit usize");
+    if u32::from_be_bytes(params[12..16].try_into().unwrap()) != 0 {
+        // We don't support any segment descriptors that use inline data.
+        return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST.into());
+    }
+    let Some(cscd_list) = params.get(16..16 + cscd_list_length) else {
+        return Err(sense::PARAMETER_LIST_LENGTH_ERROR.into());
+    };
+    let Some(mut segment_list) = params
+        .get(16 + cscd_list_length..)
+        .and_then(|rest| rest.get(..segment_list_length))
+    else {
+        return Err(sense::PARAMETER_LIST_LENGTH_ERROR.into());
+    };
+
+    if cscd_list_length % CSCD_DESCRIPTOR_LENGTH != 0 {
+        return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST.into());
+    }
+    if cscd_list_length / CSCD_DESCRIPTOR_LENGTH > usize::from(MAX_CSCD_DESCRIPTORS) {
+        return Err(sense::TOO_MANY_TARGET_DESCRIPTORS.into());
+    }
+    let cscds = cscd_list
+        .chunks(CSCD_DESCRIPTOR_LENGTH)
+        .map(|descriptor| resolve_cscd(luns, descriptor))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut segments = Vec::new();
+    while !segment_list.is_empty() {
+        if segment_list[0] != SEGMENT_TYPE_BLOCK_TO_BLOCK {
+            return Err(sense::UNSUPPORTED_SEGMENT_DESCRIPTOR_TYPE_CODE.into());
+        }
+        let Some(descriptor) = segment_list.get(..SEGMENT_DESCRIPTOR_LENGTH) else {
+            return Err(sense::PARAMETER_LIST_LENGTH_ERROR.into());
+        };
+        if usize::from(u16::from_be_bytes(descriptor[2..4].try_into().unwrap()))
+            != SEGMENT_DESCRIPTOR_LENGTH - 4
+        {
+            return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST.into());
+        }
+        if segments.len() == usize::from(MAX_SEGMENT_DESCRIPTORS) {
+            return Err(sense::TOO_MANY_SEGMENT_DESCRIPTORS.into());
+        }
+
+        let cscd = |id: &[u8]| {
+            cscds
+                .get(usize::from(u16::from_be_bytes(id.try_into().unwrap())))
+                .copied()
+                .ok_or(sense::UNREACHABLE_COPY_TARGET)
+        };
+        let segment = Segment {
+            src_lun: cscd(&descriptor[4..6])?,
+            dst_lun: cscd(&descriptor[6..8])?,
+            blocks: u64::from(u16::from_be_bytes(descriptor[10..12].try_into().unwrap())),
+            src_lba: u64::from_be_bytes(descriptor[12..20].try_into().unwrap()),
+            dst_lba: u64::from_be_bytes(descriptor[20..28].try_into().unwrap()),
+        };
+        check_range(
+            luns,
+            segment.src_lun,
+            BlockRange {
+                lba: segment.src_lba,
+                blocks: segment.blocks,
+            },
+        )?;
+        check_range(
+            luns,
+            segment.dst_lun,
+            BlockRange {
+                lba: segment.dst_lba,
+                blocks: segment.blocks,
+            },
+        )?;
+        let block_size = block_size(luns, segment.src_lun)?;
+        if self::block_size(luns, segment.dst_lun)? != block_size
+            || segment.blocks * block_size > u64::from(MAX_SEGMENT_LENGTH)
+        {
+            return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST.into());
+        }
+        segments.push((segment, block_size));
+        segment_list = &segment_list[SEGMENT_DESCRIPTOR_LENGTH..];
+    }
+
+    let transfers: Vec<_> = segments
+        .iter()
+        .flat_map(|(segment, block_size)| segment_transfers(&[*segment], *block_size))
+        .collect();
+    admit(luns, &transfers)?;
+    for (segment, block_size) in &segments {
+        copy_segment(luns, stats, segment, *block_size)?;
+    }
+    Ok(())
+}
+
+/// Find the logical unit an identification descriptor CSCD descriptor (SPC-4
+/// 6.4.5.2) refers to.
+fn resolve_cscd(
+    luns: &mut [Box<dyn LogicalUnit>],
+    descriptor: &[u8],
+) -> Result<usize, SenseTriple> {
+    if descriptor[0] != CSCD_TYPE_IDENTIFICATION {
+        return Err(sense::UNSUPPORTED_TARGET_DESCRIPTOR_TYPE_CODE);
+    }
+    // NUL bit, or a peripheral device type other than block device
+    if descriptor[1] & 0b0011_1111 != 0 {
+        return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST);
+    }
+    if descriptor[5] & 0b0000_1111 != DESIGNATOR_TYPE_NAA || descriptor[7] != 8 {
+        return Err(sense::UNREACHABLE_COPY_TARGET);
+    }
+    let naa = u64::from_be_bytes(descriptor[8..16].try_into().unwrap());
+    let lun = luns
+        .iter_mut()
+        .position(|logical_unit| {
+            logical_unit
+                .copy_endpoint()
+                .is_some_and(|endpoint| endpoint.naa == Some(naa))
+        })
+        .ok_or(sense::UNREACHABLE_COPY_TARGET)?;
+
+    let block_length = u32::from_be_bytes([0, descriptor[29], descriptor[30], descriptor[31]]);
+    if block_length != 0 && u64::from(block_length) != block_size(luns, lun)? {
+        return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST);
+    }
+    Ok(lun)
+}
+
+/// Parse a block device range descriptor list (SBC-3 5.x), preceded by its
+/// length.
+fn parse_ranges(params: &[u8]) -> Result<Vec<BlockRange>, SenseTriple> {
+    let len = usize::from(u16::from_be_bytes(params[..2].try_into().unwrap()));
+    let Some(list) = params.get(2..2 + len) else {
+        return Err(sense::PARAMETER_LIST_LENGTH_ERROR);
+    };
+    if len == 0
+        || len % RANGE_DESCRIPTOR_LENGTH != 0
+        || len / RANGE_DESCRIPTOR_LENGTH > usize::from(MAX_RANGE_DESCRIPTORS)
+    {
+        return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST);
+    }
+    Ok(list
+        .chunks(RANGE_DESCRIPTOR_LENGTH)
+        .map(|descriptor| BlockRange {
+            lba: u64::from_be_bytes(descriptor[0..8].try_into().unwrap()),
+            blocks: u64::from(u32::from_be_bytes(descriptor[8..12].try_into().unwrap())),
+        })
+        .collect())
+}
+
+/// Split copying the blocks of `src`, starting `offset` blocks in, to the
+/// blocks of `dst` into segments that are contiguous on both sides. The copy
+/// ends with whichever runs out first.
+fn pair_ranges(
+    src_lun: usize,
+    src: &[BlockRange],
+    mut offset: u64,
+    dst_lun: usize,
+    dst: &[BlockRange],
+) -> Result<Vec<Segment>, SenseTriple> {
+    let mut sources = Vec::new();
+    for range in src {
+        if offset >= range.blocks {
+            offset -= range.blocks;
+        } else {
+            sources.push(BlockRange {
+                lba: range.lba + offset,
+                blocks: range.blocks - offset,
+            });
+            offset = 0;
+        }
+    }
+    if offset > 0 {
+        return Err(sense::INVALID_FIELD_IN_PARAMETER_LIST);
+    }
+
+    let mut segments = Vec::new();
+    let mut sources = sources.into_iter();
+    let mut source = sources.next();
+    for &destination in dst {
+        let mut destination = destination;
+        while destination.blocks > 0 {
+            let Some(src_range) = source.as_mut() else {
+                return Ok(segments);
+            };
+            let blocks = min(src_range.blocks, destination.blocks);
+            segments.push(Segment {
+                src_lun,
+                src_lba: src_range.lba,
+                dst_lun,
+                dst_lba: destination.lba,
+                blocks,
+            });
+            src_range.lba += blocks;
+            src_range.blocks -= blocks;
+            destination.lba += blocks;
+            destination.blocks -= blocks;
+            if src_range.blocks == 0 {
+                source = sources.next();
+            }
+        }
+    }
+    Ok(segments)
+}
+
+fn endpoint(
+    luns: &mut [Box<dyn LogicalUnit>],
+    lun: usize,
+) -> Result<CopyEndpoint<'_>, SenseTriple> {
+    luns.get_mut(lun)
+        .and_then(|logical_unit| logical_unit.copy_endpoint())
+        .ok_or(sense::UNREACHABLE_COPY_TARGET)
+}
+
+fn block_size(luns: &mut [Box<dyn LogicalUnit>], lun: usize) -> Result<u64, SenseTriple> {
+    Ok(u64::from(u32::from(
+        endpoint(luns, lun)?.backend.block_size(),
+    )))
+}
+
+/// Check that `range` lies within the logical unit.
+fn check_range(
+    luns: &mut [Box<dyn LogicalUnit>],
+    lun: usize,
+    range: BlockRange,
+) -> Result<(), SenseTriple> {
+    let endpoint = endpoint(luns, lun)?;
+    let size = endpoint.backend.size_in_blocks().map_err(|e| {
+        error!("Error getting image size for copy: {}", e);
+        sense_for_io_error(&e, sense::THIRD_PARTY_DEVICE_FAILURE)
+    })?;
+    if !matches!(range.lba.checked_add(range.blocks), Some(end) if end <= u64::from(size)) {
+        return Err(sense::LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE);
+    }
+    Ok(())
+}
+
+/// The reads and writes of copying `segments`, as (logical unit, direction,
+/// bytes) for `admit`.
+fn segment_transfers(segments: &[Segment], block_size: u64) -> Vec<(usize, IoDirection, u64)> {
+    segments
+        .iter()
+        .flat_map(|segment| {
+            let len = segment.blocks * block_size;
+            [
+                (segment.src_lun, IoDirection::Read, len),
+                (segment.dst_lun, IoDirection::Write, len),
+            ]
+        })
+        .collect()
+}
+
+/// Take the throttle tokens for `transfers`, given as (logical unit,
+/// direction, bytes), or fail with `CopyError::Deferred` if the copy has to
+/// wait. Like for other commands, each logical unit is charged one I/O per
+/// direction, however many segments it's involved in.
+fn admit(
+    luns: &mut [Box<dyn LogicalUnit>],
+    transfers: &[(usize, IoDirection, u64)],
+) -> Result<(), CopyError> {
+    let mut totals: Vec<(usize, IoDirection, u64)> = Vec::new();
+    for &(lun, direction, bytes) in transfers {
+        match totals
+            .iter_mut()
+            .find(|(l, d, _)| *l == lun && *d == direction)
+        {
+            Some((_, _, total)) => *total += bytes,
+            None => totals.push((lun, direction, bytes)),
+        }
+    }
+    for (lun, direction, bytes) in totals {
+        if let Some(throttle) = endpoint(luns, lun)?.throttle {
+            throttle
+                .admit(direction, bytes)
+                .map_err(CopyError::Deferred)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_segment(
+    luns: &mut [Box<dyn LogicalUnit>],
+    stats: &[Arc<LunStats>],
+    segment: &Segment,
+    block_size: u64,
+) -> Result<(), SenseTriple> {
+    let src_offset = segment.src_lba * block_size;
+    let dst_offset = segment.dst_lba * block_size;
+    let len = segment.blocks * block_size;
+
+    let result = if segment.src_lun == segment.dst_lun {
+        let endpoint = endpoint(luns, segment.src_lun)?;
+        copy_bytes(endpoint.backend, None, src_offset, dst_offset, len)
+    } else {
+        let (src, dst) = two_mut(luns, segment.src_lun, segment.dst_lun);
+        let (Some(src), Some(dst)) = (src.copy_endpoint(), dst.copy_endpoint()) else {
+            return Err(sense::UNREACHABLE_COPY_TARGET);
+        };
+        copy_bytes(src.backend, Some(dst.backend), src_offset, dst_offset, len)
+    };
+    result.map_err(|e| {
+        error!("Error copying blocks: {}", e);
+        sense_for_io_error(&e, sense::THIRD_PARTY_DEVICE_FAILURE)
+    })?;
+    stats[segment.src_lun].record_copy(len, 0);
+    stats[segment.dst_lun].record_copy(0, len);
+    Ok(())
+}
+
+fn two_mut<T>(slice: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    if a < b {
+        let (left, right) = slice.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = slice.split_at_mut(a);
+        (&mut right[0], &mut left[b])
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, or within `src` if `dst` is `None`.
+fn copy_bytes(
+    src: &mut dyn BlockDeviceBackend,
+    mut dst: Option<&mut dyn BlockDeviceBackend>,
+    src_offset: u64,
+    dst_offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    let src_fd = src.raw_fd();
+    let dst_fd = match &dst {
+        Some(dst) => dst.raw_fd(),
+        None => src_fd,
+    };
+    let copied = match (src_fd, dst_fd) {
+        (Some(src_fd), Some(dst_fd)) => {
+            copy_file_range(src_fd, src_offset, dst_fd, dst_offset, len)?
+        }
+        _ => 0,
+    };
+    let (src_offset, dst_offset, len) = (src_offset + copied, dst_offset + copied, len - copied);
+
+    // When copying within a backend and the destination overlaps the end of
+    // the source, copy backwards so nothing is overwritten before it's copied.
+    let backwards = dst.is_none() && src_offset < dst_offset && dst_offset < src_offset + len;
+    let mut buf =
+        vec![0; usize::try_from(min(len, COPY_CHUNK_SIZE)).expect("chunk size should fit usize")];
+    let mut done = 0;
+    while done < len {
+        let chunk = min(len - done, COPY_CHUNK_SIZE);
+        let pos = if backwards { len - done - chunk } else { done };
+        let buf = &mut buf[..usize::try_from(chunk).expect("chunk size should fit usize")];
+        src.read_exact_at(buf, ByteOffset::from(src_offset + pos))?;
+        match &mut dst {
+            Some(dst) => dst.write_exact_at(buf, ByteOffset::from(dst_offset + pos))?,
+            None => src.write_exact_at(buf, ByteOffset::from(dst_offset + pos))?,
+        }
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Copy as much as `copy_file_range` can, and return how much that was.
+/// Whatever the kernel can't copy (e.g. between file systems, or overlapping
+/// ranges within a file) is left for the caller.
+fn copy_file_range(
+    src: RawFd,
+    src_offset: u64,
+    dst: RawFd,
+    dst_offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    let mut copied = 0;
+    while copied < len {
+        let (Ok(mut off_in), Ok(mut off_out)) = (
+            i64::try_from(src_offset + copied),
+            i64::try_from(dst_offset + copied),
+        ) else {
+            break;
+        };
+        let chunk = usize::try_from(len - copied).unwrap_or(usize::MAX);
+        // SAFETY: the offsets point to valid i64s for the duration of the
+        // call, and the kernel checks the file descriptors.
+        let ret = unsafe { libc::copy_file_range(src, &mut off_in, dst, &mut off_out, chunk, 0) };
+        match u64::try_from(ret) {
+            // end of the source file; the fallback reports that
+            Ok(0) => break,
+            Ok(n) => copied += n,
+            Err(_) => {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => (),
+                    Some(libc::EXDEV | libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP) => break,
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+    Ok(copied)
+}
+
+/// Make `len` bytes at `offset` read back as zeros.
+fn zero_bytes(backend: &mut dyn BlockDeviceBackend, offset: u64, len: u64) -> io::Result<()> {
+    match backend.discard(ByteOffset::from(offset), len) {
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            let zeros = vec![
+                0;
+                usize::try_from(min(len, COPY_CHUNK_SIZE))
+                    .expect("chunk size should fit usize")
+            ];
+            let mut done = 0;
+            while done < len {
+                let chunk = min(len - done, COPY_CHUNK_SIZE);
+                backend.write_exact_at(
+                    &zeros[..usize::try_from(chunk).expect("chunk size should fit usize")],
+                    ByteOffset::from(offset + done),
+                )?;
+                done += chunk;
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// An identification descriptor CSCD descriptor for a logical unit
+/// identified by `naa`.
+fn cscd_descriptor(naa: u64) -> [u8; CSCD_DESCRIPTOR_LENGTH] {
+    let mut descriptor = [0; CSCD_DESCRIPTOR_LENGTH];
+    descriptor[0] = CSCD_TYPE_IDENTIFICATION;
+    descriptor[4] = 0x1; // code set: binary
+    descriptor[5] = DESIGNATOR_TYPE_NAA; // associated with the LU
+    descriptor[7] = 8; // designator length
+    descriptor[8..16].copy_from_slice(&naa.to_be_bytes());
+    descriptor
+}
+
+/// A ROD token (SPC-4 8.3.6.1) for the token `id`, representing `bytes` bytes
+/// of the logical unit identified by `creator_naa`.
+fn rod_token(id: u64, creator_naa: Option<u64>, bytes: u64) -> Vec<u8> {
+    let mut token = vec![0; ROD_TOKEN_LENGTH];
+    token[0..4].copy_from_slice(&ROD_TYPE_ACCESS_UPON_REFERENCE.to_be_bytes());
+    token[6..8].copy_from_slice(
+        &u16::try_from(ROD_TOKEN_LENGTH - 8)
+            .expect("ROD token length should fit u16")
+            .to_be_bytes(),
+    );
+    token[8..16].copy_from_slice(&id.to_be_bytes());
+    if let Some(naa) = creator_naa {
+        token[16..48].copy_from_slice(&cscd_descriptor(naa));
+    }
+    token[48..64].copy_from_slice(&u128::from(bytes).to_be_bytes());
+    token
+}
+
+/// The response to RECEIVE COPY OPERATING PARAMETERS (SPC-3 6.18.4).
+fn operating_parameters() -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&42_u32.to_be_bytes()); // available data
+    out.push(1); // SNLID: list identifiers are ignored
+    out.extend_from_slice(&[0; 3]); // reserved
+    out.extend_from_slice(&MAX_CSCD_DESCRIPTORS.to_be_bytes());
+    out.extend_from_slice(&MAX_SEGMENT_DESCRIPTORS.to_be_bytes());
+    out.extend_from_slice(&MAX_DESCRIPTOR_LIST_LENGTH.to_be_bytes());
+    out.extend_from_slice(&MAX_SEGMENT_LENGTH.to_be_bytes());
+    out.extend_from_slice(&0_u32.to_be_bytes()); // max inline data length
+    out.extend_from_slice(&0_u32.to_be_bytes()); // held data limit
+    out.extend_from_slice(&0_u32.to_be_bytes()); // max stream device transfer size
+    out.extend_from_slice(&[0; 2]); // reserved
+    out.extend_from_slice(&1_u16.to_be_bytes()); // total concurrent copies
+    out.push(1); // max concurrent copies
+    out.push(9); // data segment granularity: 512 bytes
+    out.push(0); // inline data granularity
+    out.push(0); // held data granularity
+    out.extend_from_slice(&[0; 3]); // reserved
+    out.push(2); // implemented descriptor list length
+    out.push(SEGMENT_TYPE_BLOCK_TO_BLOCK);
+    out.push(CSCD_TYPE_IDENTIFICATION);
+    out
+}
+
+/// The response to RECEIVE ROD TOKEN INFORMATION (SBC-3 5.x) for a completed
+/// copy.
+fn rod_token_information(result: &CopyResult) -> Vec<u8> {
+    let mut out = vec![0; 32];
+    out[4] = result.service_action;
+    out[5] = 0x1; // copy operation status: completed without errors
+    out[15] = 0xf1; // transfer count units: logical blocks
+    out[16..24].copy_from_slice(&result.transfer_count.to_be_bytes());
+    match &result.token {
+        Some(token) => {
+            // a single ROD token descriptor: two reserved bytes and the token
+            out.extend_from_slice(&(2 + ROD_TOKEN_LENGTH as u32).to_be_bytes());
+            out.extend_from_slice(&[0; 2]);
+            out.extend_from_slice(token);
+        }
+        None => out.extend_from_slice(&0_u32.to_be_bytes()),
+    }
+    let available_data = u32::try_from(out.len() - 4).expect("response should fit u32");
+    out[0..4].copy_from_slice(&available_data.to_be_bytes());
+    out
+}
+
+/// Append a third party copy descriptor, padded to a multiple of 4 bytes.
+fn write_descriptor(out: &mut Vec<u8>, descriptor_type: u16, parameters: &[u8]) {
+    let padded_len = parameters.len().next_multiple_of(4);
+    out.extend_from_slice(&descriptor_type.to_be_bytes());
+    out.extend_from_slice(
+        &u16::try_from(padded_len)
+            .expect("descriptor length should fit u16")
+            .to_be_bytes(),
+    );
+    out.extend_from_slice(parameters);
+    out.resize(out.len() + padded_len - parameters.len(), 0);
+}
+
+/// Write the descriptors of the Third Party Copy VPD page (SPC-4 7.8.17).
+pub(crate) fn write_vpd_page(out: &mut Vec<u8>) {
+    // Block Device ROD Token Limits (SBC-3 6.6.8)
+    let mut limits = vec![0; 10];
+    limits.extend_from_slice(&MAX_RANGE_DESCRIPTORS.to_be_bytes());
+    limits.extend_from_slice(&MAX_INACTIVITY_TIMEOUT.to_be_bytes());
+    limits.extend_from_slice(&DEFAULT_INACTIVITY_TIMEOUT.to_be_bytes());
+    limits.extend_from_slice(&MAX_TOKEN_TRANSFER_SIZE.to_be_bytes());
+    limits.extend_from_slice(&OPTIMAL_TRANSFER_COUNT.to_be_bytes());
+    write_descriptor(out, 0x0000, &limits);
+
+    // Supported Commands: opcode, then its service actions
+    let commands: &[u8] = &[
+        0x83, 3, 0x0, 0x10, 0x11, // EXTENDED COPY, POPULATE TOKEN, WRITE USING TOKEN
+        0x84, 2, 0x3, 0x7, // RECEIVE COPY OPERATING PARAMETERS, RECEIVE ROD TOKEN INFORMATION
+    ];
+    let mut supported_commands = vec![u8::try_from(commands.len()).unwrap()];
+    supported_commands.extend_from_slice(commands);
+    write_descriptor(out, 0x0001, &supported_commands);
+
+    // Parameter Data
+    let mut parameter_data = vec![0; 4];
+    parameter_data.extend_from_slice(&MAX_CSCD_DESCRIPTORS.to_be_bytes());
+    parameter_data.extend_from_slice(&MAX_SEGMENT_DESCRIPTORS.to_be_bytes());
+    parameter_data.extend_from_slice(&MAX_DESCRIPTOR_LIST_LENGTH.to_be_bytes());
+    parameter_data.extend_from_slice(&0_u32.to_be_bytes()); // max inline data length
+    parameter_data.extend_from_slice(&[0; 12]);
+    write_descriptor(out, 0x0008, &parameter_data);
+
+    // Supported Descriptors
+    write_descriptor(
+        out,
+        0x0004,
+        &[2, SEGMENT_TYPE_BLOCK_TO_BLOCK, CSCD_TYPE_IDENTIFICATION],
+    );
+
+    // Supported CSCD Descriptor IDs: only indexes into the CSCD list
+    write_descriptor(out, 0x000c, &[0; 2]);
+
+    // Supported ROD Types: one 64 byte descriptor each
+    let mut rod_types = vec![0; 2];
+    rod_types.extend_from_slice(&128_u16.to_be_bytes());
+    for (rod_type, flags) in [
+        (ROD_TYPE_ACCESS_UPON_REFERENCE, 0b11), // TOKEN_IN, TOKEN_OUT
+        (ROD_TYPE_BLOCK_DEVICE_ZERO, 0b10),     // TOKEN_IN
+    ] {
+        rod_types.extend_from_slice(&rod_type.to_be_bytes());
+        rod_types.push(flags);
+        rod_types.extend_from_slice(&[0; 59]);
+    }
+    write_descriptor(out, 0x0108, &rod_types);
+
+    // General Copy Operations
+    let mut general = Vec::new();
+    general.extend_from_slice(&1_u32.to_be_bytes()); // total concurrent copies
+    general.extend_from_slice(&1_u32.to_be_bytes()); // max identified concurrent copies
+    general.extend_from_slice(&MAX_SEGMENT_LENGTH.to_be_bytes());
+    general.push(9); // data segment granularity: 512 bytes
+    general.push(0); // inline data granularity
+    general.extend_from_slice(&[0; 18]);
+    write_descriptor(out, 0x8001, &general);
+}
@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Per-LUN I/O throttling, so that a single guest can't monopolize a host disk
+//! shared with others.
+//!
+//! Reads and writes each get a token bucket for IOPS and one for bytes per
+//! second. Every bucket holds up to one second worth of tokens, which is the
+//! burst we allow. A request is admitted as long as none of its buckets are in
+//! debt, and then takes its tokens even if that puts a bucket into debt; that
+//! way, requests larger than a bucket can hold still make progress. Requests
+//! that aren't admitted are deferred by `BlockDevice` (see
+//! `CmdError::Deferred`), so the vring thread never sleeps on a throttled LUN.
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error as ThisError;
+
+#[derive(Debug, ThisError, PartialEq, Eq)]
+pub(crate) enum ThrottleLimitsParseError {
+    #[error("Bad argument `{0}`")]
+    BadArgument(String),
+    #[error("Invalid key `{0}`")]
+    InvalidKey(String),
+    #[error("Invalid value `{1}` for key `{0}`")]
+    InvalidValue(String, String),
+}
+
+/// I/O limits of a LUN; `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ThrottleLimits {
+    pub read_iops: Option<u64>,
+    pub write_iops: Option<u64>,
+    pub read_bps: Option<u64>,
+    pub write_bps: Option<u64>,
+}
+
+impl ThrottleLimits {
+    /// Update the limits from comma separated key=value pairs, leaving limits
+    /// that aren't mentioned untouched. The allowed keys are `read-iops`,
+    /// `write-iops`, `read-bps` and `write-bps`; a value of 0 removes the
+    /// limit.
+    pub(crate) fn update(&mut self, s: &str) -> Result<(), ThrottleLimitsParseError> {
+        for arg in s.trim().split(',') {
+            let (key, val) = arg
+                .split_once('=')
+                .ok_or_else(|| ThrottleLimitsParseError::BadArgument(arg.to_string()))?;
+            let (key, val) = (key.trim(), val.trim());
+
+            let limit = match key {
+                "read-iops" | "read_iops" => &mut self.read_iops,
+                "write-iops" | "write_iops" => &mut self.write_iops,
+                "read-bps" | "read_bps" => &mut self.read_bps,
+                "write-bps" | "write_bps" => &mut self.write_bps,
+                _ => return Err(ThrottleLimitsParseError::InvalidKey(key.to_string())),
+            };
+            let val: u64 = val.parse().map_err(|_| {
+                ThrottleLimitsParseError::InvalidValue(key.to_string(), val.to_string())
+            })?;
+            *limit = Some(val).filter(|&v| v != 0);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ThrottleLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = |l: Option<u64>| l.unwrap_or(0);
+        write!(
+            f,
+            "read-iops={},write-iops={},read-bps={},write-bps={}",
+            limit(self.read_iops),
+            limit(self.write_iops),
+            limit(self.read_bps),
+            limit(self.write_bps)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IoDirection {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens added per second; also the capacity of the bucket.
+    rate: u64,
+    /// Negative if the bucket is in debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// How long until the bucket is out of debt.
+    fn wait_time(&self) -> Duration {
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DirectionBuckets {
+    iops: Option<TokenBucket>,
+    bps: Option<TokenBucket>,
+}
+
+impl DirectionBuckets {
+    fn set_limits(&mut self, iops: Option<u64>, bps: Option<u64>, now: Instant) {
+        fn update(bucket: &mut Option<TokenBucket>, rate: Option<u64>, now: Instant) {
+            match (bucket.as_mut(), rate) {
+                (Some(b), Some(rate)) => {
+                    b.refill(now);
+                    b.rate = rate;
+                    b.tokens = b.tokens.min(rate as f64);
+                }
+                (None, Some(rate)) => *bucket = Some(TokenBucket::new(rate, now)),
+                (_, None) => *bucket = None,
+            }
+        }
+        update(&mut self.iops, iops, now);
+        update(&mut self.bps, bps, now);
+    }
+
+    fn admit(&mut self, bytes: u64, now: Instant) -> Result<(), Duration> {
+        let mut wait = Duration::ZERO;
+        for bucket in self.iops.iter_mut().chain(self.bps.iter_mut()) {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_time());
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+
+        if let Some(iops) = self.iops.as_mut() {
+            iops.tokens -= 1.0;
+        }
+        if let Some(bps) = self.bps.as_mut() {
+            bps.tokens -= bytes as f64;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    limits: ThrottleLimits,
+    read: DirectionBuckets,
+    write: DirectionBuckets,
+}
+
+/// The throttling state of a LUN. Shared between the `BlockDevice` and the
+/// control socket, which can adjust the limits at runtime.
+#[derive(Debug)]
+pub(crate) struct Throttle(Mutex<ThrottleState>);
+
+impl Throttle {
+    pub(crate) fn new(limits: ThrottleLimits) -> Self {
+        let throttle = Self(Mutex::new(ThrottleState {
+            limits: ThrottleLimits::default(),
+            read: DirectionBuckets::default(),
+            write: DirectionBuckets::default(),
+        }));
+        throttle.set_limits(limits);
+        throttle
+    }
+
+    pub(crate) fn limits(&self) -> ThrottleLimits {
+        self.0.lock().unwrap().limits
+    }
+
+    pub(crate) fn set_limits(&self, limits: ThrottleLimits) {
+        let now = Instant::now();
+        let mut state = self.0.lock().unwrap();
+        state.limits = limits;
+        state
+            .read
+            .set_limits(limits.read_iops, limits.read_bps, now);
+        state
+            .write
+            .set_limits(limits.write_iops, limits.write_bps, now);
+    }
+
+    /// Try to take the tokens for a request of `bytes` bytes. On failure,
+    /// returns how long to wait before trying again.
+    pub(crate) fn admit(&self, direction: IoDirection, bytes: u64) -> Result<(), Duration> {
+        self.admit_at(direction, bytes, Instant::now())
+    }
+
+    pub(crate) fn admit_at(
+        &self,
+        direction: IoDirection,
+        bytes: u64,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut state = self.0.lock().unwrap();
+        match direction {
+            IoDirection::Read => state.read.admit(bytes, now),
+            IoDirection::Write => state.write.admit(bytes, now),
+        }
+    }
+}
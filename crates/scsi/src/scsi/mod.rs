@@ -3,7 +3,10 @@
 pub mod emulation;
 pub mod sense;
 
-use std::io::{self, Read, Write};
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
 
 use self::sense::SenseTriple;
 
@@ -23,9 +26,13 @@ pub struct CmdOutput {
 }
 
 impl CmdOutput {
+    pub const GOOD: u8 = 0;
+    pub const CHECK_CONDITION: u8 = 2;
+    pub const ACA_ACTIVE: u8 = 0x30;
+
     pub const fn ok() -> Self {
         Self {
-            status: 0,
+            status: Self::GOOD,
             status_qualifier: 0,
             sense: Vec::new(),
         }
@@ -33,11 +40,21 @@ impl CmdOutput {
 
     pub fn check_condition(sense: SenseTriple) -> Self {
         Self {
-            status: 2,
+            status: Self::CHECK_CONDITION,
             status_qualifier: 0,
             sense: sense.to_fixed_sense(),
         }
     }
+
+    /// The status for tasks that are rejected because an ACA condition is
+    /// established.
+    pub const fn aca_active() -> Self {
+        Self {
+            status: Self::ACA_ACTIVE,
+            status_qualifier: 0,
+            sense: Vec::new(),
+        }
+    }
 }
 
 pub struct Request<'a> {
@@ -58,6 +75,34 @@ pub enum CmdError {
     CdbTooShort,
     /// An error occurred while writing to the provided data in writer.
     DataIn(io::Error),
+    /// The command can't be executed right now (for example, because the LUN
+    /// is being throttled). Nothing has been read from data out or written to
+    /// data in, so the transport should resubmit the command after the given
+    /// delay.
+    Deferred(Duration),
+}
+
+/// A task management function, see SAM-5 7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskManagementFunction {
+    AbortTask(u64),
+    AbortTaskSet,
+    ClearAca,
+    ClearTaskSet,
+    ITNexusReset,
+    LogicalUnitReset,
+    QueryTask(u64),
+    QueryTaskSet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskManagementResponse {
+    /// The function completed. For queries, this means there is no such task.
+    FunctionComplete,
+    /// The query found a matching task.
+    FunctionSucceeded,
+    FunctionRejected,
+    IncorrectLun,
 }
 
 /// A transport-independent implementation of a SCSI target.
@@ -73,4 +118,16 @@ pub trait Target: Send + Sync {
         data_in: &mut dyn Write,
         req: Request,
     ) -> Result<CmdOutput, CmdError>;
+
+    /// Execute a task management function for `lun`.
+    ///
+    /// Only the target's view of the task set changes; the transport has to
+    /// complete any tasks that get aborted (i.e. requests it deferred).
+    fn task_management(
+        &mut self,
+        _lun: u16,
+        _function: TaskManagementFunction,
+    ) -> TaskManagementResponse {
+        TaskManagementResponse::FunctionRejected
+    }
 }
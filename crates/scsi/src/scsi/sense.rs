@@ -21,17 +21,57 @@ impl SenseTriple {
 }
 
 const NO_SENSE: u8 = 0;
+const NOT_READY: u8 = 0x2;
 const MEDIUM_ERROR: u8 = 0x3;
 const HARDWARE_ERROR: u8 = 0x4;
 const ILLEGAL_REQUEST: u8 = 0x5;
+const UNIT_ATTENTION: u8 = 0x6;
+const COPY_ABORTED: u8 = 0xa;
+const ABORTED_COMMAND: u8 = 0xb;
+const MISCOMPARE: u8 = 0xe;
 
 pub const NO_ADDITIONAL_SENSE_INFORMATION: SenseTriple = SenseTriple(NO_SENSE, 0, 0);
+pub const IDLE_CONDITION_ACTIVATED_BY_COMMAND: SenseTriple = SenseTriple(NO_SENSE, 0x5e, 0x3);
+pub const STANDBY_CONDITION_ACTIVATED_BY_COMMAND: SenseTriple = SenseTriple(NO_SENSE, 0x5e, 0x4);
 
 pub const INVALID_COMMAND_OPERATION_CODE: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x20, 0x0);
 pub const LOGICAL_BLOCK_ADDRESS_OUT_OF_RANGE: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x21, 0x0);
 pub const INVALID_FIELD_IN_CDB: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x24, 0x0);
-pub const LOGICAL_UNIT_NOT_SUPPORTED: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x21, 0x0);
+pub const LOGICAL_UNIT_NOT_SUPPORTED: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x25, 0x0);
+pub const INVALID_MESSAGE_ERROR: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x49, 0x0);
 pub const SAVING_PARAMETERS_NOT_SUPPORTED: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x39, 0x0);
+pub const PARAMETER_LIST_LENGTH_ERROR: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x1a, 0x0);
+pub const INVALID_FIELD_IN_PARAMETER_LIST: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x26, 0x0);
+pub const TOO_MANY_TARGET_DESCRIPTORS: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x26, 0x6);
+pub const UNSUPPORTED_TARGET_DESCRIPTOR_TYPE_CODE: SenseTriple =
+    SenseTriple(ILLEGAL_REQUEST, 0x26, 0x7);
+pub const TOO_MANY_SEGMENT_DESCRIPTORS: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x26, 0x8);
+pub const UNSUPPORTED_SEGMENT_DESCRIPTOR_TYPE_CODE: SenseTriple =
+    SenseTriple(ILLEGAL_REQUEST, 0x26, 0x9);
+pub const UNSUPPORTED_TOKEN_TYPE: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x23, 0x1);
+pub const TOKEN_UNKNOWN: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x23, 0x4);
+pub const MEDIUM_REMOVAL_PREVENTED: SenseTriple = SenseTriple(ILLEGAL_REQUEST, 0x53, 0x2);
 
+pub const LOGICAL_UNIT_NOT_READY: SenseTriple = SenseTriple(NOT_READY, 0x04, 0x0);
+pub const LOGICAL_UNIT_NOT_READY_INITIALIZING_COMMAND_REQUIRED: SenseTriple =
+    SenseTriple(NOT_READY, 0x04, 0x2);
+pub const LOGICAL_UNIT_NOT_READY_SELF_TEST_IN_PROGRESS: SenseTriple =
+    SenseTriple(NOT_READY, 0x04, 0x9);
+pub const MEDIUM_NOT_PRESENT: SenseTriple = SenseTriple(NOT_READY, 0x3a, 0x0);
+pub const MEDIUM_NOT_PRESENT_TRAY_OPEN: SenseTriple = SenseTriple(NOT_READY, 0x3a, 0x2);
+
+pub const NOT_READY_TO_READY_CHANGE: SenseTriple = SenseTriple(UNIT_ATTENTION, 0x28, 0x0);
+
+pub const WRITE_ERROR: SenseTriple = SenseTriple(MEDIUM_ERROR, 0x0c, 0x0);
 pub const UNRECOVERED_READ_ERROR: SenseTriple = SenseTriple(MEDIUM_ERROR, 0x11, 0x0);
 pub const TARGET_FAILURE: SenseTriple = SenseTriple(HARDWARE_ERROR, 0x44, 0x0);
+pub const LOGICAL_UNIT_FAILED_SELF_TEST: SenseTriple = SenseTriple(HARDWARE_ERROR, 0x3e, 0x3);
+
+pub const UNREACHABLE_COPY_TARGET: SenseTriple = SenseTriple(COPY_ABORTED, 0x08, 0x4);
+pub const THIRD_PARTY_DEVICE_FAILURE: SenseTriple = SenseTriple(COPY_ABORTED, 0x0d, 0x1);
+
+pub const LOGICAL_UNIT_COMMUNICATION_FAILURE: SenseTriple = SenseTriple(ABORTED_COMMAND, 0x08, 0x0);
+pub const LOGICAL_UNIT_COMMUNICATION_TIME_OUT: SenseTriple =
+    SenseTriple(ABORTED_COMMAND, 0x08, 0x1);
+
+pub const MISCOMPARE_DURING_VERIFY_OPERATION: SenseTriple = SenseTriple(MISCOMPARE, 0x1d, 0x0);
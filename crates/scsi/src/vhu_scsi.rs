@@ -1,14 +1,23 @@
 // SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
 
 use core::slice;
+use std::collections::VecDeque;
 use std::convert::{TryFrom, TryInto};
-use std::io::{self, ErrorKind};
+use std::io::{self, ErrorKind, Write};
 use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use log::{debug, error, info, warn};
 use vhost::vhost_user::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
 use vhost_user_backend::{VhostUserBackendMut, VringRwLock, VringT};
-use virtio_bindings::virtio_scsi::{virtio_scsi_config, virtio_scsi_event};
+use virtio_bindings::virtio_scsi::{
+    virtio_scsi_config, virtio_scsi_event, VIRTIO_SCSI_S_OK, VIRTIO_SCSI_T_TMF_ABORT_TASK,
+    VIRTIO_SCSI_T_TMF_ABORT_TASK_SET, VIRTIO_SCSI_T_TMF_CLEAR_ACA,
+    VIRTIO_SCSI_T_TMF_CLEAR_TASK_SET, VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET,
+    VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET, VIRTIO_SCSI_T_TMF_QUERY_TASK,
+    VIRTIO_SCSI_T_TMF_QUERY_TASK_SET,
+};
 use virtio_bindings::{
     virtio_config::VIRTIO_F_VERSION_1,
     virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC},
@@ -19,25 +28,55 @@ use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryLoadGuard, Gues
 use vmm_sys_util::{
     epoll::EventSet,
     eventfd::{EventFd, EFD_NONBLOCK},
+    timerfd::TimerFd,
 };
 
 use crate::scsi::Target;
 use crate::virtio::CDB_SIZE;
 use crate::{
-    scsi::{self, CmdError, TaskAttr},
-    virtio::{self, Request, RequestParseError, Response, ResponseCode, VirtioScsiLun, SENSE_SIZE},
+    scsi::{
+        self, sense, CmdError, CmdOutput, TaskAttr, TaskManagementFunction, TaskManagementResponse,
+    },
+    virtio::{
+        self, ControlRequest, Request, RequestParseError, Response, ResponseCode, VirtioScsiLun,
+        SENSE_SIZE,
+    },
 };
 
+const CONTROL_QUEUE: u16 = 0;
+const RESPONSE_HEADER_SIZE: u32 = 12;
 const REQUEST_QUEUE: u16 = 2;
 
+/// Event of the timer for resubmitting deferred requests. Events up to
+/// `num_queues()` are reserved for the queues and the exit event.
+pub(crate) const DEFERRED_REQUESTS_EVENT: u16 = 4;
+
+type DescriptorChain = virtio_queue::DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>;
 type DescriptorChainWriter = virtio::DescriptorChainWriter<GuestMemoryLoadGuard<GuestMemoryMmap>>;
 type DescriptorChainReader = virtio::DescriptorChainReader<GuestMemoryLoadGuard<GuestMemoryMmap>>;
 
+/// A request a target couldn't execute yet, see `CmdError::Deferred`.
+struct DeferredRequest {
+    chain: DescriptorChain,
+    target: u8,
+    lun: u16,
+    id: u64,
+    resubmit_at: Instant,
+}
+
 pub(crate) struct VhostUserScsiBackend {
     event_idx: bool,
     mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
     targets: Vec<Box<dyn Target>>,
+    deferred: VecDeque<DeferredRequest>,
+    /// Fires when the next deferred request is due. Needs to be registered
+    /// with the vring worker as `DEFERRED_REQUESTS_EVENT`.
+    pub(crate) deferred_timer: TimerFd,
     pub(crate) exit_event: EventFd,
+    /// Held while processing requests, so that holding it anywhere else
+    /// quiesces the device: the request being processed completes, and the
+    /// next one waits until it's released.
+    pub(crate) request_gate: Arc<Mutex<()>>,
 }
 
 impl VhostUserScsiBackend {
@@ -46,7 +85,10 @@ impl VhostUserScsiBackend {
             event_idx: false,
             mem: None,
             targets: Vec::new(),
+            deferred: VecDeque::new(),
+            deferred_timer: TimerFd::new().expect("Creating deferred request timer"),
             exit_event: EventFd::new(EFD_NONBLOCK).expect("Creating exit eventfd"),
+            request_gate: Arc::default(),
         }
     }
 
@@ -65,13 +107,17 @@ impl VhostUserScsiBackend {
         }
     }
 
+    /// Process a single request and write the response.
+    ///
+    /// Returns `Some((target, lun, id, delay))` without writing a response if
+    /// the target deferred the request; it needs to be resubmitted after
+    /// `delay`.
     fn process_requests(
         &mut self,
         reader: &mut DescriptorChainReader,
         writer: &mut DescriptorChainWriter,
-    ) {
+    ) -> Option<(u8, u16, u64, Duration)> {
         let mut body_writer = writer.clone();
-        const RESPONSE_HEADER_SIZE: u32 = 12;
         body_writer.skip(
             RESPONSE_HEADER_SIZE + u32::try_from(SENSE_SIZE).expect("SENSE_SIZE should fit 32bit"),
         );
@@ -104,8 +150,17 @@ impl VhostUserScsiBackend {
                     );
 
                     match output {
-                        Ok(output) => {
-                            assert!(output.sense.len() < SENSE_SIZE);
+                        Ok(mut output) => {
+                            if output.sense.len() > SENSE_SIZE {
+                                // That's all the guest has room for; the
+                                // sense key and ASC come first anyway
+                                warn!(
+                                    "Truncating {} bytes of sense data to {}",
+                                    output.sense.len(),
+                                    SENSE_SIZE
+                                );
+                                output.sense.truncate(SENSE_SIZE);
+                            }
 
                             Response {
                                 response: ResponseCode::Ok,
@@ -117,10 +172,25 @@ impl VhostUserScsiBackend {
                             }
                         }
                         Err(CmdError::CdbTooShort) => {
-                            // the CDB buffer is, by default, sized larger than any CDB we support; we don't
-                            // handle writes to config space (because QEMU doesn't let us), so there's no
-                            // way the guest can set it too small
-                            unreachable!();
+                            // The CDB buffer is, by default, sized larger than any CDB we support, and
+                            // we don't handle writes to config space (because QEMU doesn't let us), so
+                            // this shouldn't happen; but if a target thinks so, the CDB is invalid.
+                            error!("CDB too short for its command: {:?}", r.cdb);
+                            let output = CmdOutput::check_condition(sense::INVALID_FIELD_IN_CDB);
+                            Response {
+                                response: ResponseCode::Ok,
+                                status: output.status,
+                                status_qualifier: output.status_qualifier,
+                                sense: output.sense,
+                                residual: body_writer.residual(),
+                            }
+                        }
+                        Err(CmdError::Deferred(delay)) => {
+                            debug!("Deferring request {} by {:?}", r.id, delay);
+                            if let VirtioScsiLun::TargetLun(target, lun) = r.lun {
+                                return Some((target, lun, r.id, delay));
+                            }
+                            unreachable!("parse_target() only accepts target LUNs");
                         }
                         Err(CmdError::DataIn(e)) => {
                             if e.kind() == ErrorKind::WriteZero {
@@ -164,29 +234,240 @@ impl VhostUserScsiBackend {
             // rebooting. So let's just log an error and do nothing.
             error!("Error writing response to guest memory: {:?}", e);
         }
+
+        None
+    }
+
+    /// Process the request in `chain` and hand it back to the guest, unless
+    /// it got deferred. Returns whether the chain was added to the used ring.
+    fn process_chain(
+        &mut self,
+        vring: &VringRwLock,
+        chain: DescriptorChain,
+    ) -> Result<bool, io::Error> {
+        let mut writer = DescriptorChainWriter::new(chain.clone());
+        let mut reader = DescriptorChainReader::new(chain.clone());
+
+        if let Some((target, lun, id, delay)) = self.process_requests(&mut reader, &mut writer) {
+            self.deferred.push_back(DeferredRequest {
+                chain,
+                target,
+                lun,
+                id,
+                resubmit_at: Instant::now() + delay,
+            });
+            self.arm_deferred_timer()?;
+            return Ok(false);
+        }
+
+        vring
+            .add_used(chain.head_index(), writer.max_written())
+            .map_err(io::Error::other)?;
+        Ok(true)
     }
 
-    fn process_request_queue(&mut self, vring: &VringRwLock) -> Result<(), io::Error> {
+    pub(crate) fn process_request_queue(&mut self, vring: &VringRwLock) -> Result<(), io::Error> {
         let chains: Vec<_> = vring
             .get_mut()
             .get_queue_mut()
             .iter(self.mem.as_ref().unwrap().memory())
-            .map_err(|e| io::Error::new(ErrorKind::Other, e))?
+            .map_err(io::Error::other)?
             .collect();
         for dc in chains {
-            let mut writer = DescriptorChainWriter::new(dc.clone());
-            let mut reader = DescriptorChainReader::new(dc.clone());
+            self.process_chain(vring, dc)?;
+        }
 
-            self.process_requests(&mut reader, &mut writer);
+        vring.signal_used_queue().map_err(io::Error::other)?;
+        Ok(())
+    }
 
-            vring
-                .add_used(dc.head_index(), writer.max_written())
-                .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+    /// Arm the deferred request timer for the next request that is due, or
+    /// disarm it if there is none.
+    ///
+    /// Setting the timer also resets its expiration count, so this is what
+    /// acknowledges a `DEFERRED_REQUESTS_EVENT`.
+    fn arm_deferred_timer(&mut self) -> Result<(), io::Error> {
+        match self.deferred.iter().map(|r| r.resubmit_at).min() {
+            // A zero duration would disarm the timer, so fire as soon as
+            // possible instead.
+            Some(next) => self.deferred_timer.reset(
+                next.saturating_duration_since(Instant::now())
+                    .max(Duration::from_nanos(1)),
+                None,
+            ),
+            None => self.deferred_timer.clear(),
         }
+        .map_err(io::Error::from)
+    }
+
+    fn process_deferred_requests(&mut self, vring: &VringRwLock) -> Result<(), io::Error> {
+        let now = Instant::now();
+        let mut completed = false;
 
+        for request in mem::take(&mut self.deferred) {
+            if request.resubmit_at > now {
+                self.deferred.push_back(request);
+            } else {
+                completed |= self.process_chain(vring, request.chain)?;
+            }
+        }
+        self.arm_deferred_timer()?;
+
+        if completed {
+            vring.signal_used_queue().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    /// Complete a request without executing it, e.g. because it was aborted.
+    fn complete_with_error(
+        &self,
+        vring: &VringRwLock,
+        chain: DescriptorChain,
+        code: ResponseCode,
+    ) -> Result<(), io::Error> {
+        let mut writer = DescriptorChainWriter::new(chain.clone());
+        let mut body_writer = writer.clone();
+        body_writer.skip(
+            RESPONSE_HEADER_SIZE + u32::try_from(SENSE_SIZE).expect("SENSE_SIZE should fit 32bit"),
+        );
+        if let Err(e) = Response::error(code, body_writer.residual()).write(&mut writer) {
+            error!("Error writing response to guest memory: {:?}", e);
+        }
         vring
-            .signal_used_queue()
-            .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            .add_used(chain.head_index(), writer.max_written())
+            .map_err(io::Error::other)
+    }
+
+    /// Execute a task management function, and complete the deferred
+    /// requests it aborts.
+    fn task_management(
+        &mut self,
+        request_vring: &VringRwLock,
+        subtype: u32,
+        lun: [u8; 8],
+        tag: u64,
+    ) -> Result<ResponseCode, io::Error> {
+        let function = match subtype {
+            VIRTIO_SCSI_T_TMF_ABORT_TASK => TaskManagementFunction::AbortTask(tag),
+            VIRTIO_SCSI_T_TMF_ABORT_TASK_SET => TaskManagementFunction::AbortTaskSet,
+            VIRTIO_SCSI_T_TMF_CLEAR_ACA => TaskManagementFunction::ClearAca,
+            VIRTIO_SCSI_T_TMF_CLEAR_TASK_SET => TaskManagementFunction::ClearTaskSet,
+            VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET => TaskManagementFunction::ITNexusReset,
+            VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET => TaskManagementFunction::LogicalUnitReset,
+            VIRTIO_SCSI_T_TMF_QUERY_TASK => TaskManagementFunction::QueryTask(tag),
+            VIRTIO_SCSI_T_TMF_QUERY_TASK_SET => TaskManagementFunction::QueryTaskSet,
+            _ => {
+                warn!("Rejecting unknown task management function {}", subtype);
+                return Ok(ResponseCode::FunctionRejected);
+            }
+        };
+        let Some(VirtioScsiLun::TargetLun(target_id, lun)) = VirtioScsiLun::parse(lun) else {
+            return Ok(ResponseCode::BadTarget);
+        };
+        let Some(target) = self.targets.get_mut(usize::from(target_id)) else {
+            return Ok(ResponseCode::BadTarget);
+        };
+
+        debug!("Task management function {:?} for LUN {}", function, lun);
+        match target.task_management(lun, function) {
+            TaskManagementResponse::FunctionComplete => (),
+            TaskManagementResponse::FunctionSucceeded => {
+                return Ok(ResponseCode::FunctionSucceeded)
+            }
+            TaskManagementResponse::FunctionRejected => return Ok(ResponseCode::FunctionRejected),
+            TaskManagementResponse::IncorrectLun => return Ok(ResponseCode::IncorrectLun),
+        }
+
+        let same_lun = |r: &DeferredRequest| r.target == target_id && r.lun == lun;
+        if function == TaskManagementFunction::ClearAca {
+            // Tasks blocked by ACA may continue now.
+            let now = Instant::now();
+            for request in self.deferred.iter_mut().filter(|r| same_lun(r)) {
+                request.resubmit_at = now;
+            }
+        }
+        let aborted = |r: &DeferredRequest| match function {
+            TaskManagementFunction::AbortTask(tag) => same_lun(r) && r.id == tag,
+            TaskManagementFunction::AbortTaskSet
+            | TaskManagementFunction::ClearTaskSet
+            | TaskManagementFunction::LogicalUnitReset => same_lun(r),
+            TaskManagementFunction::ITNexusReset => r.target == target_id,
+            TaskManagementFunction::ClearAca
+            | TaskManagementFunction::QueryTask(_)
+            | TaskManagementFunction::QueryTaskSet => false,
+        };
+        let code = match function {
+            TaskManagementFunction::LogicalUnitReset | TaskManagementFunction::ITNexusReset => {
+                ResponseCode::Reset
+            }
+            _ => ResponseCode::Aborted,
+        };
+
+        let mut completed = false;
+        for request in mem::take(&mut self.deferred) {
+            if aborted(&request) {
+                self.complete_with_error(request_vring, request.chain, code)?;
+                completed = true;
+            } else {
+                self.deferred.push_back(request);
+            }
+        }
+        self.arm_deferred_timer()?;
+        if completed {
+            request_vring
+                .signal_used_queue()
+                .map_err(io::Error::other)?;
+        }
+
+        Ok(ResponseCode::Ok)
+    }
+
+    fn process_control_queue(
+        &mut self,
+        vring: &VringRwLock,
+        request_vring: &VringRwLock,
+    ) -> Result<(), io::Error> {
+        let chains: Vec<_> = vring
+            .get_mut()
+            .get_queue_mut()
+            .iter(self.mem.as_ref().unwrap().memory())
+            .map_err(io::Error::other)?
+            .collect();
+        for chain in chains {
+            let mut reader = DescriptorChainReader::new(chain.clone());
+            let mut writer = DescriptorChainWriter::new(chain.clone());
+
+            let written = match ControlRequest::parse(&mut reader) {
+                Ok(ControlRequest::TaskManagement { subtype, lun, tag }) => {
+                    let code = self.task_management(request_vring, subtype, lun, tag)?;
+                    writer.write_all(&[code as u8])
+                }
+                Ok(ControlRequest::AsyncNotification) => {
+                    // We don't support any asynchronous notifications.
+                    writer
+                        .write_all(&0_u32.to_le_bytes())
+                        .and_then(|()| writer.write_all(&[VIRTIO_SCSI_S_OK as u8]))
+                }
+                Ok(ControlRequest::Unknown(type_)) => {
+                    error!("Ignoring control request of unknown type {}", type_);
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("Error reading control request from guest memory: {:?}", e);
+                    Ok(())
+                }
+            };
+            if let Err(e) = written {
+                error!("Error writing control response to guest memory: {:?}", e);
+            }
+
+            vring
+                .add_used(chain.head_index(), writer.max_written())
+                .map_err(io::Error::other)?;
+        }
+
+        vring.signal_used_queue().map_err(io::Error::other)?;
         Ok(())
     }
 
@@ -215,6 +496,8 @@ impl VhostUserBackendMut<VringRwLock> for VhostUserScsiBackend {
     }
 
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        // No INFLIGHT_SHMFD: vhost-user-backend (as of 0.10) rejects
+        // GET_INFLIGHT_FD and SET_INFLIGHT_FD itself, see the README.
         VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG
     }
 
@@ -228,6 +511,19 @@ impl VhostUserBackendMut<VringRwLock> for VhostUserScsiBackend {
     ) -> std::result::Result<(), std::io::Error> {
         info!("Memory updated - guest probably booting");
         self.mem = Some(atomic_mem);
+
+        // Deferred requests refer to the old memory and queues, so they can't
+        // be completed anymore; the guest has forgotten about them anyway.
+        // Their tasks go as well, along with any ACA, like after an I_T nexus
+        // reset; otherwise they would block the guest's new tasks forever.
+        if !self.deferred.is_empty() {
+            warn!("Dropping {} deferred requests", self.deferred.len());
+            self.deferred.clear();
+        }
+        self.deferred_timer.clear().map_err(io::Error::from)?;
+        for target in &mut self.targets {
+            target.task_management(0, TaskManagementFunction::ITNexusReset);
+        }
         Ok(())
     }
 
@@ -240,12 +536,22 @@ impl VhostUserBackendMut<VringRwLock> for VhostUserScsiBackend {
     ) -> io::Result<bool> {
         assert!(evset == EventSet::IN);
         assert!(vrings.len() == 3);
-        assert!((device_event as usize) < vrings.len());
         assert!(thread_id == 0);
 
-        let vring = &vrings[device_event as usize];
+        let request_gate = Arc::clone(&self.request_gate);
+        let _processing = request_gate.lock().unwrap();
+
         match device_event {
-            REQUEST_QUEUE => {
+            CONTROL_QUEUE | REQUEST_QUEUE => {
+                let vring = &vrings[usize::from(device_event)];
+                let request_vring = &vrings[usize::from(REQUEST_QUEUE)];
+                let process = |backend: &mut Self| {
+                    if device_event == CONTROL_QUEUE {
+                        backend.process_control_queue(vring, request_vring)
+                    } else {
+                        backend.process_request_queue(vring)
+                    }
+                };
                 if self.event_idx {
                     // vm-virtio's Queue implementation only checks avail_index
                     // once, so to properly support EVENT_IDX we need to keep
@@ -253,16 +559,19 @@ impl VhostUserBackendMut<VringRwLock> for VhostUserScsiBackend {
                     // new requests on the queue.
                     loop {
                         vring.disable_notification().unwrap();
-                        self.process_request_queue(vring)?;
+                        process(self)?;
                         if !vring.enable_notification().unwrap() {
                             break;
                         }
                     }
                 } else {
                     // Without EVENT_IDX, a single call is enough.
-                    self.process_request_queue(vring)?;
+                    process(self)?;
                 }
             }
+            DEFERRED_REQUESTS_EVENT => {
+                self.process_deferred_requests(&vrings[usize::from(REQUEST_QUEUE)])?;
+            }
             _ => {
                 error!("Ignoring descriptor on queue {}", device_event);
             }
@@ -321,17 +630,20 @@ mod tests {
         convert::TryInto,
         io::{self, Read, Write},
         sync::{Arc, Mutex},
+        thread,
+        time::Duration,
     };
 
     use vhost_user_backend::{VhostUserBackendMut, VringRwLock, VringT};
     use virtio_bindings::{
         virtio_ring::VRING_DESC_F_WRITE,
         virtio_scsi::{
-            virtio_scsi_cmd_req, virtio_scsi_config, VIRTIO_SCSI_S_BAD_TARGET,
-            VIRTIO_SCSI_S_FAILURE, VIRTIO_SCSI_S_OK,
+            virtio_scsi_cmd_req, virtio_scsi_config, virtio_scsi_ctrl_tmf_req,
+            VIRTIO_SCSI_S_ABORTED, VIRTIO_SCSI_S_BAD_TARGET, VIRTIO_SCSI_S_FAILURE,
+            VIRTIO_SCSI_S_OK, VIRTIO_SCSI_T_TMF, VIRTIO_SCSI_T_TMF_ABORT_TASK,
         },
     };
-    use virtio_queue::{mock::MockSplitQueue, Descriptor};
+    use virtio_queue::{mock::MockSplitQueue, Descriptor, QueueT};
     use vm_memory::{
         Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
         GuestMemoryMmap,
@@ -339,13 +651,27 @@ mod tests {
 
     use super::VhostUserScsiBackend;
     use crate::{
-        scsi::{CmdOutput, Target, TaskAttr},
+        scsi::{
+            emulation::{
+                block_device::{BlockDevice, FileBackend},
+                target::EmulatedTarget,
+                throttle::{IoDirection, Throttle, ThrottleLimits},
+            },
+            sense, CmdOutput, Target, TaskAttr, TaskManagementFunction, TaskManagementResponse,
+        },
         virtio::{
             tests::{VirtioScsiCmdReq, VirtioScsiCmdResp},
-            VirtioScsiLun, CDB_SIZE,
+            VirtioScsiLun, CDB_SIZE, SENSE_SIZE,
         },
     };
 
+    #[derive(Debug, Default, Clone, Copy)]
+    #[repr(transparent)]
+    struct VirtioScsiCtrlTmfReq(virtio_scsi_ctrl_tmf_req);
+    /// SAFETY: struct is a transparent wrapper around the request
+    /// which can be read from a byte array
+    unsafe impl ByteValued for VirtioScsiCtrlTmfReq {}
+
     #[allow(dead_code)]
     struct RecordedCommand {
         lun: u16,
@@ -409,6 +735,14 @@ mod tests {
             });
             (self.callback)(lun, req)
         }
+
+        fn task_management(
+            &mut self,
+            _lun: u16,
+            _function: TaskManagementFunction,
+        ) -> TaskManagementResponse {
+            TaskManagementResponse::FunctionComplete
+        }
     }
 
     fn setup(
@@ -467,6 +801,42 @@ mod tests {
         (backend, vring, mem)
     }
 
+    /// Put `req` on a control queue, set up in the same memory as `setup()`.
+    fn setup_control_queue(
+        mem: &GuestMemoryAtomic<GuestMemoryMmap>,
+        req: impl ByteValued,
+    ) -> VringRwLock {
+        let v = vec![
+            Descriptor::new(0x40_0000, 0x100, 0, 0), // request
+            Descriptor::new(0x50_0000, 0x100, VRING_DESC_F_WRITE as u16, 0), // response
+        ];
+        mem.memory()
+            .write_obj(req, GuestAddress(0x40_0000))
+            .expect("writing to succeed");
+
+        let mem_handle = mem.memory();
+        let queue = MockSplitQueue::create(&*mem_handle, GuestAddress(0x30_0000), 16);
+        queue.build_desc_chain(&v).unwrap();
+        mem.memory()
+            .write_obj(0u16, queue.avail_addr().unchecked_add(4))
+            .unwrap();
+        mem.memory()
+            .write_obj(1u16, queue.avail_addr().unchecked_add(2))
+            .unwrap();
+
+        let vring = VringRwLock::new(mem.clone(), 16).unwrap();
+        vring.set_queue_size(16);
+        vring
+            .set_queue_info(
+                queue.desc_table_addr().0,
+                queue.avail_addr().0,
+                queue.used_addr().0,
+            )
+            .unwrap();
+        vring.set_queue_ready(true);
+        vring
+    }
+
     fn get_response(mem: &GuestMemoryAtomic<GuestMemoryMmap>) -> VirtioScsiCmdResp {
         mem.memory()
             .read_obj::<VirtioScsiCmdResp>(GuestAddress(0x20_0000))
@@ -553,6 +923,230 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_oversized_sense_data() {
+        let collector = FakeTargetCommandCollector::new();
+        let fake_target = Box::new(FakeTarget::new(collector, |_, _| {
+            Ok(CmdOutput {
+                status: CmdOutput::CHECK_CONDITION,
+                status_qualifier: 0,
+                sense: vec![0x70; SENSE_SIZE + 1],
+            })
+        }));
+
+        let req = VirtioScsiCmdReq(virtio_scsi_cmd_req {
+            lun: create_lun_specifier(0, 0),
+            tag: 0,
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        });
+
+        let (mut backend, vring, mem) = setup(req);
+        backend.add_target(fake_target);
+        backend.process_request_queue(&vring).unwrap();
+
+        // the sense data is truncated to what fits the sense buffer
+        let res = get_response(&mem);
+        assert_eq!(res.0.response, VIRTIO_SCSI_S_OK as u8);
+        assert_eq!(res.0.status, CmdOutput::CHECK_CONDITION);
+        assert_eq!(res.0.sense_len as usize, SENSE_SIZE);
+    }
+
+    #[test]
+    fn test_cdb_too_short() {
+        let collector = FakeTargetCommandCollector::new();
+        let fake_target = Box::new(FakeTarget::new(collector, |_, _| {
+            Err(crate::scsi::CmdError::CdbTooShort)
+        }));
+
+        let req = VirtioScsiCmdReq(virtio_scsi_cmd_req {
+            lun: create_lun_specifier(0, 0),
+            tag: 0,
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        });
+
+        let (mut backend, vring, mem) = setup(req);
+        backend.add_target(fake_target);
+        backend.process_request_queue(&vring).unwrap();
+
+        let res = get_response(&mem);
+        assert_eq!(res.0.response, VIRTIO_SCSI_S_OK as u8);
+        assert_eq!(res.0.status, CmdOutput::CHECK_CONDITION);
+        let sense = sense::INVALID_FIELD_IN_CDB.to_fixed_sense();
+        assert_eq!(res.0.sense_len as usize, sense.len());
+        assert_eq!(&res.0.sense[..sense.len()], &sense[..]);
+    }
+
+    #[test]
+    fn test_deferred_request() {
+        let collector = FakeTargetCommandCollector::new();
+        let mut deferrals = 0;
+        let fake_target = Box::new(FakeTarget::new(collector.clone(), move |_, _| {
+            deferrals += 1;
+            if deferrals == 1 {
+                Err(crate::scsi::CmdError::Deferred(Duration::from_millis(1)))
+            } else {
+                Ok(CmdOutput::ok())
+            }
+        }));
+
+        let req = VirtioScsiCmdReq(virtio_scsi_cmd_req {
+            lun: create_lun_specifier(0, 0),
+            tag: 0,
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        });
+
+        let (mut backend, vring, mem) = setup(req);
+        backend.add_target(fake_target);
+        backend.process_request_queue(&vring).unwrap();
+
+        assert_eq!(backend.deferred.len(), 1);
+        assert_eq!(vring.get_ref().get_queue().next_used(), 0);
+
+        thread::sleep(Duration::from_millis(2));
+        backend.process_deferred_requests(&vring).unwrap();
+
+        assert!(backend.deferred.is_empty());
+        assert_eq!(vring.get_ref().get_queue().next_used(), 1);
+        let res = get_response(&mem);
+        assert_eq!(res.0.response, VIRTIO_SCSI_S_OK as u8);
+
+        let collector = collector.lock().unwrap();
+        assert_eq!(
+            collector.received_commands.len(),
+            2,
+            "expect the command to be passed to Target again"
+        );
+    }
+
+    #[test]
+    fn test_abort_deferred_request() {
+        let collector = FakeTargetCommandCollector::new();
+        let fake_target = Box::new(FakeTarget::new(collector, |_, _| {
+            Err(crate::scsi::CmdError::Deferred(Duration::from_secs(3600)))
+        }));
+
+        let req = VirtioScsiCmdReq(virtio_scsi_cmd_req {
+            lun: create_lun_specifier(0, 0),
+            tag: 42,
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        });
+
+        let (mut backend, vring, mem) = setup(req);
+        backend.add_target(fake_target);
+        backend.process_request_queue(&vring).unwrap();
+        assert_eq!(backend.deferred.len(), 1);
+
+        let tmf = VirtioScsiCtrlTmfReq(virtio_scsi_ctrl_tmf_req {
+            type_: VIRTIO_SCSI_T_TMF,
+            subtype: VIRTIO_SCSI_T_TMF_ABORT_TASK,
+            lun: create_lun_specifier(0, 0),
+            tag: 42,
+        });
+        let control_vring = setup_control_queue(&mem, tmf);
+        backend
+            .process_control_queue(&control_vring, &vring)
+            .unwrap();
+
+        assert!(backend.deferred.is_empty());
+        assert_eq!(vring.get_ref().get_queue().next_used(), 1);
+        assert_eq!(get_response(&mem).0.response, VIRTIO_SCSI_S_ABORTED as u8);
+
+        assert_eq!(control_vring.get_ref().get_queue().next_used(), 1);
+        let tmf_response: u8 = mem.memory().read_obj(GuestAddress(0x50_0000)).unwrap();
+        assert_eq!(tmf_response, VIRTIO_SCSI_S_OK as u8);
+    }
+
+    #[test]
+    fn test_update_memory_drops_deferred_requests() {
+        let collector = FakeTargetCommandCollector::new();
+        let fake_target = Box::new(FakeTarget::new(collector, |_, _| {
+            Err(crate::scsi::CmdError::Deferred(Duration::from_secs(3600)))
+        }));
+
+        let req = VirtioScsiCmdReq(virtio_scsi_cmd_req {
+            lun: create_lun_specifier(0, 0),
+            tag: 0,
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        });
+
+        let (mut backend, vring, mem) = setup(req);
+        backend.add_target(fake_target);
+        backend.process_request_queue(&vring).unwrap();
+        assert_eq!(backend.deferred.len(), 1);
+        assert!(backend.deferred_timer.is_armed().unwrap());
+
+        backend.update_memory(mem).unwrap();
+        assert!(backend.deferred.is_empty());
+        assert!(!backend.deferred_timer.is_armed().unwrap());
+        assert_eq!(vring.get_ref().get_queue().next_used(), 0);
+    }
+
+    #[test]
+    fn test_update_memory_resets_task_sets() {
+        let image = tempfile::tempfile().unwrap();
+        image.set_len(1024 * 1024).unwrap();
+        let throttle = Arc::new(Throttle::new(ThrottleLimits {
+            read_iops: Some(1),
+            ..Default::default()
+        }));
+        // exhaust the throttle, and then some
+        throttle.admit(IoDirection::Read, 512).unwrap();
+        throttle.admit(IoDirection::Read, 512).unwrap();
+        let mut dev = BlockDevice::new(FileBackend::new(image));
+        dev.set_throttle(throttle);
+        let mut target = EmulatedTarget::new();
+        target.add_lun(Box::new(dev));
+
+        // An ORDERED READ (10), which the throttle defers.
+        let mut cdb = [0; CDB_SIZE];
+        cdb[..10].copy_from_slice(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        let req = VirtioScsiCmdReq(virtio_scsi_cmd_req {
+            lun: create_lun_specifier(0, 0),
+            tag: 1,
+            task_attr: 1,
+            prio: 0,
+            crn: 0,
+            cdb,
+        });
+
+        let (mut backend, vring, mem) = setup(req);
+        backend.add_target(Box::new(target));
+        backend.process_request_queue(&vring).unwrap();
+        assert_eq!(backend.deferred.len(), 1);
+
+        backend.update_memory(mem).unwrap();
+
+        // A SIMPLE TEST UNIT READY isn't blocked by the dropped ORDERED task.
+        let output = backend.targets[0].execute_command(
+            0,
+            &mut &[][..],
+            &mut Vec::new(),
+            crate::scsi::Request {
+                id: 2,
+                cdb: &[0; 6],
+                task_attr: TaskAttr::Simple,
+                crn: 0,
+                prio: 0,
+            },
+        );
+        assert_eq!(output.unwrap().status, CmdOutput::GOOD);
+    }
+
     #[test]
     fn test_command_to_unknown_lun() {
         let collector = FakeTargetCommandCollector::new();
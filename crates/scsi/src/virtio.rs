@@ -13,7 +13,10 @@ use std::{
 };
 
 use log::error;
-use virtio_bindings::virtio_scsi::virtio_scsi_cmd_req;
+use virtio_bindings::virtio_scsi::{
+    virtio_scsi_cmd_req, virtio_scsi_ctrl_an_req, virtio_scsi_ctrl_tmf_req, VIRTIO_SCSI_T_AN_QUERY,
+    VIRTIO_SCSI_T_AN_SUBSCRIBE, VIRTIO_SCSI_T_TMF,
+};
 use virtio_queue::{Descriptor, DescriptorChain, DescriptorChainRwIter};
 use vm_memory::{Bytes, GuestAddress, GuestMemory};
 
@@ -59,8 +62,13 @@ impl VirtioScsiLun {
 pub(crate) enum ResponseCode {
     Ok = 0,
     Overrun = 1,
+    Aborted = 2,
     BadTarget = 3,
+    Reset = 4,
     Failure = 9,
+    FunctionSucceeded = 10,
+    FunctionRejected = 11,
+    IncorrectLun = 12,
 }
 
 // These are the defaults given in the virtio spec; QEMU doesn't let the driver
@@ -107,6 +115,50 @@ impl Request {
     }
 }
 
+/// A request on the control queue, see 5.6.6.2 of virtio v1.1.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ControlRequest {
+    /// A task management function. `lun` is left unparsed, since a bad LUN
+    /// is reported in the response rather than failing the request.
+    TaskManagement {
+        subtype: u32,
+        lun: [u8; 8],
+        tag: u64,
+    },
+    /// An asynchronous notification query or subscription.
+    AsyncNotification,
+    Unknown(u32),
+}
+
+impl ControlRequest {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, io::Error> {
+        let mut type_ = [0; 4];
+        reader.read_exact(&mut type_)?;
+
+        match u32::from_le_bytes(type_) {
+            VIRTIO_SCSI_T_TMF => {
+                let mut request = [0; mem::size_of::<virtio_scsi_ctrl_tmf_req>() - 4];
+                reader.read_exact(&mut request)?;
+                Ok(Self::TaskManagement {
+                    subtype: u32::from_le_bytes(
+                        request[0..4].try_into().expect("slice is of length 4"),
+                    ),
+                    lun: request[4..12].try_into().expect("slice is of length 8"),
+                    tag: u64::from_le_bytes(
+                        request[12..20].try_into().expect("slice is of length 8"),
+                    ),
+                })
+            }
+            VIRTIO_SCSI_T_AN_QUERY | VIRTIO_SCSI_T_AN_SUBSCRIBE => {
+                let mut request = [0; mem::size_of::<virtio_scsi_ctrl_an_req>() - 4];
+                reader.read_exact(&mut request)?;
+                Ok(Self::AsyncNotification)
+            }
+            type_ => Ok(Self::Unknown(type_)),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct Response {
     pub response: ResponseCode,
@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Capture of the packets exchanged with the guest into a pcap file, in the
+//! `LINKTYPE_VSOCK` format of the Linux vsockmon device, which Wireshark
+//! decodes.
+//!
+//! Every record is an `af_vsockmon_hdr`, followed by the virtio vsock header
+//! and the payload of the packet.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use virtio_vsock::packet::{VsockPacket, PKT_HEADER_SIZE};
+use vm_memory::bitmap::BitmapSlice;
+
+use crate::vhu_vsock::{
+    VSOCK_OP_CREDIT_REQUEST, VSOCK_OP_CREDIT_UPDATE, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE,
+    VSOCK_OP_RST, VSOCK_OP_RW, VSOCK_OP_SHUTDOWN,
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 0x4_0000;
+const PCAP_GLOBAL_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+const LINKTYPE_VSOCK: u32 = 271;
+
+/// Size of `struct af_vsockmon_hdr`.
+const VSOCKMON_HEADER_SIZE: usize = 32;
+// enum af_vsockmon_op
+const AF_VSOCK_OP_UNKNOWN: u16 = 0;
+const AF_VSOCK_OP_CONNECT: u16 = 1;
+const AF_VSOCK_OP_DISCONNECT: u16 = 2;
+const AF_VSOCK_OP_CONTROL: u16 = 3;
+const AF_VSOCK_OP_PAYLOAD: u16 = 4;
+// enum af_vsockmon_transport
+const AF_VSOCK_TRANSPORT_VIRTIO: u16 = 2;
+
+/// A pcap file packets are appended to.
+#[derive(Debug)]
+pub(crate) struct PacketCapture {
+    file: File,
+}
+
+impl PacketCapture {
+    /// Open the capture file at `path`, appending to it if it already holds a
+    /// capture, e.g. from a previous connection of the VMM.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            let mut header = [0u8; PCAP_GLOBAL_HEADER_SIZE];
+            LittleEndian::write_u32(&mut header[0..4], PCAP_MAGIC);
+            LittleEndian::write_u16(&mut header[4..6], PCAP_VERSION_MAJOR);
+            LittleEndian::write_u16(&mut header[6..8], PCAP_VERSION_MINOR);
+            // thiszone and sigfigs are always 0
+            LittleEndian::write_u32(&mut header[16..20], PCAP_SNAPLEN);
+            LittleEndian::write_u32(&mut header[20..24], LINKTYPE_VSOCK);
+            file.write_all(&header)?;
+        }
+        Ok(Self { file })
+    }
+
+    /// Append a packet, with the part of its data its header accounts for.
+    pub fn write_pkt<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) -> io::Result<()> {
+        let mut hdr = [0u8; PKT_HEADER_SIZE];
+        pkt.header_slice().copy_to(&mut hdr);
+        let mut data = Vec::new();
+        if let Some(buf) = pkt.data_slice() {
+            data.resize(std::cmp::min(buf.len(), pkt.len() as usize), 0);
+            buf.copy_to(&mut data);
+        }
+        self.write_record(pkt, &hdr, &data)
+    }
+
+    fn write_record<B: BitmapSlice>(
+        &mut self,
+        pkt: &VsockPacket<B>,
+        hdr: &[u8],
+        data: &[u8],
+    ) -> io::Result<()> {
+        let len = VSOCKMON_HEADER_SIZE + hdr.len() + data.len();
+        let mut record = vec![0u8; PCAP_RECORD_HEADER_SIZE + VSOCKMON_HEADER_SIZE];
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        LittleEndian::write_u32(&mut record[0..4], now.as_secs() as u32);
+        LittleEndian::write_u32(&mut record[4..8], now.subsec_micros());
+        LittleEndian::write_u32(&mut record[8..12], len as u32);
+        LittleEndian::write_u32(&mut record[12..16], len as u32);
+
+        let vsockmon = &mut record[PCAP_RECORD_HEADER_SIZE..];
+        LittleEndian::write_u64(&mut vsockmon[0..8], pkt.src_cid());
+        LittleEndian::write_u64(&mut vsockmon[8..16], pkt.dst_cid());
+        LittleEndian::write_u32(&mut vsockmon[16..20], pkt.src_port());
+        LittleEndian::write_u32(&mut vsockmon[20..24], pkt.dst_port());
+        LittleEndian::write_u16(&mut vsockmon[24..26], vsockmon_op(pkt.op()));
+        LittleEndian::write_u16(&mut vsockmon[26..28], AF_VSOCK_TRANSPORT_VIRTIO);
+        LittleEndian::write_u16(&mut vsockmon[28..30], hdr.len() as u16);
+
+        record.extend_from_slice(hdr);
+        record.extend_from_slice(data);
+        // A single write, so that records aren't torn if writing fails
+        self.file.write_all(&record)
+    }
+}
+
+/// Map a virtio vsock operation to its vsockmon one, like the Linux
+/// virtio transport does.
+fn vsockmon_op(op: u16) -> u16 {
+    match op {
+        VSOCK_OP_REQUEST | VSOCK_OP_RESPONSE => AF_VSOCK_OP_CONNECT,
+        VSOCK_OP_RST | VSOCK_OP_SHUTDOWN => AF_VSOCK_OP_DISCONNECT,
+        VSOCK_OP_RW => AF_VSOCK_OP_PAYLOAD,
+        VSOCK_OP_CREDIT_REQUEST | VSOCK_OP_CREDIT_UPDATE => AF_VSOCK_OP_CONTROL,
+        _ => AF_VSOCK_OP_UNKNOWN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhu_vsock::{VSOCK_HOST_CID, VSOCK_TYPE_STREAM};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_packet_capture() {
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let path = test_dir.path().join("test_packet_capture.pcap");
+        let path = path.to_str().unwrap();
+
+        let mut pkt_raw = [0u8; PKT_HEADER_SIZE + 8];
+        let (hdr_raw, data_raw) = pkt_raw.split_at_mut(PKT_HEADER_SIZE);
+        data_raw.copy_from_slice(b"hello!!!");
+        // SAFETY: Safe as hdr_raw and data_raw are guaranteed to be valid.
+        let mut pkt = unsafe { VsockPacket::new(hdr_raw, Some(data_raw)).unwrap() };
+        pkt.set_src_cid(3)
+            .set_dst_cid(VSOCK_HOST_CID)
+            .set_src_port(4321)
+            .set_dst_port(1234)
+            .set_type(VSOCK_TYPE_STREAM)
+            .set_op(VSOCK_OP_RW)
+            .set_len(5);
+
+        let mut capture = PacketCapture::open(path).unwrap();
+        capture.write_pkt(&pkt).unwrap();
+        drop(capture);
+
+        let pcap = fs::read(path).unwrap();
+        assert_eq!(LittleEndian::read_u32(&pcap[0..4]), PCAP_MAGIC);
+        assert_eq!(LittleEndian::read_u32(&pcap[20..24]), LINKTYPE_VSOCK);
+        let record_len = VSOCKMON_HEADER_SIZE + PKT_HEADER_SIZE + 5;
+        assert_eq!(
+            pcap.len(),
+            PCAP_GLOBAL_HEADER_SIZE + PCAP_RECORD_HEADER_SIZE + record_len
+        );
+
+        let record = &pcap[PCAP_GLOBAL_HEADER_SIZE..];
+        assert_eq!(LittleEndian::read_u32(&record[8..12]), record_len as u32);
+        assert_eq!(LittleEndian::read_u32(&record[12..16]), record_len as u32);
+        let vsockmon = &record[PCAP_RECORD_HEADER_SIZE..];
+        assert_eq!(LittleEndian::read_u64(&vsockmon[0..8]), 3);
+        assert_eq!(LittleEndian::read_u64(&vsockmon[8..16]), VSOCK_HOST_CID);
+        assert_eq!(LittleEndian::read_u32(&vsockmon[16..20]), 4321);
+        assert_eq!(LittleEndian::read_u32(&vsockmon[20..24]), 1234);
+        assert_eq!(
+            LittleEndian::read_u16(&vsockmon[24..26]),
+            AF_VSOCK_OP_PAYLOAD
+        );
+        assert_eq!(
+            LittleEndian::read_u16(&vsockmon[26..28]),
+            AF_VSOCK_TRANSPORT_VIRTIO
+        );
+        assert_eq!(
+            LittleEndian::read_u16(&vsockmon[28..30]) as usize,
+            PKT_HEADER_SIZE
+        );
+        let hdr = &vsockmon[VSOCKMON_HEADER_SIZE..VSOCKMON_HEADER_SIZE + PKT_HEADER_SIZE];
+        assert_eq!(LittleEndian::read_u16(&hdr[30..32]), VSOCK_OP_RW);
+        assert_eq!(
+            &vsockmon[VSOCKMON_HEADER_SIZE + PKT_HEADER_SIZE..],
+            b"hello"
+        );
+
+        // reopening appends records without another global header
+        let mut capture = PacketCapture::open(path).unwrap();
+        pkt.set_op(VSOCK_OP_RST).set_len(0);
+        capture.write_pkt(&pkt).unwrap();
+        let pcap = fs::read(path).unwrap();
+        let second = &pcap[PCAP_GLOBAL_HEADER_SIZE + PCAP_RECORD_HEADER_SIZE + record_len..];
+        assert_eq!(
+            second.len(),
+            PCAP_RECORD_HEADER_SIZE + VSOCKMON_HEADER_SIZE + PKT_HEADER_SIZE
+        );
+        assert_eq!(
+            LittleEndian::read_u16(&second[PCAP_RECORD_HEADER_SIZE + 24..]),
+            AF_VSOCK_OP_DISCONNECT
+        );
+
+        test_dir.close().unwrap();
+    }
+}
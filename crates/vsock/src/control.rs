@@ -0,0 +1,432 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! A Unix socket for adding and removing devices while the daemon is running.
+//!
+//! The protocol is line based: every line sent by the client is a command, and
+//! gets a single line in response, which is either `OK` or `ERROR`, followed
+//! by a space and further information. The supported commands are:
+//!
+//! - `add <params>`: start a device for another guest, with parameters in the
+//!   same format as `--vm` (e.g. `guest-cid=5,socket=/tmp/vhost5.socket,uds-path=/tmp/vm5.vsock`).
+//! - `remove <cid>`: stop the device of the guest with the given CID. A device
+//!   in use by a VMM stops processing packets immediately, and is torn down
+//!   once the VMM disconnects.
+//! - `list`: show the CIDs of the running devices.
+//! - `stats [<cid>]`: show the state of the connections and the packet
+//!   counters of a device, or of all devices keyed by CID, as JSON. The
+//!   counters restart whenever a VMM connects to the device.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        io::BorrowedFd,
+        net::{UnixListener, UnixStream},
+        prelude::{AsRawFd, OwnedFd, RawFd},
+    },
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+};
+
+use log::{debug, error, info, warn};
+use thiserror::Error as ThisError;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::{
+    create_backend, parse_vm_params, start_backend_server,
+    stats::DeviceStats,
+    vhu_vsock::{CidMap, VhostUserVsockBackend, VsockConfig},
+    BackendError, VmArgsParseError,
+};
+
+#[derive(Debug, ThisError)]
+pub(crate) enum ControlError {
+    #[error("Empty command")]
+    EmptyCommand,
+    #[error("Unknown command `{0}`")]
+    UnknownCommand(String),
+    #[error("Missing argument `{0}`")]
+    MissingArgument(&'static str),
+    #[error("Too many arguments")]
+    TooManyArguments,
+    #[error("Invalid device parameters: {0}")]
+    InvalidParams(VmArgsParseError),
+    #[error("Invalid CID `{0}`")]
+    InvalidCid(String),
+    #[error("No device for guest CID {0}")]
+    NoSuchDevice(u64),
+    #[error("{0}")]
+    Backend(BackendError),
+}
+
+/// The outcome of a device's server thread, with the guest CID of the device.
+pub(crate) type DeviceResult = (u64, Result<(), BackendError>);
+
+#[derive(Default)]
+struct StopState {
+    stopped: bool,
+    /// Another handle to the socket a VMM is awaited on.
+    listener: Option<OwnedFd>,
+    /// The exit event of the backend serving a VMM.
+    exit_event: Option<EventFd>,
+}
+
+/// Shared between the server thread of a device and the control socket, to
+/// stop the thread, waking it up if it's waiting for a VMM to connect, and to
+/// inspect the backend it's serving.
+#[derive(Default)]
+pub(crate) struct DeviceHandle {
+    state: Mutex<StopState>,
+    backend: Mutex<Option<Arc<VhostUserVsockBackend>>>,
+}
+
+impl DeviceHandle {
+    /// Set the backend the server thread uses for the next VMM, or `None`
+    /// while it replaces the previous one.
+    pub(crate) fn set_backend(&self, backend: Option<Arc<VhostUserVsockBackend>>) {
+        *self.backend.lock().unwrap() = backend;
+    }
+
+    pub(crate) fn stats(&self) -> Option<DeviceStats> {
+        let backend = self.backend.lock().unwrap().clone();
+        backend.map(|backend| backend.stats())
+    }
+
+    /// Request the server thread to stop.
+    pub(crate) fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        if let Some(listener) = &state.listener {
+            // SAFETY: shutdown() takes no pointers, and the fd is valid.
+            // It makes the accept() waiting for a VMM fail.
+            unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RDWR) };
+        }
+        if let Some(exit_event) = &state.exit_event {
+            let _ = exit_event.write(1);
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped
+    }
+
+    /// Set what `stop()` has to wake up: the socket listening for a VMM, or
+    /// the exit event of the backend serving one.
+    ///
+    /// Returns `false` if the thread was already requested to stop.
+    pub(crate) fn watch(&self, listener: Option<RawFd>, exit_event: Option<&EventFd>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        // SAFETY: the caller's listener outlives this call, and the fd is
+        // only borrowed to duplicate it.
+        state.listener = listener.and_then(|fd| {
+            unsafe { BorrowedFd::borrow_raw(fd) }
+                .try_clone_to_owned()
+                .map_err(|e| warn!("Could not duplicate the vhost-user listener: {}", e))
+                .ok()
+        });
+        state.exit_event = exit_event.and_then(|event| event.try_clone().ok());
+        !state.stopped
+    }
+}
+
+/// The devices of the daemon, which the control socket adds and removes.
+pub(crate) struct Devices {
+    cid_map: Arc<RwLock<CidMap>>,
+    devices: Mutex<BTreeMap<u64, Arc<DeviceHandle>>>,
+    results: Sender<DeviceResult>,
+}
+
+impl Devices {
+    /// Create an empty set of devices, and the receiver of the outcomes of
+    /// their server threads.
+    pub(crate) fn new() -> (Self, Receiver<DeviceResult>) {
+        let (results, receiver) = mpsc::channel();
+        let devices = Self {
+            cid_map: Arc::new(RwLock::new(HashMap::new())),
+            devices: Mutex::new(BTreeMap::new()),
+            results,
+        };
+        (devices, receiver)
+    }
+
+    /// Create the backend of a device, and start serving VMMs with it in a
+    /// new thread.
+    pub(crate) fn add(&self, config: VsockConfig) -> Result<(), BackendError> {
+        let cid = config.get_guest_cid();
+        let mut devices = self.devices.lock().unwrap();
+        if devices.contains_key(&cid) {
+            return Err(BackendError::GuestCidInUse(cid));
+        }
+
+        let backend = create_backend(&config, &self.cid_map)?;
+        let handle = Arc::new(DeviceHandle::default());
+        handle.set_backend(Some(backend.clone()));
+        let thread_handle = handle.clone();
+        let cid_map = self.cid_map.clone();
+        let results = self.results.clone();
+        thread::Builder::new()
+            .name(format!("vhu-vsock-cid-{}", cid))
+            .spawn(move || {
+                let result = start_backend_server(config, cid_map, backend, thread_handle);
+                let _ = results.send((cid, result));
+            })
+            .unwrap();
+
+        devices.insert(cid, handle);
+        Ok(())
+    }
+
+    /// Stop the device of the guest with CID `cid`.
+    pub(crate) fn remove(&self, cid: u64) -> Result<(), ControlError> {
+        let handle = self
+            .devices
+            .lock()
+            .unwrap()
+            .remove(&cid)
+            .ok_or(ControlError::NoSuchDevice(cid))?;
+        handle.stop();
+        // Siblings can't reach the guest anymore, even while its VMM is still
+        // connected
+        self.cid_map.write().unwrap().remove(&cid);
+        Ok(())
+    }
+
+    /// Return the CIDs of the guests that have a device.
+    pub(crate) fn cids(&self) -> Vec<u64> {
+        self.devices.lock().unwrap().keys().copied().collect()
+    }
+
+    fn stats(&self, cid: Option<&str>) -> Result<String, ControlError> {
+        let devices = self.devices.lock().unwrap();
+        // unwraps are safe: the stats only contain strings and integers
+        if let Some(cid) = cid {
+            let cid = parse_cid(cid)?;
+            let stats = devices
+                .get(&cid)
+                .and_then(|handle| handle.stats())
+                .ok_or(ControlError::NoSuchDevice(cid))?;
+            Ok(serde_json::to_string(&stats).unwrap())
+        } else {
+            let stats: BTreeMap<_, _> = devices
+                .iter()
+                .filter_map(|(cid, handle)| Some((cid, handle.stats()?)))
+                .collect();
+            Ok(serde_json::to_string(&stats).unwrap())
+        }
+    }
+
+    /// Execute a single command, returning the text of the `OK` response.
+    pub(crate) fn handle_command(&self, line: &str) -> Result<String, ControlError> {
+        let mut args = line.split_whitespace();
+        let command = args.next().ok_or(ControlError::EmptyCommand)?;
+        // every command takes at most one argument, check before acting
+        let arg = args.next();
+        if args.next().is_some() {
+            return Err(ControlError::TooManyArguments);
+        }
+
+        match command {
+            "add" => {
+                let params = arg.ok_or(ControlError::MissingArgument("params"))?;
+                let config = parse_vm_params(params).map_err(ControlError::InvalidParams)?;
+                let cid = config.get_guest_cid();
+                self.add(config).map_err(ControlError::Backend)?;
+                info!("Added the device of guest CID {}", cid);
+                Ok(format!("cid={}", cid))
+            }
+            "remove" => {
+                let cid = parse_cid(arg.ok_or(ControlError::MissingArgument("cid"))?)?;
+                self.remove(cid)?;
+                info!("Removed the device of guest CID {}", cid);
+                Ok(format!("cid={}", cid))
+            }
+            "stats" => self.stats(arg),
+            "list" if arg.is_some() => Err(ControlError::TooManyArguments),
+            "list" => {
+                let cids: Vec<String> = self.cids().iter().map(u64::to_string).collect();
+                Ok(format!("cids={}", cids.join(",")))
+            }
+            _ => Err(ControlError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    fn handle_client(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            debug!("Control command: {}", line);
+            match self.handle_command(&line) {
+                Ok(response) => writeln!(writer, "OK {}", response)?,
+                Err(e) => writeln!(writer, "ERROR {}", e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_cid(cid: &str) -> Result<u64, ControlError> {
+    cid.parse()
+        .map_err(|_| ControlError::InvalidCid(cid.to_string()))
+}
+
+/// Listen for control connections on `path`, one client at a time, in a
+/// background thread. A stale socket at `path` is replaced.
+pub(crate) fn spawn_control_server(path: &Path, devices: Arc<Devices>) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Listening for control connections on {}", path.display());
+
+    thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = devices.handle_client(stream) {
+                            warn!("Control connection failed: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed accepting control connection: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_control_command_errors() {
+        let (devices, _results) = Devices::new();
+
+        assert!(matches!(
+            devices.handle_command("   "),
+            Err(ControlError::EmptyCommand)
+        ));
+        assert!(matches!(
+            devices.handle_command("start 3"),
+            Err(ControlError::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            devices.handle_command("add"),
+            Err(ControlError::MissingArgument("params"))
+        ));
+        assert!(matches!(
+            devices.handle_command("add guest-cid=3 socket=/tmp/a"),
+            Err(ControlError::TooManyArguments)
+        ));
+        assert!(matches!(
+            devices.handle_command("add guest-cid=3"),
+            Err(ControlError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            devices.handle_command("remove"),
+            Err(ControlError::MissingArgument("cid"))
+        ));
+        assert!(matches!(
+            devices.handle_command("remove three"),
+            Err(ControlError::InvalidCid(_))
+        ));
+        assert!(matches!(
+            devices.handle_command("remove 3"),
+            Err(ControlError::NoSuchDevice(3))
+        ));
+        assert!(matches!(
+            devices.handle_command("list 3"),
+            Err(ControlError::TooManyArguments)
+        ));
+        assert_eq!(devices.handle_command("list").unwrap(), "cids=");
+    }
+
+    #[test]
+    fn test_control_add_remove() {
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let (devices, results) = Devices::new();
+
+        let params = |cid: u64| {
+            format!(
+                "guest-cid={},socket={},uds-path={}",
+                cid,
+                test_dir
+                    .path()
+                    .join(format!("vhost{}.socket", cid))
+                    .display(),
+                test_dir.path().join(format!("vm{}.vsock", cid)).display(),
+            )
+        };
+
+        assert_eq!(
+            devices
+                .handle_command(&format!("add {}", params(3)))
+                .unwrap(),
+            "cid=3"
+        );
+        assert_eq!(
+            devices
+                .handle_command(&format!("add {}", params(4)))
+                .unwrap(),
+            "cid=4"
+        );
+        assert!(matches!(
+            devices.handle_command(&format!("add {}", params(3))),
+            Err(ControlError::Backend(BackendError::GuestCidInUse(3)))
+        ));
+        assert_eq!(devices.handle_command("list").unwrap(), "cids=3,4");
+        assert!(devices.cid_map.read().unwrap().contains_key(&3));
+
+        let stats: serde_json::Value =
+            serde_json::from_str(&devices.handle_command("stats 4").unwrap()).unwrap();
+        assert_eq!(stats["guest_cid"], 4);
+        assert_eq!(stats["connections"], serde_json::json!([]));
+        assert_eq!(stats["packets_from_guest"], 0);
+        let all: serde_json::Value =
+            serde_json::from_str(&devices.handle_command("stats").unwrap()).unwrap();
+        assert_eq!(all["4"], stats);
+        assert_eq!(all["3"]["guest_cid"], 3);
+        assert!(matches!(
+            devices.handle_command("stats 5"),
+            Err(ControlError::NoSuchDevice(5))
+        ));
+        assert!(matches!(
+            devices.handle_command("stats four"),
+            Err(ControlError::InvalidCid(_))
+        ));
+
+        // the server thread waiting for a VMM is woken up
+        assert_eq!(devices.handle_command("remove 3").unwrap(), "cid=3");
+        let (cid, result) = results.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(cid, 3);
+        assert!(result.is_ok());
+        assert_eq!(devices.handle_command("list").unwrap(), "cids=4");
+        assert!(!devices.cid_map.read().unwrap().contains_key(&3));
+        assert!(devices.cid_map.read().unwrap().contains_key(&4));
+
+        // the CID can be used again
+        devices
+            .handle_command(&format!("add {}", params(3)))
+            .unwrap();
+        assert_eq!(devices.handle_command("list").unwrap(), "cids=3,4");
+
+        devices.remove(3).unwrap();
+        devices.remove(4).unwrap();
+        for _ in 0..2 {
+            let (_, result) = results.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert!(result.is_ok());
+        }
+        assert!(devices.cids().is_empty());
+
+        test_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Host-side streams of the vsock connections, which may be unix sockets,
+//! TCP connections or `AF_VSOCK` connections of the host.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+    net::{SocketAddr, TcpStream},
+    os::unix::{
+        net::UnixStream,
+        prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+};
+
+use crate::host_vsock::VsockStream;
+
+/// The host-side stream of a connection.
+#[derive(Debug)]
+pub(crate) enum HostStream {
+    /// A `SOCK_STREAM` or `SOCK_SEQPACKET` unix socket.
+    Unix(UnixStream),
+    /// A connection of a `tcp_listen` listener, or to a `tcp_forward`
+    /// address.
+    Tcp(TcpStream),
+    /// A connection of a `vsock_listen` listener.
+    Vsock(VsockStream),
+}
+
+impl HostStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::Unix(stream) => Self::Unix(stream.try_clone()?),
+            Self::Tcp(stream) => Self::Tcp(stream.try_clone()?),
+            Self::Vsock(stream) => Self::Vsock(stream.try_clone()?),
+        })
+    }
+}
+
+impl Read for HostStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Vsock(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for HostStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Vsock(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+            Self::Vsock(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for HostStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Unix(stream) => stream.as_raw_fd(),
+            Self::Tcp(stream) => stream.as_raw_fd(),
+            Self::Vsock(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl From<UnixStream> for HostStream {
+    fn from(stream: UnixStream) -> Self {
+        Self::Unix(stream)
+    }
+}
+
+impl From<TcpStream> for HostStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<VsockStream> for HostStream {
+    fn from(stream: VsockStream) -> Self {
+        Self::Vsock(stream)
+    }
+}
+
+/// Start connecting a non-blocking TCP stream to `addr`, which std has no
+/// API for.
+///
+/// The stream becomes writable once the connection is established or has
+/// failed, which `TcpStream::take_error` then tells apart.
+pub(crate) fn connect_tcp(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: socket() takes no pointers, and we check the result.
+    let fd = unsafe {
+        libc::socket(
+            domain,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the fd was just created and nothing else owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: sockaddr_in is plain old data, for which all zeroes is
+            // valid.
+            let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            // SAFETY: sin is a valid sockaddr_in of the length given.
+            unsafe {
+                libc::connect(
+                    fd.as_raw_fd(),
+                    &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: sockaddr_in6 is plain old data, for which all zeroes is
+            // valid.
+            let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            // SAFETY: sin6 is a valid sockaddr_in6 of the length given.
+            unsafe {
+                libc::connect(
+                    fd.as_raw_fd(),
+                    &sin6 as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+    Ok(TcpStream::from(fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_connect_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = connect_tcp(&listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        tcp.set_nonblocking(false).unwrap();
+        assert!(tcp.take_error().unwrap().is_none());
+
+        let mut stream = HostStream::from(tcp);
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // nothing listens on the port of a closed listener
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let refused = connect_tcp(&addr).and_then(|stream| {
+            stream.set_nonblocking(false)?;
+            // a blocking write waits for the connection to complete
+            (&stream).write_all(b"hello")
+        });
+        assert!(refused.is_err());
+    }
+}
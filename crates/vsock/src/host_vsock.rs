@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Host-side `AF_VSOCK` listeners, whose connections are bridged to ports of
+//! the guest.
+//!
+//! Host applications reach them through the kernel's `vsock_loopback`
+//! transport, i.e. by connecting to CID 1 (`VMADDR_CID_LOCAL`), so they don't
+//! need to know about the `CONNECT PORT\n` handshake. The listeners are bound
+//! to that CID only, so that guests of the kernel's vhost-vsock can't reach
+//! them.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+    os::unix::prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+/// A connected `AF_VSOCK` stream socket.
+#[derive(Debug)]
+pub(crate) struct VsockStream(OwnedFd);
+
+impl VsockStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        self.0.try_clone().map(Self)
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: buf is valid for writes of its length, and we check the
+        // result.
+        let ret = unsafe { libc::read(self.0.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: buf is valid for reads of its length, and we check the
+        // result. MSG_NOSIGNAL reports a closed peer as EPIPE, like std does.
+        let ret = unsafe {
+            libc::send(
+                self.0.as_raw_fd(),
+                buf.as_ptr().cast(),
+                buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Create a non-blocking `AF_VSOCK` socket listening on `port` of the local
+/// CID.
+pub(crate) fn bind(port: u32) -> io::Result<OwnedFd> {
+    // SAFETY: socket() takes no pointers, and we check the result.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_VSOCK,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the fd was just created and nothing else owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: sockaddr_vm is plain old data, for which all zeroes is valid.
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = libc::VMADDR_CID_LOCAL;
+    addr.svm_port = port;
+    // SAFETY: addr is a valid sockaddr_vm of the length given.
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: listen() takes no pointers, and we check the result.
+    if unsafe { libc::listen(fd.as_raw_fd(), 128) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Accept a connection on `listener`, as a non-blocking stream.
+pub(crate) fn accept(listener: &OwnedFd) -> io::Result<VsockStream> {
+    // SAFETY: accept4() may take null pointers for the peer's address, and
+    // we check the result.
+    let fd = unsafe {
+        libc::accept4(
+            listener.as_raw_fd(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the fd was just created and nothing else owns it.
+    Ok(VsockStream(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Get the port an `AF_VSOCK` socket is bound to.
+#[cfg(test)]
+pub(crate) fn local_port(fd: &OwnedFd) -> io::Result<u32> {
+    // SAFETY: sockaddr_vm is plain old data, for which all zeroes is valid.
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+    // SAFETY: addr and len are valid for writes of the sizes given.
+    let ret = unsafe {
+        libc::getsockname(
+            fd.as_raw_fd(),
+            &mut addr as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr.svm_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_vsock() {
+        // without AF_VSOCK support, or its loopback transport, there's
+        // nothing to test
+        let listener = match bind(libc::VMADDR_PORT_ANY) {
+            Ok(listener) => listener,
+            Err(e)
+                if e.raw_os_error() == Some(libc::EAFNOSUPPORT)
+                    || e.raw_os_error() == Some(libc::EADDRNOTAVAIL) =>
+            {
+                return
+            }
+            Err(e) => panic!("Could not bind an AF_VSOCK listener: {:?}", e),
+        };
+        let port = local_port(&listener).unwrap();
+        assert_ne!(port, libc::VMADDR_PORT_ANY);
+
+        // the port is taken, and there is nobody to accept
+        assert!(bind(port).is_err());
+        assert_eq!(
+            accept(&listener).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
@@ -1,7 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
 
+mod capture;
+mod control;
+mod host_stream;
+mod host_vsock;
+mod policy;
 mod rxops;
 mod rxqueue;
+mod seqpacket;
+mod stats;
 mod thread_backend;
 mod txbuf;
 mod vhu_vsock;
@@ -9,14 +16,19 @@ mod vhu_vsock_thread;
 mod vsock_conn;
 
 use std::{
-    collections::HashMap,
     convert::TryFrom,
+    net::SocketAddr,
+    os::unix::prelude::AsRawFd,
+    path::{Path, PathBuf},
     process::exit,
     sync::{Arc, RwLock},
-    thread,
 };
 
-use crate::vhu_vsock::{CidMap, VhostUserVsockBackend, VsockConfig};
+use crate::{
+    control::{DeviceHandle, Devices},
+    policy::{AccessRules, Endpoint, VsockPolicy},
+    vhu_vsock::{CidMap, VhostUserVsockBackend, VsockConfig},
+};
 use clap::{Args, Parser};
 use log::{error, info, warn};
 use serde::Deserialize;
@@ -35,6 +47,10 @@ enum CliError {
     NoArgsProvided,
     #[error("Failed to parse configuration file")]
     ConfigParse,
+    #[error("Failed to parse vsock ports to listen on: {0}")]
+    VsockListenParse(std::num::ParseIntError),
+    #[error("Failed to parse TCP port mapping `{0}`")]
+    TcpMappingParse(String),
 }
 
 #[derive(Debug, ThisError)]
@@ -47,6 +63,8 @@ enum VmArgsParseError {
     ParseInteger(std::num::ParseIntError),
     #[error("Required key `{0}` not found")]
     RequiredKeyNotFound(String),
+    #[error("Invalid TCP port mapping `{0}`")]
+    InvalidTcpMapping(String),
 }
 
 #[derive(Debug, ThisError)]
@@ -55,6 +73,12 @@ enum BackendError {
     CouldNotCreateBackend(vhu_vsock::Error),
     #[error("Could not create daemon: {0}")]
     CouldNotCreateDaemon(vhost_user_backend::Error),
+    #[error("Could not start daemon: {0}")]
+    CouldNotStartDaemon(vhost_user_backend::Error),
+    #[error("Guest CID {0} already has a device")]
+    GuestCidInUse(u64),
+    #[error("Could not create control socket: {0}")]
+    CouldNotCreateControlSocket(std::io::Error),
 }
 
 #[derive(Args, Clone, Debug)]
@@ -90,6 +114,47 @@ struct VsockParam {
         verbatim_doc_comment
     )]
     groups: String,
+
+    /// The list of host vsock ports to listen on, concatenated with '+' delimiter.
+    /// Connections to them are bridged to the same port of the guest, or to the
+    /// one given after ':', e.g. 1234+5000:22.
+    #[arg(
+        long,
+        conflicts_with = "config",
+        conflicts_with = "vm",
+        verbatim_doc_comment
+    )]
+    vsock_listen: Option<String>,
+
+    /// The list of guest ports forwarded to host TCP addresses, concatenated with '+' delimiter.
+    /// Each guest port is followed by '@' and the address, e.g. 80@127.0.0.1:8080+22@[::1]:22.
+    #[arg(
+        long,
+        conflicts_with = "config",
+        conflicts_with = "vm",
+        verbatim_doc_comment
+    )]
+    tcp_forward: Option<String>,
+
+    /// The list of host TCP addresses to listen on, concatenated with '+' delimiter.
+    /// Connections to them are bridged to the guest port given before '@', e.g. 22@0.0.0.0:2222.
+    #[arg(
+        long,
+        conflicts_with = "config",
+        conflicts_with = "vm",
+        verbatim_doc_comment
+    )]
+    tcp_listen: Option<String>,
+
+    /// Capture the packets exchanged with the guest to a pcap file, which Wireshark decodes.
+    /// Packets are appended if the file already exists.
+    #[arg(
+        long,
+        conflicts_with = "config",
+        conflicts_with = "vm",
+        verbatim_doc_comment
+    )]
+    capture: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -99,6 +164,24 @@ struct ConfigFileVsockParam {
     uds_path: String,
     tx_buffer_size: Option<u32>,
     groups: Option<String>,
+    vsock_listen: Option<String>,
+    tcp_forward: Option<String>,
+    tcp_listen: Option<String>,
+    policy: Option<ConfigFilePolicy>,
+    capture: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ConfigFileAccessRules {
+    allow: Option<String>,
+    deny: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ConfigFilePolicy {
+    guest_to_host: Option<ConfigFileAccessRules>,
+    host_to_guest: Option<ConfigFileAccessRules>,
+    siblings: Option<ConfigFileAccessRules>,
 }
 
 #[derive(Parser, Debug)]
@@ -108,9 +191,9 @@ struct VsockArgs {
     param: Option<VsockParam>,
 
     /// Device parameters corresponding to a VM in the form of comma separated key=value pairs.
-    /// The allowed keys are: guest_cid, socket, uds_path, tx_buffer_size and group.
+    /// The allowed keys are: guest_cid, socket, uds_path, tx_buffer_size, group, vsock_listen, tcp_forward, tcp_listen and capture.
     /// Example:
-    ///   --vm guest-cid=3,socket=/tmp/vhost3.socket,uds-path=/tmp/vm3.vsock,tx-buffer-size=65536,groups=group1+group2
+    ///   --vm guest-cid=3,socket=/tmp/vhost3.socket,uds-path=/tmp/vm3.vsock,tx-buffer-size=65536,groups=group1+group2,vsock-listen=1234+5000:22,tcp-forward=80@127.0.0.1:8080,tcp-listen=22@0.0.0.0:2222,capture=/tmp/vm3.pcap
     /// Multiple instances of this argument can be provided to configure devices for multiple guests.
     #[arg(long, conflicts_with = "config", verbatim_doc_comment, value_parser = parse_vm_params)]
     vm: Option<Vec<VsockConfig>>,
@@ -118,6 +201,91 @@ struct VsockArgs {
     /// Load from a given configuration file
     #[arg(long)]
     config: Option<String>,
+
+    /// Unix socket to listen on for commands adding and removing devices at runtime.
+    /// See `control.rs` for the protocol.
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+}
+
+/// Parse a list of host vsock ports concatenated with '+' delimiter, each
+/// followed by ':' and the guest port it's bridged to if that's another one.
+fn parse_vsock_listen(s: &str) -> Result<Vec<(u32, u32)>, std::num::ParseIntError> {
+    s.trim()
+        .split('+')
+        .map(|ports| match ports.split_once(':') {
+            Some((host_port, guest_port)) => Ok((host_port.parse()?, guest_port.parse()?)),
+            None => ports.parse().map(|port| (port, port)),
+        })
+        .collect()
+}
+
+/// Parse a list of guest ports each followed by '@' and a host TCP address,
+/// concatenated with '+' delimiter. The invalid entry is returned on error.
+fn parse_tcp_ports(s: &str) -> Result<Vec<(u32, SocketAddr)>, String> {
+    s.trim()
+        .split('+')
+        .map(|entry| {
+            entry
+                .split_once('@')
+                .and_then(|(port, addr)| Some((port.parse().ok()?, addr.parse().ok()?)))
+                .ok_or_else(|| entry.to_string())
+        })
+        .collect()
+}
+
+/// Parse a list of ports concatenated with '+' delimiter.
+fn parse_ports(s: &str) -> Result<Vec<Endpoint>, std::num::ParseIntError> {
+    s.trim()
+        .split('+')
+        .map(|port| port.parse().map(Endpoint::port))
+        .collect()
+}
+
+/// Parse a list of sibling CIDs concatenated with '+' delimiter, each
+/// followed by ':' and a port if only that one is meant.
+fn parse_sibling_endpoints(s: &str) -> Result<Vec<Endpoint>, std::num::ParseIntError> {
+    s.trim()
+        .split('+')
+        .map(|endpoint| {
+            let (cid, port) = match endpoint.split_once(':') {
+                Some((cid, port)) => (cid, Some(port.parse()?)),
+                None => (endpoint, None),
+            };
+            Ok(Endpoint {
+                cid: Some(cid.parse()?),
+                port,
+            })
+        })
+        .collect()
+}
+
+/// Parse the allow and deny lists of a configuration file policy.
+fn parse_access_rules(
+    rules: Option<ConfigFileAccessRules>,
+    parse: fn(&str) -> Result<Vec<Endpoint>, std::num::ParseIntError>,
+) -> Result<AccessRules, std::num::ParseIntError> {
+    let rules = match rules {
+        Some(rules) => rules,
+        None => return Ok(AccessRules::default()),
+    };
+    Ok(AccessRules {
+        allow: rules.allow.as_deref().map(parse).transpose()?,
+        deny: rules
+            .deny
+            .as_deref()
+            .map(parse)
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+fn parse_policy(policy: ConfigFilePolicy) -> Result<VsockPolicy, std::num::ParseIntError> {
+    Ok(VsockPolicy {
+        guest_to_host: parse_access_rules(policy.guest_to_host, parse_ports)?,
+        host_to_guest: parse_access_rules(policy.host_to_guest, parse_ports)?,
+        siblings: parse_access_rules(policy.siblings, parse_sibling_endpoints)?,
+    })
 }
 
 fn parse_vm_params(s: &str) -> Result<VsockConfig, VmArgsParseError> {
@@ -126,6 +294,10 @@ fn parse_vm_params(s: &str) -> Result<VsockConfig, VmArgsParseError> {
     let mut uds_path = None;
     let mut tx_buffer_size = None;
     let mut groups = None;
+    let mut vsock_listen = None;
+    let mut tcp_forward = None;
+    let mut tcp_listen = None;
+    let mut capture = None;
 
     for arg in s.trim().split(',') {
         let mut parts = arg.split('=');
@@ -142,17 +314,35 @@ fn parse_vm_params(s: &str) -> Result<VsockConfig, VmArgsParseError> {
                 tx_buffer_size = Some(val.parse().map_err(VmArgsParseError::ParseInteger)?)
             }
             "groups" => groups = Some(val.split('+').map(String::from).collect()),
+            "vsock_listen" | "vsock-listen" => {
+                vsock_listen =
+                    Some(parse_vsock_listen(val).map_err(VmArgsParseError::ParseInteger)?)
+            }
+            "tcp_forward" | "tcp-forward" => {
+                tcp_forward =
+                    Some(parse_tcp_ports(val).map_err(VmArgsParseError::InvalidTcpMapping)?)
+            }
+            "tcp_listen" | "tcp-listen" => {
+                tcp_listen =
+                    Some(parse_tcp_ports(val).map_err(VmArgsParseError::InvalidTcpMapping)?)
+            }
+            "capture" => capture = Some(val.to_string()),
             _ => return Err(VmArgsParseError::InvalidKey(key.to_string())),
         }
     }
 
-    Ok(VsockConfig::new(
+    let mut config = VsockConfig::new(
         guest_cid.unwrap_or(DEFAULT_GUEST_CID),
         socket.ok_or_else(|| VmArgsParseError::RequiredKeyNotFound("socket".to_string()))?,
         uds_path.ok_or_else(|| VmArgsParseError::RequiredKeyNotFound("uds-path".to_string()))?,
         tx_buffer_size.unwrap_or(DEFAULT_TX_BUFFER_SIZE),
         groups.unwrap_or(vec![DEFAULT_GROUP_NAME.to_string()]),
-    ))
+    );
+    config.set_vsock_listen(vsock_listen.unwrap_or_default());
+    config.set_tcp_forward(tcp_forward.unwrap_or_default());
+    config.set_tcp_listen(tcp_listen.unwrap_or_default());
+    config.set_capture(capture);
+    Ok(config)
 }
 
 impl VsockArgs {
@@ -164,10 +354,10 @@ impl VsockArgs {
             if let Ok(s) = b {
                 let mut v = s.get::<Vec<ConfigFileVsockParam>>("vms").unwrap();
                 if !v.is_empty() {
-                    let parsed: Vec<VsockConfig> = v
+                    let parsed: Result<Vec<VsockConfig>, CliError> = v
                         .drain(..)
                         .map(|p| {
-                            VsockConfig::new(
+                            let mut config = VsockConfig::new(
                                 p.guest_cid.unwrap_or(DEFAULT_GUEST_CID),
                                 p.socket.trim().to_string(),
                                 p.uds_path.trim().to_string(),
@@ -175,10 +365,33 @@ impl VsockArgs {
                                 p.groups.map_or(vec![DEFAULT_GROUP_NAME.to_string()], |g| {
                                     g.trim().split('+').map(String::from).collect()
                                 }),
-                            )
+                            );
+                            if let Some(ports) = p.vsock_listen {
+                                config.set_vsock_listen(
+                                    parse_vsock_listen(&ports)
+                                        .map_err(|_| CliError::ConfigParse)?,
+                                );
+                            }
+                            if let Some(ports) = p.tcp_forward {
+                                config.set_tcp_forward(
+                                    parse_tcp_ports(&ports).map_err(|_| CliError::ConfigParse)?,
+                                );
+                            }
+                            if let Some(ports) = p.tcp_listen {
+                                config.set_tcp_listen(
+                                    parse_tcp_ports(&ports).map_err(|_| CliError::ConfigParse)?,
+                                );
+                            }
+                            if let Some(policy) = p.policy {
+                                config.set_policy(
+                                    parse_policy(policy).map_err(|_| CliError::ConfigParse)?,
+                                );
+                            }
+                            config.set_capture(p.capture.map(|c| c.trim().to_string()));
+                            Ok(config)
                         })
                         .collect();
-                    return Some(Ok(parsed));
+                    return Some(parsed);
                 } else {
                     return Some(Err(CliError::ConfigParse));
                 }
@@ -200,31 +413,67 @@ impl TryFrom<VsockArgs> for Vec<VsockConfig> {
             _ => match cmd_args.vm {
                 Some(v) => Ok(v),
                 _ => cmd_args.param.map_or(Err(CliError::NoArgsProvided), |p| {
-                    Ok(vec![VsockConfig::new(
+                    let mut config = VsockConfig::new(
                         p.guest_cid,
                         p.socket.trim().to_string(),
                         p.uds_path.trim().to_string(),
                         p.tx_buffer_size,
                         p.groups.trim().split('+').map(String::from).collect(),
-                    )])
+                    );
+                    if let Some(ports) = p.vsock_listen {
+                        config.set_vsock_listen(
+                            parse_vsock_listen(&ports).map_err(CliError::VsockListenParse)?,
+                        );
+                    }
+                    if let Some(ports) = p.tcp_forward {
+                        config.set_tcp_forward(
+                            parse_tcp_ports(&ports).map_err(CliError::TcpMappingParse)?,
+                        );
+                    }
+                    if let Some(ports) = p.tcp_listen {
+                        config.set_tcp_listen(
+                            parse_tcp_ports(&ports).map_err(CliError::TcpMappingParse)?,
+                        );
+                    }
+                    config.set_capture(p.capture);
+                    Ok(vec![config])
                 }),
             },
         }
     }
 }
 
+/// Create the backend of a device, registering the guest in `cid_map`.
+pub(crate) fn create_backend(
+    config: &VsockConfig,
+    cid_map: &Arc<RwLock<CidMap>>,
+) -> Result<Arc<VhostUserVsockBackend>, BackendError> {
+    Ok(Arc::new(
+        VhostUserVsockBackend::new(config.clone(), cid_map.clone())
+            .map_err(BackendError::CouldNotCreateBackend)?,
+    ))
+}
+
 /// This is the public API through which an external program starts the
 /// vhost-device-vsock backend server.
+///
+/// `backend` serves the first VMM, and a new backend every following one,
+/// until the server is requested to stop through `handle`.
 pub(crate) fn start_backend_server(
     config: VsockConfig,
     cid_map: Arc<RwLock<CidMap>>,
+    backend: Arc<VhostUserVsockBackend>,
+    handle: Arc<DeviceHandle>,
 ) -> Result<(), BackendError> {
+    let mut next_backend = Some(backend);
     loop {
-        let backend = Arc::new(
-            VhostUserVsockBackend::new(config.clone(), cid_map.clone())
-                .map_err(BackendError::CouldNotCreateBackend)?,
-        );
-
+        // The previous backend has to be gone before creating the next one,
+        // which binds the same sockets
+        let backend = match next_backend.take() {
+            Some(backend) => backend,
+            None => create_backend(&config, &cid_map)?,
+        };
+        handle.set_backend(Some(backend.clone()));
         let listener = Listener::new(config.get_socket_path(), true).unwrap();
 
         let mut daemon = VhostUserDaemon::new(
@@ -243,43 +492,84 @@ pub(crate) fn start_backend_server(
                 .set_vring_worker(Some(vring_workers.remove(0)));
         }
 
-        daemon.start(listener).unwrap();
+        // Waiting for a VMM ends early if the server is stopped
+        let mut started = Ok(());
+        if handle.watch(Some(listener.as_raw_fd()), None) {
+            started = daemon.start(listener);
+        }
 
-        match daemon.wait() {
-            Ok(()) => {
-                info!("Stopping cleanly");
-            }
-            Err(vhost_user_backend::Error::HandleRequest(
-                vhost_user::Error::PartialMessage | vhost_user::Error::Disconnected,
-            )) => {
-                info!("vhost-user connection closed with partial message. If the VM is shutting down, this is expected behavior; otherwise, it might be a bug.");
-            }
-            Err(e) => {
-                warn!("Error running daemon: {:?}", e);
+        if started.is_ok() && handle.watch(None, Some(&backend.exit_event)) {
+            match daemon.wait() {
+                Ok(()) => {
+                    info!("Stopping cleanly");
+                }
+                Err(vhost_user_backend::Error::HandleRequest(
+                    vhost_user::Error::PartialMessage | vhost_user::Error::Disconnected,
+                )) => {
+                    info!("vhost-user connection closed with partial message. If the VM is shutting down, this is expected behavior; otherwise, it might be a bug.");
+                }
+                Err(e) => {
+                    warn!("Error running daemon: {:?}", e);
+                }
             }
         }
+        handle.watch(None, None);
 
         // No matter the result, we need to shut down the worker thread.
         backend.exit_event.write(1).unwrap();
+
+        // Release the backend, which removes its sockets. The vring workers
+        // refer back to it, so they have to go first.
+        for thread in backend.threads.iter() {
+            thread.lock().unwrap().set_vring_worker(None);
+        }
+        drop(daemon);
+        handle.set_backend(None);
+        drop(backend);
+
+        if handle.is_stopped() {
+            return Ok(());
+        }
+        started.map_err(BackendError::CouldNotStartDaemon)?;
     }
 }
 
-pub(crate) fn start_backend_servers(configs: &[VsockConfig]) -> Result<(), BackendError> {
-    let cid_map: Arc<RwLock<CidMap>> = Arc::new(RwLock::new(HashMap::new()));
-    let mut handles = Vec::new();
+/// Start the devices of `configs`, and if there is a `control_socket`, listen
+/// on it for devices to add and remove.
+///
+/// Returns the first error of a device, unless there is a control socket: it
+/// is logged then, and the other devices keep running.
+pub(crate) fn start_backend_servers(
+    configs: &[VsockConfig],
+    control_socket: Option<&Path>,
+) -> Result<(), BackendError> {
+    let (devices, results) = Devices::new();
+    let devices = Arc::new(devices);
 
     for c in configs.iter() {
-        let config = c.clone();
-        let cid_map = cid_map.clone();
-        let handle = thread::Builder::new()
-            .name(format!("vhu-vsock-cid-{}", c.get_guest_cid()))
-            .spawn(move || start_backend_server(config, cid_map))
-            .unwrap();
-        handles.push(handle);
+        devices.add(c.clone())?;
     }
 
-    for handle in handles {
-        handle.join().unwrap()?;
+    if let Some(path) = control_socket {
+        control::spawn_control_server(path, devices.clone())
+            .map_err(BackendError::CouldNotCreateControlSocket)?;
+    }
+
+    // Without a control socket, devices can't be added, and the daemon exits
+    // once the initial ones are done
+    let expected = if control_socket.is_some() {
+        usize::MAX
+    } else {
+        configs.len()
+    };
+    for (cid, result) in results.iter().take(expected) {
+        match result {
+            Err(e) if control_socket.is_some() => {
+                error!("Device of guest CID {} failed: {}", cid, e);
+                let _ = devices.remove(cid);
+            }
+            result => result?,
+        }
     }
 
     Ok(())
@@ -288,7 +578,9 @@ pub(crate) fn start_backend_servers(configs: &[VsockConfig]) -> Result<(), Backe
 fn main() {
     env_logger::init();
 
-    let configs = match Vec::<VsockConfig>::try_from(VsockArgs::parse()) {
+    let args = VsockArgs::parse();
+    let control_socket = args.control_socket.clone();
+    let configs = match Vec::<VsockConfig>::try_from(args) {
         Ok(c) => c,
         Err(e) => {
             println!("Error parsing arguments: {}", e);
@@ -296,7 +588,7 @@ fn main() {
         }
     };
 
-    if let Err(e) = start_backend_servers(&configs) {
+    if let Err(e) = start_backend_servers(&configs, control_socket.as_deref()) {
         error!("{e}");
         exit(1);
     }
@@ -305,8 +597,12 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
     use std::fs::File;
     use std::io::Write;
+    use std::os::unix::{fs::MetadataExt, net::UnixStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
     use tempfile::tempdir;
 
     impl VsockArgs {
@@ -324,9 +620,14 @@ mod tests {
                     uds_path: uds_path.to_string(),
                     tx_buffer_size,
                     groups: groups.to_string(),
+                    vsock_listen: None,
+                    tcp_forward: None,
+                    tcp_listen: None,
+                    capture: None,
                 }),
                 vm: None,
                 config: None,
+                control_socket: None,
             }
         }
         fn from_file(config: &str) -> Self {
@@ -334,6 +635,7 @@ mod tests {
                 param: None,
                 vm: None,
                 config: Some(config.to_string()),
+                control_socket: None,
             }
         }
     }
@@ -358,10 +660,72 @@ mod tests {
         assert_eq!(config.get_uds_path(), uds_path);
         assert_eq!(config.get_tx_buffer_size(), 64 * 1024);
         assert_eq!(config.get_groups(), vec!["group1".to_string()]);
+        assert!(config.get_vsock_listen().is_empty());
+        assert!(config.get_tcp_forward().is_empty());
+        assert!(config.get_tcp_listen().is_empty());
+        assert_eq!(config.get_capture(), None);
 
         test_dir.close().unwrap();
     }
 
+    #[test]
+    fn test_parse_vsock_listen() {
+        assert_eq!(
+            parse_vsock_listen("1234+5000:22").unwrap(),
+            vec![(1234, 1234), (5000, 22)]
+        );
+        assert!(parse_vsock_listen("").is_err());
+        assert!(parse_vsock_listen("1234+").is_err());
+        assert!(parse_vsock_listen("1:2:3").is_err());
+        assert!(parse_vsock_listen("port").is_err());
+    }
+
+    #[test]
+    fn test_parse_policy_endpoints() {
+        assert_eq!(
+            parse_ports("22+80").unwrap(),
+            vec![Endpoint::port(22), Endpoint::port(80)]
+        );
+        assert!(parse_ports("").is_err());
+        assert!(parse_ports("4:22").is_err());
+        assert_eq!(
+            parse_sibling_endpoints("4+5:22").unwrap(),
+            vec![
+                Endpoint {
+                    cid: Some(4),
+                    port: None
+                },
+                Endpoint {
+                    cid: Some(5),
+                    port: Some(22)
+                }
+            ]
+        );
+        assert!(parse_sibling_endpoints("4:").is_err());
+        assert!(parse_sibling_endpoints(":22").is_err());
+    }
+
+    #[test]
+    fn test_parse_tcp_ports() {
+        assert_eq!(
+            parse_tcp_ports("80@127.0.0.1:8080+22@[::1]:22").unwrap(),
+            vec![
+                (80, "127.0.0.1:8080".parse().unwrap()),
+                (22, "[::1]:22".parse().unwrap())
+            ]
+        );
+        assert_eq!(parse_tcp_ports("").unwrap_err(), "");
+        assert_eq!(parse_tcp_ports("80").unwrap_err(), "80");
+        assert_eq!(
+            parse_tcp_ports("80@localhost:8080").unwrap_err(),
+            "80@localhost:8080"
+        );
+        assert_eq!(
+            parse_tcp_ports("port@127.0.0.1:8080").unwrap_err(),
+            "port@127.0.0.1:8080"
+        );
+    }
+
     #[test]
     fn test_vsock_config_setup_from_vm_args() {
         let test_dir = tempdir().expect("Could not create a temp test directory.");
@@ -378,7 +742,7 @@ mod tests {
         ];
         let params = format!(
             "--vm socket={vhost3_socket},uds_path={vm3_vsock} \
-             --vm socket={vhost4_socket},uds-path={vm4_vsock},guest-cid=4,tx_buffer_size=65536,groups=group1 \
+             --vm socket={vhost4_socket},uds-path={vm4_vsock},guest-cid=4,tx_buffer_size=65536,groups=group1,vsock-listen=1234+5000:22,tcp-forward=80@127.0.0.1:8080,tcp-listen=22@0.0.0.0:2222,capture={vm4_pcap} \
              --vm groups=group2+group3,guest-cid=5,socket={vhost5_socket},uds_path={vm5_vsock},tx-buffer-size=32768",
             vhost3_socket = socket_paths[0].display(),
             vhost4_socket = socket_paths[1].display(),
@@ -386,6 +750,7 @@ mod tests {
             vm3_vsock = uds_paths[0].display(),
             vm4_vsock = uds_paths[1].display(),
             vm5_vsock = uds_paths[2].display(),
+            vm4_pcap = test_dir.path().join("vm4.pcap").display(),
         );
 
         let mut params = params.split_whitespace().collect::<Vec<&str>>();
@@ -418,6 +783,19 @@ mod tests {
         assert_eq!(config.get_uds_path(), uds_paths[1].display().to_string());
         assert_eq!(config.get_tx_buffer_size(), 65536);
         assert_eq!(config.get_groups(), vec!["group1".to_string()]);
+        assert_eq!(config.get_vsock_listen(), [(1234, 1234), (5000, 22)]);
+        assert_eq!(
+            config.get_tcp_forward(),
+            [(80, "127.0.0.1:8080".parse().unwrap())]
+        );
+        assert_eq!(
+            config.get_tcp_listen(),
+            [(22, "0.0.0.0:2222".parse().unwrap())]
+        );
+        assert_eq!(
+            config.get_capture(),
+            Some(test_dir.path().join("vm4.pcap").to_str().unwrap())
+        );
 
         let config = configs.get(2).unwrap();
         assert_eq!(config.get_guest_cid(), 5);
@@ -451,7 +829,19 @@ mod tests {
       socket: {}
       uds_path: {}
       tx_buffer_size: 32768
-      groups: group1+group2",
+      groups: group1+group2
+      vsock_listen: 1234+5000:22
+      tcp_forward: 80@127.0.0.1:8080
+      tcp_listen: 22@0.0.0.0:2222+23@0.0.0.0:2323
+      policy:
+        guest_to_host:
+          allow: 1234+5678
+        host_to_guest:
+          deny: 22
+        siblings:
+          allow: 5+6:1234
+          deny: 5:22
+      capture: /tmp/vm4.pcap",
                 socket_path.display(),
                 uds_path.display(),
             )
@@ -472,6 +862,32 @@ mod tests {
             config.get_groups(),
             vec!["group1".to_string(), "group2".to_string()]
         );
+        assert_eq!(config.get_vsock_listen(), [(1234, 1234), (5000, 22)]);
+        assert_eq!(
+            config.get_tcp_forward(),
+            [(80, "127.0.0.1:8080".parse().unwrap())]
+        );
+        assert_eq!(
+            config.get_tcp_listen(),
+            [
+                (22, "0.0.0.0:2222".parse().unwrap()),
+                (23, "0.0.0.0:2323".parse().unwrap())
+            ]
+        );
+        let policy = config.get_policy();
+        assert_eq!(
+            policy.guest_to_host.allow,
+            Some(vec![Endpoint::port(1234), Endpoint::port(5678)])
+        );
+        assert!(policy.guest_to_host.deny.is_empty());
+        assert_eq!(policy.host_to_guest.allow, None);
+        assert_eq!(policy.host_to_guest.deny, vec![Endpoint::port(22)]);
+        assert!(policy.siblings.allows(5, 1234));
+        assert!(!policy.siblings.allows(5, 22));
+        assert!(policy.siblings.allows(6, 1234));
+        assert!(!policy.siblings.allows(6, 22));
+        assert!(!policy.siblings.allows(7, 1234));
+        assert_eq!(config.get_capture(), Some("/tmp/vm4.pcap"));
 
         // Now test that optional parameters are correctly set to their default values.
         let mut yaml = File::create(&config_path).unwrap();
@@ -497,6 +913,11 @@ mod tests {
         assert_eq!(config.get_uds_path(), uds_path.display().to_string());
         assert_eq!(config.get_tx_buffer_size(), DEFAULT_TX_BUFFER_SIZE);
         assert_eq!(config.get_groups(), vec![DEFAULT_GROUP_NAME.to_string()]);
+        assert!(config.get_vsock_listen().is_empty());
+        assert!(config.get_tcp_forward().is_empty());
+        assert!(config.get_tcp_listen().is_empty());
+        assert_eq!(config.get_policy(), &VsockPolicy::default());
+        assert_eq!(config.get_capture(), None);
 
         std::fs::remove_file(&config_path).unwrap();
         test_dir.close().unwrap();
@@ -548,4 +969,63 @@ mod tests {
 
         test_dir.close().unwrap();
     }
+
+    #[test]
+    fn test_vsock_server_reconnect() {
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let vhost_socket_path = test_dir.path().join("test_vsock_server_reconnect.socket");
+        let uds_path = test_dir.path().join("test_vsock_server_reconnect.vsock");
+        let config = VsockConfig::new(
+            3,
+            vhost_socket_path.display().to_string(),
+            uds_path.display().to_string(),
+            64 * 1024,
+            vec![DEFAULT_GROUP_NAME.to_string()],
+        );
+
+        let cid_map: Arc<RwLock<CidMap>> = Arc::new(RwLock::new(HashMap::new()));
+        let backend = create_backend(&config, &cid_map).unwrap();
+        let handle = Arc::new(DeviceHandle::default());
+        let server = {
+            let handle = handle.clone();
+            thread::spawn(move || start_backend_server(config, cid_map, backend, handle))
+        };
+
+        let uds_ino = || {
+            std::fs::symlink_metadata(&uds_path)
+                .ok()
+                // inodes get reused, a new socket has a new ctime though
+                .map(|metadata| (metadata.ino(), metadata.ctime(), metadata.ctime_nsec()))
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for _ in 0..2 {
+            // a VMM connects and goes away again
+            let ino = uds_ino();
+            let vmm = loop {
+                match UnixStream::connect(&vhost_socket_path) {
+                    Ok(vmm) => break vmm,
+                    Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                    Err(e) => panic!("Could not connect to the vhost-user socket: {}", e),
+                }
+            };
+            drop(vmm);
+
+            // and the server binds the host socket again for the next one
+            while uds_ino().is_none() || uds_ino() == ino {
+                assert!(
+                    Instant::now() < deadline,
+                    "The host socket wasn't bound again"
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        // the previous backends didn't remove the socket of the current one
+        thread::sleep(Duration::from_millis(100));
+        UnixStream::connect(&uds_path).unwrap();
+
+        handle.stop();
+        server.join().unwrap().unwrap();
+        test_dir.close().unwrap();
+    }
 }
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Access control policy of a device, restricting which ports the guest,
+//! host-side applications and sibling VMs may connect to.
+
+/// A CID and port pattern, where `None` matches any CID or port.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Endpoint {
+    pub cid: Option<u64>,
+    pub port: Option<u32>,
+}
+
+impl Endpoint {
+    /// Pattern matching `port` of any CID.
+    pub fn port(port: u32) -> Self {
+        Self {
+            cid: None,
+            port: Some(port),
+        }
+    }
+
+    fn matches(&self, cid: u64, port: u32) -> bool {
+        let cid_matches = match self.cid {
+            Some(c) => c == cid,
+            None => true,
+        };
+        let port_matches = match self.port {
+            Some(p) => p == port,
+            None => true,
+        };
+        cid_matches && port_matches
+    }
+}
+
+/// Allow and deny lists for one direction of connections.
+///
+/// Endpoints are allowed if they match the allow list, when there is one,
+/// and don't match the deny list.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct AccessRules {
+    pub allow: Option<Vec<Endpoint>>,
+    pub deny: Vec<Endpoint>,
+}
+
+impl AccessRules {
+    /// Check if connections to `port` of `cid` are allowed.
+    pub fn allows(&self, cid: u64, port: u32) -> bool {
+        let allowed = match &self.allow {
+            Some(allow) => allow.iter().any(|e| e.matches(cid, port)),
+            None => true,
+        };
+        allowed && !self.deny.iter().any(|e| e.matches(cid, port))
+    }
+}
+
+/// Access control policy of a device. Everything is allowed by default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct VsockPolicy {
+    /// Host ports the guest may connect to.
+    pub guest_to_host: AccessRules,
+    /// Guest ports host-side applications may connect to.
+    pub host_to_guest: AccessRules,
+    /// Sibling CIDs the guest may exchange connections with, and the ports
+    /// connections may be requested to: ports of the sibling for the ones
+    /// the guest requests, ports of the guest for the ones the sibling
+    /// requests.
+    pub siblings: AccessRules,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_rules() {
+        // everything is allowed by default
+        let rules = AccessRules::default();
+        assert!(rules.allows(2, 1234));
+
+        let rules = AccessRules {
+            allow: None,
+            deny: vec![Endpoint::port(22)],
+        };
+        assert!(rules.allows(2, 1234));
+        assert!(!rules.allows(2, 22));
+
+        let rules = AccessRules {
+            allow: Some(vec![
+                Endpoint {
+                    cid: Some(4),
+                    port: None,
+                },
+                Endpoint {
+                    cid: Some(5),
+                    port: Some(1234),
+                },
+            ]),
+            deny: vec![Endpoint {
+                cid: Some(4),
+                port: Some(22),
+            }],
+        };
+        assert!(rules.allows(4, 1234));
+        assert!(!rules.allows(4, 22));
+        assert!(rules.allows(5, 1234));
+        assert!(!rules.allows(5, 22));
+        assert!(!rules.allows(6, 1234));
+
+        // an empty allow list allows nothing
+        let rules = AccessRules {
+            allow: Some(Vec::new()),
+            deny: Vec::new(),
+        };
+        assert!(!rules.allows(2, 1234));
+    }
+}
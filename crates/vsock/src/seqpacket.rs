@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Host-side `SOCK_SEQPACKET` unix sockets, which std has no API for.
+//!
+//! They are wrapped in a `UnixStream` or `UnixListener`, whose I/O works on
+//! them unchanged: every `write` sends a single message, and every `read`
+//! receives a single message, discarding whatever doesn't fit the buffer.
+
+use std::{
+    io, mem,
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+};
+
+/// Create a `SOCK_SEQPACKET` socket.
+fn socket() -> io::Result<OwnedFd> {
+    // SAFETY: socket() takes no pointers, and we check the result.
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the fd was just created and nothing else owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Build the address of the unix socket at `path`.
+fn sockaddr(path: &str) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    // SAFETY: sockaddr_un is plain old data, for which all zeroes is valid.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // leave room for the terminating NUL
+    if path.len() >= addr.sun_path.len() || path.as_bytes().contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid unix socket path",
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+    Ok((addr, len as libc::socklen_t))
+}
+
+/// Connect to the `SOCK_SEQPACKET` socket listening at `path`.
+pub(crate) fn connect(path: &str) -> io::Result<UnixStream> {
+    let (addr, len) = sockaddr(path)?;
+    let fd = socket()?;
+    // SAFETY: addr is a valid sockaddr_un of length len.
+    let ret = unsafe {
+        libc::connect(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(UnixStream::from(fd))
+}
+
+/// Create a `SOCK_SEQPACKET` socket listening at `path`.
+pub(crate) fn bind(path: &str) -> io::Result<UnixListener> {
+    let (addr, len) = sockaddr(path)?;
+    let fd = socket()?;
+    // SAFETY: addr is a valid sockaddr_un of length len.
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: listen() takes no pointers, and we check the result.
+    if unsafe { libc::listen(fd.as_raw_fd(), 128) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(UnixListener::from(fd))
+}
+
+/// Check if `fd` is a `SOCK_SEQPACKET` socket.
+pub(crate) fn is_seqpacket(fd: RawFd) -> bool {
+    let mut type_: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: type_ and len are valid for writes of the sizes given.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut type_ as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    ret == 0 && type_ == libc::SOCK_SEQPACKET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_seqpacket() {
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let path = test_dir.path().join("test_seqpacket.sock");
+        let path = path.to_str().unwrap();
+
+        let listener = bind(path).unwrap();
+        let mut client = connect(path).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        assert!(is_seqpacket(client.as_raw_fd()));
+        assert!(is_seqpacket(server.as_raw_fd()));
+        assert!(!is_seqpacket(UnixStream::pair().unwrap().0.as_raw_fd()));
+        assert!(!is_seqpacket(-1));
+
+        // message boundaries are preserved
+        client.write_all(b"hello").unwrap();
+        client.write_all(b"world!").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(server.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(server.read(&mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"world!");
+
+        assert!(connect(&format!("{}_missing", path)).is_err());
+        assert!(bind(path).is_err());
+        assert!(bind(&"x".repeat(200)).is_err());
+
+        test_dir.close().unwrap();
+    }
+}
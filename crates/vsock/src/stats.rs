@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Statistics of a device and its connections, shown as JSON by the `stats`
+//! command of the control socket.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Why a packet from the guest, or a connection request from a sibling VM,
+/// was dropped, or answered with a RST instead of being delivered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum DropReason {
+    /// The descriptor chain doesn't hold a valid packet.
+    InvalidPacket,
+    /// The source CID isn't the CID of the guest.
+    InconsistentSrcCid,
+    /// No device has the destination CID.
+    UnknownCid,
+    /// The sibling VM shares no group with the guest.
+    GroupMismatch,
+    /// The access control policy denies the connection.
+    PolicyDenied,
+    /// The socket type is neither SOCK_STREAM nor SOCK_SEQPACKET.
+    UnknownType,
+    /// The socket type doesn't match the one of the connection.
+    WrongType,
+    /// There is no connection the packet belongs to.
+    UnknownConnection,
+    /// Nothing accepted the connection requested by the guest on the host.
+    ConnectFailed,
+    /// A data packet without data.
+    EmptyPacket,
+    /// The guest sent more data than its credit allows.
+    TxBufFull,
+    /// Writing to the host-side stream failed.
+    WriteError,
+}
+
+impl DropReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::InvalidPacket => "invalid_packet",
+            Self::InconsistentSrcCid => "inconsistent_src_cid",
+            Self::UnknownCid => "unknown_cid",
+            Self::GroupMismatch => "group_mismatch",
+            Self::PolicyDenied => "policy_denied",
+            Self::UnknownType => "unknown_type",
+            Self::WrongType => "wrong_type",
+            Self::UnknownConnection => "unknown_connection",
+            Self::ConnectFailed => "connect_failed",
+            Self::EmptyPacket => "empty_packet",
+            Self::TxBufFull => "tx_buf_full",
+            Self::WriteError => "write_error",
+        }
+    }
+}
+
+/// Counters of a device, since it was created.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub(crate) struct DeviceCounters {
+    /// Packets sent by the guest.
+    pub packets_from_guest: u64,
+    /// Data bytes of the packets sent by the guest.
+    pub bytes_from_guest: u64,
+    /// Packets delivered to the guest, including the ones from siblings.
+    pub packets_to_guest: u64,
+    /// Data bytes of the packets delivered to the guest.
+    pub bytes_to_guest: u64,
+    /// Packets from the guest, and connection requests from siblings,
+    /// dropped or reset, by reason.
+    pub dropped: BTreeMap<&'static str, u64>,
+}
+
+impl DeviceCounters {
+    pub fn record_drop(&mut self, reason: DropReason) {
+        *self.dropped.entry(reason.as_str()).or_default() += 1;
+    }
+}
+
+/// State of a connection.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct ConnStats {
+    pub local_port: u32,
+    pub peer_port: u32,
+    /// `stream` or `seqpacket`.
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    /// Whether the guest accepted the connection, if it was initiated by the
+    /// host.
+    pub connected: bool,
+    /// Bytes forwarded to the host-side stream, as advertised to the guest.
+    pub fwd_cnt: u32,
+    /// Bytes sent to the guest.
+    pub rx_cnt: u32,
+    /// Buffer size the guest allocated for the connection.
+    pub peer_buf_alloc: u32,
+    /// Bytes the guest forwarded to its application.
+    pub peer_fwd_cnt: u32,
+    /// Bytes that can be sent to the guest before it runs out of buffer.
+    pub peer_credit: u32,
+    /// Bytes from the guest waiting for the host-side stream to be writable.
+    pub tx_buf_len: usize,
+    pub tx_buf_size: u32,
+    /// Operations waiting for a buffer of the guest's rx queue.
+    pub pending_rx_ops: Vec<&'static str>,
+    /// Data bytes received from the guest, without wrapping around.
+    pub bytes_from_guest: u64,
+    /// Data bytes sent to the guest, without wrapping around.
+    pub bytes_to_guest: u64,
+}
+
+/// State and counters of a device, as shown by the control socket.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DeviceStats {
+    pub guest_cid: u64,
+    pub connections: Vec<ConnStats>,
+    /// Connections with operations waiting for the guest's rx queue.
+    pub pending_rx: usize,
+    /// Packets from siblings waiting for the guest's rx queue.
+    pub pending_raw_pkts: usize,
+    #[serde(flatten)]
+    pub counters: DeviceCounters,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_counters() {
+        let mut counters = DeviceCounters::default();
+        counters.record_drop(DropReason::UnknownCid);
+        counters.record_drop(DropReason::UnknownCid);
+        counters.record_drop(DropReason::TxBufFull);
+
+        let stats = DeviceStats {
+            guest_cid: 3,
+            connections: Vec::new(),
+            pending_rx: 0,
+            pending_raw_pkts: 0,
+            counters,
+        };
+        let json: serde_json::Value = serde_json::to_value(stats).unwrap();
+        assert_eq!(json["guest_cid"], 3);
+        assert_eq!(json["dropped"]["unknown_cid"], 2);
+        assert_eq!(json["dropped"]["tx_buf_full"], 1);
+        assert_eq!(json["bytes_from_guest"], 0);
+    }
+}
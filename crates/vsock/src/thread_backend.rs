@@ -2,12 +2,15 @@
 
 use std::{
     collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{SocketAddr, TcpStream},
     ops::Deref,
     os::unix::{
         net::UnixStream,
         prelude::{AsRawFd, RawFd},
     },
     sync::{Arc, RwLock},
+    time::Instant,
 };
 
 use log::{info, warn};
@@ -15,10 +18,16 @@ use virtio_vsock::packet::{VsockPacket, PKT_HEADER_SIZE};
 use vm_memory::bitmap::BitmapSlice;
 
 use crate::{
+    capture::PacketCapture,
+    host_stream::{self, HostStream},
+    policy::VsockPolicy,
     rxops::*,
+    rxqueue::RxQueue,
+    seqpacket,
+    stats::{DeviceCounters, DeviceStats, DropReason},
     vhu_vsock::{
         CidMap, ConnMapKey, Error, Result, VSOCK_HOST_CID, VSOCK_OP_REQUEST, VSOCK_OP_RST,
-        VSOCK_TYPE_STREAM,
+        VSOCK_OP_RW, VSOCK_TYPE_SEQPACKET, VSOCK_TYPE_STREAM,
     },
     vhu_vsock_thread::VhostUserVsockThread,
     vsock_conn::*,
@@ -49,15 +58,25 @@ impl RawVsockPacket {
     }
 }
 
+/// A connection to a forwarded TCP address requested by the guest, which is
+/// answered once it's established or failed.
+pub(crate) struct TcpConnect {
+    stream: TcpStream,
+    /// Header of the guest's connection request.
+    request: [u8; PKT_HEADER_SIZE],
+    /// When the connection request is reset, if it's still pending.
+    deadline: Instant,
+}
+
 pub(crate) struct VsockThreadBackend {
     /// Map of ConnMapKey objects indexed by raw file descriptors.
     pub listener_map: HashMap<RawFd, ConnMapKey>,
     /// Map of vsock connection objects indexed by ConnMapKey objects.
-    pub conn_map: HashMap<ConnMapKey, VsockConnection<UnixStream>>,
+    pub conn_map: HashMap<ConnMapKey, VsockConnection<HostStream>>,
     /// Queue of ConnMapKey objects indicating pending rx operations.
     pub backend_rxq: VecDeque<ConnMapKey>,
-    /// Map of host-side unix streams indexed by raw file descriptors.
-    pub stream_map: HashMap<i32, UnixStream>,
+    /// Map of host-side streams indexed by raw file descriptors.
+    pub stream_map: HashMap<i32, HostStream>,
     /// Host side socket for listening to new connections from the host.
     host_socket_path: String,
     /// epoll for registering new host-side connections.
@@ -73,6 +92,17 @@ pub(crate) struct VsockThreadBackend {
     pub raw_pkts_queue: Arc<RwLock<RawPktsQ>>,
     /// Set of groups assigned to the device which it is allowed to communicate with.
     groups_set: Arc<RwLock<HashSet<String>>>,
+    /// Host TCP addresses indexed by the guest ports forwarded to them.
+    pub tcp_forward: HashMap<u32, SocketAddr>,
+    /// Connections to forwarded TCP addresses being established, indexed by
+    /// raw file descriptors.
+    pub tcp_connects: HashMap<RawFd, TcpConnect>,
+    /// Access control policy of the device.
+    pub policy: VsockPolicy,
+    /// Packet counters of the device.
+    pub counters: DeviceCounters,
+    /// Capture of the packets exchanged with the guest.
+    pub capture: Option<PacketCapture>,
 }
 
 impl VsockThreadBackend {
@@ -100,6 +130,11 @@ impl VsockThreadBackend {
             cid_map,
             raw_pkts_queue: Arc::new(RwLock::new(VecDeque::new())),
             groups_set,
+            tcp_forward: HashMap::new(),
+            tcp_connects: HashMap::new(),
+            policy: VsockPolicy::default(),
+            counters: DeviceCounters::default(),
+            capture: None,
         }
     }
 
@@ -151,17 +186,24 @@ impl VsockThreadBackend {
                 .set_src_port(conn.local_port)
                 .set_dst_port(conn.peer_port)
                 .set_len(0)
-                .set_type(VSOCK_TYPE_STREAM)
+                .set_type(conn.type_)
                 .set_flags(0)
                 .set_buf_alloc(0)
                 .set_fwd_cnt(0);
 
+            self.count_to_guest(pkt);
             return Ok(());
         }
 
         // Handle other packet types per connection
         conn.recv_pkt(pkt)?;
 
+        if conn.rx_queue.contains(RxOps::Rw.bitmask()) {
+            // More fragments of a SOCK_SEQPACKET message are pending
+            self.backend_rxq.push_back(key);
+        }
+        self.count_to_guest(pkt);
+
         Ok(())
     }
 
@@ -173,17 +215,37 @@ impl VsockThreadBackend {
     /// Returns:
     /// - always `Ok(())` if packet has been consumed correctly
     pub fn send_pkt<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) -> Result<()> {
+        self.counters.packets_from_guest += 1;
+        if pkt.op() == VSOCK_OP_RW {
+            self.counters.bytes_from_guest += u64::from(pkt.len());
+        }
+        self.capture_pkt(pkt);
+
         if pkt.src_cid() != self.guest_cid {
             warn!(
                 "vsock: dropping packet with inconsistent src_cid: {:?} from guest configured with CID: {:?}",
                 pkt.src_cid(), self.guest_cid
             );
+            self.counters.record_drop(DropReason::InconsistentSrcCid);
             return Ok(());
         }
 
         let dst_cid = pkt.dst_cid();
         if dst_cid != VSOCK_HOST_CID {
-            let cid_map = self.cid_map.read().unwrap();
+            if pkt.op() == VSOCK_OP_REQUEST && !self.policy.siblings.allows(dst_cid, pkt.dst_port())
+            {
+                info!(
+                    "vsock: policy denies connections to cid: {:?}, port: {:?}",
+                    dst_cid,
+                    pkt.dst_port()
+                );
+                self.counters.record_drop(DropReason::PolicyDenied);
+                self.enq_rst(pkt);
+                return Ok(());
+            }
+
+            let cid_map = self.cid_map.clone();
+            let cid_map = cid_map.read().unwrap();
             if cid_map.contains_key(&dst_cid) {
                 let (sibling_raw_pkts_queue, sibling_groups_set, sibling_event_fd) =
                     cid_map.get(&dst_cid).unwrap();
@@ -198,6 +260,7 @@ impl VsockThreadBackend {
                         "vsock: dropping packet for cid: {:?} due to group mismatch",
                         dst_cid
                     );
+                    self.counters.record_drop(DropReason::GroupMismatch);
                     return Ok(());
                 }
 
@@ -208,29 +271,60 @@ impl VsockThreadBackend {
                 let _ = sibling_event_fd.write(1);
             } else {
                 warn!("vsock: dropping packet for unknown cid: {:?}", dst_cid);
+                self.counters.record_drop(DropReason::UnknownCid);
             }
 
             return Ok(());
         }
 
-        // TODO: Rst if packet has unsupported type
-        if pkt.type_() != VSOCK_TYPE_STREAM {
-            info!("vsock: dropping packet of unknown type");
+        if pkt.type_() != VSOCK_TYPE_STREAM && pkt.type_() != VSOCK_TYPE_SEQPACKET {
+            info!("vsock: resetting packet of unknown type: {:?}", pkt.type_());
+            self.counters.record_drop(DropReason::UnknownType);
+            self.enq_rst(pkt);
             return Ok(());
         }
 
         let key = ConnMapKey::new(pkt.dst_port(), pkt.src_port());
 
-        // TODO: Handle cases where connection does not exist and packet op
-        // is not VSOCK_OP_REQUEST
-        if !self.conn_map.contains_key(&key) {
-            // The packet contains a new connection request
-            if pkt.op() == VSOCK_OP_REQUEST {
-                self.handle_new_guest_conn(pkt);
-            } else {
-                // TODO: send back RST
+        match self.conn_map.get(&key) {
+            None => {
+                if pkt.op() == VSOCK_OP_REQUEST {
+                    if !self
+                        .policy
+                        .guest_to_host
+                        .allows(VSOCK_HOST_CID, pkt.dst_port())
+                    {
+                        info!(
+                            "vsock: policy denies guest connections to host port: {:?}",
+                            pkt.dst_port()
+                        );
+                        self.counters.record_drop(DropReason::PolicyDenied);
+                        self.enq_rst(pkt);
+                        return Ok(());
+                    }
+                    // The packet contains a new connection request
+                    self.handle_new_guest_conn(pkt);
+                } else {
+                    // There is no connection this packet belongs to. RSTs
+                    // for connections closed in the meantime are expected.
+                    if pkt.op() == VSOCK_OP_RST {
+                        // The guest may give up on a connection to a TCP
+                        // address before it's established
+                        self.cancel_tcp_connect(&key);
+                    } else {
+                        self.counters.record_drop(DropReason::UnknownConnection);
+                    }
+                    self.enq_rst(pkt);
+                }
+                return Ok(());
             }
-            return Ok(());
+            Some(conn) if conn.type_ != pkt.type_() => {
+                info!("vsock: resetting packet of the wrong type for its connection");
+                self.counters.record_drop(DropReason::WrongType);
+                self.enq_rst(pkt);
+                return Ok(());
+            }
+            Some(_) => {}
         }
 
         if pkt.op() == VSOCK_OP_RST {
@@ -239,7 +333,9 @@ impl VsockThreadBackend {
             if conn.rx_queue.contains(RxOps::Reset.bitmask()) {
                 return Ok(());
             }
-            let conn = self.conn_map.remove(&key).unwrap();
+            let mut conn = self.conn_map.remove(&key).unwrap();
+            // The guest refused a host initiated connection
+            conn.reply_error("connection refused");
             self.listener_map.remove(&conn.stream.as_raw_fd());
             self.stream_map.remove(&conn.stream.as_raw_fd());
             self.local_port_set.remove(&conn.local_port);
@@ -257,6 +353,9 @@ impl VsockThreadBackend {
         // Forward this packet to its listening connection
         let conn = self.conn_map.get_mut(&key).unwrap();
         conn.send_pkt(pkt)?;
+        if let Some(reason) = conn.dropped.take() {
+            self.counters.record_drop(reason);
+        }
 
         if conn.rx_queue.pending_rx() {
             // Required if the connection object adds new rx operations
@@ -266,18 +365,131 @@ impl VsockThreadBackend {
         Ok(())
     }
 
+    /// Snapshot of the state of the device and its connections.
+    pub fn stats(&self) -> DeviceStats {
+        let mut connections: Vec<_> = self.conn_map.values().map(|conn| conn.stats()).collect();
+        connections.sort_by_key(|conn| (conn.local_port, conn.peer_port));
+        DeviceStats {
+            guest_cid: self.guest_cid,
+            connections,
+            pending_rx: self.backend_rxq.len(),
+            pending_raw_pkts: self.raw_pkts_queue.read().unwrap().len(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Account for a packet delivered to the guest.
+    fn count_to_guest<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) {
+        self.counters.packets_to_guest += 1;
+        if pkt.op() == VSOCK_OP_RW {
+            self.counters.bytes_to_guest += u64::from(pkt.len());
+        }
+        self.capture_pkt(pkt);
+    }
+
+    /// Write a packet to the capture file, if any. Capturing stops on the
+    /// first error.
+    fn capture_pkt<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) {
+        if let Some(capture) = &mut self.capture {
+            if let Err(e) = capture.write_pkt(pkt) {
+                warn!("vsock: stopping packet capture: {:?}", e);
+                self.capture = None;
+            }
+        }
+    }
+
+    /// Reset the host initiated connections the guest didn't accept in time,
+    /// and the guest's requests for connections to TCP addresses that weren't
+    /// established in time.
+    ///
+    /// Returns whether there are connection requests still waiting for the
+    /// guest or a TCP address.
+    pub fn reset_timed_out_conns(&mut self) -> bool {
+        let now = Instant::now();
+        let mut pending = false;
+        for (key, conn) in self.conn_map.iter_mut() {
+            if conn.connect
+                || conn.connect_deadline.is_none()
+                || conn.rx_queue.contains(RxOps::Reset.bitmask())
+            {
+                continue;
+            }
+            if !conn.request_timed_out(now) {
+                pending = true;
+                continue;
+            }
+            info!(
+                "vsock: guest didn't accept connection (lp={}, pp={}) in time",
+                conn.local_port, conn.peer_port
+            );
+            conn.reply_error("connection timed out");
+            // Nothing but the RST is left to send to the guest
+            conn.rx_queue = RxQueue::new();
+            conn.rx_queue.enqueue(RxOps::Reset);
+            self.backend_rxq.push_back(key.clone());
+        }
+
+        let timed_out: Vec<RawFd> = self
+            .tcp_connects
+            .iter()
+            .filter(|(_, connect)| now >= connect.deadline)
+            .map(|(&fd, _)| fd)
+            .collect();
+        for fd in timed_out {
+            if let Some(connect) = self.remove_tcp_connect(fd) {
+                self.fail_tcp_connect(
+                    connect,
+                    io::Error::new(io::ErrorKind::TimedOut, "connection timed out"),
+                );
+            }
+        }
+        pending || !self.tcp_connects.is_empty()
+    }
+
     /// Deliver a raw vsock packet sent from a sibling VM to the guest vsock driver.
     ///
+    /// Connection requests the policy denies are reset instead of being
+    /// delivered.
+    ///
     /// Returns:
     /// - `Ok(())` if packet was successfully filled in
     /// - `Err(Error::EmptyRawPktsQueue)` if there was no available data
     pub fn recv_raw_pkt<B: BitmapSlice>(&mut self, pkt: &mut VsockPacket<B>) -> Result<()> {
-        let raw_vsock_pkt = self
-            .raw_pkts_queue
-            .write()
-            .unwrap()
-            .pop_front()
-            .ok_or(Error::EmptyRawPktsQueue)?;
+        let raw_vsock_pkt = loop {
+            let raw_vsock_pkt = self
+                .raw_pkts_queue
+                .write()
+                .unwrap()
+                .pop_front()
+                .ok_or(Error::EmptyRawPktsQueue)?;
+            let mut header = [0; PKT_HEADER_SIZE];
+            let sibling_pkt = pkt_from_raw_header(&raw_vsock_pkt.header, &mut header);
+            if sibling_pkt.op() != VSOCK_OP_REQUEST
+                || self
+                    .policy
+                    .siblings
+                    .allows(sibling_pkt.src_cid(), sibling_pkt.dst_port())
+            {
+                break raw_vsock_pkt;
+            }
+
+            info!(
+                "vsock: policy denies connections from cid: {:?} to port: {:?}",
+                sibling_pkt.src_cid(),
+                sibling_pkt.dst_port()
+            );
+            self.counters.record_drop(DropReason::PolicyDenied);
+            let cid_map = self.cid_map.read().unwrap();
+            if let Some((sibling_raw_pkts_queue, _, sibling_event_fd)) =
+                cid_map.get(&sibling_pkt.src_cid())
+            {
+                sibling_raw_pkts_queue
+                    .write()
+                    .unwrap()
+                    .push_back(rst_pkt(&sibling_pkt));
+                let _ = sibling_event_fd.write(1);
+            }
+        };
 
         pkt.set_header_from_raw(&raw_vsock_pkt.header).unwrap();
         if !raw_vsock_pkt.data.is_empty() {
@@ -285,6 +497,7 @@ impl VsockThreadBackend {
             buf.copy_from(&raw_vsock_pkt.data);
         }
 
+        self.count_to_guest(pkt);
         Ok(())
     }
 
@@ -293,20 +506,152 @@ impl VsockThreadBackend {
     /// Attempts to connect to a host side unix socket listening on a path
     /// corresponding to the destination port as follows:
     /// - "{self.host_sock_path}_{local_port}""
+    ///
+    /// The host side socket has to be a SOCK_SEQPACKET one for SOCK_SEQPACKET
+    /// connections.
+    ///
+    /// Connections to ports forwarded to the host network stack go to their
+    /// TCP address instead. They are established without blocking the
+    /// thread, and only answered once `finish_tcp_connect` is called.
     fn handle_new_guest_conn<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) {
         let port_path = format!("{}_{}", self.host_socket_path, pkt.dst_port());
 
-        UnixStream::connect(port_path)
+        if let Some(&addr) = self.tcp_forward.get(&pkt.dst_port()) {
+            if pkt.type_() == VSOCK_TYPE_STREAM {
+                self.start_tcp_connect(&addr, pkt);
+            } else {
+                info!("vsock: only SOCK_STREAM connections can be forwarded to TCP");
+                self.counters.record_drop(DropReason::ConnectFailed);
+                self.enq_rst(pkt);
+            }
+            return;
+        }
+
+        let stream = if pkt.type_() == VSOCK_TYPE_SEQPACKET {
+            seqpacket::connect(&port_path)
+        } else {
+            UnixStream::connect(port_path)
+        };
+        stream
             .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
             .map_err(Error::UnixConnect)
-            .and_then(|stream| self.add_new_guest_conn(stream, pkt))
-            .unwrap_or_else(|_| self.enq_rst());
+            .and_then(|stream| self.add_new_guest_conn(HostStream::from(stream), pkt))
+            .unwrap_or_else(|_| {
+                self.counters.record_drop(DropReason::ConnectFailed);
+                self.enq_rst(pkt)
+            });
+    }
+
+    /// Start connecting to the TCP address `addr` on behalf of the guest's
+    /// connection request `pkt`, polling for the outcome.
+    fn start_tcp_connect<B: BitmapSlice>(&mut self, addr: &SocketAddr, pkt: &VsockPacket<B>) {
+        let key = ConnMapKey::new(pkt.dst_port(), pkt.src_port());
+        if self.tcp_connect_fd(&key).is_some() {
+            // The guest repeated a request we are still working on
+            return;
+        }
+
+        let mut request = [0; PKT_HEADER_SIZE];
+        pkt.header_slice().copy_to(&mut request);
+        let result = host_stream::connect_tcp(addr)
+            .map_err(Error::UnixConnect)
+            .and_then(|stream| {
+                let fd = stream.as_raw_fd();
+                VhostUserVsockThread::epoll_register(self.epoll_fd, fd, epoll::Events::EPOLLOUT)?;
+                self.tcp_connects.insert(
+                    fd,
+                    TcpConnect {
+                        stream,
+                        request,
+                        deadline: Instant::now() + CONN_REQUEST_TIMEOUT,
+                    },
+                );
+                Ok(())
+            });
+        if let Err(err) = result {
+            info!("vsock: could not connect to {}: {:?}", addr, err);
+            self.counters.record_drop(DropReason::ConnectFailed);
+            self.enq_rst(pkt);
+        }
+    }
+
+    /// Answer the guest's request for a connection to a TCP address, once
+    /// its stream `fd` is writable, i.e. the connection is established or
+    /// failed.
+    pub fn finish_tcp_connect(&mut self, fd: RawFd) {
+        let connect = match self.remove_tcp_connect(fd) {
+            Some(connect) => connect,
+            None => return,
+        };
+        let result = match connect.stream.take_error() {
+            Ok(None) => connect.stream.set_nodelay(true),
+            Ok(Some(err)) | Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            self.fail_tcp_connect(connect, err);
+            return;
+        }
+
+        let mut header = [0; PKT_HEADER_SIZE];
+        let pkt = pkt_from_raw_header(&connect.request, &mut header);
+        if self
+            .add_new_guest_conn(HostStream::from(connect.stream), &pkt)
+            .is_err()
+        {
+            self.counters.record_drop(DropReason::ConnectFailed);
+            self.enq_rst(&pkt);
+        }
+    }
+
+    /// Reset the guest's request for a connection to a TCP address that
+    /// couldn't be established.
+    fn fail_tcp_connect(&mut self, connect: TcpConnect, err: io::Error) {
+        let mut header = [0; PKT_HEADER_SIZE];
+        let pkt = pkt_from_raw_header(&connect.request, &mut header);
+        info!(
+            "vsock: could not forward connection to port {:?}: {:?}",
+            pkt.dst_port(),
+            err
+        );
+        self.counters.record_drop(DropReason::ConnectFailed);
+        self.enq_rst(&pkt);
+    }
+
+    /// Stop establishing the connection to a TCP address the guest requested
+    /// with `key`, if any.
+    fn cancel_tcp_connect(&mut self, key: &ConnMapKey) {
+        if let Some(fd) = self.tcp_connect_fd(key) {
+            self.remove_tcp_connect(fd);
+        }
+    }
+
+    /// Find the stream of the connection to a TCP address being established
+    /// for `key`.
+    fn tcp_connect_fd(&self, key: &ConnMapKey) -> Option<RawFd> {
+        self.tcp_connects
+            .iter()
+            .find(|(_, connect)| {
+                let mut header = [0; PKT_HEADER_SIZE];
+                let pkt = pkt_from_raw_header(&connect.request, &mut header);
+                ConnMapKey::new(pkt.dst_port(), pkt.src_port()) == *key
+            })
+            .map(|(&fd, _)| fd)
+    }
+
+    /// Stop polling the stream `fd` of a connection to a TCP address being
+    /// established.
+    fn remove_tcp_connect(&mut self, fd: RawFd) -> Option<TcpConnect> {
+        let connect = self.tcp_connects.remove(&fd)?;
+        VhostUserVsockThread::epoll_unregister(self.epoll_fd, fd).unwrap_or_else(|err| {
+            warn!("Could not remove epoll listener for fd {:?}: {:?}", fd, err)
+        });
+        Some(connect)
     }
 
     /// Wrapper to add new connection to relevant HashMaps.
     fn add_new_guest_conn<B: BitmapSlice>(
         &mut self,
-        stream: UnixStream,
+        stream: HostStream,
         pkt: &VsockPacket<B>,
     ) -> Result<()> {
         let conn = VsockConnection::new_peer_init(
@@ -318,6 +663,7 @@ impl VsockThreadBackend {
             self.epoll_fd,
             pkt.buf_alloc(),
             self.tx_buffer_size,
+            pkt.type_(),
         );
         let stream_fd = conn.stream.as_raw_fd();
         self.listener_map
@@ -339,20 +685,59 @@ impl VsockThreadBackend {
         Ok(())
     }
 
-    /// Enqueue RST packets to be sent to guest.
-    fn enq_rst(&mut self) {
-        // TODO
-        dbg!("New guest conn error: Enqueue RST");
+    /// Enqueue a RST packet in reply to a guest packet, to be sent to the
+    /// guest through the raw vsock packets queue.
+    ///
+    /// Like Linux's `virtio_transport_reset_no_sock()`, this never replies to
+    /// a RST packet.
+    fn enq_rst<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) {
+        if pkt.op() == VSOCK_OP_RST {
+            return;
+        }
+
+        self.raw_pkts_queue.write().unwrap().push_back(rst_pkt(pkt));
     }
 }
 
+/// Build a packet whose header, parsed from `raw_header`, lives in `buf`.
+fn pkt_from_raw_header<'a>(
+    raw_header: &[u8; PKT_HEADER_SIZE],
+    buf: &'a mut [u8; PKT_HEADER_SIZE],
+) -> VsockPacket<'a, ()> {
+    // SAFETY: Safe as the header buffer is valid and outlives the packet.
+    let mut pkt = unsafe { VsockPacket::new(buf, None) }.unwrap();
+    pkt.set_header_from_raw(raw_header).unwrap();
+    pkt
+}
+
+/// Build a RST packet in reply to `pkt`.
+fn rst_pkt<B: BitmapSlice>(pkt: &VsockPacket<B>) -> RawVsockPacket {
+    let mut raw_pkt = RawVsockPacket {
+        header: [0; PKT_HEADER_SIZE],
+        data: Vec::new(),
+    };
+    // SAFETY: Safe as the header buffer is valid and outlives the packet.
+    let mut rst = unsafe { VsockPacket::new(&mut raw_pkt.header, None) }.unwrap();
+    rst.set_op(VSOCK_OP_RST)
+        .set_type(pkt.type_())
+        .set_src_cid(pkt.dst_cid())
+        .set_dst_cid(pkt.src_cid())
+        .set_src_port(pkt.dst_port())
+        .set_dst_port(pkt.src_port());
+    raw_pkt
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vhu_vsock::{VhostUserVsockBackend, VsockConfig, VSOCK_OP_RW};
-    use std::os::unix::net::UnixListener;
+    use crate::{
+        policy::Endpoint,
+        vhu_vsock::{VhostUserVsockBackend, VsockConfig, VSOCK_OP_RW},
+    };
+    use std::{convert::TryInto, net::TcpListener, os::unix::net::UnixListener};
     use tempfile::tempdir;
     use virtio_vsock::packet::{VsockPacket, PKT_HEADER_SIZE};
+    use vmm_sys_util::eventfd::EventFd;
 
     const DATA_LEN: usize = 16;
     const CONN_TX_BUF_SIZE: u32 = 64 * 1024;
@@ -426,6 +811,293 @@ mod tests {
         test_dir.close().unwrap();
     }
 
+    #[test]
+    fn test_vsock_thread_backend_rst() {
+        const CID: u64 = 3;
+        const GUEST_PORT: u32 = 4321;
+        const VSOCK_PEER_PORT: u32 = 1234;
+
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let vsock_socket_path = test_dir.path().join("test_vsock_thread_backend_rst.vsock");
+        let vsock_peer_path = test_dir
+            .path()
+            .join("test_vsock_thread_backend_rst.vsock_1234");
+        let _listener = UnixListener::bind(&vsock_peer_path).unwrap();
+
+        let groups_set: HashSet<String> = vec![GROUP_NAME.to_string()].into_iter().collect();
+        let mut vtp = VsockThreadBackend::new(
+            vsock_socket_path.display().to_string(),
+            epoll::create(false).unwrap(),
+            CID,
+            CONN_TX_BUF_SIZE,
+            Arc::new(RwLock::new(groups_set)),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+        let capture_path = test_dir.path().join("test_vsock_thread_backend_rst.pcap");
+        vtp.capture = Some(PacketCapture::open(capture_path.to_str().unwrap()).unwrap());
+
+        let mut pkt_raw = [0u8; PKT_HEADER_SIZE + DATA_LEN];
+        let (hdr_raw, data_raw) = pkt_raw.split_at_mut(PKT_HEADER_SIZE);
+        // SAFETY: Safe as hdr_raw and data_raw are guaranteed to be valid.
+        let mut packet = unsafe { VsockPacket::new(hdr_raw, Some(data_raw)).unwrap() };
+        packet
+            .set_src_cid(CID)
+            .set_dst_cid(VSOCK_HOST_CID)
+            .set_src_port(GUEST_PORT)
+            .set_dst_port(VSOCK_PEER_PORT)
+            .set_buf_alloc(CONN_TX_BUF_SIZE);
+
+        let mut rst_raw = [0u8; PKT_HEADER_SIZE];
+        // SAFETY: Safe as rst_raw is guaranteed to be valid.
+        let mut rst = unsafe { VsockPacket::new(&mut rst_raw, None).unwrap() };
+        let mut expect_rst = |vtp: &mut VsockThreadBackend, type_: u16, src_port: u32| {
+            assert!(vtp.pending_raw_pkts());
+            vtp.recv_raw_pkt(&mut rst).unwrap();
+            assert!(!vtp.pending_raw_pkts());
+            assert_eq!(rst.op(), VSOCK_OP_RST);
+            assert_eq!(rst.type_(), type_);
+            assert_eq!(rst.src_cid(), VSOCK_HOST_CID);
+            assert_eq!(rst.dst_cid(), CID);
+            assert_eq!(rst.src_port(), src_port);
+            assert_eq!(rst.dst_port(), GUEST_PORT);
+            assert_eq!(rst.len(), 0);
+            assert_eq!(rst.flags(), 0);
+            assert_eq!(rst.buf_alloc(), 0);
+            assert_eq!(rst.fwd_cnt(), 0);
+        };
+
+        // packets of unsupported types, e.g. SOCK_DGRAM
+        packet.set_type(3).set_op(VSOCK_OP_REQUEST);
+        vtp.send_pkt(&packet).unwrap();
+        expect_rst(&mut vtp, 3, VSOCK_PEER_PORT);
+
+        // packets for connections that don't exist
+        packet.set_type(VSOCK_TYPE_STREAM).set_op(VSOCK_OP_RW);
+        vtp.send_pkt(&packet).unwrap();
+        expect_rst(&mut vtp, VSOCK_TYPE_STREAM, VSOCK_PEER_PORT);
+
+        // connection requests to ports nobody listens on
+        packet
+            .set_dst_port(VSOCK_PEER_PORT + 1)
+            .set_op(VSOCK_OP_REQUEST);
+        vtp.send_pkt(&packet).unwrap();
+        expect_rst(&mut vtp, VSOCK_TYPE_STREAM, VSOCK_PEER_PORT + 1);
+        packet.set_dst_port(VSOCK_PEER_PORT);
+
+        // packets of another type than their connection
+        vtp.send_pkt(&packet).unwrap();
+        assert!(!vtp.pending_raw_pkts());
+        packet.set_type(VSOCK_TYPE_SEQPACKET).set_op(VSOCK_OP_RW);
+        vtp.send_pkt(&packet).unwrap();
+        expect_rst(&mut vtp, VSOCK_TYPE_SEQPACKET, VSOCK_PEER_PORT);
+
+        // RST packets are never replied to
+        packet
+            .set_dst_port(VSOCK_PEER_PORT + 1)
+            .set_op(VSOCK_OP_RST);
+        vtp.send_pkt(&packet).unwrap();
+        assert!(!vtp.pending_raw_pkts());
+
+        let stats = vtp.stats();
+        assert_eq!(stats.guest_cid, CID);
+        assert_eq!(stats.counters.packets_from_guest, 6);
+        assert_eq!(stats.counters.packets_to_guest, 4);
+        let dropped: Vec<_> = stats.counters.dropped.into_iter().collect();
+        assert_eq!(
+            dropped,
+            vec![
+                ("connect_failed", 1),
+                ("unknown_connection", 1),
+                ("unknown_type", 1),
+                ("wrong_type", 1)
+            ]
+        );
+        assert_eq!(stats.connections.len(), 1);
+        assert_eq!(stats.connections[0].local_port, VSOCK_PEER_PORT);
+        assert_eq!(stats.connections[0].peer_port, GUEST_PORT);
+        assert_eq!(stats.connections[0].type_, "stream");
+        assert_eq!(stats.connections[0].pending_rx_ops, vec!["response"]);
+        assert_eq!(stats.pending_rx, 1);
+
+        // every packet from and to the guest was captured
+        let pcap = std::fs::read(&capture_path).unwrap();
+        let mut records = 0;
+        let mut offset = 24;
+        while offset < pcap.len() {
+            let len = u32::from_le_bytes(pcap[offset + 8..offset + 12].try_into().unwrap());
+            offset += 16 + len as usize;
+            records += 1;
+        }
+        assert_eq!(offset, pcap.len());
+        assert_eq!(records, 10);
+
+        test_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_vsock_thread_backend_policy() {
+        const CID: u64 = 3;
+        const SIBLING_CID: u64 = 4;
+        const GUEST_PORT: u32 = 4321;
+        const VSOCK_PEER_PORT: u32 = 1234;
+
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let vsock_socket_path = test_dir
+            .path()
+            .join("test_vsock_thread_backend_policy.vsock");
+        let vsock_peer_path = test_dir
+            .path()
+            .join("test_vsock_thread_backend_policy.vsock_1234");
+        let _listener = UnixListener::bind(vsock_peer_path).unwrap();
+
+        let groups_set: HashSet<String> = vec![GROUP_NAME.to_string()].into_iter().collect();
+        let mut vtp = VsockThreadBackend::new(
+            vsock_socket_path.display().to_string(),
+            epoll::create(false).unwrap(),
+            CID,
+            CONN_TX_BUF_SIZE,
+            Arc::new(RwLock::new(groups_set)),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+        vtp.policy.guest_to_host.deny = vec![Endpoint::port(VSOCK_PEER_PORT)];
+        vtp.policy.siblings.allow = Some(Vec::new());
+
+        let mut pkt_raw = [0u8; PKT_HEADER_SIZE + DATA_LEN];
+        let (hdr_raw, data_raw) = pkt_raw.split_at_mut(PKT_HEADER_SIZE);
+        // SAFETY: Safe as hdr_raw and data_raw are guaranteed to be valid.
+        let mut packet = unsafe { VsockPacket::new(hdr_raw, Some(data_raw)).unwrap() };
+        packet
+            .set_src_cid(CID)
+            .set_dst_cid(VSOCK_HOST_CID)
+            .set_src_port(GUEST_PORT)
+            .set_dst_port(VSOCK_PEER_PORT)
+            .set_type(VSOCK_TYPE_STREAM)
+            .set_op(VSOCK_OP_REQUEST)
+            .set_buf_alloc(CONN_TX_BUF_SIZE);
+
+        // the host-side socket exists, but the policy denies connecting to it
+        vtp.send_pkt(&packet).unwrap();
+        assert!(vtp.conn_map.is_empty());
+        assert_eq!(vtp.raw_pkts_queue.read().unwrap().len(), 1);
+
+        vtp.policy.guest_to_host.deny.clear();
+        vtp.send_pkt(&packet).unwrap();
+        assert_eq!(vtp.conn_map.len(), 1);
+
+        // packets for siblings are reset before looking the CID up
+        packet.set_dst_cid(SIBLING_CID);
+        vtp.send_pkt(&packet).unwrap();
+        assert_eq!(vtp.raw_pkts_queue.read().unwrap().len(), 2);
+
+        test_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_vsock_thread_backend_tcp_forward() {
+        const CID: u64 = 3;
+        const GUEST_PORT: u32 = 4321;
+
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let vsock_socket_path = test_dir
+            .path()
+            .join("test_vsock_thread_backend_tcp_forward.vsock");
+
+        let groups_set: HashSet<String> = vec![GROUP_NAME.to_string()].into_iter().collect();
+        let mut vtp = VsockThreadBackend::new(
+            vsock_socket_path.display().to_string(),
+            epoll::create(false).unwrap(),
+            CID,
+            CONN_TX_BUF_SIZE,
+            Arc::new(RwLock::new(groups_set)),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        vtp.tcp_forward.insert(80, listener.local_addr().unwrap());
+        // nobody listens there once the listener is dropped
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        vtp.tcp_forward.insert(81, closed.local_addr().unwrap());
+        drop(closed);
+
+        let mut pkt_raw = [0u8; PKT_HEADER_SIZE + DATA_LEN];
+        let (hdr_raw, data_raw) = pkt_raw.split_at_mut(PKT_HEADER_SIZE);
+        // SAFETY: Safe as hdr_raw and data_raw are guaranteed to be valid.
+        let mut packet = unsafe { VsockPacket::new(hdr_raw, Some(data_raw)).unwrap() };
+        packet
+            .set_src_cid(CID)
+            .set_dst_cid(VSOCK_HOST_CID)
+            .set_src_port(GUEST_PORT)
+            .set_dst_port(80)
+            .set_type(VSOCK_TYPE_STREAM)
+            .set_op(VSOCK_OP_REQUEST)
+            .set_buf_alloc(CONN_TX_BUF_SIZE);
+
+        // Answer the connection requests whose TCP connection completed
+        let finish_tcp_connects = |vtp: &mut VsockThreadBackend| {
+            let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 8];
+            while !vtp.tcp_connects.is_empty() {
+                let cnt = epoll::wait(vtp.epoll_fd, 1000, &mut events).unwrap();
+                assert!(cnt > 0, "TCP connection didn't complete");
+                for event in &events[..cnt] {
+                    vtp.finish_tcp_connect(event.data as RawFd);
+                }
+            }
+        };
+
+        // connections to forwarded ports go to their TCP address, and are
+        // answered once established
+        vtp.send_pkt(&packet).unwrap();
+        assert!(vtp.conn_map.is_empty());
+        assert_eq!(vtp.tcp_connects.len(), 1);
+        // repeated requests don't start another connection
+        vtp.send_pkt(&packet).unwrap();
+        assert_eq!(vtp.tcp_connects.len(), 1);
+        finish_tcp_connects(&mut vtp);
+        assert!(vtp.conn_map.contains_key(&ConnMapKey::new(80, GUEST_PORT)));
+        assert!(!vtp.pending_raw_pkts());
+        listener.accept().unwrap();
+
+        // only SOCK_STREAM connections can be forwarded
+        packet
+            .set_src_port(GUEST_PORT + 1)
+            .set_type(VSOCK_TYPE_SEQPACKET);
+        vtp.send_pkt(&packet).unwrap();
+        assert!(vtp.pending_raw_pkts());
+        vtp.raw_pkts_queue.write().unwrap().clear();
+
+        // the connection is reset if the TCP address can't be reached
+        packet.set_dst_port(81).set_type(VSOCK_TYPE_STREAM);
+        vtp.send_pkt(&packet).unwrap();
+        finish_tcp_connects(&mut vtp);
+        assert!(vtp.pending_raw_pkts());
+        assert_eq!(vtp.conn_map.len(), 1);
+        vtp.raw_pkts_queue.write().unwrap().clear();
+
+        // or if it isn't established in time
+        packet.set_dst_port(80).set_src_port(GUEST_PORT + 2);
+        vtp.send_pkt(&packet).unwrap();
+        assert!(vtp.reset_timed_out_conns());
+        for connect in vtp.tcp_connects.values_mut() {
+            connect.deadline = Instant::now();
+        }
+        assert!(!vtp.reset_timed_out_conns());
+        assert!(vtp.tcp_connects.is_empty());
+        assert!(vtp.pending_raw_pkts());
+        vtp.raw_pkts_queue.write().unwrap().clear();
+
+        // the guest may give up before the connection is established
+        packet.set_src_port(GUEST_PORT + 3);
+        vtp.send_pkt(&packet).unwrap();
+        packet.set_op(VSOCK_OP_RST);
+        vtp.send_pkt(&packet).unwrap();
+        assert!(vtp.tcp_connects.is_empty());
+        assert!(!vtp.pending_raw_pkts());
+        assert_eq!(vtp.conn_map.len(), 1);
+        assert_eq!(vtp.stats().counters.dropped["connect_failed"], 3);
+
+        test_dir.close().unwrap();
+    }
+
     #[test]
     fn test_vsock_thread_backend_sibling_vms() {
         const CID: u64 = 3;
@@ -479,7 +1151,15 @@ mod tests {
             CID,
             CONN_TX_BUF_SIZE,
             Arc::new(RwLock::new(groups_set)),
-            cid_map,
+            cid_map.clone(),
+        );
+        cid_map.write().unwrap().insert(
+            CID,
+            (
+                vtp.raw_pkts_queue.clone(),
+                vtp.groups_set.clone(),
+                EventFd::new(0).unwrap(),
+            ),
         );
 
         assert!(!vtp.pending_raw_pkts());
@@ -539,6 +1219,38 @@ mod tests {
         assert_eq!(recvd_data_raw[2], 0xBAu8);
         assert_eq!(recvd_data_raw[3], 0xBEu8);
 
+        // the policy of the sibling resets connection requests it denies
+        sibling_backend.threads[0]
+            .lock()
+            .unwrap()
+            .thread_backend
+            .policy
+            .siblings
+            .deny = vec![Endpoint::port(SIBLING_LISTENING_PORT)];
+        packet.set_op(VSOCK_OP_REQUEST).set_len(0);
+        assert!(vtp.send_pkt(&packet).is_ok());
+        {
+            let mut sibling = sibling_backend.threads[0].lock().unwrap();
+            assert_eq!(
+                sibling
+                    .thread_backend
+                    .recv_raw_pkt(&mut recvd_packet)
+                    .unwrap_err()
+                    .to_string(),
+                Error::EmptyRawPktsQueue.to_string()
+            );
+            assert_eq!(
+                sibling.thread_backend.stats().counters.dropped["policy_denied"],
+                1
+            );
+        }
+
+        assert!(vtp.recv_raw_pkt(&mut packet).is_ok());
+        assert_eq!(packet.op(), VSOCK_OP_RST);
+        assert_eq!(packet.src_cid(), SIBLING_CID);
+        assert_eq!(packet.dst_cid(), CID);
+        assert_eq!(packet.src_port(), SIBLING_LISTENING_PORT);
+
         test_dir.close().unwrap();
     }
 }
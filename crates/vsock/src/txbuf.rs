@@ -93,7 +93,7 @@ impl LocalTxBuf {
     }
 
     /// Return amount of data in the buffer.
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         (self.tail - self.head).0 as usize
     }
 }
@@ -3,6 +3,7 @@
 use std::{
     collections::{HashMap, HashSet},
     io::{self, Result as IoResult},
+    net::SocketAddr,
     sync::{Arc, Mutex, RwLock},
     u16, u32, u64, u8,
 };
@@ -21,6 +22,9 @@ use vmm_sys_util::{
     eventfd::{EventFd, EFD_NONBLOCK},
 };
 
+use crate::capture::PacketCapture;
+use crate::policy::VsockPolicy;
+use crate::stats::DeviceStats;
 use crate::thread_backend::RawPktsQ;
 use crate::vhu_vsock_thread::*;
 
@@ -50,6 +54,8 @@ pub(crate) const VSOCK_HOST_CID: u64 = 2;
 
 /// Connection oriented packet
 pub(crate) const VSOCK_TYPE_STREAM: u16 = 1;
+/// Connection oriented packet preserving message boundaries
+pub(crate) const VSOCK_TYPE_SEQPACKET: u16 = 2;
 
 /// Vsock packet operation ID
 
@@ -74,6 +80,13 @@ pub(crate) const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
 pub(crate) const VSOCK_FLAGS_SHUTDOWN_RCV: u32 = 1;
 /// VSOCK_OP_SHUTDOWN: Packet sender will send no more data
 pub(crate) const VSOCK_FLAGS_SHUTDOWN_SEND: u32 = 2;
+/// VSOCK_OP_RW: Packet is the last one of a message (SOCK_SEQPACKET)
+pub(crate) const VSOCK_FLAGS_SEQ_EOM: u32 = 1;
+/// VSOCK_OP_RW: Message is the end of a record (SOCK_SEQPACKET)
+pub(crate) const VSOCK_FLAGS_SEQ_EOR: u32 = 2;
+
+/// Feature bit for SOCK_SEQPACKET support
+const VIRTIO_VSOCK_F_SEQPACKET: u64 = 1;
 
 // Queue mask to select vrings.
 const QUEUE_MASK: u64 = 0b11;
@@ -135,6 +148,14 @@ pub(crate) enum Error {
     EventFdCreate(std::io::Error),
     #[error("Raw vsock packets queue is empty")]
     EmptyRawPktsQueue,
+    #[error("Failed to listen on host vsock port {0}: {1}")]
+    VsockListen(u32, std::io::Error),
+    #[error("Failed to listen on host TCP address {0}: {1}")]
+    TcpListen(SocketAddr, std::io::Error),
+    #[error("Failed to create a TimerFd")]
+    TimerFdCreate(std::io::Error),
+    #[error("Failed to open the capture file {0}: {1}")]
+    CaptureOpen(String, std::io::Error),
 }
 
 impl std::convert::From<Error> for std::io::Error {
@@ -152,6 +173,11 @@ pub(crate) struct VsockConfig {
     uds_path: String,
     tx_buffer_size: u32,
     groups: Vec<String>,
+    vsock_listen: Vec<(u32, u32)>,
+    tcp_forward: Vec<(u32, SocketAddr)>,
+    tcp_listen: Vec<(u32, SocketAddr)>,
+    policy: VsockPolicy,
+    capture: Option<String>,
 }
 
 impl VsockConfig {
@@ -170,9 +196,42 @@ impl VsockConfig {
             uds_path,
             tx_buffer_size,
             groups,
+            vsock_listen: Vec::new(),
+            tcp_forward: Vec::new(),
+            tcp_listen: Vec::new(),
+            policy: VsockPolicy::default(),
+            capture: None,
         }
     }
 
+    /// Set the host AF_VSOCK ports to listen on, each with the guest port
+    /// its connections are bridged to.
+    pub fn set_vsock_listen(&mut self, vsock_listen: Vec<(u32, u32)>) {
+        self.vsock_listen = vsock_listen;
+    }
+
+    /// Set the guest ports whose connections are forwarded to host TCP
+    /// addresses, instead of the unix sockets at `{uds_path}_{port}`.
+    pub fn set_tcp_forward(&mut self, tcp_forward: Vec<(u32, SocketAddr)>) {
+        self.tcp_forward = tcp_forward;
+    }
+
+    /// Set the host TCP addresses to listen on, each with the guest port its
+    /// connections are bridged to.
+    pub fn set_tcp_listen(&mut self, tcp_listen: Vec<(u32, SocketAddr)>) {
+        self.tcp_listen = tcp_listen;
+    }
+
+    /// Set the access control policy of the device.
+    pub fn set_policy(&mut self, policy: VsockPolicy) {
+        self.policy = policy;
+    }
+
+    /// Set the pcap file the packets exchanged with the guest are captured to.
+    pub fn set_capture(&mut self, capture: Option<String>) {
+        self.capture = capture;
+    }
+
     /// Return the guest's current CID.
     pub fn get_guest_cid(&self) -> u64 {
         self.guest_cid
@@ -197,6 +256,26 @@ impl VsockConfig {
     pub fn get_groups(&self) -> Vec<String> {
         self.groups.clone()
     }
+
+    pub fn get_vsock_listen(&self) -> &[(u32, u32)] {
+        &self.vsock_listen
+    }
+
+    pub fn get_tcp_forward(&self) -> &[(u32, SocketAddr)] {
+        &self.tcp_forward
+    }
+
+    pub fn get_tcp_listen(&self) -> &[(u32, SocketAddr)] {
+        &self.tcp_listen
+    }
+
+    pub fn get_policy(&self) -> &VsockPolicy {
+        &self.policy
+    }
+
+    pub fn get_capture(&self) -> Option<&str> {
+        self.capture.as_deref()
+    }
 }
 
 /// A local port and peer port pair used to retrieve
@@ -236,13 +315,29 @@ pub(crate) struct VhostUserVsockBackend {
 
 impl VhostUserVsockBackend {
     pub fn new(config: VsockConfig, cid_map: Arc<RwLock<CidMap>>) -> Result<Self> {
-        let thread = Mutex::new(VhostUserVsockThread::new(
+        let mut thread = VhostUserVsockThread::new(
             config.get_uds_path(),
             config.get_guest_cid(),
             config.get_tx_buffer_size(),
             config.get_groups(),
             cid_map,
-        )?);
+        )?;
+        for &(host_port, guest_port) in config.get_vsock_listen() {
+            thread.add_vsock_listener(host_port, guest_port)?;
+        }
+        for &(guest_port, addr) in config.get_tcp_forward() {
+            thread.add_tcp_forward(guest_port, addr);
+        }
+        for &(guest_port, addr) in config.get_tcp_listen() {
+            thread.add_tcp_listener(addr, guest_port)?;
+        }
+        thread.set_policy(config.get_policy().clone());
+        if let Some(path) = config.get_capture() {
+            let capture =
+                PacketCapture::open(path).map_err(|e| Error::CaptureOpen(path.to_string(), e))?;
+            thread.set_capture(capture);
+        }
+        let thread = Mutex::new(thread);
         let queues_per_thread = vec![QUEUE_MASK];
 
         Ok(Self {
@@ -254,6 +349,12 @@ impl VhostUserVsockBackend {
             exit_event: EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?,
         })
     }
+
+    /// Snapshot of the state of the device and its connections.
+    pub fn stats(&self) -> DeviceStats {
+        // There is a single thread handling all the queues
+        self.threads[0].lock().unwrap().thread_backend.stats()
+    }
 }
 
 impl VhostUserBackend<VringRwLock, ()> for VhostUserVsockBackend {
@@ -269,6 +370,7 @@ impl VhostUserBackend<VringRwLock, ()> for VhostUserVsockBackend {
         1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_F_NOTIFY_ON_EMPTY
             | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_VSOCK_F_SEQPACKET
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
     }
 
@@ -411,6 +513,7 @@ mod tests {
         assert_eq!(backend.num_queues(), NUM_QUEUES);
         assert_eq!(backend.max_queue_size(), QUEUE_SIZE);
         assert_ne!(backend.features(), 0);
+        assert_ne!(backend.features() & (1 << VIRTIO_VSOCK_F_SEQPACKET), 0);
         assert!(!backend.protocol_features().is_empty());
         backend.set_event_idx(false);
 
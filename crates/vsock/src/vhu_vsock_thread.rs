@@ -1,22 +1,24 @@
 // SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::File,
     io,
-    io::Read,
+    io::{Read, Write},
     iter::FromIterator,
+    net::{SocketAddr, TcpListener},
     num::Wrapping,
     ops::Deref,
     os::unix::{
+        fs::MetadataExt,
         net::{UnixListener, UnixStream},
-        prelude::{AsRawFd, FromRawFd, RawFd},
+        prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd},
     },
     sync::{Arc, RwLock},
 };
 
 use futures::executor::{ThreadPool, ThreadPoolBuilder};
-use log::warn;
+use log::{info, warn};
 use vhost_user_backend::{VringEpollHandler, VringRwLock, VringT};
 use virtio_queue::QueueOwnedT;
 use virtio_vsock::packet::{VsockPacket, PKT_HEADER_SIZE};
@@ -24,14 +26,21 @@ use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::{
     epoll::EventSet,
     eventfd::{EventFd, EFD_NONBLOCK},
+    timerfd::TimerFd,
 };
 
 use crate::{
+    capture::PacketCapture,
+    host_stream::HostStream,
+    host_vsock,
+    policy::VsockPolicy,
     rxops::*,
+    seqpacket,
+    stats::DropReason,
     thread_backend::*,
     vhu_vsock::{
         CidMap, ConnMapKey, Error, Result, VhostUserVsockBackend, BACKEND_EVENT, SIBLING_VM_EVENT,
-        VSOCK_HOST_CID,
+        VSOCK_HOST_CID, VSOCK_TYPE_SEQPACKET, VSOCK_TYPE_STREAM,
     },
     vsock_conn::*,
 };
@@ -51,8 +60,25 @@ pub(crate) struct VhostUserVsockThread {
     host_sock: RawFd,
     /// Host socket path
     host_sock_path: String,
+    /// Device and inode of the host socket, to tell if the path still refers
+    /// to it.
+    host_sock_id: Option<(u64, u64)>,
     /// Listener listening for new connections on the host.
     host_listener: UnixListener,
+    /// Host SOCK_SEQPACKET socket raw file descriptor.
+    host_seqpacket_sock: RawFd,
+    /// Host SOCK_SEQPACKET socket path
+    host_seqpacket_sock_path: String,
+    /// Device and inode of the host SOCK_SEQPACKET socket.
+    host_seqpacket_sock_id: Option<(u64, u64)>,
+    /// Listener listening for new SOCK_SEQPACKET connections on the host.
+    host_seqpacket_listener: UnixListener,
+    /// Host AF_VSOCK listeners indexed by raw file descriptors, with the
+    /// guest port their connections are bridged to.
+    vsock_listeners: HashMap<RawFd, (OwnedFd, u32)>,
+    /// Host TCP listeners indexed by raw file descriptors, with the guest
+    /// port their connections are bridged to.
+    tcp_listeners: HashMap<RawFd, (TcpListener, u32)>,
     /// Instance of VringWorker.
     vring_worker: Option<Arc<VringEpollHandler<ArcVhostBknd, VringRwLock, ()>>>,
     /// epoll fd to which new host connections are added.
@@ -73,6 +99,9 @@ pub(crate) struct VhostUserVsockThread {
     /// Keeps track of which RX queue was processed first in the last iteration.
     /// Used to alternate between the RX queues to prevent the starvation of one by the other.
     last_processed: RxQueueType,
+    /// Timer armed while host initiated connections wait for the guest to
+    /// accept them.
+    request_timer: TimerFd,
 }
 
 impl VhostUserVsockThread {
@@ -90,6 +119,14 @@ impl VhostUserVsockThread {
             .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
             .map_err(Error::UnixBind)?;
 
+        let seqpacket_path = Self::seqpacket_path(&uds_path);
+        let _ = std::fs::remove_file(&seqpacket_path);
+        let seqpacket_sock = seqpacket::bind(&seqpacket_path)
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+            .map_err(Error::UnixBind)?;
+        let host_sock_id = socket_id(&uds_path);
+        let host_seqpacket_sock_id = socket_id(&seqpacket_path);
+
         let epoll_fd = epoll::create(true).map_err(Error::EpollFdCreate)?;
         // SAFETY: Safe as the fd is guaranteed to be valid here.
         let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
@@ -125,7 +162,14 @@ impl VhostUserVsockThread {
             event_idx: false,
             host_sock: host_sock.as_raw_fd(),
             host_sock_path: uds_path,
+            host_sock_id,
             host_listener: host_sock,
+            host_seqpacket_sock: seqpacket_sock.as_raw_fd(),
+            host_seqpacket_sock_path: seqpacket_path,
+            host_seqpacket_sock_id,
+            host_seqpacket_listener: seqpacket_sock,
+            vsock_listeners: HashMap::new(),
+            tcp_listeners: HashMap::new(),
             vring_worker: None,
             epoll_file,
             thread_backend,
@@ -138,13 +182,68 @@ impl VhostUserVsockThread {
             tx_buffer_size,
             sibling_event_fd,
             last_processed: RxQueueType::Standard,
+            request_timer: TimerFd::new().map_err(|e| Error::TimerFdCreate(e.into()))?,
         };
 
         VhostUserVsockThread::epoll_register(epoll_fd, host_raw_fd, epoll::Events::EPOLLIN)?;
+        VhostUserVsockThread::epoll_register(
+            epoll_fd,
+            thread.host_seqpacket_sock,
+            epoll::Events::EPOLLIN,
+        )?;
+        VhostUserVsockThread::epoll_register(
+            epoll_fd,
+            thread.request_timer.as_raw_fd(),
+            epoll::Events::EPOLLIN,
+        )?;
 
         Ok(thread)
     }
 
+    /// Listen on the host AF_VSOCK port `host_port`, and bridge connections
+    /// to it to `guest_port` of the guest.
+    pub fn add_vsock_listener(&mut self, host_port: u32, guest_port: u32) -> Result<()> {
+        let listener = host_vsock::bind(host_port).map_err(|e| Error::VsockListen(host_port, e))?;
+        let fd = listener.as_raw_fd();
+        Self::epoll_register(self.get_epoll_fd(), fd, epoll::Events::EPOLLIN)?;
+        self.vsock_listeners.insert(fd, (listener, guest_port));
+        Ok(())
+    }
+
+    /// Listen on the host TCP address `addr`, and bridge connections to it to
+    /// `guest_port` of the guest.
+    pub fn add_tcp_listener(&mut self, addr: SocketAddr, guest_port: u32) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+            .map_err(|e| Error::TcpListen(addr, e))?;
+        let fd = listener.as_raw_fd();
+        Self::epoll_register(self.get_epoll_fd(), fd, epoll::Events::EPOLLIN)?;
+        self.tcp_listeners.insert(fd, (listener, guest_port));
+        Ok(())
+    }
+
+    /// Forward guest connections to `guest_port` to the host TCP address
+    /// `addr`.
+    pub fn add_tcp_forward(&mut self, guest_port: u32, addr: SocketAddr) {
+        self.thread_backend.tcp_forward.insert(guest_port, addr);
+    }
+
+    /// Set the access control policy enforced on connections.
+    pub fn set_policy(&mut self, policy: VsockPolicy) {
+        self.thread_backend.policy = policy;
+    }
+
+    /// Capture the packets exchanged with the guest.
+    pub fn set_capture(&mut self, capture: PacketCapture) {
+        self.thread_backend.capture = Some(capture);
+    }
+
+    /// Path of the SOCK_SEQPACKET socket listening for host-side connections,
+    /// next to the SOCK_STREAM one at `uds_path`.
+    pub fn seqpacket_path(uds_path: &str) -> String {
+        format!("{}_seqpacket", uds_path)
+    }
+
     /// Register a file with an epoll to listen for events in evset.
     pub fn epoll_register(epoll_fd: RawFd, fd: RawFd, evset: epoll::Events) -> Result<()> {
         epoll::ctl(
@@ -189,26 +288,25 @@ impl VhostUserVsockThread {
         self.epoll_file.as_raw_fd()
     }
 
-    /// Set self's VringWorker.
+    /// Set self's VringWorker, or drop it with `None`. The worker refers to
+    /// the backend, so it has to be dropped for the backend to be.
     pub fn set_vring_worker(
         &mut self,
         vring_worker: Option<Arc<VringEpollHandler<ArcVhostBknd, VringRwLock, ()>>>,
     ) {
         self.vring_worker = vring_worker;
-        self.vring_worker
-            .as_ref()
-            .unwrap()
-            .register_listener(self.get_epoll_fd(), EventSet::IN, u64::from(BACKEND_EVENT))
-            .unwrap();
-        self.vring_worker
-            .as_ref()
-            .unwrap()
-            .register_listener(
-                self.sibling_event_fd.as_raw_fd(),
-                EventSet::IN,
-                u64::from(SIBLING_VM_EVENT),
-            )
-            .unwrap();
+        if let Some(vring_worker) = self.vring_worker.as_ref() {
+            vring_worker
+                .register_listener(self.get_epoll_fd(), EventSet::IN, u64::from(BACKEND_EVENT))
+                .unwrap();
+            vring_worker
+                .register_listener(
+                    self.sibling_event_fd.as_raw_fd(),
+                    EventSet::IN,
+                    u64::from(SIBLING_VM_EVENT),
+                )
+                .unwrap();
+        }
     }
 
     /// Process a BACKEND_EVENT received by VhostUserVsockBackend.
@@ -238,9 +336,24 @@ impl VhostUserVsockThread {
     /// Handle a BACKEND_EVENT by either accepting a new connection or
     /// forwarding a request to the appropriate connection object.
     fn handle_event(&mut self, fd: RawFd, evset: epoll::Events) {
-        if fd == self.host_sock {
+        if fd == self.request_timer.as_raw_fd() {
+            // Time to reset the connection requests the guest ignored
+            if let Err(err) = self.request_timer.wait() {
+                warn!("Could not read the connection request timer: {:?}", err);
+            }
+            if !self.thread_backend.reset_timed_out_conns() {
+                self.request_timer.clear().unwrap_or_else(|err| {
+                    warn!("Could not disarm the connection request timer: {:?}", err);
+                });
+            }
+        } else if fd == self.host_sock || fd == self.host_seqpacket_sock {
             // This is a new connection initiated by an application running on the host
-            let conn = self.host_listener.accept().map_err(Error::UnixAccept);
+            let listener = if fd == self.host_sock {
+                &self.host_listener
+            } else {
+                &self.host_seqpacket_listener
+            };
+            let conn = listener.accept().map_err(Error::UnixAccept);
             if self.mem.is_some() {
                 conn.and_then(|(stream, _)| {
                     stream
@@ -259,6 +372,24 @@ impl VhostUserVsockThread {
                     warn!("Error closing an incoming connection: {:?}", err);
                 });
             }
+        } else if let Some((listener, guest_port)) = self.vsock_listeners.get(&fd) {
+            // This is a new connection to a host vsock port bridged to the guest
+            let guest_port = *guest_port;
+            let stream = host_vsock::accept(listener).map(HostStream::from);
+            self.add_bridged_conn(stream, guest_port);
+        } else if let Some((listener, guest_port)) = self.tcp_listeners.get(&fd) {
+            // This is a new connection to a host TCP address bridged to the guest
+            let guest_port = *guest_port;
+            let stream = listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                Ok(HostStream::from(stream))
+            });
+            self.add_bridged_conn(stream, guest_port);
+        } else if self.thread_backend.tcp_connects.contains_key(&fd) {
+            // A connection to a TCP address the guest requested is
+            // established, or failed
+            self.thread_backend.finish_tcp_connect(fd);
         } else {
             // Check if the stream represented by fd has already established a
             // connection with the application running in the guest
@@ -270,7 +401,7 @@ impl VhostUserVsockThread {
                     // Has to be EPOLLIN as it was not connected previously
                     return;
                 }
-                let mut unix_stream = match self.thread_backend.stream_map.remove(&fd) {
+                let mut stream = match self.thread_backend.stream_map.remove(&fd) {
                     Some(uds) => uds,
                     None => {
                         warn!("Error while searching fd in the stream map");
@@ -278,8 +409,14 @@ impl VhostUserVsockThread {
                     }
                 };
 
+                let type_ = if seqpacket::is_seqpacket(fd) {
+                    VSOCK_TYPE_SEQPACKET
+                } else {
+                    VSOCK_TYPE_STREAM
+                };
+
                 // Local peer is sending a "connect PORT\n" command
-                let peer_port = match Self::read_local_stream_port(&mut unix_stream) {
+                let peer_port = match Self::read_local_stream_port(&mut stream, type_) {
                     Ok(port) => port,
                     Err(err) => {
                         warn!("Error while parsing \"connect PORT\n\" command: {:?}", err);
@@ -287,41 +424,16 @@ impl VhostUserVsockThread {
                     }
                 };
 
-                // Allocate a local port number
-                let local_port = match self.allocate_local_port() {
-                    Ok(lp) => lp,
-                    Err(err) => {
-                        warn!("Error while allocating local port: {:?}", err);
-                        return;
-                    }
-                };
-
-                // Insert the fd into the backend's maps
-                self.thread_backend
-                    .listener_map
-                    .insert(fd, ConnMapKey::new(local_port, peer_port));
-
-                // Create a new connection object an enqueue a connection request
-                // packet to be sent to the guest
-                let conn_map_key = ConnMapKey::new(local_port, peer_port);
-                let mut new_conn = VsockConnection::new_local_init(
-                    unix_stream,
-                    VSOCK_HOST_CID,
-                    local_port,
-                    self.guest_cid,
-                    peer_port,
-                    self.get_epoll_fd(),
-                    self.tx_buffer_size,
-                );
-                new_conn.rx_queue.enqueue(RxOps::Request);
-                new_conn.set_peer_port(peer_port);
-
-                // Add connection object into the backend's maps
-                self.thread_backend.conn_map.insert(conn_map_key, new_conn);
+                if !self.host_to_guest_allowed(peer_port) {
+                    // Tell the application why its stream gets closed
+                    let _ = stream.write_all(b"ERR permission denied\n");
+                    return;
+                }
 
-                self.thread_backend
-                    .backend_rxq
-                    .push_back(ConnMapKey::new(local_port, peer_port));
+                if let Err(err) = self.add_local_conn(stream, peer_port, type_, true) {
+                    warn!("Error while allocating local port: {:?}", err);
+                    return;
+                }
 
                 // Re-register the fd to listen for EPOLLIN and EPOLLOUT events
                 Self::epoll_modify(
@@ -337,7 +449,7 @@ impl VhostUserVsockThread {
 
                 if evset.bits() == epoll::Events::EPOLLOUT.bits() {
                     // Flush any remaining data from the tx buffer
-                    match conn.tx_buf.flush_to(&mut conn.stream) {
+                    match conn.flush_tx() {
                         Ok(cnt) => {
                             if cnt > 0 {
                                 conn.fwd_cnt += Wrapping(cnt as u32);
@@ -367,6 +479,103 @@ impl VhostUserVsockThread {
         }
     }
 
+    /// Bridge a connection accepted by a host AF_VSOCK or TCP listener to
+    /// `guest_port` of the guest.
+    fn add_bridged_conn(&mut self, stream: io::Result<HostStream>, guest_port: u32) {
+        let stream = stream.map_err(Error::UnixAccept);
+        if self.mem.is_some() && self.host_to_guest_allowed(guest_port) {
+            stream
+                .and_then(|stream| {
+                    self.add_local_conn(stream, guest_port, VSOCK_TYPE_STREAM, false)
+                })
+                .unwrap_or_else(|err| {
+                    warn!("Unable to accept new bridged connection: {:?}", err);
+                });
+        } else {
+            // If we aren't ready to process requests, or may not, accept and
+            // immediately close the connection.
+            stream.map(drop).unwrap_or_else(|err| {
+                warn!("Error closing an incoming connection: {:?}", err);
+            });
+        }
+    }
+
+    /// Check if the policy allows host-side applications to connect to
+    /// `peer_port` of the guest.
+    fn host_to_guest_allowed(&self, peer_port: u32) -> bool {
+        let allowed = self
+            .thread_backend
+            .policy
+            .host_to_guest
+            .allows(self.guest_cid, peer_port);
+        if !allowed {
+            info!(
+                "vsock: policy denies host connections to guest port: {:?}",
+                peer_port
+            );
+        }
+        allowed
+    }
+
+    /// Create a new connection object for a host-side stream connecting to
+    /// `peer_port` in the guest, and enqueue a connection request packet to
+    /// be sent to the guest.
+    fn add_local_conn(
+        &mut self,
+        stream: HostStream,
+        peer_port: u32,
+        type_: u16,
+        handshake: bool,
+    ) -> Result<()> {
+        // Allocate a local port number
+        let local_port = self.allocate_local_port()?;
+
+        // Insert the fd into the backend's maps
+        self.thread_backend
+            .listener_map
+            .insert(stream.as_raw_fd(), ConnMapKey::new(local_port, peer_port));
+
+        let conn_map_key = ConnMapKey::new(local_port, peer_port);
+        let mut new_conn = VsockConnection::new_local_init(
+            stream,
+            VSOCK_HOST_CID,
+            local_port,
+            self.guest_cid,
+            peer_port,
+            self.get_epoll_fd(),
+            self.tx_buffer_size,
+            type_,
+        );
+        new_conn.handshake = handshake;
+        new_conn.rx_queue.enqueue(RxOps::Request);
+        new_conn.set_peer_port(peer_port);
+
+        // Add connection object into the backend's maps
+        self.thread_backend.conn_map.insert(conn_map_key, new_conn);
+
+        self.thread_backend
+            .backend_rxq
+            .push_back(ConnMapKey::new(local_port, peer_port));
+
+        // Check regularly if the guest accepted the connection in time
+        self.arm_request_timer();
+
+        Ok(())
+    }
+
+    /// Start checking regularly for connection requests that timed out, if
+    /// we aren't already.
+    fn arm_request_timer(&mut self) {
+        if !self.request_timer.is_armed().unwrap_or(false) {
+            let interval = CONN_REQUEST_TIMEOUT / 4;
+            self.request_timer
+                .reset(interval, Some(interval))
+                .unwrap_or_else(|err| {
+                    warn!("Could not arm the connection request timer: {:?}", err);
+                });
+        }
+    }
+
     /// Allocate a new local port number.
     fn allocate_local_port(&mut self) -> Result<u32> {
         // TODO: Improve space efficiency of this operation
@@ -394,25 +603,31 @@ impl VhostUserVsockThread {
     }
 
     /// Read `CONNECT PORT_NUM\n` from the connected stream.
-    fn read_local_stream_port(stream: &mut UnixStream) -> Result<u32> {
+    fn read_local_stream_port(stream: &mut HostStream, type_: u16) -> Result<u32> {
         let mut buf = [0u8; 32];
 
         // Minimum number of bytes we should be able to read
         // Corresponds to 'CONNECT 0\n'
         const MIN_READ_LEN: usize = 10;
 
-        // Read in the minimum number of bytes we can read
-        stream
-            .read_exact(&mut buf[..MIN_READ_LEN])
-            .map_err(Error::UnixRead)?;
-
-        let mut read_len = MIN_READ_LEN;
-        while buf[read_len - 1] != b'\n' && read_len < buf.len() {
+        let read_len = if type_ == VSOCK_TYPE_SEQPACKET {
+            // The command is a message of its own
+            stream.read(&mut buf).map_err(Error::UnixRead)?
+        } else {
+            // Read in the minimum number of bytes we can read
             stream
-                .read_exact(&mut buf[read_len..read_len + 1])
+                .read_exact(&mut buf[..MIN_READ_LEN])
                 .map_err(Error::UnixRead)?;
-            read_len += 1;
-        }
+
+            let mut read_len = MIN_READ_LEN;
+            while buf[read_len - 1] != b'\n' && read_len < buf.len() {
+                stream
+                    .read_exact(&mut buf[read_len..read_len + 1])
+                    .map_err(Error::UnixRead)?;
+                read_len += 1;
+            }
+            read_len
+        };
 
         let mut word_iter = std::str::from_utf8(&buf[..read_len])
             .map_err(Error::ConvertFromUtf8)?
@@ -436,7 +651,9 @@ impl VhostUserVsockThread {
     /// Add a stream to epoll to listen for EPOLLIN events.
     fn add_stream_listener(&mut self, stream: UnixStream) -> Result<()> {
         let stream_fd = stream.as_raw_fd();
-        self.thread_backend.stream_map.insert(stream_fd, stream);
+        self.thread_backend
+            .stream_map
+            .insert(stream_fd, HostStream::from(stream));
         VhostUserVsockThread::epoll_register(
             self.get_epoll_fd(),
             stream_fd,
@@ -635,6 +852,9 @@ impl VhostUserVsockThread {
                 Ok(pkt) => pkt,
                 Err(e) => {
                     dbg!("vsock: error reading TX packet: {:?}", e);
+                    self.thread_backend
+                        .counters
+                        .record_drop(DropReason::InvalidPacket);
                     continue;
                 }
             };
@@ -700,24 +920,50 @@ impl VhostUserVsockThread {
         } else {
             self.process_tx_queue(vring_lock)?;
         }
+        if !self.thread_backend.tcp_connects.is_empty() {
+            // Check regularly if connections to TCP addresses the guest
+            // requested are established in time
+            self.arm_request_timer();
+        }
         Ok(false)
     }
 }
 
+/// The device and inode of the file at `path`, if there is one.
+fn socket_id(path: &str) -> Option<(u64, u64)> {
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+/// Remove the socket at `path`, unless it's no longer the one identified by
+/// `id`, i.e. a newer device of the same guest has bound it again.
+fn remove_socket(path: &str, id: Option<(u64, u64)>) {
+    if id.is_some() && socket_id(path) == id {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
 impl Drop for VhostUserVsockThread {
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.host_sock_path);
-        self.thread_backend
-            .cid_map
-            .write()
-            .unwrap()
-            .remove(&self.guest_cid);
+        remove_socket(&self.host_sock_path, self.host_sock_id);
+        remove_socket(&self.host_seqpacket_sock_path, self.host_seqpacket_sock_id);
+        // A new device of the same guest may have replaced this one already
+        let mut cid_map = self.thread_backend.cid_map.write().unwrap();
+        if let Some((raw_pkts_queue, _, _)) = cid_map.get(&self.guest_cid) {
+            if Arc::ptr_eq(raw_pkts_queue, &self.thread_backend.raw_pkts_queue) {
+                cid_map.remove(&self.guest_cid);
+            }
+        }
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::policy::Endpoint;
     use std::collections::HashMap;
+    use std::io::Write;
+    use std::time::Instant;
     use tempfile::tempdir;
     use vm_memory::GuestAddress;
     use vmm_sys_util::eventfd::EventFd;
@@ -802,6 +1048,203 @@ mod tests {
         test_dir.close().unwrap();
     }
 
+    #[test]
+    fn test_vsock_thread_seqpacket() {
+        let cid_map: Arc<RwLock<CidMap>> = Arc::new(RwLock::new(HashMap::new()));
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let vsock_socket_path = test_dir
+            .path()
+            .join("test_vsock_thread_seqpacket.vsock")
+            .display()
+            .to_string();
+
+        let t = VhostUserVsockThread::new(
+            vsock_socket_path.clone(),
+            3,
+            CONN_TX_BUF_SIZE,
+            vec![String::from("default")],
+            cid_map,
+        )
+        .unwrap();
+
+        // host-initiated connections send "CONNECT PORT\n" as a message of its own
+        let seqpacket_path = VhostUserVsockThread::seqpacket_path(&vsock_socket_path);
+        let mut client = seqpacket::connect(&seqpacket_path).unwrap();
+        let (stream, _) = t.host_seqpacket_listener.accept().unwrap();
+        let mut stream = HostStream::from(stream);
+        assert!(seqpacket::is_seqpacket(stream.as_raw_fd()));
+        client.write_all(b"CONNECT 1234\n").unwrap();
+        client.write_all(b"hello").unwrap();
+        assert_eq!(
+            VhostUserVsockThread::read_local_stream_port(&mut stream, VSOCK_TYPE_SEQPACKET)
+                .unwrap(),
+            1234
+        );
+        // the data after the command is left for the connection
+        let mut buf = [0u8; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        drop(t);
+        assert!(!std::path::Path::new(&seqpacket_path).exists());
+
+        test_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_vsock_thread_connect_errors() {
+        let cid_map: Arc<RwLock<CidMap>> = Arc::new(RwLock::new(HashMap::new()));
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let vsock_socket_path = test_dir
+            .path()
+            .join("test_vsock_thread_connect_errors.vsock")
+            .display()
+            .to_string();
+
+        let mut t = VhostUserVsockThread::new(
+            vsock_socket_path.clone(),
+            3,
+            CONN_TX_BUF_SIZE,
+            vec![String::from("default")],
+            cid_map,
+        )
+        .unwrap();
+        t.mem = Some(GuestMemoryAtomic::new(
+            GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+        ));
+
+        let mut client = UnixStream::connect(&vsock_socket_path).unwrap();
+        t.handle_event(t.host_sock, epoll::Events::EPOLLIN);
+        let fd = *t.thread_backend.stream_map.keys().next().unwrap();
+        client.write_all(b"CONNECT 1234\n").unwrap();
+        t.handle_event(fd, epoll::Events::EPOLLIN);
+        assert_eq!(t.thread_backend.conn_map.len(), 1);
+        assert!(t.request_timer.is_armed().unwrap());
+
+        // the policy may deny connecting to a port
+        t.thread_backend.policy.host_to_guest.deny = vec![Endpoint::port(4321)];
+        let mut denied = UnixStream::connect(&vsock_socket_path).unwrap();
+        t.handle_event(t.host_sock, epoll::Events::EPOLLIN);
+        let denied_fd = *t.thread_backend.stream_map.keys().next().unwrap();
+        denied.write_all(b"CONNECT 4321\n").unwrap();
+        t.handle_event(denied_fd, epoll::Events::EPOLLIN);
+        assert_eq!(t.thread_backend.conn_map.len(), 1);
+        let mut reply = String::new();
+        denied.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ERR permission denied\n");
+
+        // still waiting for the guest
+        assert!(t.thread_backend.reset_timed_out_conns());
+
+        // the guest took too long to accept
+        let conn = t.thread_backend.conn_map.values_mut().next().unwrap();
+        conn.connect_deadline = Some(Instant::now());
+        assert!(!t.thread_backend.reset_timed_out_conns());
+        let conn = t.thread_backend.conn_map.values().next().unwrap();
+        assert_eq!(conn.rx_queue.peek(), Some(RxOps::Reset));
+        let mut buf = [0u8; 25];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ERR connection timed out\n");
+
+        // the timer is disarmed once no request is pending
+        t.handle_event(t.request_timer.as_raw_fd(), epoll::Events::EPOLLIN);
+        assert!(!t.request_timer.is_armed().unwrap());
+
+        test_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_vsock_thread_tcp_listener() {
+        let cid_map: Arc<RwLock<CidMap>> = Arc::new(RwLock::new(HashMap::new()));
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let mut t = VhostUserVsockThread::new(
+            test_dir
+                .path()
+                .join("test_vsock_thread_tcp_listener.vsock")
+                .display()
+                .to_string(),
+            3,
+            CONN_TX_BUF_SIZE,
+            vec![String::from("default")],
+            cid_map,
+        )
+        .unwrap();
+        t.mem = Some(GuestMemoryAtomic::new(
+            GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+        ));
+
+        t.add_tcp_listener("127.0.0.1:0".parse().unwrap(), 22)
+            .unwrap();
+        let (&fd, (listener, _)) = t.tcp_listeners.iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the address is taken
+        assert!(t.add_tcp_listener(addr, 22).is_err());
+
+        // connections are bridged to the guest port, without "CONNECT PORT\n"
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        t.handle_event(fd, epoll::Events::EPOLLIN);
+        assert_eq!(t.thread_backend.conn_map.len(), 1);
+        let conn = t.thread_backend.conn_map.values().next().unwrap();
+        assert_eq!(conn.peer_port, 22);
+        assert!(!conn.handshake);
+        assert_eq!(conn.rx_queue.peek(), Some(RxOps::Request));
+
+        // the policy may deny them
+        t.thread_backend.policy.host_to_guest.deny = vec![Endpoint::port(22)];
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        t.handle_event(fd, epoll::Events::EPOLLIN);
+        assert_eq!(t.thread_backend.conn_map.len(), 1);
+
+        t.add_tcp_forward(80, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(
+            t.thread_backend.tcp_forward.get(&80),
+            Some(&"127.0.0.1:8080".parse().unwrap())
+        );
+
+        test_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_vsock_thread_vsock_listener() {
+        let cid_map: Arc<RwLock<CidMap>> = Arc::new(RwLock::new(HashMap::new()));
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let mut t = VhostUserVsockThread::new(
+            test_dir
+                .path()
+                .join("test_vsock_thread_vsock_listener.vsock")
+                .display()
+                .to_string(),
+            3,
+            CONN_TX_BUF_SIZE,
+            vec![String::from("default")],
+            cid_map,
+        )
+        .unwrap();
+
+        // without AF_VSOCK support, or its loopback transport, there's
+        // nothing to test
+        match t.add_vsock_listener(libc::VMADDR_PORT_ANY, 1234) {
+            Err(Error::VsockListen(_, e))
+                if e.raw_os_error() == Some(libc::EAFNOSUPPORT)
+                    || e.raw_os_error() == Some(libc::EADDRNOTAVAIL) => {}
+            res => {
+                res.unwrap();
+                assert_eq!(t.vsock_listeners.len(), 1);
+                let (&fd, (_, guest_port)) = t.vsock_listeners.iter().next().unwrap();
+                assert_eq!(*guest_port, 1234);
+
+                // nobody connected yet
+                t.mem = Some(GuestMemoryAtomic::new(
+                    GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+                ));
+                t.handle_event(fd, epoll::Events::EPOLLIN);
+                assert!(t.thread_backend.conn_map.is_empty());
+            }
+        }
+
+        test_dir.close().unwrap();
+    }
+
     #[test]
     fn test_vsock_thread_failures() {
         let groups: Vec<String> = vec![String::from("default")];
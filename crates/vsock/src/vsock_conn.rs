@@ -1,33 +1,46 @@
 // SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
 
 use std::{
+    collections::VecDeque,
     io::{ErrorKind, Read, Write},
     num::Wrapping,
     os::unix::prelude::{AsRawFd, RawFd},
+    time::{Duration, Instant},
 };
 
-use log::info;
+use log::{info, warn};
 use virtio_vsock::packet::{VsockPacket, PKT_HEADER_SIZE};
 use vm_memory::{bitmap::BitmapSlice, Bytes, VolatileSlice};
 
 use crate::{
     rxops::*,
     rxqueue::*,
+    stats::{ConnStats, DropReason},
     txbuf::*,
     vhu_vsock::{
-        Error, Result, VSOCK_FLAGS_SHUTDOWN_RCV, VSOCK_FLAGS_SHUTDOWN_SEND,
-        VSOCK_OP_CREDIT_REQUEST, VSOCK_OP_CREDIT_UPDATE, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE,
-        VSOCK_OP_RST, VSOCK_OP_RW, VSOCK_OP_SHUTDOWN, VSOCK_TYPE_STREAM,
+        Error, Result, VSOCK_FLAGS_SEQ_EOM, VSOCK_FLAGS_SEQ_EOR, VSOCK_FLAGS_SHUTDOWN_RCV,
+        VSOCK_FLAGS_SHUTDOWN_SEND, VSOCK_OP_CREDIT_REQUEST, VSOCK_OP_CREDIT_UPDATE,
+        VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE, VSOCK_OP_RST, VSOCK_OP_RW, VSOCK_OP_SHUTDOWN,
+        VSOCK_TYPE_SEQPACKET,
     },
     vhu_vsock_thread::VhostUserVsockThread,
 };
 
+/// Maximum size of a message read from a host-side SOCK_SEQPACKET socket.
+const MAX_MSG_SIZE: u32 = 1024 * 1024;
+
+/// Time the guest has to accept a host initiated connection.
+pub(crate) const CONN_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 pub(crate) struct VsockConnection<S> {
     /// Host-side stream corresponding to this vsock connection.
     pub stream: S,
     /// Specifies if the stream is connected to a listener on the host.
     pub connect: bool,
+    /// Specifies if the host-side application sent `CONNECT PORT\n`, and
+    /// expects `OK ...\n` once the guest accepts the connection.
+    pub handshake: bool,
     /// Port at which a guest application is listening to.
     pub peer_port: u32,
     /// Queue holding pending rx operations per connection.
@@ -54,11 +67,33 @@ pub(crate) struct VsockConnection<S> {
     pub tx_buf: LocalTxBuf,
     /// Local tx buffer size
     tx_buffer_size: u32,
+    /// Socket type, VSOCK_TYPE_STREAM or VSOCK_TYPE_SEQPACKET.
+    pub type_: u16,
+    /// SOCK_SEQPACKET: Fragments of the message being sent by the guest.
+    tx_msg: Vec<u8>,
+    /// SOCK_SEQPACKET: Messages waiting for the host-side socket to be writable.
+    tx_msgs: VecDeque<Vec<u8>>,
+    /// SOCK_SEQPACKET: Buffer holding the message being sent to the guest.
+    rx_msg: Vec<u8>,
+    /// SOCK_SEQPACKET: Length of the message in rx_msg.
+    rx_msg_len: usize,
+    /// SOCK_SEQPACKET: Number of bytes of rx_msg already sent to the guest.
+    rx_msg_sent: usize,
+    /// Time by which the guest has to accept a host initiated connection.
+    pub connect_deadline: Option<Instant>,
+    /// Total number of data bytes received from the guest.
+    bytes_from_guest: u64,
+    /// Total number of data bytes sent to the guest.
+    bytes_to_guest: u64,
+    /// Why the last packet from the guest was dropped, if it was. Taken by
+    /// the thread backend to count drops.
+    pub dropped: Option<DropReason>,
 }
 
 impl<S: AsRawFd + Read + Write> VsockConnection<S> {
     /// Create a new vsock connection object for locally i.e host-side
     /// inititated connections.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_local_init(
         stream: S,
         local_cid: u64,
@@ -67,10 +102,12 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
         guest_port: u32,
         epoll_fd: RawFd,
         tx_buffer_size: u32,
+        type_: u16,
     ) -> Self {
         Self {
             stream,
             connect: false,
+            handshake: true,
             peer_port: guest_port,
             rx_queue: RxQueue::new(),
             local_cid,
@@ -84,6 +121,16 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
             epoll_fd,
             tx_buf: LocalTxBuf::new(tx_buffer_size),
             tx_buffer_size,
+            type_,
+            tx_msg: Vec::new(),
+            tx_msgs: VecDeque::new(),
+            rx_msg: Vec::new(),
+            rx_msg_len: 0,
+            rx_msg_sent: 0,
+            connect_deadline: Some(Instant::now() + CONN_REQUEST_TIMEOUT),
+            bytes_from_guest: 0,
+            bytes_to_guest: 0,
+            dropped: None,
         }
     }
 
@@ -99,12 +146,14 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
         epoll_fd: RawFd,
         peer_buf_alloc: u32,
         tx_buffer_size: u32,
+        type_: u16,
     ) -> Self {
         let mut rx_queue = RxQueue::new();
         rx_queue.enqueue(RxOps::Response);
         Self {
             stream,
             connect: false,
+            handshake: false,
             peer_port: guest_port,
             rx_queue,
             local_cid,
@@ -118,6 +167,53 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
             epoll_fd,
             tx_buf: LocalTxBuf::new(tx_buffer_size),
             tx_buffer_size,
+            type_,
+            tx_msg: Vec::new(),
+            tx_msgs: VecDeque::new(),
+            rx_msg: Vec::new(),
+            rx_msg_len: 0,
+            rx_msg_sent: 0,
+            connect_deadline: None,
+            bytes_from_guest: 0,
+            bytes_to_guest: 0,
+            dropped: None,
+        }
+    }
+
+    /// Snapshot of the state of the connection, for the control socket.
+    pub fn stats(&self) -> ConnStats {
+        let rx_ops = [
+            (RxOps::Request, "request"),
+            (RxOps::Rw, "rw"),
+            (RxOps::Response, "response"),
+            (RxOps::CreditUpdate, "credit_update"),
+            (RxOps::Reset, "reset"),
+        ];
+        ConnStats {
+            local_port: self.local_port,
+            peer_port: self.peer_port,
+            type_: if self.type_ == VSOCK_TYPE_SEQPACKET {
+                "seqpacket"
+            } else {
+                "stream"
+            },
+            connected: self.connect,
+            fwd_cnt: self.fwd_cnt.0,
+            rx_cnt: self.rx_cnt.0,
+            peer_buf_alloc: self.peer_buf_alloc,
+            peer_fwd_cnt: self.peer_fwd_cnt.0,
+            peer_credit: self.peer_avail_credit() as u32,
+            tx_buf_len: self.tx_buf.len()
+                + self.tx_msg.len()
+                + self.tx_msgs.iter().map(Vec::len).sum::<usize>(),
+            tx_buf_size: self.tx_buffer_size,
+            pending_rx_ops: rx_ops
+                .iter()
+                .filter(|(op, _)| self.rx_queue.contains(op.bitmask()))
+                .map(|(_, name)| *name)
+                .collect(),
+            bytes_from_guest: self.bytes_from_guest,
+            bytes_to_guest: self.bytes_to_guest,
         }
     }
 
@@ -126,6 +222,26 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
         self.peer_port = peer_port;
     }
 
+    /// Check if the guest didn't accept this host initiated connection in
+    /// time.
+    pub fn request_timed_out(&self, now: Instant) -> bool {
+        !self.connect && matches!(self.connect_deadline, Some(deadline) if now >= deadline)
+    }
+
+    /// Tell the host-side application why its `CONNECT PORT\n` failed.
+    ///
+    /// Nothing is written to applications that didn't send the command, or
+    /// once the guest accepted the connection.
+    pub fn reply_error(&mut self, reason: &str) {
+        if !self.handshake || self.connect {
+            return;
+        }
+        let reply = format!("ERR {}\n", reason);
+        if let Err(err) = self.stream.write_all(reply.as_bytes()) {
+            warn!("Could not write to the host-side stream: {:?}", err);
+        }
+    }
+
     /// Process a vsock packet that is meant for this connection.
     /// Forward data to the host-side application if the vsock packet
     /// contains a RW operation.
@@ -153,6 +269,11 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
                     pkt.set_op(VSOCK_OP_CREDIT_REQUEST);
                     return Ok(());
                 }
+
+                if self.type_ == VSOCK_TYPE_SEQPACKET {
+                    return self.recv_msg_fragment(pkt);
+                }
+
                 let buf = pkt.data_slice().ok_or(Error::PktBufMissing)?;
 
                 // Perform a credit check to find the maximum read size. The read
@@ -183,6 +304,7 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
 
                     // Update the rx_cnt with the amount of data in the vsock packet.
                     self.rx_cnt += Wrapping(pkt.len());
+                    self.bytes_to_guest += u64::from(pkt.len());
                     self.last_fwd_cnt = self.fwd_cnt;
                 }
                 Ok(())
@@ -214,15 +336,67 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
         // Update peer credit information
         self.peer_buf_alloc = pkt.buf_alloc();
         self.peer_fwd_cnt = Wrapping(pkt.fwd_cnt());
+        self.dropped = None;
+        if pkt.op() == VSOCK_OP_RW {
+            self.bytes_from_guest += u64::from(pkt.len());
+        }
 
         match pkt.op() {
             VSOCK_OP_RESPONSE => {
                 // Confirmation for a host initiated connection
-                // TODO: Handle stream write error in a better manner
-                let response = format!("OK {}\n", self.peer_port);
-                self.stream.write_all(response.as_bytes()).unwrap();
+                if self.connect || self.rx_queue.contains(RxOps::Reset.bitmask()) {
+                    // Already confirmed, or timed out in the meantime
+                    return Ok(());
+                }
+                if self.handshake {
+                    // Tell the host-side application the port assigned to it
+                    let response = format!("OK {}\n", self.local_port);
+                    if let Err(err) = self.stream.write_all(response.as_bytes()) {
+                        warn!("Could not write to the host-side stream: {:?}", err);
+                        self.rx_queue.enqueue(RxOps::Reset);
+                        return Ok(());
+                    }
+                } else {
+                    // The stream is only polled once the guest accepted
+                    self.epoll_listen().unwrap_or_else(|err| {
+                        warn!("Could not add epoll listener: {:?}", err);
+                    });
+                }
                 self.connect = true;
             }
+            VSOCK_OP_RW if self.type_ == VSOCK_TYPE_SEQPACKET => {
+                if self.rx_queue.contains(RxOps::Reset.bitmask()) {
+                    // The rest of a message that couldn't be delivered
+                    return Ok(());
+                }
+                // A message can be split into several packets, the last of
+                // which has the EOM flag set
+                if let Some(buf) = pkt.data_slice() {
+                    if self.tx_msg.len() + buf.len() > self.tx_buffer_size as usize {
+                        // The guest ignored our credit. Delivering the rest of
+                        // the message would splice it, so reset the connection.
+                        warn!(
+                            "Message from guest exceeds the tx buffer (lp={}, pp={})",
+                            self.local_port, self.peer_port
+                        );
+                        self.dropped = Some(DropReason::TxBufFull);
+                        self.tx_msg.clear();
+                        self.rx_queue.enqueue(RxOps::Reset);
+                        return Ok(());
+                    }
+                    let start = self.tx_msg.len();
+                    self.tx_msg.resize(start + buf.len(), 0);
+                    buf.copy_to(&mut self.tx_msg[start..]);
+                }
+                if pkt.flags() & VSOCK_FLAGS_SEQ_EOM != 0 {
+                    let msg = std::mem::take(&mut self.tx_msg);
+                    if self.send_msg(msg).is_err() {
+                        self.dropped = Some(DropReason::WriteError);
+                        self.rx_queue.enqueue(RxOps::Reset);
+                        return Ok(());
+                    }
+                }
+            }
             VSOCK_OP_RW => {
                 // Data has to be written to the host-side stream
                 match pkt.data_slice() {
@@ -231,11 +405,16 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
                             "Dropping empty packet from guest (lp={}, pp={})",
                             self.local_port, self.peer_port
                         );
+                        self.dropped = Some(DropReason::EmptyPacket);
                         return Ok(());
                     }
                     Some(buf) => {
                         if let Err(err) = self.send_bytes(buf) {
                             // TODO: Terminate this connection
+                            self.dropped = Some(match err {
+                                Error::LocalTxBufFull => DropReason::TxBufFull,
+                                _ => DropReason::WriteError,
+                            });
                             dbg!("err:{:?}", err);
                             return Ok(());
                         }
@@ -245,6 +424,11 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
             VSOCK_OP_CREDIT_UPDATE => {
                 // Already updated the credit
 
+                if self.rx_msg_sent < self.rx_msg_len {
+                    // The rest of a message was waiting for credit
+                    self.rx_queue.enqueue(RxOps::Rw);
+                }
+
                 // Re-register the stream file descriptor for read and write events
                 if VhostUserVsockThread::epoll_modify(
                     self.epoll_fd,
@@ -270,7 +454,7 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
                 let recv_off = pkt.flags() & VSOCK_FLAGS_SHUTDOWN_RCV != 0;
                 let send_off = pkt.flags() & VSOCK_FLAGS_SHUTDOWN_SEND != 0;
 
-                if recv_off && send_off && self.tx_buf.is_empty() {
+                if recv_off && send_off && self.tx_buf.is_empty() && self.tx_msgs.is_empty() {
                     self.rx_queue.enqueue(RxOps::Reset);
                 }
             }
@@ -310,20 +494,7 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
         };
 
         if written_count > 0 {
-            // Increment forwarded count by number of bytes written to the stream
-            self.fwd_cnt += Wrapping(written_count as u32);
-
-            // At what point in available credits should we send a credit update.
-            // This is set to 1/4th of the tx buffer size. If we keep it too low,
-            // we will end up sending too many credit updates. If we keep it too
-            // high, we will end up sending too few credit updates and cause stalls.
-            // Stalls are more bad than too many credit updates.
-            let free_space = self
-                .tx_buffer_size
-                .wrapping_sub((self.fwd_cnt - self.last_fwd_cnt).0);
-            if free_space < self.tx_buffer_size / 4 {
-                self.rx_queue.enqueue(RxOps::CreditUpdate);
-            }
+            self.forwarded(written_count);
         }
 
         if written_count != buf.len() {
@@ -333,6 +504,148 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
         Ok(())
     }
 
+    /// Write a complete message from the guest to the host-side socket,
+    /// queueing it if the socket isn't writable.
+    ///
+    /// Returns:
+    /// - Err(Error::UnixWrite) if there was an error writing to the socket
+    fn send_msg(&mut self, msg: Vec<u8>) -> Result<()> {
+        if !self.tx_msgs.is_empty() {
+            // Keep the messages in order
+            self.tx_msgs.push_back(msg);
+            return Ok(());
+        }
+
+        match self.stream.write(&msg) {
+            Ok(_) => self.forwarded(msg.len()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => self.tx_msgs.push_back(msg),
+            Err(e) => {
+                warn!("Could not write message to the host-side stream: {:?}", e);
+                return Err(Error::UnixWrite);
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush buffered data to the host-side stream.
+    ///
+    /// Returns:
+    /// - Ok(cnt) where cnt is the number of bytes written to the stream
+    /// - Err(Error::LocalTxBufFlush) if there was an error writing to the stream
+    pub fn flush_tx(&mut self) -> Result<usize> {
+        if self.type_ != VSOCK_TYPE_SEQPACKET {
+            return self.tx_buf.flush_to(&mut self.stream);
+        }
+
+        let mut cnt = 0;
+        while let Some(msg) = self.tx_msgs.front() {
+            match self.stream.write(msg) {
+                Ok(_) => cnt += self.tx_msgs.pop_front().unwrap().len(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(Error::LocalTxBufFlush(e)),
+            }
+        }
+        Ok(cnt)
+    }
+
+    /// Account for `cnt` bytes written to the host-side stream.
+    fn forwarded(&mut self, cnt: usize) {
+        // Increment forwarded count by number of bytes written to the stream
+        self.fwd_cnt += Wrapping(cnt as u32);
+
+        // At what point in available credits should we send a credit update.
+        // This is set to 1/4th of the tx buffer size. If we keep it too low,
+        // we will end up sending too many credit updates. If we keep it too
+        // high, we will end up sending too few credit updates and cause stalls.
+        // Stalls are more bad than too many credit updates.
+        let free_space = self
+            .tx_buffer_size
+            .wrapping_sub((self.fwd_cnt - self.last_fwd_cnt).0);
+        if free_space < self.tx_buffer_size / 4 {
+            self.rx_queue.enqueue(RxOps::CreditUpdate);
+        }
+    }
+
+    /// Fill a packet with the next fragment of the message read from the
+    /// host-side SOCK_SEQPACKET socket, reading a new message if the last one
+    /// has been sent. The last fragment of a message has the EOM and EOR flags
+    /// set, as every message is a record.
+    fn recv_msg_fragment<B: BitmapSlice>(&mut self, pkt: &mut VsockPacket<B>) -> Result<()> {
+        if self.rx_msg_sent == self.rx_msg_len {
+            // Messages can't be larger than the guest's buffer. Read one byte
+            // more than that to detect longer messages.
+            let max_len = std::cmp::min(self.peer_buf_alloc, MAX_MSG_SIZE) as usize;
+            self.rx_msg.resize(max_len + 1, 0);
+            match self.stream.read(&mut self.rx_msg) {
+                Ok(0) => {
+                    // The socket was closed down. Send a shutdown packet to
+                    // the guest-side application.
+                    pkt.set_op(VSOCK_OP_SHUTDOWN)
+                        .set_flag(VSOCK_FLAGS_SHUTDOWN_RCV)
+                        .set_flag(VSOCK_FLAGS_SHUTDOWN_SEND);
+                    return Ok(());
+                }
+                Ok(len) if len <= max_len => {
+                    self.rx_msg_len = len;
+                    self.rx_msg_sent = 0;
+                }
+                res => {
+                    if let Ok(len) = res {
+                        warn!(
+                            "Dropping message of at least {} bytes, too long for the guest (lp={}, pp={})",
+                            len, self.local_port, self.peer_port
+                        );
+                    }
+                    // Waste the rx buffer and wait for the next message
+                    pkt.set_op(VSOCK_OP_CREDIT_UPDATE);
+                    self.last_fwd_cnt = self.fwd_cnt;
+                    return self.epoll_listen();
+                }
+            }
+        }
+
+        let buf = pkt.data_slice().ok_or(Error::PktBufMissing)?;
+        let len = std::cmp::min(
+            std::cmp::min(buf.len(), self.peer_avail_credit()),
+            self.rx_msg_len - self.rx_msg_sent,
+        );
+        buf.copy_from(&self.rx_msg[self.rx_msg_sent..self.rx_msg_sent + len]);
+        self.rx_msg_sent += len;
+        pkt.set_op(VSOCK_OP_RW).set_len(len as u32);
+
+        // Update the rx_cnt with the amount of data in the vsock packet.
+        self.rx_cnt += Wrapping(len as u32);
+        self.bytes_to_guest += len as u64;
+        self.last_fwd_cnt = self.fwd_cnt;
+
+        if self.rx_msg_sent < self.rx_msg_len {
+            // Send the next fragment
+            self.rx_queue.enqueue(RxOps::Rw);
+            return Ok(());
+        }
+
+        pkt.set_flag(VSOCK_FLAGS_SEQ_EOM)
+            .set_flag(VSOCK_FLAGS_SEQ_EOR);
+        self.epoll_listen()
+    }
+
+    /// Listen for read and write events on the stream, whether or not it is
+    /// still registered with the epoll.
+    fn epoll_listen(&self) -> Result<()> {
+        VhostUserVsockThread::epoll_modify(
+            self.epoll_fd,
+            self.stream.as_raw_fd(),
+            epoll::Events::EPOLLIN | epoll::Events::EPOLLOUT,
+        )
+        .or_else(|_| {
+            VhostUserVsockThread::epoll_register(
+                self.epoll_fd,
+                self.stream.as_raw_fd(),
+                epoll::Events::EPOLLIN | epoll::Events::EPOLLOUT,
+            )
+        })
+    }
+
     /// Initialize all header fields in the vsock packet.
     fn init_pkt<'a, 'b, B: BitmapSlice>(
         &self,
@@ -345,7 +658,7 @@ impl<S: AsRawFd + Read + Write> VsockConnection<S> {
             .set_dst_cid(self.guest_cid)
             .set_src_port(self.local_port)
             .set_dst_port(self.peer_port)
-            .set_type(VSOCK_TYPE_STREAM)
+            .set_type(self.type_)
             .set_buf_alloc(self.tx_buffer_size)
             .set_fwd_cnt(self.fwd_cnt.0)
     }
@@ -368,9 +681,12 @@ mod tests {
     use byteorder::{ByteOrder, LittleEndian};
 
     use super::*;
+    use crate::seqpacket;
     use crate::vhu_vsock::{VSOCK_HOST_CID, VSOCK_OP_RW, VSOCK_TYPE_STREAM};
     use std::io::Result as IoResult;
     use std::ops::Deref;
+    use std::os::unix::net::UnixStream;
+    use tempfile::tempdir;
     use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
     use virtio_queue::{mock::MockSplitQueue, Descriptor, DescriptorChain, Queue, QueueOwnedT};
     use vm_memory::{
@@ -515,9 +831,11 @@ mod tests {
             5001,
             -1,
             CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
         );
 
         assert!(!conn_local.connect);
+        assert!(conn_local.handshake);
         assert_eq!(conn_local.peer_port, 5001);
         assert_eq!(conn_local.rx_queue, RxQueue::new());
         assert_eq!(conn_local.local_cid, VSOCK_HOST_CID);
@@ -539,6 +857,7 @@ mod tests {
             -1,
             65536,
             CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
         );
 
         assert!(!conn_peer.connect);
@@ -563,6 +882,7 @@ mod tests {
             5001,
             -1,
             CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
         );
 
         assert_eq!(conn_local.peer_avail_credit(), 0);
@@ -596,6 +916,7 @@ mod tests {
             5001,
             -1,
             CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
         );
 
         // write only descriptor chain
@@ -632,6 +953,7 @@ mod tests {
             5001,
             -1,
             CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
         );
 
         // write only descriptor chain
@@ -728,6 +1050,7 @@ mod tests {
             5001,
             -1,
             CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
         );
 
         // write only descriptor chain
@@ -753,7 +1076,19 @@ mod tests {
         assert!(conn_local.connect);
         let mut resp_buf = vec![0; 8];
         conn_local.stream.read_exact(&mut resp_buf).unwrap();
-        assert_eq!(resp_buf, b"OK 5001\n");
+        assert_eq!(resp_buf, b"OK 5000\n");
+
+        // the connection is only confirmed once
+        conn_local.stream.data.clear();
+        conn_local.send_pkt(&pkt).unwrap();
+        assert!(conn_local.stream.data.is_empty());
+
+        // no reply for host-side applications that didn't send "CONNECT PORT\n"
+        conn_local.connect = false;
+        conn_local.handshake = false;
+        conn_local.send_pkt(&pkt).unwrap();
+        assert!(conn_local.connect);
+        assert!(conn_local.stream.data.is_empty());
 
         // VSOCK_OP_RW
         pkt.set_op(VSOCK_OP_RW);
@@ -764,6 +1099,12 @@ mod tests {
         let mut resp_buf = vec![0; 5];
         conn_local.stream.read_exact(&mut resp_buf).unwrap();
         assert_eq!(resp_buf, b"hello");
+        assert!(conn_local.dropped.is_none());
+        let stats = conn_local.stats();
+        assert_eq!(stats.bytes_from_guest, 5);
+        assert_eq!(stats.fwd_cnt, 5);
+        assert_eq!(stats.tx_buf_len, 0);
+        assert!(stats.connected);
 
         // VSOCK_OP_CREDIT_REQUEST
         pkt.set_op(VSOCK_OP_CREDIT_REQUEST);
@@ -778,4 +1119,220 @@ mod tests {
         assert!(shutdown_response.is_ok());
         assert!(conn_local.rx_queue.contains(RxOps::Reset.bitmask()));
     }
+
+    #[test]
+    fn test_vsock_conn_request_failure() {
+        let mut conn_local = VsockConnection::new_local_init(
+            VsockDummySocket::new(),
+            VSOCK_HOST_CID,
+            5000,
+            3,
+            5001,
+            -1,
+            CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
+        );
+        let conn_peer = VsockConnection::new_peer_init(
+            VsockDummySocket::new(),
+            VSOCK_HOST_CID,
+            5000,
+            3,
+            5001,
+            -1,
+            65536,
+            CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_STREAM,
+        );
+
+        // only host initiated connections time out
+        let now = Instant::now();
+        assert!(!conn_local.request_timed_out(now));
+        assert!(conn_local.request_timed_out(now + CONN_REQUEST_TIMEOUT));
+        assert!(!conn_peer.request_timed_out(now + CONN_REQUEST_TIMEOUT));
+
+        conn_local.reply_error("connection refused");
+        assert_eq!(conn_local.stream.data, b"ERR connection refused\n");
+
+        // nothing for confirmed connections, or without "CONNECT PORT\n"
+        conn_local.stream.data.clear();
+        conn_local.connect = true;
+        conn_local.reply_error("connection refused");
+        assert!(!conn_local.request_timed_out(now + CONN_REQUEST_TIMEOUT));
+        conn_local.connect = false;
+        conn_local.handshake = false;
+        conn_local.reply_error("connection refused");
+        assert!(conn_local.stream.data.is_empty());
+    }
+
+    #[test]
+    fn test_vsock_conn_seqpacket() {
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let path = test_dir.path().join("test_vsock_conn_seqpacket.vsock_5000");
+        let path = path.to_str().unwrap();
+        let listener = seqpacket::bind(path).unwrap();
+        let stream = seqpacket::connect(path).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (mut host_app, _) = listener.accept().unwrap();
+        host_app.set_nonblocking(true).unwrap();
+        let epoll_fd = epoll::create(false).unwrap();
+
+        let mut conn: VsockConnection<UnixStream> = VsockConnection::new_peer_init(
+            stream,
+            VSOCK_HOST_CID,
+            5000,
+            3,
+            5001,
+            epoll_fd,
+            8,
+            CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_SEQPACKET,
+        );
+        assert_eq!(conn.rx_queue.dequeue(), Some(RxOps::Response));
+        conn.connect = true;
+
+        // a message from the guest, in two packets
+        let head_params = HeadParams::new(PKT_HEADER_SIZE, 5);
+        let (mem, mut descr_chain) = prepare_desc_chain_vsock(false, &head_params, 1, 5);
+        let mem = mem.memory();
+        let mut pkt =
+            VsockPacket::from_tx_virtq_chain(mem.deref(), &mut descr_chain, CONN_TX_BUF_SIZE)
+                .unwrap();
+        pkt.set_op(VSOCK_OP_RW).set_buf_alloc(8);
+        pkt.data_slice().unwrap().copy_from(b"hello");
+        conn.send_pkt(&pkt).unwrap();
+        let mut buf = [0u8; 32];
+        assert_eq!(
+            host_app.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        pkt.set_flags(VSOCK_FLAGS_SEQ_EOM);
+        pkt.data_slice().unwrap().copy_from(b"world");
+        conn.send_pkt(&pkt).unwrap();
+        assert_eq!(host_app.read(&mut buf).unwrap(), 10);
+        assert_eq!(&buf[..10], b"helloworld");
+        assert_eq!(conn.fwd_cnt, Wrapping(10));
+
+        let (mem, mut descr_chain) = prepare_desc_chain_vsock(true, &head_params, 1, 5);
+        let mem = mem.memory();
+        let mut pkt =
+            VsockPacket::from_rx_virtq_chain(mem.deref(), &mut descr_chain, CONN_TX_BUF_SIZE)
+                .unwrap();
+
+        // messages longer than the guest's buffer are dropped
+        host_app.write_all(b"too long message").unwrap();
+        conn.rx_queue.enqueue(RxOps::Rw);
+        conn.recv_pkt(&mut pkt).unwrap();
+        assert_eq!(pkt.op(), VSOCK_OP_CREDIT_UPDATE);
+        assert!(!conn.rx_queue.pending_rx());
+
+        // a message to the guest, split into fragments that fit its rx
+        // buffers, the last of them marked as the end of the message
+        conn.peer_buf_alloc = 65536;
+        host_app.write_all(b"hello world!").unwrap();
+        conn.rx_queue.enqueue(RxOps::Rw);
+        let mut msg = Vec::new();
+        for (len, flags) in [
+            (5, 0),
+            (5, 0),
+            (2, VSOCK_FLAGS_SEQ_EOM | VSOCK_FLAGS_SEQ_EOR),
+        ] {
+            conn.recv_pkt(&mut pkt).unwrap();
+            assert_eq!(pkt.op(), VSOCK_OP_RW);
+            assert_eq!(pkt.type_(), VSOCK_TYPE_SEQPACKET);
+            assert_eq!(pkt.len(), len);
+            assert_eq!(pkt.flags(), flags);
+            let mut data = vec![0u8; len as usize];
+            pkt.data_slice().unwrap().copy_to(&mut data[..]);
+            msg.extend(data);
+        }
+        assert_eq!(msg, b"hello world!");
+        assert!(!conn.rx_queue.pending_rx());
+        assert_eq!(conn.rx_cnt, Wrapping(12));
+
+        // the host-side application closed the socket
+        drop(host_app);
+        conn.rx_queue.enqueue(RxOps::Rw);
+        conn.recv_pkt(&mut pkt).unwrap();
+        assert_eq!(pkt.op(), VSOCK_OP_SHUTDOWN);
+
+        test_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_vsock_conn_seqpacket_reset() {
+        let test_dir = tempdir().expect("Could not create a temp test directory.");
+        let path = test_dir
+            .path()
+            .join("test_vsock_conn_seqpacket_reset.vsock_5000");
+        let path = path.to_str().unwrap();
+        let listener = seqpacket::bind(path).unwrap();
+        let epoll_fd = epoll::create(false).unwrap();
+
+        let head_params = HeadParams::new(PKT_HEADER_SIZE, 5);
+        let (mem, mut descr_chain) = prepare_desc_chain_vsock(false, &head_params, 1, 5);
+        let mem = mem.memory();
+        let mut pkt =
+            VsockPacket::from_tx_virtq_chain(mem.deref(), &mut descr_chain, CONN_TX_BUF_SIZE)
+                .unwrap();
+        pkt.set_op(VSOCK_OP_RW).set_buf_alloc(8);
+        pkt.data_slice().unwrap().copy_from(b"hello");
+
+        // a message exceeding the tx buffer resets the connection, instead
+        // of being delivered without the fragment that didn't fit
+        let stream = seqpacket::connect(path).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (mut host_app, _) = listener.accept().unwrap();
+        host_app.set_nonblocking(true).unwrap();
+        let mut conn: VsockConnection<UnixStream> = VsockConnection::new_peer_init(
+            stream,
+            VSOCK_HOST_CID,
+            5000,
+            3,
+            5001,
+            epoll_fd,
+            8,
+            8,
+            VSOCK_TYPE_SEQPACKET,
+        );
+        assert_eq!(conn.rx_queue.dequeue(), Some(RxOps::Response));
+        conn.connect = true;
+
+        conn.send_pkt(&pkt).unwrap();
+        assert!(!conn.rx_queue.contains(RxOps::Reset.bitmask()));
+        conn.send_pkt(&pkt).unwrap();
+        assert!(conn.rx_queue.contains(RxOps::Reset.bitmask()));
+        assert_eq!(conn.dropped, Some(DropReason::TxBufFull));
+        assert!(conn.tx_msg.is_empty());
+        pkt.set_flags(VSOCK_FLAGS_SEQ_EOM);
+        conn.send_pkt(&pkt).unwrap();
+        let mut buf = [0u8; 32];
+        assert_eq!(
+            host_app.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        // so does a message that can't be written to the host-side socket
+        let stream = seqpacket::connect(path).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (host_app, _) = listener.accept().unwrap();
+        let mut conn: VsockConnection<UnixStream> = VsockConnection::new_peer_init(
+            stream,
+            VSOCK_HOST_CID,
+            5000,
+            3,
+            5001,
+            epoll_fd,
+            8,
+            CONN_TX_BUF_SIZE,
+            VSOCK_TYPE_SEQPACKET,
+        );
+        assert_eq!(conn.rx_queue.dequeue(), Some(RxOps::Response));
+        conn.connect = true;
+        drop(host_app);
+        conn.send_pkt(&pkt).unwrap();
+        assert!(conn.rx_queue.contains(RxOps::Reset.bitmask()));
+        assert_eq!(conn.dropped, Some(DropReason::WriteError));
+
+        test_dir.close().unwrap();
+    }
 }